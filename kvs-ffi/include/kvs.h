@@ -0,0 +1,54 @@
+/* C bindings for embedding a kvs engine, see kvs-ffi/src/lib.rs. */
+
+#ifndef KVS_H
+#define KVS_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Success */
+#define KVS_OK 0
+/* The key does not exist */
+#define KVS_ERR_NOT_FOUND 1
+/* A null pointer, or a string that is not UTF-8 */
+#define KVS_ERR_INVALID_ARGUMENT 2
+/* An IO error */
+#define KVS_ERR_IO 3
+/* The data directory holds data of an engine this library cannot open */
+#define KVS_ERR_WRONG_ENGINE 4
+/* Any other error */
+#define KVS_ERR_OTHER 5
+
+/* An open store. Not thread-safe: use one handle per thread, or lock. */
+typedef struct KvsHandle KvsHandle;
+
+/* Open the store in directory `path` into `*handle`. */
+int kvs_open(const char *path, KvsHandle **handle);
+
+/* Get the value of `key` into `*value`, to be released with
+ * kvs_free_string. A missing key is KVS_ERR_NOT_FOUND, with `*value` set
+ * to NULL. */
+int kvs_get(KvsHandle *handle, const char *key, char **value);
+
+/* Set the value of `key`. */
+int kvs_set(KvsHandle *handle, const char *key, const char *value);
+
+/* Remove `key`, KVS_ERR_NOT_FOUND if it does not exist. */
+int kvs_remove(KvsHandle *handle, const char *key);
+
+/* Close a store opened by kvs_open. Closing NULL does nothing. */
+void kvs_close(KvsHandle *handle);
+
+/* Release a value returned by kvs_get. Releasing NULL does nothing. */
+void kvs_free_string(char *value);
+
+/* The message of the last failure on the calling thread, or NULL. It stays
+ * valid until the next call on the thread. */
+const char *kvs_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KVS_H */
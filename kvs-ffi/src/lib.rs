@@ -0,0 +1,232 @@
+//! C bindings for embedding a kvs engine, without the network hop.
+//!
+//! Every function returns one of the `KVS_*` codes, with `KVS_OK` on
+//! success; `kvs_last_error` describes the last failure on the calling
+//! thread. See `include/kvs.h` for the C declarations.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::path::Path;
+use std::ptr;
+
+use kvs::{EngineKind, KvStore, KvsEngine, KvsError};
+
+/// Success
+pub const KVS_OK: c_int = 0;
+/// The key does not exist
+pub const KVS_ERR_NOT_FOUND: c_int = 1;
+/// A null pointer, or a string that is not UTF-8
+pub const KVS_ERR_INVALID_ARGUMENT: c_int = 2;
+/// An IO error
+pub const KVS_ERR_IO: c_int = 3;
+/// The data directory holds data of an engine this library cannot open
+pub const KVS_ERR_WRONG_ENGINE: c_int = 4;
+/// Any other error
+pub const KVS_ERR_OTHER: c_int = 5;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An open store, handed out by `kvs_open` and released by `kvs_close`.
+pub struct KvsHandle {
+    engine: Box<dyn KvsEngine>,
+}
+
+/// Open the store in directory `path` into `*handle`, with the engine its
+/// data was written by.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string and `handle` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char, handle: *mut *mut KvsHandle) -> c_int {
+    if handle.is_null() {
+        return invalid("handle is null");
+    }
+    let path = match str_arg(path) {
+        Ok(path) => path,
+        Err(code) => return code,
+    };
+    match open(Path::new(path)) {
+        Ok(engine) => {
+            *handle = Box::into_raw(Box::new(KvsHandle { engine }));
+            KVS_OK
+        }
+        Err(e) => fail(e),
+    }
+}
+
+/// Get the value of `key` into `*value`, to be released with
+/// `kvs_free_string`. A missing key is `KVS_ERR_NOT_FOUND`, with `*value`
+/// set to null.
+///
+/// # Safety
+///
+/// `handle` must come from `kvs_open`, `key` must be a NUL-terminated
+/// string and `value` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    handle: *mut KvsHandle,
+    key: *const c_char,
+    value: *mut *mut c_char,
+) -> c_int {
+    let Some(handle) = handle.as_mut() else {
+        return invalid("handle is null");
+    };
+    if value.is_null() {
+        return invalid("value is null");
+    }
+    *value = ptr::null_mut();
+    let key = match str_arg(key) {
+        Ok(key) => key,
+        Err(code) => return code,
+    };
+    match handle.engine.get(key.to_owned()) {
+        Ok(Some(found)) => match CString::new(found) {
+            Ok(found) => {
+                *value = found.into_raw();
+                KVS_OK
+            }
+            Err(_) => fail(KvsError::InvalidValue(
+                "the value holds a NUL byte".to_owned(),
+            )),
+        },
+        Ok(None) => fail(KvsError::KeyNotFound),
+        Err(e) => fail(e),
+    }
+}
+
+/// Set the value of `key`.
+///
+/// # Safety
+///
+/// `handle` must come from `kvs_open`, `key` and `value` must be
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    handle: *mut KvsHandle,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    let Some(handle) = handle.as_mut() else {
+        return invalid("handle is null");
+    };
+    let (key, value) = match (str_arg(key), str_arg(value)) {
+        (Ok(key), Ok(value)) => (key, value),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+    match handle.engine.set(key.to_owned(), value.to_owned()) {
+        Ok(()) => KVS_OK,
+        Err(e) => fail(e),
+    }
+}
+
+/// Remove `key`, `KVS_ERR_NOT_FOUND` if it does not exist.
+///
+/// # Safety
+///
+/// `handle` must come from `kvs_open` and `key` must be a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(handle: *mut KvsHandle, key: *const c_char) -> c_int {
+    let Some(handle) = handle.as_mut() else {
+        return invalid("handle is null");
+    };
+    let key = match str_arg(key) {
+        Ok(key) => key,
+        Err(code) => return code,
+    };
+    match handle.engine.remove(key.to_owned()) {
+        Ok(()) => KVS_OK,
+        Err(e) => fail(e),
+    }
+}
+
+/// Close a store opened by `kvs_open`. Closing null does nothing.
+///
+/// # Safety
+///
+/// `handle` must come from `kvs_open`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(handle: *mut KvsHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Release a value returned by `kvs_get`. Releasing null does nothing.
+///
+/// # Safety
+///
+/// `value` must come from `kvs_get`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free_string(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// The message of the last failure on the calling thread, or null. It
+/// stays valid until the next call on the thread.
+#[no_mangle]
+pub extern "C" fn kvs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+// the engine of the data in `path`, its marker when it holds none yet
+fn open(path: &Path) -> kvs::Result<Box<dyn KvsEngine>> {
+    let engine = match EngineKind::detect(path)? {
+        Some(engine) => Some(engine),
+        None => EngineKind::from_marker(path)?,
+    };
+    match engine {
+        #[cfg(feature = "engine-sled")]
+        Some(EngineKind::Sled) => Ok(Box::new(kvs::SledStore::new(sled::open(path)?))),
+        #[cfg(not(feature = "engine-sled"))]
+        Some(EngineKind::Sled) => Err(KvsError::WrongEngine {
+            expected: EngineKind::Kvs,
+            found: EngineKind::Sled,
+        }),
+        Some(EngineKind::Kvs) | None => Ok(Box::new(KvStore::open(path)?)),
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(invalid("string argument is null"));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| invalid("string argument is not UTF-8"))
+}
+
+fn invalid(message: &str) -> c_int {
+    set_last_error(message.to_owned());
+    KVS_ERR_INVALID_ARGUMENT
+}
+
+fn fail(err: KvsError) -> c_int {
+    let root = match &err {
+        KvsError::Context { source, .. } => source,
+        err => err,
+    };
+    let code = match root {
+        KvsError::KeyNotFound => KVS_ERR_NOT_FOUND,
+        KvsError::Io(_) => KVS_ERR_IO,
+        KvsError::WrongEngine { .. } => KVS_ERR_WRONG_ENGINE,
+        _ => KVS_ERR_OTHER,
+    };
+    set_last_error(err.to_string());
+    code
+}
+
+fn set_last_error(message: String) {
+    // messages come from Rust strings, which may hold NUL bytes
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
@@ -0,0 +1,75 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use kvs_ffi::*;
+use tempfile::TempDir;
+
+fn c(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+// Should store values through the C API and keep them across reopening.
+#[test]
+fn set_get_remove_across_reopen() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = c(temp_dir.path().to_str().unwrap());
+    unsafe {
+        let mut handle = ptr::null_mut();
+        assert_eq!(kvs_open(path.as_ptr(), &mut handle), KVS_OK);
+        assert_eq!(
+            kvs_set(handle, c("key1").as_ptr(), c("value1").as_ptr()),
+            KVS_OK
+        );
+        assert_eq!(
+            kvs_set(handle, c("key2").as_ptr(), c("value2").as_ptr()),
+            KVS_OK
+        );
+        assert_eq!(kvs_remove(handle, c("key2").as_ptr()), KVS_OK);
+        assert_eq!(kvs_remove(handle, c("key2").as_ptr()), KVS_ERR_NOT_FOUND);
+        assert_eq!(
+            CStr::from_ptr(kvs_last_error()).to_str().unwrap(),
+            "Key not found"
+        );
+        kvs_close(handle);
+
+        let mut handle = ptr::null_mut();
+        assert_eq!(kvs_open(path.as_ptr(), &mut handle), KVS_OK);
+        let mut value = ptr::null_mut();
+        assert_eq!(kvs_get(handle, c("key1").as_ptr(), &mut value), KVS_OK);
+        assert_eq!(CStr::from_ptr(value).to_str().unwrap(), "value1");
+        kvs_free_string(value);
+        assert_eq!(
+            kvs_get(handle, c("key2").as_ptr(), &mut value),
+            KVS_ERR_NOT_FOUND
+        );
+        assert!(value.is_null());
+        kvs_close(handle);
+    }
+}
+
+// Should reject null and non-UTF-8 arguments instead of crashing.
+#[test]
+fn invalid_arguments() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = c(temp_dir.path().to_str().unwrap());
+    unsafe {
+        let mut handle = ptr::null_mut();
+        assert_eq!(kvs_open(ptr::null(), &mut handle), KVS_ERR_INVALID_ARGUMENT);
+        assert_eq!(
+            kvs_open(path.as_ptr(), ptr::null_mut()),
+            KVS_ERR_INVALID_ARGUMENT
+        );
+        assert_eq!(kvs_open(path.as_ptr(), &mut handle), KVS_OK);
+        let not_utf8 = CString::new(vec![0xff, 0xfe]).unwrap();
+        assert_eq!(
+            kvs_set(handle, not_utf8.as_ptr(), c("value").as_ptr()),
+            KVS_ERR_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            kvs_remove(ptr::null_mut(), c("key").as_ptr()),
+            KVS_ERR_INVALID_ARGUMENT
+        );
+        kvs_close(handle);
+        kvs_close(ptr::null_mut());
+    }
+}
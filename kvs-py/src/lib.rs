@@ -0,0 +1,88 @@
+//! Python bindings for the kvs client.
+//!
+//! ```python
+//! import kvs_py
+//!
+//! client = kvs_py.connect("127.0.0.1:4000")
+//! client.set("key1", "value1")
+//! assert client.get("key1") == "value1"
+//! keys = client.scan(count=100)
+//! ```
+//!
+//! A missing key raises `KeyError` on `remove`, any other failure raises
+//! `kvs_py.KvsError`. The GIL is released while waiting on the server.
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError};
+use pyo3::prelude::*;
+
+use kvs::KvsClient;
+
+create_exception!(
+    kvs_py,
+    KvsError,
+    PyException,
+    "An error reported by the server, or by the connection to it."
+);
+
+fn to_py(err: kvs::KvsError) -> PyErr {
+    match err {
+        kvs::KvsError::KeyNotFound => PyKeyError::new_err(err.to_string()),
+        err => KvsError::new_err(err.to_string()),
+    }
+}
+
+/// A connection to a kvs server, opened by `connect`.
+#[pyclass(module = "kvs_py")]
+pub struct Client {
+    inner: KvsClient,
+}
+
+#[pymethods]
+impl Client {
+    /// Get the value of `key`, or `None` if it does not exist.
+    fn get(&mut self, py: Python<'_>, key: String) -> PyResult<Option<String>> {
+        py.allow_threads(|| self.inner.get(key)).map_err(to_py)
+    }
+
+    /// Set the value of `key`.
+    fn set(&mut self, py: Python<'_>, key: String, value: String) -> PyResult<()> {
+        py.allow_threads(|| self.inner.set(key, value))
+            .map_err(to_py)
+    }
+
+    /// Remove `key`, raising `KeyError` if it does not exist.
+    fn remove(&mut self, py: Python<'_>, key: String) -> PyResult<()> {
+        py.allow_threads(|| self.inner.remove(key)).map_err(to_py)
+    }
+
+    /// Up to `count` keys in order, starting after `after`. Pass the last
+    /// key returned to get the next page; an empty page ends the scan.
+    #[pyo3(signature = (after = None, count = 100))]
+    fn scan(
+        &mut self,
+        py: Python<'_>,
+        after: Option<String>,
+        count: usize,
+    ) -> PyResult<Vec<String>> {
+        py.allow_threads(|| self.inner.scan(after, count))
+            .map_err(to_py)
+    }
+}
+
+/// Connect to the kvs server at `addr`, e.g. `"127.0.0.1:4000"`.
+#[pyfunction]
+fn connect(py: Python<'_>, addr: String) -> PyResult<Client> {
+    py.allow_threads(|| KvsClient::connect(addr))
+        .map(|inner| Client { inner })
+        .map_err(to_py)
+}
+
+/// The `kvs_py` Python module.
+#[pymodule]
+pub fn kvs_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_class::<Client>()?;
+    m.add("KvsError", m.py().get_type::<KvsError>())?;
+    Ok(())
+}
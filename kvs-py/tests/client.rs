@@ -0,0 +1,48 @@
+use std::ffi::CString;
+use std::thread;
+use std::time::Duration;
+
+use kvs::{KvStore, KvsServer};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use tempfile::TempDir;
+
+// Drive a server from Python through the module.
+#[test]
+fn python_client_round_trip() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run("127.0.0.1:4038").unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    Python::with_gil(|py| {
+        let module = PyModule::new(py, "kvs_py").unwrap();
+        kvs_py::kvs_py(&module).unwrap();
+        let locals = PyDict::new(py);
+        locals.set_item("kvs_py", module).unwrap();
+        let code = CString::new(
+            r#"
+client = kvs_py.connect("127.0.0.1:4038")
+client.set("key1", "value1")
+client.set("key2", "value2")
+assert client.get("key1") == "value1"
+assert client.get("missing") is None
+assert client.scan() == ["key1", "key2"]
+assert client.scan(after="key1", count=1) == ["key2"]
+client.remove("key1")
+try:
+    client.remove("key1")
+    raise AssertionError("removed a missing key")
+except KeyError:
+    pass
+try:
+    kvs_py.connect("not an address")
+    raise AssertionError("connected to nowhere")
+except kvs_py.KvsError:
+    pass
+"#,
+        )
+        .unwrap();
+        py.run(&code, None, Some(&locals)).unwrap();
+    });
+}
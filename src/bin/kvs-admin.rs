@@ -0,0 +1,427 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+
+use kvs::diff::{self, Replica};
+use kvs::trace;
+use kvs::{
+    cluster, fsck, EngineKind, KvStore, KvsClient, KvsEngine, KvsError, PrefixUsage, Result,
+    SlotRange,
+};
+use log::info;
+
+/// Exit status of a command out of its `--max-duration-secs`, EX_TEMPFAIL:
+/// running it again resumes it or starts it over.
+const EXIT_OUT_OF_TIME: i32 = 75;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Administration of kvs servers", long_about = None)]
+struct Args {
+    /// Stop the command once it ran for this long, exiting with status 75.
+    /// Reshard and clone stop between batches and can be resumed, the
+    /// others are cut short, but fsck --repair which runs to completion
+    #[clap(long, global = true, value_name = "SECS")]
+    max_duration_secs: Option<u64>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Move hash slots from one node to another while both keep serving
+    Reshard {
+        /// Address of the node currently serving the slots
+        #[clap(long, value_name = "IP:PORT")]
+        from: String,
+        /// Address of the node to move the slots to
+        #[clap(long, value_name = "IP:PORT")]
+        to: String,
+        /// Slots to move, e.g. `0-99,200`
+        #[clap(long, value_name = "SLOTS", value_delimiter = ',', required = true)]
+        slots: Vec<SlotRange>,
+        /// Number of keys moved per round trip
+        #[clap(long, default_value = "100")]
+        batch: usize,
+    },
+    /// Copy the whole keyspace of a server to another one
+    Clone {
+        /// Address of the server to copy from
+        #[clap(long, value_name = "IP:PORT")]
+        from: String,
+        /// Address of the server to copy to
+        #[clap(long, value_name = "IP:PORT")]
+        to: String,
+        /// Number of keys copied per batch
+        #[clap(long, default_value = "100")]
+        batch: usize,
+        /// File recording the last copied key; an interrupted copy resumes
+        /// from it, and it is removed once the copy completes
+        #[clap(long, value_name = "FILE")]
+        checkpoint: Option<PathBuf>,
+    },
+    /// Compare every key of two servers or data directories, e.g. a
+    /// replica or a restored backup, exiting with 1 if they differ
+    Diff {
+        /// Address of a server, or data directory of a stopped one
+        left: String,
+        /// Address of a server, or data directory of a stopped one
+        right: String,
+    },
+    /// Count the keys and the bytes they take up by key prefix, largest
+    /// first, to see which namespace consumes the store
+    Usage {
+        /// Address of a server, or data directory of a stopped one
+        target: String,
+        /// Number of key components in a prefix
+        #[clap(short, long, default_value = "1")]
+        depth: usize,
+        /// Separator between the components of a key
+        #[clap(short, long, default_value = "/")]
+        separator: String,
+    },
+    /// Report the working set of a trace written by `kvs-server
+    /// --trace-file` and the hit rate LRU caches of several sizes would get
+    AnalyzeTrace {
+        /// Trace file
+        file: PathBuf,
+        /// Cache size to simulate, in bytes; may be repeated, defaults to
+        /// fractions of the working set
+        #[clap(long = "size", value_name = "BYTES")]
+        sizes: Vec<u64>,
+    },
+    /// Repair the engine marker of a data directory to match its data
+    FixEngine {
+        /// Engine the data was written by; detected from the data when not
+        /// given
+        #[clap(long, value_name = "ENGINE")]
+        actual: Option<EngineKind>,
+        /// Data directory of the server
+        #[clap(long, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Move every sealed generation of a stopped kvs server to its cold
+    /// directory
+    Tier {
+        /// Data directory of the server
+        #[clap(long, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+        /// Other data directories of the server, as given with --extra-dir
+        #[clap(long, value_name = "DIR")]
+        extra_dir: Vec<PathBuf>,
+        /// Cold directory to move the generations to
+        #[clap(long, value_name = "DIR")]
+        cold: PathBuf,
+    },
+    /// Validate the data directory of a stopped kvs server and print a
+    /// JSON report, exiting with 1 if problems remain
+    Fsck {
+        /// Data directory of the server
+        #[clap(long, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+        /// Repair what can be repaired without losing data
+        #[clap(long)]
+        repair: bool,
+    },
+}
+
+fn main() -> Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let args = Args::parse();
+    let budget = Budget::new(args.max_duration_secs);
+
+    match run(args.command, budget) {
+        Err(e) if budget.spent() => out_of_time(&format!("stopped on {}", e)),
+        result => result,
+    }
+}
+
+fn run(command: Command, budget: Budget) -> Result<()> {
+    match command {
+        Command::Reshard {
+            from,
+            to,
+            slots,
+            batch,
+        } => {
+            let mut source = budget.connect(&from)?;
+            let mut target = budget.connect(&to)?;
+            let mut total = 0;
+            for (i, range) in slots.iter().enumerate() {
+                for slot in range.start..=range.end {
+                    if budget.spent() {
+                        // the slots moved so far stay with their new node
+                        let left: Vec<String> = std::iter::once(SlotRange {
+                            start: slot,
+                            ..*range
+                        })
+                        .chain(slots[i + 1..].iter().copied())
+                        .map(|range| range.to_string())
+                        .collect();
+                        out_of_time(&format!(
+                            "moved {} keys, resume with --slots {}",
+                            total,
+                            left.join(",")
+                        ));
+                    }
+                    let moved =
+                        cluster::migrate_slot(&mut source, &mut target, &from, &to, slot, batch)?;
+                    if moved > 0 {
+                        info!("slot {}: moved {} keys", slot, moved);
+                    }
+                    total += moved;
+                }
+            }
+            println!("Moved {} keys from {} to {}", total, from, to);
+            Ok(())
+        }
+        Command::Clone {
+            from,
+            to,
+            batch,
+            checkpoint,
+        } => {
+            let after = match &checkpoint {
+                Some(path) => match fs::read_to_string(path) {
+                    Ok(last) => {
+                        info!("resuming after key {}", last);
+                        Some(last)
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into()),
+                },
+                None => None,
+            };
+            let mut source = budget.connect(&from)?;
+            let mut target = budget.connect(&to)?;
+            let copied = source.copy_to(&mut target, after, batch, |last| {
+                if let Some(path) = &checkpoint {
+                    fs::write(path, last)?;
+                }
+                if budget.spent() {
+                    out_of_time(&match &checkpoint {
+                        Some(path) => format!(
+                            "copied up to key {}, resume with --checkpoint {}",
+                            last,
+                            path.display()
+                        ),
+                        None => format!("copied up to key {}, pass --checkpoint to resume", last),
+                    });
+                }
+                Ok(())
+            })?;
+            if let Some(path) = &checkpoint {
+                fs::remove_file(path).or_else(|e| match e.kind() {
+                    ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })?;
+            }
+            println!("Copied {} keys from {} to {}", copied, from, to);
+            Ok(())
+        }
+        Command::Diff { left, right } => {
+            budget.enforce();
+            let report = diff::diff(&mut *replica(&left)?, &mut *replica(&right)?)?;
+            for only in &report.only_left {
+                println!("only in {}: {} ({:016x})", left, only.key, only.checksum);
+            }
+            for only in &report.only_right {
+                println!("only in {}: {} ({:016x})", right, only.key, only.checksum);
+            }
+            for mismatch in &report.mismatched {
+                println!(
+                    "mismatched: {} ({:016x} vs {:016x})",
+                    mismatch.key, mismatch.left, mismatch.right
+                );
+            }
+            println!(
+                "{} keys match, {} differ",
+                report.matched,
+                report.only_left.len() + report.only_right.len() + report.mismatched.len()
+            );
+            if !report.is_empty() {
+                exit(1);
+            }
+            Ok(())
+        }
+        Command::Usage {
+            target,
+            depth,
+            separator,
+        } => {
+            budget.enforce();
+            let mut usage = usage(&target, separator, depth)?;
+            usage.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.prefix.cmp(&b.prefix)));
+            println!("{:>12} {:>10}  PREFIX", "BYTES", "KEYS");
+            for prefix in usage {
+                println!(
+                    "{:>12} {:>10}  {}",
+                    prefix.bytes, prefix.keys, prefix.prefix
+                );
+            }
+            Ok(())
+        }
+        Command::AnalyzeTrace { file, sizes } => {
+            budget.enforce();
+            let report = trace::analyze(&trace::read(&file)?, &sizes)?;
+            println!(
+                "{} events sampled at {}, {} reads of existing keys",
+                report.events, report.sample_rate, report.gets
+            );
+            println!("Working set: {} keys, {} bytes", report.keys, report.bytes);
+            println!("{:>12} {:>9}", "CACHE BYTES", "HIT RATE");
+            for point in report.curve {
+                println!("{:>12} {:>8.1}%", point.cache_bytes, point.hit_rate * 100.0);
+            }
+            Ok(())
+        }
+        Command::FixEngine { actual, dir } => {
+            let detected = EngineKind::detect(&dir)?;
+            let actual = match (actual, detected) {
+                (Some(actual), Some(found)) if actual != found => {
+                    return Err(KvsError::WrongEngine {
+                        expected: actual,
+                        found,
+                    });
+                }
+                (Some(engine), _) | (None, Some(engine)) => engine,
+                (None, None) => {
+                    return Err(KvsError::Other(format!(
+                        "no engine data found in {}, pass --actual",
+                        dir.display()
+                    )));
+                }
+            };
+            match EngineKind::from_marker(&dir) {
+                Ok(Some(marker)) if marker == actual => {
+                    println!("Engine marker already says {}", actual);
+                    return Ok(());
+                }
+                Ok(_) => {}
+                // an unreadable marker is what is being repaired
+                Err(e) => info!("replacing engine marker: {}", e),
+            }
+            actual.write_marker(&dir)?;
+            println!("Engine marker set to {}", actual);
+            Ok(())
+        }
+        Command::Tier {
+            dir,
+            extra_dir,
+            cold,
+        } => {
+            // a generation moved halfway is resolved on the next open
+            budget.enforce();
+            let dirs: Vec<PathBuf> = std::iter::once(dir).chain(extra_dir).collect();
+            // the age does not matter, every sealed generation moves
+            let mut store = KvStore::open_tiered(&dirs, &cold, u64::MAX)?;
+            let moved = store.tier()?;
+            println!("Moved {} generations to {}", moved, cold.display());
+            Ok(())
+        }
+        Command::Fsck { dir, repair } => {
+            if !repair {
+                budget.enforce();
+            }
+            let report = fsck::check(&dir, repair)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.is_clean() {
+                exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+// the time left to a command under --max-duration-secs
+#[derive(Clone, Copy)]
+struct Budget {
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    fn new(secs: Option<u64>) -> Self {
+        Budget {
+            deadline: secs.map(|secs| Instant::now() + Duration::from_secs(secs)),
+        }
+    }
+
+    fn spent(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // connect to a server, no request of the connection outlasting the
+    // budget
+    fn connect(&self, addr: &str) -> Result<KvsClient> {
+        let mut builder = KvsClient::builder();
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                out_of_time(&format!("not connected to {}", addr));
+            }
+            builder = builder
+                .connect_timeout(left)
+                .read_timeout(left)
+                .write_timeout(left);
+        }
+        builder.connect(addr)
+    }
+
+    // exit once the budget is spent, for a command safe to cut short
+    // anywhere
+    fn enforce(&self) {
+        let Some(deadline) = self.deadline else {
+            return;
+        };
+        if self.spent() {
+            out_of_time("not started");
+        }
+        thread::spawn(move || {
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            out_of_time("cut short");
+        });
+    }
+}
+
+fn out_of_time(progress: &str) -> ! {
+    eprintln!("Out of time: {}", progress);
+    exit(EXIT_OUT_OF_TIME)
+}
+
+// a data directory if `target` is one, opened without writing to it, else
+// the address of a server
+fn replica(target: &str) -> Result<Box<dyn Replica>> {
+    let dir = Path::new(target);
+    if !dir.is_dir() {
+        return Ok(Box::new(KvsClient::connect(target)?));
+    }
+    match EngineKind::detect(dir)? {
+        #[cfg(feature = "engine-sled")]
+        Some(EngineKind::Sled) => Ok(Box::new(kvs::SledStore::new(sled::open(dir)?))),
+        _ => Ok(Box::new(KvStore::open_read_only(
+            &[dir.to_path_buf()],
+            None,
+        )?)),
+    }
+}
+
+// the usage of the data directory `target` if it is one, opened without
+// writing to it, else of the server at that address
+fn usage(target: &str, separator: String, depth: usize) -> Result<Vec<PrefixUsage>> {
+    let dir = Path::new(target);
+    if !dir.is_dir() {
+        return KvsClient::connect(target)?.usage(separator, depth);
+    }
+    match EngineKind::detect(dir)? {
+        #[cfg(feature = "engine-sled")]
+        Some(EngineKind::Sled) => kvs::SledStore::new(sled::open(dir)?).usage(separator, depth),
+        _ => KvStore::open_read_only(&[dir.to_path_buf()], None)?.usage(separator, depth),
+    }
+}
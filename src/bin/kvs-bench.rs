@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+
+use kvs::trace::{self, TraceEvent};
+use kvs::{KvsClient, KvsError, Result};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Load testing of kvs servers", long_about = None)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Play a trace written by `kvs-server --trace-file` against a server,
+    /// at the pace it was traced at or faster. Keys are named after the
+    /// hashes they were traced by, values filled up to the traced sizes.
+    Replay {
+        /// Trace file
+        trace: PathBuf,
+        /// Address of the server
+        #[clap(short, long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
+        addr: String,
+        /// How many times faster than traced to play the events; 0 plays
+        /// them back to back, as do traces without times
+        #[clap(long, value_name = "FACTOR", default_value = "1")]
+        speed: f64,
+        /// Prefix of the keys written and read
+        #[clap(long, default_value = "replay:")]
+        prefix: String,
+    },
+}
+
+fn main() -> Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let args = Args::parse();
+
+    match args.command {
+        Command::Replay {
+            trace,
+            addr,
+            speed,
+            prefix,
+        } => {
+            if !(speed >= 0.0 && speed.is_finite()) {
+                return Err(KvsError::InvalidCommand(format!(
+                    "speed must be a factor of 0 or more, not {}",
+                    speed
+                )));
+            }
+            let events = trace::read_timed(&trace)?;
+            let mut client = KvsClient::connect(addr)?;
+            let first = events.iter().find_map(|timed| timed.at_ms);
+            let start = Instant::now();
+            let mut latencies: BTreeMap<&str, Vec<Duration>> = BTreeMap::new();
+            let mut errors = 0;
+            let mut max_lag = Duration::ZERO;
+            for timed in &events {
+                // when the event is due, relative to the start of the replay
+                let due = match (timed.at_ms, first) {
+                    (Some(at), Some(first)) if speed > 0.0 => Some(Duration::from_secs_f64(
+                        at.saturating_sub(first) as f64 / 1000.0 / speed,
+                    )),
+                    _ => None,
+                };
+                if let Some(due) = due {
+                    match due.checked_sub(start.elapsed()) {
+                        Some(wait) => thread::sleep(wait),
+                        None => max_lag = max_lag.max(start.elapsed() - due),
+                    }
+                }
+                let sent = Instant::now();
+                let (op, result) = match timed.event {
+                    TraceEvent::Start { .. } => continue,
+                    TraceEvent::Get { key, .. } => {
+                        ("get", client.get(key_name(&prefix, key)).map(drop))
+                    }
+                    TraceEvent::Set { key, size } => (
+                        "set",
+                        client.set(key_name(&prefix, key), "x".repeat(size as usize)),
+                    ),
+                    // the key may not have been written during the replay
+                    TraceEvent::Remove { key } => match client.remove(key_name(&prefix, key)) {
+                        Err(KvsError::KeyNotFound) => ("remove", Ok(())),
+                        result => ("remove", result),
+                    },
+                };
+                latencies.entry(op).or_default().push(sent.elapsed());
+                if let Err(e) = result {
+                    errors += 1;
+                    log::warn!("{} failed: {}", op, e);
+                }
+            }
+
+            let elapsed = start.elapsed();
+            let total: usize = latencies.values().map(Vec::len).sum();
+            println!(
+                "Replayed {} requests in {:.3}s, {:.0} per second, {} errors",
+                total,
+                elapsed.as_secs_f64(),
+                total as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+                errors
+            );
+            if max_lag > Duration::ZERO {
+                println!(
+                    "Fell behind the trace by up to {:.3}s",
+                    max_lag.as_secs_f64()
+                );
+            }
+            println!(
+                "{:<8} {:>8} {:>10} {:>10} {:>10}",
+                "OP", "COUNT", "P50 US", "P99 US", "MAX US"
+            );
+            for (op, mut samples) in latencies {
+                samples.sort_unstable();
+                let at = |q: f64| samples[((samples.len() - 1) as f64 * q) as usize].as_micros();
+                println!(
+                    "{:<8} {:>8} {:>10} {:>10} {:>10}",
+                    op,
+                    samples.len(),
+                    at(0.5),
+                    at(0.99),
+                    at(1.0)
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+// the key standing for the one traced with `hash`
+fn key_name(prefix: &str, hash: u64) -> String {
+    format!("{}{:016x}", prefix, hash)
+}
@@ -1,8 +1,13 @@
-use std::{process::exit};
+use std::io;
+use std::process::exit;
+use std::time::{Duration, UNIX_EPOCH};
 
-use clap::{Parser, Subcommand};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 
-use kvs::{KvsClient, KvsError, Result};
+use kvs::{Health, KvsClient, KvsError, Result, ValueFilter, ValueType};
 use log::debug;
 
 // NOTE: we can also use `structopt` instead of `clap` for parsing command line arguments.
@@ -14,6 +19,76 @@ struct Args {
 
     #[clap(short, long, value_name = "IP:PORT", default_value = "127.0.0.1:4000", value_parser = validate_addr)]
     addr: Option<String>,
+
+    /// Give up connecting, or waiting for a response, after this many
+    /// milliseconds
+    #[clap(long, value_name = "MS")]
+    timeout_ms: Option<u64>,
+
+    /// How keys, prefixes and hash fields are given and shown; patterns
+    /// stay raw
+    #[clap(long, value_enum, default_value = "raw")]
+    key_format: Format,
+
+    /// How values, list elements and set members are given and shown
+    #[clap(long, value_enum, default_value = "raw")]
+    value_format: Format,
+}
+
+/// How a key or a value is written on the command line, so non-printable
+/// ones go through the shell untouched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// As is
+    Raw,
+    /// Hexadecimal bytes
+    Hex,
+    /// Standard base64
+    Base64,
+}
+
+impl Format {
+    // the string `arg` stands for, keys and values other than bytes being
+    // UTF-8
+    fn decode(self, arg: String) -> Result<String> {
+        if self == Format::Raw {
+            return Ok(arg);
+        }
+        String::from_utf8(self.decode_bytes(&arg)?)
+            .map_err(|e| KvsError::InvalidValue(format!("{} is not UTF-8: {}", arg, e)))
+    }
+
+    // the bytes `arg` stands for
+    fn decode_bytes(self, arg: &str) -> Result<Vec<u8>> {
+        Ok(match self {
+            Format::Raw => arg.as_bytes().to_vec(),
+            Format::Hex => (0..arg.len())
+                .step_by(2)
+                .map(|i| {
+                    arg.get(i..i + 2)
+                        .filter(|byte| byte.bytes().all(|b| b.is_ascii_hexdigit()))
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| KvsError::InvalidValue(format!("not hexadecimal: {}", arg)))?,
+            Format::Base64 => BASE64
+                .decode(arg)
+                .map_err(|e| KvsError::InvalidValue(format!("not base64: {}", e)))?,
+        })
+    }
+
+    fn encode(self, value: impl AsRef<[u8]>) -> String {
+        let bytes = value.as_ref();
+        match self {
+            Format::Raw => String::from_utf8_lossy(bytes).into_owned(),
+            Format::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            Format::Base64 => BASE64.encode(bytes),
+        }
+    }
+
+    fn decode_all(self, args: Vec<String>) -> Result<Vec<String>> {
+        args.into_iter().map(|arg| self.decode(arg)).collect()
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -21,6 +96,9 @@ enum Command {
     Set {
         key: String,
         value: String,
+        /// Type of the value: string, json, bytes or int
+        #[clap(short = 't', long = "type", default_value = "string")]
+        value_type: ValueType,
     },
     Get {
         key: String,
@@ -29,6 +107,223 @@ enum Command {
     Remove {
         key: String,
     },
+    /// Get the values of several keys in one request, one line each
+    #[clap(name = "get-many")]
+    GetMany {
+        #[clap(required = true)]
+        keys: Vec<String>,
+    },
+    /// Get the part of a JSON value at a JSON pointer, like /users/0/name
+    #[clap(name = "get-field")]
+    GetField {
+        key: String,
+        pointer: String,
+    },
+    /// Show the size, type and TTL of a string key, and where and when it
+    /// was written if the server records it
+    Meta {
+        key: String,
+    },
+    /// Set a string key at a later time, in milliseconds since the Unix
+    /// epoch
+    SetAt {
+        key: String,
+        value: String,
+        at_ms: u64,
+    },
+    /// Remove a string key at a later time, in milliseconds since the Unix
+    /// epoch
+    RemoveAt {
+        key: String,
+        at_ms: u64,
+    },
+    /// Restore a key removed within the trash window of the server
+    #[clap(alias = "restore")]
+    Undelete {
+        key: String,
+    },
+    /// Push values at the head of a list
+    #[clap(name = "lpush")]
+    LPush {
+        key: String,
+        #[clap(required = true)]
+        values: Vec<String>,
+    },
+    /// Push values at the tail of a list
+    #[clap(name = "rpush")]
+    RPush {
+        key: String,
+        #[clap(required = true)]
+        values: Vec<String>,
+    },
+    /// Show the elements of a list between two inclusive indexes,
+    /// negative ones counting from the tail
+    #[clap(name = "lrange", allow_negative_numbers = true)]
+    LRange {
+        key: String,
+        #[clap(default_value = "0")]
+        start: i64,
+        #[clap(default_value = "-1")]
+        stop: i64,
+    },
+    /// Pop the head of a list
+    #[clap(name = "lpop")]
+    LPop {
+        key: String,
+    },
+    /// Set a field of a hash
+    #[clap(name = "hset")]
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+    },
+    /// Get a field of a hash
+    #[clap(name = "hget")]
+    HGet {
+        key: String,
+        field: String,
+    },
+    /// Show every field of a hash
+    #[clap(name = "hgetall")]
+    HGetAll {
+        key: String,
+    },
+    /// Remove a field of a hash
+    #[clap(name = "hdel")]
+    HDel {
+        key: String,
+        field: String,
+    },
+    /// Add members to a set
+    #[clap(name = "sadd")]
+    SAdd {
+        key: String,
+        #[clap(required = true)]
+        members: Vec<String>,
+    },
+    /// Remove members from a set
+    #[clap(name = "srem")]
+    SRem {
+        key: String,
+        #[clap(required = true)]
+        members: Vec<String>,
+    },
+    /// Check whether a member belongs to a set
+    #[clap(name = "sismember")]
+    SIsMember {
+        key: String,
+        member: String,
+    },
+    /// Show the members of a set
+    #[clap(name = "smembers")]
+    SMembers {
+        key: String,
+    },
+    /// Count one hit against a sliding-window rate limit and tell whether
+    /// it is allowed
+    Rate {
+        key: String,
+        /// Number of hits allowed per window
+        limit: u64,
+        /// Length of the window, in milliseconds
+        #[clap(short, long, default_value = "1000")]
+        window_ms: u64,
+    },
+    /// Get the next ID of a sequence
+    #[clap(name = "next-id")]
+    NextId {
+        sequence: String,
+    },
+    /// List the keys and "directories" right under a prefix, seeing keys
+    /// as paths split by a separator
+    Ls {
+        #[clap(default_value = "")]
+        prefix: String,
+        /// Separator between the components of a key
+        #[clap(short, long, default_value = "/")]
+        separator: String,
+        /// Show the whole tree under the prefix
+        #[clap(short, long)]
+        tree: bool,
+    },
+    /// List the keys matching a glob pattern, e.g. 'user:*:settings'
+    Keys {
+        #[clap(default_value = "*")]
+        pattern: String,
+        /// Only list keys whose value contains this substring
+        #[clap(long, value_name = "TEXT", conflicts_with = "regex")]
+        contains: Option<String>,
+        /// Only list keys whose value matches this regular expression
+        #[clap(long, value_name = "REGEX")]
+        regex: Option<String>,
+    },
+    /// Print every key changed since an LSN along with its data, one JSON
+    /// object per line, its dump being null once removed; the LSN to
+    /// export from next time is printed last, to stderr
+    Export {
+        /// LSN printed by the previous export, 0 for every key
+        #[clap(long, value_name = "LSN", default_value = "0")]
+        since_lsn: u64,
+    },
+    /// Show the nodes of the cluster and the slots they serve
+    #[clap(name = "cluster-info")]
+    ClusterInfo,
+    /// Check the server is up, printing "ok", or "draining" and exiting
+    /// with 1 if it is shutting down
+    Ping,
+    /// Show scheduling statistics of the server
+    Stats,
+    /// Show request and traffic counters per client IP
+    Clients,
+    /// Have the server compact its data now, e.g. before a backup, rather
+    /// than once enough of it is stale
+    Compact,
+    /// Switch the server in or out of read-only mode
+    ReadOnly {
+        #[arg(value_enum)]
+        mode: Toggle,
+    },
+    /// Show the most frequently accessed keys
+    #[clap(name = "hotkeys")]
+    HotKeys {
+        #[clap(short, long, default_value = "10")]
+        limit: usize,
+    },
+    /// Print the completion script of kvs-client for a shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page of kvs-client
+    Man,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Toggle {
+    On,
+    Off,
+}
+
+// print the children of `prefix` indented by `depth`, then their own
+// children, directories first
+fn print_tree(
+    cli: &mut KvsClient,
+    prefix: &str,
+    separator: &str,
+    depth: usize,
+    format: Format,
+) -> Result<()> {
+    let children = cli.list_children(prefix.to_owned(), separator.to_owned())?;
+    let indent = "  ".repeat(depth);
+    for dir in children.dirs {
+        println!("{}{}", indent, format.encode(&dir[prefix.len()..]));
+        print_tree(cli, &dir, separator, depth + 1, format)?;
+    }
+    for key in children.keys {
+        println!("{}{}", indent, format.encode(&key[prefix.len()..]));
+    }
+    Ok(())
 }
 
 fn validate_addr(s: &str) -> std::result::Result<String, String> {
@@ -54,37 +349,419 @@ fn main() -> Result<()> {
         .filter_level(log::LevelFilter::Debug)
         .init();
     let args = Args::parse();
+    match args.command {
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Args::command(), "kvs-client", &mut io::stdout());
+            return Ok(());
+        }
+        Command::Man => {
+            return Ok(clap_mangen::Man::new(Args::command()).render(&mut io::stdout())?)
+        }
+        _ => {}
+    }
 
     // let log_file = format!("{}/rust/kvs/kvs.log", env!("HOME"));
     // let log_file = current_dir().unwrap();
     // let mut kv_store = kvs::KvStore::open(std::path::Path::new(&log_file))?;
 
-    let mut cli = KvsClient::connect(args.addr.unwrap())?;
+    let mut builder = KvsClient::builder();
+    if let Some(ms) = args.timeout_ms {
+        let timeout = Duration::from_millis(ms);
+        builder = builder
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .write_timeout(timeout);
+    }
+    let mut cli = builder.connect(args.addr.unwrap())?;
+    let (keys, values) = (args.key_format, args.value_format);
 
     match args.command {
-        Command::Set { key, value } => {
-            debug!("set key: {}, value: {}", key, value);
-            cli.set(key, value)?;
+        Command::Set {
+            key,
+            value,
+            value_type,
+        } => {
+            let key = keys.decode(key)?;
+            if value_type == ValueType::Bytes {
+                let value = values.decode_bytes(&value)?;
+                debug!("set key: {}, {} bytes", key, value.len());
+                cli.set_bytes(key, value)?;
+                return Ok(());
+            }
+            let value = values.decode(value)?;
+            debug!("set key: {}, value: {}, type: {}", key, value, value_type);
+            cli.set_with_type(key, value, value_type)?;
             Ok(())
         }
         Command::Get { key } => {
+            let key = keys.decode(key)?;
             debug!("get key: {}", key);
-            match cli.get(key)? {
-                Some(value) => println!("{}", value),
+            match cli.get_with_type(key)? {
+                Some((value, value_type)) if values == Format::Raw => {
+                    println!("{}", render(value, value_type))
+                }
+                Some((value, ValueType::Bytes)) => {
+                    let bytes = BASE64
+                        .decode(&value)
+                        .map_err(|e| KvsError::InvalidValue(format!("not base64: {}", e)))?;
+                    println!("{}", values.encode(bytes))
+                }
+                Some((value, _)) => println!("{}", values.encode(&value)),
                 None => println!("Key not found"),
             }
             Ok(())
         }
+        Command::GetMany { keys: names } => {
+            let names = keys.decode_all(names)?;
+            debug!("get-many keys: {:?}", names);
+            for value in cli.get_many(names)? {
+                match value {
+                    Some(value) => println!("{}", values.encode(&value)),
+                    None => println!("Key not found"),
+                }
+            }
+            Ok(())
+        }
+        Command::GetField { key, pointer } => {
+            let key = keys.decode(key)?;
+            debug!("get-field key: {}, pointer: {}", key, pointer);
+            match cli.get_field(key, pointer)? {
+                Some(field) if values == Format::Raw => {
+                    println!("{}", render(field, ValueType::Json))
+                }
+                Some(field) => println!("{}", values.encode(&field)),
+                None => println!("Key not found"),
+            }
+            Ok(())
+        }
+        Command::Meta { key } => {
+            let key = keys.decode(key)?;
+            debug!("meta key: {}", key);
+            let Some(meta) = cli.meta(key)? else {
+                println!("Key not found");
+                return Ok(());
+            };
+            println!("size: {}", meta.size);
+            println!("type: {}", meta.value_type);
+            if let Some(ttl_ms) = meta.ttl_ms {
+                println!("ttl_ms: {}", ttl_ms);
+            }
+            if let Some(written_at) = meta.written_at {
+                println!("written_at: {}", written_at);
+            }
+            if let Some(lsn) = meta.lsn {
+                println!("lsn: {}", lsn);
+            }
+            Ok(())
+        }
+        Command::SetAt { key, value, at_ms } => {
+            let key = keys.decode(key)?;
+            debug!("set key at {}: {}", at_ms, key);
+            let at = UNIX_EPOCH + Duration::from_millis(at_ms);
+            cli.set_at(key, values.decode(value)?, at)
+        }
+        Command::RemoveAt { key, at_ms } => {
+            let key = keys.decode(key)?;
+            debug!("remove key at {}: {}", at_ms, key);
+            cli.remove_at(key, UNIX_EPOCH + Duration::from_millis(at_ms))
+        }
+        Command::Undelete { key } => {
+            let key = keys.decode(key)?;
+            debug!("undelete key: {}", key);
+            match cli.undelete(key) {
+                Ok(()) => Ok(()),
+                Err(KvsError::KeyNotFound) => {
+                    eprintln!("Key not found");
+                    exit(1);
+                }
+                Err(e) => Err(e),
+            }
+        }
         Command::Remove { key } => {
+            let key = keys.decode(key)?;
             debug!("remove key: {}", key);
             match cli.remove(key) {
                 Ok(()) => Ok(()),
                 Err(KvsError::KeyNotFound) => {
-                    println!("Key not found");
+                    eprintln!("Key not found");
+                    exit(1);
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Command::LPush {
+            key,
+            values: elements,
+        } => {
+            let key = keys.decode(key)?;
+            let elements = values.decode_all(elements)?;
+            debug!("lpush key: {}, values: {:?}", key, elements);
+            println!("{}", cli.lpush(key, elements)?);
+            Ok(())
+        }
+        Command::RPush {
+            key,
+            values: elements,
+        } => {
+            let key = keys.decode(key)?;
+            let elements = values.decode_all(elements)?;
+            debug!("rpush key: {}, values: {:?}", key, elements);
+            println!("{}", cli.rpush(key, elements)?);
+            Ok(())
+        }
+        Command::LRange { key, start, stop } => {
+            let key = keys.decode(key)?;
+            debug!("lrange key: {}, start: {}, stop: {}", key, start, stop);
+            for value in cli.lrange(key, start, stop)? {
+                println!("{}", values.encode(&value));
+            }
+            Ok(())
+        }
+        Command::LPop { key } => {
+            let key = keys.decode(key)?;
+            debug!("lpop key: {}", key);
+            match cli.lpop(key)? {
+                Some(value) => println!("{}", values.encode(&value)),
+                None => println!("Key not found"),
+            }
+            Ok(())
+        }
+        Command::HSet { key, field, value } => {
+            let key = keys.decode(key)?;
+            let field = keys.decode(field)?;
+            let value = values.decode(value)?;
+            debug!("hset key: {}, field: {}, value: {}", key, field, value);
+            cli.hset(key, field, value)
+        }
+        Command::HGet { key, field } => {
+            let key = keys.decode(key)?;
+            let field = keys.decode(field)?;
+            debug!("hget key: {}, field: {}", key, field);
+            match cli.hget(key, field)? {
+                Some(value) => println!("{}", values.encode(&value)),
+                None => println!("Key not found"),
+            }
+            Ok(())
+        }
+        Command::HGetAll { key } => {
+            let key = keys.decode(key)?;
+            debug!("hgetall key: {}", key);
+            for (field, value) in cli.hgetall(key)? {
+                println!("{}: {}", keys.encode(&field), values.encode(&value));
+            }
+            Ok(())
+        }
+        Command::HDel { key, field } => {
+            let key = keys.decode(key)?;
+            let field = keys.decode(field)?;
+            debug!("hdel key: {}, field: {}", key, field);
+            match cli.hdel(key, field) {
+                Ok(()) => Ok(()),
+                Err(KvsError::KeyNotFound) => {
+                    eprintln!("Key not found");
                     exit(1);
                 }
                 Err(e) => Err(e),
             }
         }
+        Command::SAdd { key, members } => {
+            let key = keys.decode(key)?;
+            let members = values.decode_all(members)?;
+            debug!("sadd key: {}, members: {:?}", key, members);
+            println!("{}", cli.sadd(key, members)?);
+            Ok(())
+        }
+        Command::SRem { key, members } => {
+            let key = keys.decode(key)?;
+            let members = values.decode_all(members)?;
+            debug!("srem key: {}, members: {:?}", key, members);
+            println!("{}", cli.srem(key, members)?);
+            Ok(())
+        }
+        Command::SIsMember { key, member } => {
+            let key = keys.decode(key)?;
+            let member = values.decode(member)?;
+            debug!("sismember key: {}, member: {}", key, member);
+            println!("{}", cli.sismember(key, member)?);
+            Ok(())
+        }
+        Command::SMembers { key } => {
+            let key = keys.decode(key)?;
+            debug!("smembers key: {}", key);
+            for member in cli.smembers(key)? {
+                println!("{}", values.encode(&member));
+            }
+            Ok(())
+        }
+        Command::Ls {
+            prefix,
+            separator,
+            tree,
+        } => {
+            let prefix = keys.decode(prefix)?;
+            debug!("ls prefix: {}, separator: {}", prefix, separator);
+            if tree {
+                print_tree(&mut cli, &prefix, &separator, 0, keys)
+            } else {
+                let children = cli.list_children(prefix, separator)?;
+                for entry in children.dirs.iter().chain(&children.keys) {
+                    println!("{}", keys.encode(entry));
+                }
+                Ok(())
+            }
+        }
+        Command::Keys {
+            pattern,
+            contains,
+            regex,
+        } => {
+            debug!("keys pattern: {}", pattern);
+            let value = contains
+                .map(ValueFilter::Contains)
+                .or(regex.map(ValueFilter::Regex));
+            let mut after = None;
+            loop {
+                let page = match &value {
+                    Some(value) => {
+                        cli.scan_values(Some(pattern.clone()), value.clone(), after.take(), 1000)?
+                    }
+                    None => cli.scan_matching(pattern.clone(), after.take(), 1000)?,
+                };
+                let Some(last) = page.last().cloned() else {
+                    return Ok(());
+                };
+                for key in page {
+                    println!("{}", keys.encode(&key));
+                }
+                after = Some(last);
+            }
+        }
+        Command::Export { since_lsn } => {
+            debug!("export since lsn: {}", since_lsn);
+            let lsn = cli.export(since_lsn, |key, dump| {
+                let line = serde_json::json!({ "key": key, "dump": dump });
+                println!("{}", line);
+                Ok(())
+            })?;
+            if let Some(lsn) = lsn {
+                eprintln!("lsn: {}", lsn);
+            }
+            Ok(())
+        }
+        Command::Rate {
+            key,
+            limit,
+            window_ms,
+        } => {
+            let key = keys.decode(key)?;
+            debug!(
+                "rate key: {}, limit: {}, window: {}ms",
+                key, limit, window_ms
+            );
+            let rate = cli.rate(key, Duration::from_millis(window_ms), limit)?;
+            println!("allowed: {}", rate.allowed);
+            println!("count: {}", rate.count);
+            println!("reset_ms: {}", rate.reset_ms);
+            if !rate.allowed {
+                exit(1);
+            }
+            Ok(())
+        }
+        Command::NextId { sequence } => {
+            let sequence = keys.decode(sequence)?;
+            debug!("next id of sequence: {}", sequence);
+            println!("{}", cli.next_id(sequence)?);
+            Ok(())
+        }
+        Command::ClusterInfo => {
+            println!("{:<24} {:>6} {:>6}  SLOTS", "ADDR", "EPOCH", "ALIVE");
+            for node in cli.cluster_info()?.nodes {
+                let slots: Vec<String> = node.slots.iter().map(ToString::to_string).collect();
+                println!(
+                    "{:<24} {:>6} {:>6}  {}",
+                    node.addr,
+                    node.epoch,
+                    node.alive,
+                    slots.join(",")
+                );
+            }
+            Ok(())
+        }
+        Command::Ping => {
+            let health = cli.ping()?;
+            println!("{}", health);
+            if health == Health::Draining {
+                exit(1);
+            }
+            Ok(())
+        }
+        Command::Stats => {
+            let stats = cli.stats()?;
+            println!("connections: {}", stats.connections);
+            println!("requests: {}", stats.requests);
+            println!("queued: {}", stats.queued);
+            println!("yields: {}", stats.yields);
+            println!("max_wait_us: {}", stats.max_wait_us);
+            println!("read_only: {}", stats.read_only);
+            if let Some(epoch) = stats.epoch {
+                println!("epoch: {}", epoch);
+            }
+            for (name, value) in stats.engine {
+                println!("engine.{}: {}", name, value);
+            }
+            Ok(())
+        }
+        Command::Compact => cli.compact(),
+        Command::ReadOnly { mode } => cli.set_read_only(matches!(mode, Toggle::On)),
+        Command::HotKeys { limit } => {
+            println!("{:>10} {:>10}  KEY", "COUNT", "ERROR");
+            for hot in cli.hot_keys(limit)? {
+                println!(
+                    "{:>10} {:>10}  {}",
+                    hot.count,
+                    hot.error,
+                    keys.encode(&hot.key)
+                );
+            }
+            Ok(())
+        }
+        // printed before connecting
+        Command::Completions { .. } | Command::Man => Ok(()),
+        Command::Clients => {
+            println!(
+                "{:<40} {:>6} {:>10} {:>12} {:>12}  OPS",
+                "IP", "CONNS", "REQUESTS", "BYTES_IN", "BYTES_OUT"
+            );
+            for client in cli.clients()? {
+                let ops: Vec<String> = client
+                    .ops
+                    .iter()
+                    .map(|(op, count)| format!("{}={}", op, count))
+                    .collect();
+                println!(
+                    "{:<40} {:>6} {:>10} {:>12} {:>12}  {}",
+                    client.ip,
+                    client.connections,
+                    client.requests,
+                    client.bytes_in,
+                    client.bytes_out,
+                    ops.join(",")
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+fn render(value: String, value_type: ValueType) -> String {
+    match value_type {
+        ValueType::Json => serde_json::from_str::<serde_json::Value>(&value)
+            .and_then(|json| serde_json::to_string_pretty(&json))
+            .unwrap_or(value),
+        ValueType::Bytes => match BASE64.decode(&value) {
+            Ok(bytes) => Format::Hex.encode(bytes),
+            Err(_) => value,
+        },
+        ValueType::String | ValueType::Int => value,
     }
 }
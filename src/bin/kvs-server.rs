@@ -1,41 +1,255 @@
 use std::{
-    env::current_dir, fmt::Display, fs, net::SocketAddr, path::Path, process::exit, str::FromStr,
+    env::current_dir,
+    fmt::Display,
+    fs::OpenOptions,
+    io,
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
 };
 
-use clap::{Parser, ValueEnum};
-use kvs::{KvsEngine, KvsServer, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use kvs::cluster::Cluster;
+use kvs::fsck::IssueKind;
+use kvs::{EngineKind, KvsClient, KvsEngine, KvsError, KvsServer, Lease, Result, SlotRange};
 use log::{error, info, warn};
 
 // NOTE: we can also use `structopt` instead of `clap` for parsing command line arguments.
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long, value_name = "IP:PORT", default_value = "127.0.0.1:4000", value_parser = validate_addr)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[clap(short, long, global = true, value_name = "IP:PORT", default_value = "127.0.0.1:4000", value_parser = validate_addr)]
     addr: Option<String>,
 
     #[arg(value_enum)]
     #[clap(short, long, value_name = "ENGINE", default_value = "kvs")]
     engine: Engine,
+
+    /// Requests served from one connection before yielding to the others
+    #[clap(long, value_name = "N", default_value = "32")]
+    budget: usize,
+
+    /// Time one scan may spend filtering values, in milliseconds
+    #[clap(long, value_name = "MS", default_value = "500")]
+    scan_budget_ms: u64,
+
+    /// IDs of a sequence leased at once, each lease writing to the store
+    #[clap(long, value_name = "N", default_value = "100")]
+    id_block: u64,
+
+    /// Start in read-only mode, rejecting writes until switched off
+    #[clap(long)]
+    read_only: bool,
+
+    /// Append the server version, the command and its key to the errors
+    /// sent back to clients
+    #[clap(long)]
+    error_context: bool,
+
+    /// Share the working directory with other servers, e.g. on network
+    /// storage: only the server holding a lease of this many milliseconds
+    /// on it serves writes, the others serve reads and take over once it
+    /// expires (kvs engine only)
+    #[clap(long, value_name = "MS")]
+    lease_ttl_ms: Option<u64>,
+
+    /// Refuse writes while the disk of the working directory has less than
+    /// this many bytes free
+    #[clap(long, value_name = "BYTES")]
+    min_free_bytes: Option<u64>,
+
+    /// Track the most accessed keys, keeping counters for up to N keys
+    #[clap(long, value_name = "N")]
+    hotkeys: Option<usize>,
+
+    /// Keep removed keys for this many seconds, to be undeleted (kvs engine
+    /// only)
+    #[clap(long, value_name = "SECS")]
+    trash_window_secs: Option<u64>,
+
+    /// Run as a cache of at most this many bytes of string keys, evicting
+    /// the least recently used ones (kvs engine only)
+    #[clap(long, value_name = "BYTES")]
+    cache_budget: Option<u64>,
+
+    /// Write string values longer than this many bytes as several records
+    /// (kvs engine only)
+    #[clap(long, value_name = "BYTES")]
+    chunk_size: Option<u64>,
+
+    /// Keep at most this many generation files open for reading, reopening
+    /// them as needed (kvs engine only)
+    #[clap(long, value_name = "N")]
+    max_open_files: Option<usize>,
+
+    /// Compact on start if the log holds more than RATIO times as many
+    /// stale bytes as live ones (kvs engine only)
+    #[clap(long, value_name = "RATIO")]
+    compact_ratio: Option<u64>,
+
+    /// Record the time every string value is written, shown by
+    /// `kvs-client meta` (kvs engine only)
+    #[clap(long)]
+    write_times: bool,
+
+    /// Order to list and scan keys in, `bytewise` or `natural` (digits by
+    /// value), chosen when the data directory is created and kept after
+    /// (kvs engine only)
+    #[clap(long, value_name = "ORDER")]
+    key_order: Option<kvs::KeyOrder>,
+
+    /// Encoding to write new generations in, `json` lines or `binary`
+    /// records about half the size; existing ones are rewritten by
+    /// compaction. Defaults to that of the latest generation (kvs engine
+    /// only)
+    #[clap(long, value_name = "ENCODING")]
+    log_encoding: Option<kvs::LogEncoding>,
+
+    /// Compress string values of at least this many bytes with zstd, the
+    /// values written before being compressed by compaction (kvs engine
+    /// only)
+    #[cfg(feature = "compression")]
+    #[clap(long, value_name = "BYTES")]
+    compress_above: Option<usize>,
+
+    /// Write the generation compaction produces as a zstd-compressed
+    /// segment, for stores mostly holding cold data (kvs engine only)
+    #[cfg(feature = "compression")]
+    #[clap(long)]
+    compress_segments: bool,
+
+    /// Encrypt the data at rest with the AES-256 key in this file, written
+    /// as 64 hex digits; the data written before is encrypted by compaction
+    /// (kvs engine only)
+    #[cfg(feature = "encryption")]
+    #[clap(long, value_name = "FILE")]
+    encryption_key_file: Option<PathBuf>,
+
+    /// What reads do with string keys found expired: `hide` them until
+    /// swept, the default of the kvs engine, or `delete` them right away,
+    /// the default of the sled engine
+    #[clap(long, value_name = "POLICY")]
+    expired_reads: Option<kvs::ExpiredReads>,
+
+    /// Spread new generations across this directory too, besides the
+    /// working directory; repeat for more (kvs engine only)
+    #[clap(long, value_name = "DIR")]
+    extra_dir: Vec<PathBuf>,
+
+    /// Move sealed generations to this directory, e.g. on a cheaper disk
+    /// (kvs engine only)
+    #[clap(long, value_name = "DIR")]
+    cold_dir: Option<PathBuf>,
+
+    /// Move a sealed generation to the cold directory once this many newer
+    /// generations exist
+    #[clap(long, value_name = "GENS", default_value = "1", requires = "cold_dir")]
+    cold_after: u64,
+
+    /// Join a cluster serving these hash slots, e.g. 0-8191,10000
+    #[clap(long, value_name = "SLOTS", value_delimiter = ',')]
+    cluster_slots: Option<Vec<SlotRange>>,
+
+    /// Nodes of the cluster to gossip with at first
+    #[clap(long, value_name = "IP:PORT", value_delimiter = ',')]
+    cluster_peers: Vec<String>,
+
+    /// Serve an admin console on a Unix socket at this path, e.g. for
+    /// `nc -U PATH`: stats, clients, compact, readonly and loglevel
+    #[cfg(unix)]
+    #[clap(long, value_name = "PATH")]
+    admin_socket: Option<PathBuf>,
+
+    /// On `shutdown` from the admin console, answer pings with "draining"
+    /// for this long before closing the listener and draining connections
+    #[clap(long, value_name = "MS", default_value = "5000")]
+    drain_delay_ms: u64,
+
+    /// Trace the reads and writes of a sample of the keys to this file, for
+    /// `kvs-admin analyze-trace` to size a cache
+    #[clap(long, value_name = "PATH")]
+    trace_file: Option<PathBuf>,
+
+    /// Share of the keys traced to --trace-file, between 0 and 1
+    #[clap(
+        long,
+        value_name = "P",
+        default_value = "0.01",
+        requires = "trace_file"
+    )]
+    trace_sample_rate: f64,
+
+    /// Export the spans of connections, requests, engine calls and file IO
+    /// to this OpenTelemetry collector, e.g. http://localhost:4318/v1/traces
+    #[cfg(feature = "otlp")]
+    #[clap(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Inject faults for testing clients: delay every request by this much
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_name = "MS", default_value = "0")]
+    chaos_latency_ms: u64,
+
+    /// Inject faults for testing clients: add a random delay up to this much
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_name = "MS", default_value = "0")]
+    chaos_jitter_ms: u64,
+
+    /// Inject faults for testing clients: probability of dropping the
+    /// connection instead of answering
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_name = "P", default_value = "0")]
+    chaos_drop: f64,
+
+    /// Inject faults for testing clients: probability of answering with an
+    /// error instead of serving
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_name = "P", default_value = "0")]
+    chaos_error: f64,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Replace the server running at the address with the kvs-server binary
+    /// now installed, keeping its listening socket open, then let the old
+    /// process exit once its connections are closed
+    Upgrade,
+    /// Print the completion script of kvs-server for a shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page of kvs-server
+    Man,
+    /// Write, read back, compact, reopen and remove keys of its own in a
+    /// data directory not in use, checking every step, e.g. before routing
+    /// traffic to a new deployment. Exits with an error on the first step
+    /// failing.
+    Selftest {
+        /// Data directory to test, the working directory by default
+        #[clap(long, value_name = "DIR")]
+        data_dir: Option<PathBuf>,
+    },
 }
 
+/// Keys written by a self-test.
+const SELFTEST_KEYS: usize = 100;
+
+/// Tells a server started by an upgrade that the listening socket of the
+/// server it replaces is this file descriptor.
+const LISTEN_FD_ENV: &str = "KVS_LISTEN_FD";
+
 #[derive(Clone, Copy, Debug, ValueEnum, PartialEq, Eq)]
 enum Engine {
     Kvs,
     Sled,
 }
 
-impl FromStr for Engine {
-    fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
-        match s {
-            "kvs" => Ok(Engine::Kvs),
-            "sled" => Ok(Engine::Sled),
-            _ => Err(format!("Unknown engine: {}", s)),
-        }
-    }
-
-    type Err = String;
-}
-
 impl Display for Engine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -64,13 +278,90 @@ fn validate_addr(s: &str) -> std::result::Result<String, String> {
 }
 
 fn main() -> Result<()> {
+    // everything reaches the logger, the admin console may lower the bar
     env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(log::LevelFilter::Trace)
         .init();
+    log::set_max_level(log::LevelFilter::Info);
     let args = Args::parse();
+    match args.command {
+        Some(Command::Upgrade) => {
+            let addr = args.addr.unwrap();
+            KvsClient::connect(&addr)?.upgrade()?;
+            info!("kvs-server at {} is handing over to a new process", addr);
+            return Ok(());
+        }
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Args::command(), "kvs-server", &mut io::stdout());
+            return Ok(());
+        }
+        Some(Command::Man) => {
+            return Ok(clap_mangen::Man::new(Args::command()).render(&mut io::stdout())?)
+        }
+        Some(Command::Selftest { ref data_dir }) => {
+            let dir = match data_dir {
+                Some(dir) => dir.clone(),
+                None => current_dir()?,
+            };
+            return selftest(&dir, &args);
+        }
+        None => {}
+    }
     let cwd = current_dir()?;
+    #[cfg(feature = "otlp")]
+    let _tracer = match &args.otlp_endpoint {
+        Some(endpoint) => Some(init_otlp(endpoint)?),
+        None => None,
+    };
 
     check_engine(args.engine);
+    if args.engine == Engine::Sled && args.lease_ttl_ms.is_some() {
+        error!("The sled engine cannot be shared, --lease-ttl-ms needs the kvs engine");
+        exit(1);
+    }
+    if args.engine == Engine::Sled && args.trash_window_secs.is_some() {
+        warn!("The sled engine has no trash, --trash-window-secs is ignored");
+    }
+    if args.engine == Engine::Sled && args.cache_budget.is_some() {
+        warn!("The sled engine has no cache mode, --cache-budget is ignored");
+    }
+    if args.engine == Engine::Sled && args.chunk_size.is_some() {
+        warn!("The sled engine does not chunk values, --chunk-size is ignored");
+    }
+    if args.engine == Engine::Sled && args.max_open_files.is_some() {
+        warn!("The sled engine manages its own files, --max-open-files is ignored");
+    }
+    if args.engine == Engine::Sled && args.compact_ratio.is_some() {
+        warn!("The sled engine compacts on its own, --compact-ratio is ignored");
+    }
+    if args.engine == Engine::Sled && args.write_times {
+        warn!("The sled engine records no write times, --write-times is ignored");
+    }
+    if args.engine == Engine::Sled && args.key_order.is_some() {
+        warn!("The sled engine lists keys bytewise, --key-order is ignored");
+    }
+    if args.engine == Engine::Sled && args.log_encoding.is_some() {
+        warn!("The sled engine has no log, --log-encoding is ignored");
+    }
+    #[cfg(feature = "compression")]
+    if args.engine == Engine::Sled && args.compress_above.is_some() {
+        warn!("The sled engine compresses on its own, --compress-above is ignored");
+    }
+    #[cfg(feature = "compression")]
+    if args.engine == Engine::Sled && args.compress_segments {
+        warn!("The sled engine compresses on its own, --compress-segments is ignored");
+    }
+    #[cfg(feature = "encryption")]
+    if args.engine == Engine::Sled && args.encryption_key_file.is_some() {
+        error!("The sled engine cannot encrypt its data, drop --encryption-key-file");
+        exit(1);
+    }
+    if args.engine == Engine::Sled && !args.extra_dir.is_empty() {
+        warn!("The sled engine uses a single directory, --extra-dir is ignored");
+    }
+    if args.engine == Engine::Sled && args.cold_dir.is_some() {
+        warn!("The sled engine has no cold tier, --cold-dir is ignored");
+    }
 
     info!("kvs-server startup args: {:?}", args);
     info!("kvs-server working directory: {}", cwd.display());
@@ -78,57 +369,491 @@ fn main() -> Result<()> {
     info!("kvs-server engine: {:?}", args.engine);
     info!("kvs-server listening on: {}", args.addr.clone().unwrap());
 
-    // write engine to file named "engine" in current directory
-    fs::write(current_dir()?.join("engine"), format!("{}", args.engine))?;
+    // a server started by an upgrade waits here for the old one to exit,
+    // new connections queueing on the listener meanwhile; servers sharing
+    // the directory under a lease follow the leader instead
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cwd.join("lock"))?;
+    if args.lease_ttl_ms.is_none() && lock.try_lock().is_err() {
+        info!("Waiting for the previous server to release the data directory");
+        lock.lock()?;
+    }
+
+    EngineKind::from(args.engine).write_marker(&cwd)?;
 
     let path = Path::new(&cwd);
-    let socket_addr = args.addr.unwrap().parse::<SocketAddr>().unwrap();
+    let socket_addr = args.addr.as_ref().unwrap().parse::<SocketAddr>().unwrap();
 
     match args.engine {
-        Engine::Kvs => start_engine(kvs::KvStore::open(&path)?, socket_addr)?,
-        Engine::Sled => start_engine(kvs::SledStore::new(sled::open(&path)?), socket_addr)?,
+        Engine::Kvs => {
+            let dirs: Vec<PathBuf> = std::iter::once(cwd.clone())
+                .chain(args.extra_dir.iter().cloned())
+                .collect();
+            let server = match args.lease_ttl_ms {
+                Some(ttl) => {
+                    let lease = Lease::new(
+                        cwd.join("leader"),
+                        socket_addr.to_string(),
+                        Duration::from_millis(ttl),
+                    );
+                    let store = open_kvs(&dirs, &args, false)?;
+                    let reopen = args.clone();
+                    KvsServer::new(store)
+                        .lease(lease, move |writable| open_kvs(&dirs, &reopen, writable))
+                }
+                None => KvsServer::new(open_kvs(&dirs, &args, true)?),
+            };
+            start_engine(server, socket_addr, &args)?
+        }
+        #[cfg(feature = "engine-sled")]
+        Engine::Sled => start_engine(KvsServer::new(open_sled(path, &args)?), socket_addr, &args)?,
+        #[cfg(not(feature = "engine-sled"))]
+        Engine::Sled => {
+            error!("kvs-server was built without the sled engine");
+            exit(1);
+        }
     }
 
     Ok(())
 }
 
-fn start_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
-}
-
-fn check_engine(target_engine: Engine) {
-    match current_engine() {
-        Err(e) => {
-            error!("Failed to check current engine: {}", e);
-            exit(1);
+// run the self-test against the engine of the server in `dir`, which no
+// server may be using meanwhile
+fn selftest(dir: &Path, args: &Args) -> Result<()> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join("lock"))?;
+    if lock.try_lock().is_err() {
+        return Err(KvsError::Other(format!(
+            "{} is in use by a running server",
+            dir.display()
+        )));
+    }
+    EngineKind::from(args.engine).check(dir)?;
+    EngineKind::from(args.engine).write_marker(dir)?;
+    println!(
+        "kvs-server {} self-test of the {} engine in {}",
+        env!("CARGO_PKG_VERSION"),
+        args.engine,
+        dir.display()
+    );
+    match args.engine {
+        Engine::Kvs => {
+            let dirs: Vec<PathBuf> = std::iter::once(dir.to_path_buf())
+                .chain(args.extra_dir.iter().cloned())
+                .collect();
+            selftest_cycle(|| open_kvs(&dirs, args, true))?;
+            // compacting the keys of the test away leaves an empty
+            // generation behind, harmless
+            step(
+                "fsck",
+                (|| {
+                    let issues: Vec<_> = kvs::fsck::check(dir, false)?
+                        .issues
+                        .into_iter()
+                        .filter(|issue| issue.kind != IssueKind::EmptyGeneration)
+                        .collect();
+                    match issues.first() {
+                        Some(issue) => Err(KvsError::Other(format!(
+                            "{} issues, first in {}: {}",
+                            issues.len(),
+                            issue.file,
+                            issue.detail
+                        ))),
+                        None => Ok(()),
+                    }
+                })(),
+            )?;
         }
-        Ok(None) => {
-            info!("No engine file found, starting with {:?}", target_engine);
+        #[cfg(feature = "engine-sled")]
+        Engine::Sled => selftest_cycle(|| Ok(kvs::SledStore::new(sled::open(dir)?)))?,
+        #[cfg(not(feature = "engine-sled"))]
+        Engine::Sled => {
+            return Err(KvsError::Other(
+                "kvs-server was built without the sled engine".to_owned(),
+            ))
         }
-        Ok(Some(engine)) => {
-            if target_engine != engine {
-                error!(
-                    "Current engine is {:?}, but you are trying to start {:?} engine",
-                    engine, target_engine
-                );
-                exit(1);
+    }
+    println!("self-test passed");
+    Ok(())
+}
+
+// write, read, compact, reopen and remove the keys of the self-test, which
+// are made unique to this process, checking their values and checksums
+fn selftest_cycle<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let prefix = format!("__selftest:{}:", std::process::id());
+    let keys: Vec<String> = (0..SELFTEST_KEYS)
+        .map(|i| format!("{}{}", prefix, i))
+        .collect();
+    let values: Vec<Option<String>> = (0..SELFTEST_KEYS)
+        .map(|i| Some(format!("{}{}", i, "v".repeat(i * 37))))
+        .collect();
+    let list = format!("{}list", prefix);
+
+    let mut engine = step("open", open())?;
+    step(
+        "write",
+        (|| {
+            for (key, value) in keys.iter().zip(&values) {
+                engine.set(key.clone(), value.clone().unwrap())?;
+            }
+            engine.rpush(list.clone(), vec!["a".to_owned(), "b".to_owned()])?;
+            Ok(())
+        })(),
+    )?;
+    step("read", expect_values(&mut engine, &keys, &values))?;
+    let checksums = step("checksum", selftest_checksums(&mut engine, &keys, &list))?;
+    step("compact", engine.compact())?;
+    step(
+        "verify",
+        expect_checksums(&mut engine, &keys, &list, &checksums),
+    )?;
+    drop(engine);
+    let mut engine = step("reopen", open())?;
+    step(
+        "verify",
+        expect_checksums(&mut engine, &keys, &list, &checksums),
+    )?;
+    step(
+        "remove",
+        (|| {
+            for key in keys.iter().chain([&list]) {
+                engine.purge(key.clone())?;
             }
+            Ok(())
+        })(),
+    )?;
+    step(
+        "read",
+        (|| {
+            expect_values(&mut engine, &keys, &vec![None; SELFTEST_KEYS])?;
+            match engine.exists(list.clone())? {
+                true => Err(KvsError::Other(format!("{} still holds data", list))),
+                false => Ok(()),
+            }
+        })(),
+    )?;
+    step("compact", engine.compact())
+}
+
+// print how a step of the self-test went
+fn step<T>(name: &str, result: Result<T>) -> Result<T> {
+    match &result {
+        Ok(_) => println!("{:<8} ok", name),
+        Err(e) => println!("{:<8} FAILED: {}", name, e),
+    }
+    result
+}
+
+fn expect_values<E: KvsEngine>(
+    engine: &mut E,
+    keys: &[String],
+    expected: &[Option<String>],
+) -> Result<()> {
+    let found = engine.get_many(keys.to_vec())?;
+    match keys
+        .iter()
+        .zip(found.iter().zip(expected))
+        .find(|(_, (found, expected))| found != expected)
+    {
+        Some((key, (found, expected))) => Err(KvsError::Other(format!(
+            "{} holds {:?}, expected {:?}",
+            key, found, expected
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn selftest_checksums<E: KvsEngine>(
+    engine: &mut E,
+    keys: &[String],
+    list: &str,
+) -> Result<Vec<u64>> {
+    keys.iter()
+        .map(String::as_str)
+        .chain([list])
+        .map(|key| match engine.dump(key.to_owned())? {
+            Some(dump) => Ok(kvs::diff::checksum(&dump)),
+            None => Err(KvsError::Other(format!("{} holds no data", key))),
+        })
+        .collect()
+}
+
+fn expect_checksums<E: KvsEngine>(
+    engine: &mut E,
+    keys: &[String],
+    list: &str,
+    expected: &[u64],
+) -> Result<()> {
+    let found = selftest_checksums(engine, keys, list)?;
+    match found
+        .iter()
+        .zip(expected)
+        .position(|(found, expected)| found != expected)
+    {
+        Some(i) => Err(KvsError::Other(format!(
+            "the checksum of {} changed",
+            keys.get(i).map_or(list, String::as_str)
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "engine-sled")]
+fn open_sled(path: &Path, args: &Args) -> Result<kvs::SledStore> {
+    let mut store = kvs::SledStore::new(sled::open(path)?);
+    if let Some(policy) = args.expired_reads {
+        store = store.expired_reads(policy);
+    }
+    Ok(store)
+}
+
+// open the kvs engine over `dirs`, read-only when following the leader of
+// a shared directory
+fn open_kvs(dirs: &[PathBuf], args: &Args, writable: bool) -> Result<kvs::KvStore> {
+    #[cfg(feature = "encryption")]
+    if let Some(path) = &args.encryption_key_file {
+        if !writable || args.cold_dir.is_some() {
+            return Err(KvsError::InvalidCommand(
+                "--encryption-key-file is for a writable store with no cold tier".to_owned(),
+            ));
+        }
+        let config = kvs::EncryptionConfig::key(read_key(path)?);
+        let store = kvs::KvStore::open_encrypted(dirs, config)?;
+        return configure_kvs(store, args, writable);
+    }
+    let store = match &args.cold_dir {
+        _ if !writable => kvs::KvStore::open_read_only(dirs, args.cold_dir.as_deref())?,
+        Some(cold) => kvs::KvStore::open_tiered(dirs, cold, args.cold_after)?,
+        None => match args.key_order {
+            Some(order) => kvs::KvStore::open_ordered(dirs, order)?,
+            None => kvs::KvStore::open_dirs(dirs)?,
+        },
+    };
+    configure_kvs(store, args, writable)
+}
+
+// apply the options of `args` to the kvs engine just opened
+fn configure_kvs(mut store: kvs::KvStore, args: &Args, writable: bool) -> Result<kvs::KvStore> {
+    // otherwise opened in the order recorded
+    if let Some(order) = args.key_order.filter(|&order| order != store.key_order()) {
+        return Err(KvsError::InvalidCommand(format!(
+            "the data directory is in the {} key order, not {}",
+            store.key_order(),
+            order
+        )));
+    }
+    if let Some(secs) = args.trash_window_secs {
+        store = store.trash_window(Duration::from_secs(secs));
+    }
+    if let Some(budget) = args.cache_budget {
+        store = store.cache_budget(budget);
+    }
+    if let Some(size) = args.chunk_size {
+        store = store.chunk_size(size);
+    }
+    if let Some(max) = args.max_open_files {
+        store = store.max_open_files(max);
+    }
+    if let Some(policy) = args.expired_reads {
+        store = store.expired_reads(policy);
+    }
+    if let Some(encoding) = args.log_encoding {
+        store = store.log_encoding(encoding);
+    }
+    #[cfg(feature = "compression")]
+    if let Some(size) = args.compress_above {
+        store = store.compress_above(size);
+    }
+    #[cfg(feature = "compression")]
+    {
+        store = store.compress_segments(args.compress_segments);
+    }
+    if let Some(ratio) = args.compact_ratio.filter(|_| writable) {
+        if store.compact_if_bloated(ratio)? {
+            info!("Compacted the log, mostly stale on start");
         }
     }
+    Ok(store.write_times(args.write_times))
+}
+
+// the AES-256 key in `path`, as 64 hex digits
+#[cfg(feature = "encryption")]
+fn read_key(path: &Path) -> Result<[u8; 32]> {
+    let text = std::fs::read_to_string(path)?;
+    let text = text.trim();
+    let invalid = || {
+        KvsError::InvalidCommand(format!(
+            "{} does not hold an AES-256 key as 64 hex digits",
+            path.display()
+        ))
+    };
+    if text.len() != 64 || !text.is_ascii() {
+        return Err(invalid());
+    }
+    let mut key = [0; 32];
+    for (byte, pair) in key.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+fn start_engine<E: KvsEngine>(server: KvsServer<E>, addr: SocketAddr, args: &Args) -> Result<()> {
+    let mut server = server
+        .budget(args.budget)
+        .scan_budget(Duration::from_millis(args.scan_budget_ms))
+        .id_block(args.id_block)
+        .read_only(args.read_only)
+        .error_context(args.error_context)
+        .drain_delay(Duration::from_millis(args.drain_delay_ms))
+        .fence_file(current_dir()?.join("fence"));
+    if let Some(bytes) = args.min_free_bytes {
+        server = server.min_free_space(current_dir()?, bytes);
+    }
+    if let Some(capacity) = args.hotkeys {
+        server = server.hot_keys(capacity);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.admin_socket {
+        server = server.admin_socket(path);
+    }
+    if let Some(path) = &args.trace_file {
+        server = server.trace(path, args.trace_sample_rate);
+    }
+    if args.cluster_slots.is_some() || !args.cluster_peers.is_empty() {
+        let slots = args.cluster_slots.clone().unwrap_or_default();
+        let cluster = Cluster::new(addr.to_string(), slots).peers(args.cluster_peers.clone());
+        server = server.cluster(cluster);
+    }
+    #[cfg(feature = "chaos")]
+    if args.chaos_latency_ms > 0
+        || args.chaos_jitter_ms > 0
+        || args.chaos_drop > 0.0
+        || args.chaos_error > 0.0
+    {
+        warn!("kvs-server chaos mode enabled, faults will be injected");
+        server = server.chaos(
+            kvs::Chaos::new()
+                .latency(Duration::from_millis(args.chaos_latency_ms))
+                .jitter(Duration::from_millis(args.chaos_jitter_ms))
+                .drop_rate(args.chaos_drop)
+                .error_rate(args.chaos_error),
+        );
+    }
+    server
+        .upgrade(|listener: &TcpListener| spawn_successor(listener))
+        .run_on(listener(addr)?)
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine_file = current_dir()?.join("engine");
-    if !engine_file.exists() {
-        return Ok(None);
+// the listener handed over by the server this one replaces, if any
+#[cfg(unix)]
+fn listener(addr: SocketAddr) -> Result<TcpListener> {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    match std::env::var(LISTEN_FD_ENV) {
+        Ok(fd) => {
+            let fd: RawFd = fd
+                .parse()
+                .map_err(|e| KvsError::Other(format!("Bad {}: {}", LISTEN_FD_ENV, e)))?;
+            info!("kvs-server taking over the listener of the previous server");
+            // SAFETY: the previous server started this process with the
+            // listener on `fd`, and nothing else uses it
+            Ok(unsafe { TcpListener::from_raw_fd(fd) })
+        }
+        Err(_) => Ok(TcpListener::bind(addr)?),
     }
+}
 
-    match fs::read_to_string(engine_file)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
+#[cfg(not(unix))]
+fn listener(addr: SocketAddr) -> Result<TcpListener> {
+    Ok(TcpListener::bind(addr)?)
+}
+
+// start the installed binary with the same arguments, the listener as its
+// standard input
+#[cfg(unix)]
+fn spawn_successor(listener: &TcpListener) -> Result<()> {
+    use std::os::fd::OwnedFd;
+    use std::process::{Command, Stdio};
+
+    // argv[0] rather than `current_exe`, which keeps naming the old binary
+    // once it is replaced
+    let mut args = std::env::args_os();
+    let program = args
+        .next()
+        .ok_or_else(|| KvsError::Other("no program name".to_owned()))?;
+    let child = Command::new(program)
+        .args(args)
+        .env(LISTEN_FD_ENV, "0")
+        .stdin(Stdio::from(OwnedFd::from(listener.try_clone()?)))
+        .spawn()?;
+    info!("kvs-server started process {} to take over", child.id());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn spawn_successor(_listener: &TcpListener) -> Result<()> {
+    Err(KvsError::Other(
+        "upgrade is only supported on unix".to_owned(),
+    ))
+}
+
+// install a subscriber exporting every span to `endpoint`; spans still
+// batched are flushed when the returned provider is dropped
+#[cfg(feature = "otlp")]
+fn init_otlp(endpoint: &str) -> Result<opentelemetry_sdk::trace::SdkTracerProvider> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| KvsError::Other(format!("OTLP exporter: {}", e)))?;
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name("kvs-server")
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer("kvs-server");
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .map_err(|e| KvsError::Other(format!("tracing subscriber: {}", e)))?;
+    info!("kvs-server exporting spans to {}", endpoint);
+    Ok(provider)
+}
+
+fn check_engine(target_engine: Engine) {
+    let cwd = match current_dir() {
+        Ok(cwd) => cwd,
         Err(e) => {
-            warn!("Failed to parse engine file: {}", e);
-            Ok(None)
+            error!("Failed to check current engine: {}", e);
+            exit(1);
+        }
+    };
+    if let Err(e) = EngineKind::from(target_engine).check(&cwd) {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+impl From<Engine> for EngineKind {
+    fn from(engine: Engine) -> Self {
+        match engine {
+            Engine::Kvs => EngineKind::Kvs,
+            Engine::Sled => EngineKind::Sled,
         }
     }
 }
@@ -0,0 +1,106 @@
+//! Fault injection for testing clients against a misbehaving server.
+
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Faults a server injects while serving requests, see `KvsServer::chaos`.
+///
+/// Every request is delayed by `latency` plus a random amount up to
+/// `jitter`. The delay is spent on the engine thread, so like a slow disk it
+/// holds up every connection. Then, with the given probabilities, the
+/// connection is dropped without an answer, or the request is answered with
+/// an error instead of being served.
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    latency: Duration,
+    jitter: Duration,
+    drop_rate: f64,
+    error_rate: f64,
+    state: u64,
+}
+
+/// What happens to a request after its delay.
+pub(crate) enum Fault {
+    Drop,
+    Error,
+}
+
+impl Chaos {
+    /// Inject no fault at all, until configured otherwise.
+    pub fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos() as u64);
+        Chaos {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            drop_rate: 0.0,
+            error_rate: 0.0,
+            state: 0,
+        }
+        .seed(seed)
+    }
+
+    /// Delay every request by `latency`.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Delay every request by a random amount up to `jitter`, on top of the
+    /// fixed latency.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Drop the connection instead of answering with probability `rate`.
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        self.drop_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Answer with an error instead of serving with probability `rate`.
+    pub fn error_rate(mut self, rate: f64) -> Self {
+        self.error_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Seed the random faults, to replay the same sequence of faults.
+    pub fn seed(mut self, seed: u64) -> Self {
+        // xorshift gets stuck on a zero state
+        self.state = seed | 1;
+        self
+    }
+
+    /// Delay the current request, then pick the fault to inject, if any.
+    pub(crate) fn inject(&mut self) -> Option<Fault> {
+        let jitter = self.jitter.mul_f64(self.random());
+        let delay = self.latency + jitter;
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        if self.random() < self.drop_rate {
+            Some(Fault::Drop)
+        } else if self.random() < self.error_rate {
+            Some(Fault::Error)
+        } else {
+            None
+        }
+    }
+
+    // uniform in [0, 1), from xorshift64*
+    fn random(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        bits as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Chaos::new()
+    }
+}
@@ -1,13 +1,29 @@
 use crate::{
-    protocol::{GetResponse, Request},
-    Result,
+    cluster::{key_slot, SLOT_COUNT},
+    engines::decode_bytes,
+    protocol::{
+        ChildrenResponse, ClientStats, ClientsResponse, ClusterInfo, ClusterInfoResponse,
+        CountResponse, DumpResponse, ExportResponse, GetManyResponse, GetResponse,
+        GetStreamResponse, GetWithTypeResponse, HGetAllResponse, Health, HotKey, HotKeysResponse,
+        KeysResponse, LRangeResponse, MetaResponse, PingResponse, RateResponse, RemoveResponse,
+        Request, SIsMemberResponse, SMembersResponse, ServerStats, SetMetaResponse, SetResponse,
+        SlotState, StatsResponse, TokenResponse, UsageResponse, ValueFilter, WriteMeta,
+        STREAM_CHUNK_SIZE,
+    },
+    Children, ClientMetrics, KeyDump, KeyMeta, KvsError, PrefixUsage, Rate, Result, ValueType,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpStream, ToSocketAddrs},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::de::IoRead;
 use serde_json::Deserializer;
 
@@ -15,49 +31,1104 @@ use serde_json::Deserializer;
 pub struct KvsClient {
     reader: Deserializer<IoRead<BufReader<TcpStream>>>,
     writer: BufWriter<TcpStream>,
+    metrics: Option<ClientMetrics>,
+    // the request waiting for its response, and since when if timed
+    op: &'static str,
+    sent: Option<Instant>,
 }
 
-impl KvsClient {
-    /// Connect to the server to get a client
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+/// Default size of the read and write buffers of a connection.
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Default time to give up connecting to an address after.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time to wait for a connection attempt before starting the next.
+const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Options of the connection of a `KvsClient`, see `KvsClient::builder`.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    connect_timeout: Duration,
+    attempt_delay: Duration,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    nodelay: bool,
+    buffer_size: usize,
+    metrics: Option<ClientMetrics>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        ClientBuilder {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+            read_timeout: None,
+            write_timeout: None,
+            keepalive: None,
+            nodelay: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            metrics: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Give up connecting to an address after `timeout`, 10 seconds by
+    /// default, rather than after the minutes the OS may wait on an
+    /// address dropping packets.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Start connecting to the next address the server resolves to when
+    /// the attempts so far did not succeed within `delay`, 250ms by
+    /// default, IPv6 and IPv4 addresses taking turns. The first connection
+    /// made is kept, the others are dropped.
+    pub fn attempt_delay(mut self, delay: Duration) -> Self {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// Fail a request with `KvsError::Io` when its response takes longer
+    /// than `timeout`. The connection is then out of step with the server
+    /// and should be dropped.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail a request with `KvsError::Io` when sending it blocks longer
+    /// than `timeout`, e.g. on a server not reading anymore.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Send TCP keepalive probes once the connection is idle for `idle`, so
+    /// a dead server or a dropped route is noticed on idle connections.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Send requests right away rather than coalescing small writes,
+    /// trading bandwidth for latency.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Set the size of the read and write buffers of the connection.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size.max(1);
+        self
+    }
+
+    /// Record the latency and outcome of every request in `metrics`, see
+    /// `KvsClient::metrics`. The clients built share it, along with every
+    /// clone of it.
+    pub fn metrics(mut self, metrics: ClientMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Connect to the server at `addr` with these options. Fails with
+    /// `KvsError::Resolve` if `addr` cannot be resolved.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<KvsClient> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs().map_err(KvsError::Resolve)?.collect();
+        if addrs.is_empty() {
+            return Err(KvsError::Resolve(io::Error::new(
+                io::ErrorKind::NotFound,
+                "address resolved to nothing",
+            )));
+        }
+        let stream = self.connect_any(addrs)?;
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+            socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        }
+        let writer = stream.try_clone()?;
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
+            reader: Deserializer::from_reader(BufReader::with_capacity(self.buffer_size, stream)),
+            writer: BufWriter::with_capacity(self.buffer_size, writer),
+            metrics: self.metrics.clone(),
+            op: "",
+            sent: None,
         })
     }
+}
+
+impl ClientBuilder {
+    // connect to the first of `addrs` accepting, in turns of address
+    // family, starting an attempt every `attempt_delay` or as soon as the
+    // previous one failed
+    fn connect_any(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.into_iter().partition(SocketAddr::is_ipv6);
+        let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+        let mut ordered = Vec::new();
+        loop {
+            let (a, b) = (v6.next(), v4.next());
+            if a.is_none() && b.is_none() {
+                break;
+            }
+            ordered.extend(a.into_iter().chain(b));
+        }
+        let mut pending = ordered.into_iter();
+
+        let (sender, receiver) = mpsc::channel();
+        let timeout = self.connect_timeout;
+        let start = |addr: SocketAddr| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                // a late connection is dropped along with the send
+                let _ = sender.send(TcpStream::connect_timeout(&addr, timeout));
+            });
+        };
+        let mut running = 1;
+        start(pending.next().unwrap());
+        let mut last_err = None;
+        loop {
+            let received = match pending.len() {
+                0 => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                _ => receiver.recv_timeout(self.attempt_delay),
+            };
+            match received {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    running -= 1;
+                    last_err = Some(e);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => unreachable!("the sender is held"),
+            }
+            match pending.next() {
+                Some(addr) => {
+                    start(addr);
+                    running += 1;
+                }
+                None if running == 0 => return Err(last_err.unwrap()),
+                None => {}
+            }
+        }
+    }
+}
+
+impl KvsClient {
+    /// Connect to the server to get a client, with the default options of
+    /// `KvsClient::builder`
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        ClientBuilder::default().connect(addr)
+    }
+
+    /// Set up the options of a connection: timeouts, keepalive, buffer
+    /// sizes, then `connect`
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// The statistics of the requests of this client, if it was built with
+    /// `ClientBuilder::metrics`. `stats` gets those of the server.
+    pub fn metrics(&self) -> Option<&ClientMetrics> {
+        self.metrics.as_ref()
+    }
 
     /// Get the value of a key
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        self.send(&Request::Get { key })?;
+        let resp = self.recv::<GetResponse>()?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(err) => Err(err.into()),
+            GetResponse::Err(err) => Err(self.remote_error(err)),
         }
     }
 
     /// Set the value of a key
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        self.set_with_type(key, value, ValueType::String)
+    }
+
+    /// Set the value of a key, tagged with the type of its content
+    pub fn set_with_type(
+        &mut self,
+        key: String,
+        value: String,
+        value_type: ValueType,
+    ) -> Result<()> {
+        let req = Request::Set {
+            key,
+            value,
+            value_type,
+            meta: false,
+        };
+        self.send(&req)?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Set the value of a key like `set_with_type`, getting what the server
+    /// reports about the write: its processing time, log sequence number
+    /// and node. A server predating the metadata reports none.
+    pub fn set_with_meta(
+        &mut self,
+        key: String,
+        value: String,
+        value_type: ValueType,
+    ) -> Result<Option<WriteMeta>> {
+        let req = Request::Set {
+            key,
+            value,
+            value_type,
+            meta: true,
+        };
+        self.send(&req)?;
+        let resp = self.recv::<SetMetaResponse>()?;
+        match resp {
+            SetMetaResponse::Ok(meta) => Ok(meta),
+            SetMetaResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Set the value of a key to the content of `value`, which must be
+    /// UTF-8, sent in pieces as it is read. Neither side holds the value
+    /// whole: the server keeps the pieces in a temporary file until the
+    /// last one, and the kvs engine stores them as chunks.
+    pub fn set_reader(&mut self, key: String, mut value: impl Read) -> Result<()> {
+        self.upload(&Request::SetBegin { key })?;
+        let mut buf = Vec::new();
+        loop {
+            let missing = STREAM_CHUNK_SIZE - buf.len();
+            let eof = (&mut value).take(missing as u64).read_to_end(&mut buf)? < missing;
+            // a char cut short is completed by the next piece
+            let end = match std::str::from_utf8(&buf) {
+                Ok(_) => buf.len(),
+                Err(e) if e.error_len().is_none() && !eof => e.valid_up_to(),
+                Err(e) => return Err(KvsError::InvalidValue(format!("not UTF-8: {}", e))),
+            };
+            if end > 0 {
+                let data = String::from_utf8(buf.drain(..end).collect()).unwrap();
+                self.upload(&Request::SetChunk { data })?;
+            }
+            if eof {
+                break;
+            }
+        }
+        self.upload(&Request::SetCommit)
+    }
+
+    // send a request of an upload and wait for its acknowledgment
+    fn upload(&mut self, req: &Request) -> Result<()> {
+        self.send(req)?;
+        let resp = self.recv::<SetResponse>()?;
         match resp {
-            GetResponse::Ok(_) => Ok(()),
-            GetResponse::Err(err) => Err(err.into()),
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Write the value of a key to `out` as it is received, in pieces, and
+    /// return whether the key exists. On error, `out` may hold part of the
+    /// value already.
+    pub fn get_writer(&mut self, key: String, mut out: impl Write) -> Result<bool> {
+        self.send(&Request::GetStream { key })?;
+        loop {
+            match self.recv_part::<GetStreamResponse>()? {
+                GetStreamResponse::Chunk(data) => out.write_all(data.as_bytes())?,
+                GetStreamResponse::Ok(found) => {
+                    self.finish(false);
+                    return Ok(found);
+                }
+                GetStreamResponse::Err(err) => return Err(self.remote_error(err)),
+            }
+        }
+    }
+
+    /// Get the value of a key along with its type tag
+    pub fn get_with_type(&mut self, key: String) -> Result<Option<(String, ValueType)>> {
+        self.send(&Request::GetWithType { key })?;
+        let resp = self.recv::<GetWithTypeResponse>()?;
+        match resp {
+            GetWithTypeResponse::Ok(value) => Ok(value.map(|v| (v.value, v.value_type))),
+            GetWithTypeResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Set the value of a key to arbitrary bytes, typed as
+    /// `ValueType::Bytes`, travelling and stored encoded in base64
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set_with_type(key, BASE64.encode(value), ValueType::Bytes)
+    }
+
+    /// Get the value of a key as bytes: decoded from base64 for a value
+    /// typed as `ValueType::Bytes`, the UTF-8 text of the others
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        match self.get_with_type(key)? {
+            Some((value, ValueType::Bytes)) => Ok(Some(decode_bytes(&value)?)),
+            Some((value, _)) => Ok(Some(value.into_bytes())),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the value of a key to `value` serialized as JSON, typed as
+    /// `ValueType::Json`
+    pub fn set_typed<T: Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+        self.set_with_type(key, serde_json::to_string(value)?, ValueType::Json)
+    }
+
+    /// Get the value of a key deserialized from JSON, failing if it does
+    /// not hold a `T`
+    pub fn get_typed<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the part of a JSON value at a JSON pointer, encoded as JSON
+    pub fn get_field(&mut self, key: String, pointer: String) -> Result<Option<String>> {
+        self.send(&Request::GetField { key, pointer })?;
+        let resp = self.recv::<GetResponse>()?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(err) => Err(self.remote_error(err)),
         }
     }
 
     /// Remove a key
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        self.send(&Request::Remove { key })?;
+        let resp = self.recv::<RemoveResponse>()?;
+        match resp {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Restore a string key removed within the trash window of the server
+    pub fn undelete(&mut self, key: String) -> Result<()> {
+        self.send(&Request::Undelete { key })?;
+        let resp = self.recv::<RemoveResponse>()?;
+        match resp {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Set a string key to `value` at `at`, the server keeping the write
+    /// until then
+    pub fn set_at(&mut self, key: String, value: String, at: SystemTime) -> Result<()> {
+        let req = Request::SetAt {
+            key,
+            value,
+            at_ms: epoch_ms(at),
+        };
+        self.send(&req)?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Remove a string key at `at`, the server keeping the removal until
+    /// then
+    pub fn remove_at(&mut self, key: String, at: SystemTime) -> Result<()> {
+        let req = Request::RemoveAt {
+            key,
+            at_ms: epoch_ms(at),
+        };
+        self.send(&req)?;
+        let resp = self.recv::<RemoveResponse>()?;
+        match resp {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Push values at the head of a list and get its new length
+    pub fn lpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        self.send(&Request::LPush { key, values })?;
+        let resp = self.recv::<CountResponse>()?;
+        match resp {
+            CountResponse::Ok(len) => Ok(len),
+            CountResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Push values at the tail of a list and get its new length
+    pub fn rpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        self.send(&Request::RPush { key, values })?;
+        let resp = self.recv::<CountResponse>()?;
+        match resp {
+            CountResponse::Ok(len) => Ok(len),
+            CountResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the elements of a list between two inclusive, possibly negative, indexes
+    pub fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.send(&Request::LRange { key, start, stop })?;
+        let resp = self.recv::<LRangeResponse>()?;
+        match resp {
+            LRangeResponse::Ok(values) => Ok(values),
+            LRangeResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Pop the head of a list
+    pub fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        self.send(&Request::LPop { key })?;
+        let resp = self.recv::<GetResponse>()?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Set a field of a hash
+    pub fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.send(&Request::HSet { key, field, value })?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get a field of a hash
+    pub fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        self.send(&Request::HGet { key, field })?;
+        let resp = self.recv::<GetResponse>()?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get every field of a hash
+    pub fn hgetall(&mut self, key: String) -> Result<BTreeMap<String, String>> {
+        self.send(&Request::HGetAll { key })?;
+        let resp = self.recv::<HGetAllResponse>()?;
+        match resp {
+            HGetAllResponse::Ok(fields) => Ok(fields),
+            HGetAllResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Remove a field of a hash
+    pub fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        self.send(&Request::HDel { key, field })?;
+        let resp = self.recv::<RemoveResponse>()?;
+        match resp {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Add members to a set and get how many were not members yet
+    pub fn sadd(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        self.send(&Request::SAdd { key, members })?;
+        let resp = self.recv::<CountResponse>()?;
+        match resp {
+            CountResponse::Ok(added) => Ok(added),
+            CountResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Remove members from a set and get how many were members
+    pub fn srem(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        self.send(&Request::SRem { key, members })?;
+        let resp = self.recv::<CountResponse>()?;
+        match resp {
+            CountResponse::Ok(removed) => Ok(removed),
+            CountResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Check whether a member belongs to a set
+    pub fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        self.send(&Request::SIsMember { key, member })?;
+        let resp = self.recv::<SIsMemberResponse>()?;
+        match resp {
+            SIsMemberResponse::Ok(is_member) => Ok(is_member),
+            SIsMemberResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the members of a set
+    pub fn smembers(&mut self, key: String) -> Result<BTreeSet<String>> {
+        self.send(&Request::SMembers { key })?;
+        let resp = self.recv::<SMembersResponse>()?;
+        match resp {
+            SMembersResponse::Ok(members) => Ok(members),
+            SMembersResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Count one hit against a sliding-window rate limit of `limit` hits
+    /// per `window`, shared by every client using the same key. The window
+    /// is a millisecond at least, `MAX_RATE_WINDOW` at most
+    pub fn rate(&mut self, key: String, window: Duration, limit: u64) -> Result<Rate> {
+        let req = Request::Rate {
+            key,
+            window_ms: window.as_millis() as u64,
+            limit,
+        };
+        self.send(&req)?;
+        let resp = self.recv::<RateResponse>()?;
+        match resp {
+            RateResponse::Ok(rate) => Ok(rate),
+            RateResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// List the immediate children of `prefix`, seeing keys as paths split
+    /// by `separator`
+    pub fn list_children(&mut self, prefix: String, separator: String) -> Result<Children> {
+        self.send(&Request::ListChildren { prefix, separator })?;
+        let resp = self.recv::<ChildrenResponse>()?;
         match resp {
-            GetResponse::Ok(_) => Ok(()),
-            GetResponse::Err(err) => Err(err.into()),
+            ChildrenResponse::Ok(children) => Ok(children),
+            ChildrenResponse::Err(err) => Err(self.remote_error(err)),
         }
     }
+
+    /// Count the keys of the server and the bytes they take up by prefix,
+    /// up to the `depth`-th `separator` of the keys, see `KvsEngine::usage`
+    pub fn usage(&mut self, separator: String, depth: usize) -> Result<Vec<PrefixUsage>> {
+        self.send(&Request::Usage { separator, depth })?;
+        let resp = self.recv::<UsageResponse>()?;
+        match resp {
+            UsageResponse::Ok(usage) => Ok(usage),
+            UsageResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the membership and slot assignment of the cluster the server
+    /// belongs to
+    pub fn cluster_info(&mut self) -> Result<ClusterInfo> {
+        self.send(&Request::ClusterInfo)?;
+        let resp = self.recv::<ClusterInfoResponse>()?;
+        match resp {
+            ClusterInfoResponse::Ok(info) => Ok(info),
+            ClusterInfoResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Let the next request through although its slot is not served by
+    /// the server yet, after a `KvsError::Ask` redirect
+    pub fn asking(&mut self) -> Result<()> {
+        self.send(&Request::Asking)?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Change the state of a hash slot on the server, see `migrate_slot`
+    pub fn set_slot(&mut self, slot: u16, state: SlotState) -> Result<()> {
+        self.send(&Request::SetSlot { slot, state })?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get up to `count` keys of a hash slot stored on the server
+    pub fn keys_in_slot(&mut self, slot: u16, count: usize) -> Result<Vec<String>> {
+        self.send(&Request::GetKeysInSlot { slot, count })?;
+        let resp = self.recv::<KeysResponse>()?;
+        match resp {
+            KeysResponse::Ok(keys) => Ok(keys),
+            KeysResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Make the server hand keys over to the node at `target`, and get how
+    /// many were moved
+    pub fn migrate(&mut self, target: String, keys: Vec<String>) -> Result<usize> {
+        self.send(&Request::Migrate { target, keys })?;
+        let resp = self.recv::<CountResponse>()?;
+        match resp {
+            CountResponse::Ok(moved) => Ok(moved),
+            CountResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get up to `count` keys stored on the server, in order, starting
+    /// after `after` or from the first key
+    pub fn scan(&mut self, after: Option<String>, count: usize) -> Result<Vec<String>> {
+        self.scan_request(after, count, None, None)
+    }
+
+    /// Get up to `count` keys matching the glob `pattern`, e.g.
+    /// `user:*:settings`, like `scan`. The server filters the keys, only
+    /// the matching ones are sent over.
+    pub fn scan_matching(
+        &mut self,
+        pattern: String,
+        after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        self.scan_request(after, count, Some(pattern), None)
+    }
+
+    /// Get up to `count` keys whose string value matches `value`, and their
+    /// name the glob `pattern` if any, like `scan`. The server reads and
+    /// filters the values, only the matching keys are sent over.
+    ///
+    /// The server stops filtering once the scan runs over its time budget,
+    /// returning a short page; it fails the request if nothing matched by
+    /// then, narrow the pattern to go on.
+    pub fn scan_values(
+        &mut self,
+        pattern: Option<String>,
+        value: ValueFilter,
+        after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        self.scan_request(after, count, pattern, Some(value))
+    }
+
+    /// Get the values of `keys` in one round trip, `None` for the keys not
+    /// found, in the order of `keys`
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.send(&Request::GetMany { keys })?;
+        let resp = self.recv::<GetManyResponse>()?;
+        match resp {
+            GetManyResponse::Ok(values) => Ok(values),
+            GetManyResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get every key starting with `prefix`, e.g. `user:123:`, sorted
+    pub fn scan_prefix(&mut self, prefix: String) -> Result<Vec<String>> {
+        self.send(&Request::ScanPrefix { prefix })?;
+        let resp = self.recv::<KeysResponse>()?;
+        match resp {
+            KeysResponse::Ok(keys) => Ok(keys),
+            KeysResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    fn scan_request(
+        &mut self,
+        after: Option<String>,
+        count: usize,
+        pattern: Option<String>,
+        value: Option<ValueFilter>,
+    ) -> Result<Vec<String>> {
+        let req = Request::Scan {
+            after,
+            count,
+            pattern,
+            value,
+        };
+        self.send(&req)?;
+        let resp = self.recv::<KeysResponse>()?;
+        match resp {
+            KeysResponse::Ok(keys) => Ok(keys),
+            KeysResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Capture everything stored under a key, `None` if it holds no data
+    pub fn dump(&mut self, key: String) -> Result<Option<KeyDump>> {
+        self.send(&Request::Dump { key })?;
+        let resp = self.recv::<DumpResponse>()?;
+        match resp {
+            DumpResponse::Ok(dump) => Ok(dump),
+            DumpResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the size, type, time to live and, if the engine keeps them, the
+    /// write time and log position of a string key, `None` if not found
+    pub fn meta(&mut self, key: String) -> Result<Option<KeyMeta>> {
+        self.send(&Request::Meta { key })?;
+        let resp = self.recv::<MetaResponse>()?;
+        match resp {
+            MetaResponse::Ok(meta) => Ok(meta),
+            MetaResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Export the keys changed since `since_lsn`, as returned by a previous
+    /// export or found in `WriteMeta`, 0 for every key: `each` gets every
+    /// key along with its data, `None` if the key holds none anymore. Get
+    /// the LSN to export from next time, if the server has one.
+    ///
+    /// Once `each` fails, the remaining keys are skipped and its error
+    /// returned.
+    pub fn export(
+        &mut self,
+        since_lsn: u64,
+        mut each: impl FnMut(String, Option<KeyDump>) -> Result<()>,
+    ) -> Result<Option<u64>> {
+        self.send(&Request::Export { since_lsn })?;
+        let mut failed = None;
+        loop {
+            match self.recv_part::<ExportResponse>()? {
+                ExportResponse::Key { key, dump } => {
+                    if failed.is_none() {
+                        failed = each(key, dump).err();
+                    }
+                }
+                ExportResponse::Ok(lsn) => {
+                    self.finish(false);
+                    return failed.map_or(Ok(lsn), Err);
+                }
+                ExportResponse::Err(err) => return Err(self.remote_error(err)),
+            }
+        }
+    }
+
+    /// Replace everything stored under a key with a dump
+    pub fn restore(&mut self, key: String, dump: KeyDump) -> Result<()> {
+        self.send(&Request::Restore { key, dump })?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Copy every key of the server to `target`, `batch` keys at a time,
+    /// and return the number of keys copied.
+    ///
+    /// The copy starts after the key `after`, or from the first key. Once a
+    /// batch is copied, `checkpoint` is called with its last key, so an
+    /// interrupted copy can be resumed from there.
+    pub fn copy_to(
+        &mut self,
+        target: &mut KvsClient,
+        mut after: Option<String>,
+        batch: usize,
+        mut checkpoint: impl FnMut(&str) -> Result<()>,
+    ) -> Result<usize> {
+        let mut copied = 0;
+        loop {
+            let keys = self.scan(after.take(), batch.max(1))?;
+            let Some(last) = keys.last().cloned() else {
+                return Ok(copied);
+            };
+            for key in keys {
+                // the key may have been removed since the scan
+                if let Some(dump) = self.dump(key.clone())? {
+                    target.restore(key, dump)?;
+                    copied += 1;
+                }
+            }
+            checkpoint(&last)?;
+            after = Some(last);
+        }
+    }
+
+    /// Get a new fencing token from the server, superseding every token
+    /// handed out before, e.g. when becoming the primary after a failover
+    pub fn fence(&mut self) -> Result<u64> {
+        self.send(&Request::Fence)?;
+        let resp = self.recv::<TokenResponse>()?;
+        match resp {
+            TokenResponse::Ok(token) => Ok(token),
+            TokenResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the next ID of a sequence: unique and increasing, starting from
+    /// 1, though not contiguous as the server skips the IDs it leased but
+    /// had not handed out when it stopped. The sequence is stored as an
+    /// integer under its name.
+    pub fn next_id(&mut self, sequence: String) -> Result<u64> {
+        self.send(&Request::NextId { sequence })?;
+        let resp = self.recv::<TokenResponse>()?;
+        match resp {
+            TokenResponse::Ok(id) => Ok(id),
+            TokenResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Attach a fencing token to the writes of this connection: once a
+    /// newer token is handed out, they fail with `KvsError::Fenced`
+    pub fn use_fence(&mut self, token: u64) -> Result<()> {
+        self.send(&Request::UseFence { token })?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the scheduling statistics of the server
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        self.send(&Request::Stats)?;
+        let resp = self.recv::<StatsResponse>()?;
+        match resp {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Check that the server is up, and whether it is draining before a
+    /// shutdown
+    pub fn ping(&mut self) -> Result<Health> {
+        self.send(&Request::Ping)?;
+        let resp = self.recv::<PingResponse>()?;
+        match resp {
+            PingResponse::Ok(health) => Ok(health),
+            PingResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the traffic counters of every client IP seen by the server
+    pub fn clients(&mut self) -> Result<Vec<ClientStats>> {
+        self.send(&Request::Clients)?;
+        let resp = self.recv::<ClientsResponse>()?;
+        match resp {
+            ClientsResponse::Ok(clients) => Ok(clients),
+            ClientsResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Switch the server in or out of read-only mode
+    pub fn set_read_only(&mut self, enabled: bool) -> Result<()> {
+        self.send(&Request::ReadOnly { enabled })?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Have the server reclaim the space taken by stale data now, e.g. at a
+    /// quiet time or before backing its data up. Refused like writes while
+    /// the server is read-only or short of disk space
+    pub fn compact(&mut self) -> Result<()> {
+        self.send(&Request::Compact)?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Have the server hand its listener over to a new server process and
+    /// exit once its connections are closed
+    pub fn upgrade(&mut self) -> Result<()> {
+        self.send(&Request::Upgrade)?;
+        let resp = self.recv::<SetResponse>()?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+
+    /// Get the `limit` most accessed keys, hottest first
+    pub fn hot_keys(&mut self, limit: usize) -> Result<Vec<HotKey>> {
+        self.send(&Request::HotKeys { limit })?;
+        let resp = self.recv::<HotKeysResponse>()?;
+        match resp {
+            HotKeysResponse::Ok(keys) => Ok(keys),
+            HotKeysResponse::Err(err) => Err(self.remote_error(err)),
+        }
+    }
+}
+
+impl KvsClient {
+    // send a request, timed from here if metrics are recorded
+    fn send(&mut self, req: &Request) -> Result<()> {
+        self.op = req.name();
+        self.sent = self.metrics.as_ref().map(|_| Instant::now());
+        let sent = serde_json::to_writer(&mut self.writer, req)
+            .map_err(KvsError::from)
+            .and_then(|_| Ok(self.writer.flush()?));
+        if sent.is_err() {
+            self.finish(true);
+        }
+        sent
+    }
+
+    // receive the response of the request sent
+    fn recv<R: DeserializeOwned>(&mut self) -> Result<R> {
+        let resp = R::deserialize(&mut self.reader);
+        self.finish(resp.is_err());
+        Ok(resp?)
+    }
+
+    // receive a message of a response in several, the last one to be
+    // followed by `finish`
+    fn recv_part<R: DeserializeOwned>(&mut self) -> Result<R> {
+        let resp = R::deserialize(&mut self.reader);
+        if resp.is_err() {
+            self.finish(true);
+        }
+        Ok(resp?)
+    }
+
+    // record the latency of the request sent, once
+    fn finish(&mut self, failed: bool) {
+        if let (Some(metrics), Some(sent)) = (&self.metrics, self.sent.take()) {
+            metrics.record(self.op, sent.elapsed(), failed);
+        }
+    }
+
+    // the error the server answered the request sent with
+    fn remote_error(&mut self, err: String) -> KvsError {
+        self.finish(false);
+        if let Some(metrics) = &self.metrics {
+            metrics.failed(self.op);
+        }
+        KvsError::from_remote(err)
+    }
+}
+
+/// Maximum number of redirects followed for one request.
+const MAX_REDIRECTS: usize = 5;
+
+/// Client of a cluster of servers, routing every request to the node
+/// serving the slot of its key.
+///
+/// The routing table is learned from the `ClusterInfo` of a seed node and
+/// fixed up whenever a node answers with `KvsError::Moved`.
+pub struct ClusterClient {
+    seeds: Vec<String>,
+    // address of the node serving each slot, if known
+    routes: Vec<Option<String>>,
+    conns: HashMap<String, KvsClient>,
+    builder: ClientBuilder,
+    // addresses connected to before, to count reconnections
+    known: HashSet<String>,
+}
+
+impl ClusterClient {
+    /// Connect to a cluster through the first reachable seed node.
+    pub fn connect(seeds: Vec<String>) -> Result<Self> {
+        Self::connect_with(seeds, ClientBuilder::default())
+    }
+
+    /// Connect to a cluster like `connect`, connecting to every node with
+    /// the options of `builder`. Its metrics also count the connections
+    /// made again to a node after the previous one failed.
+    pub fn connect_with(seeds: Vec<String>, builder: ClientBuilder) -> Result<Self> {
+        let mut client = ClusterClient {
+            seeds,
+            routes: vec![None; SLOT_COUNT as usize],
+            conns: HashMap::new(),
+            builder,
+            known: HashSet::new(),
+        };
+        client.refresh()?;
+        Ok(client)
+    }
+
+    /// Reload the routing table from the first reachable node.
+    pub fn refresh(&mut self) -> Result<()> {
+        let mut candidates: Vec<String> = self.conns.keys().cloned().collect();
+        candidates.extend(self.seeds.iter().cloned());
+        let mut last_err = KvsError::Other("no seed node".to_owned());
+        for addr in candidates {
+            match self.conn(&addr).and_then(KvsClient::cluster_info) {
+                Ok(info) => {
+                    self.routes = vec![None; SLOT_COUNT as usize];
+                    for node in info.nodes.into_iter().filter(|node| node.alive) {
+                        for range in &node.slots {
+                            for slot in range.start..=range.end {
+                                self.routes[slot as usize] = Some(node.addr.clone());
+                            }
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.conns.remove(&addr);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Run `op` on the client of the node serving `key`, following
+    /// redirects.
+    pub fn call<T>(
+        &mut self,
+        key: &str,
+        mut op: impl FnMut(&mut KvsClient) -> Result<T>,
+    ) -> Result<T> {
+        let slot = key_slot(key);
+        let mut addr = self.routes[slot as usize]
+            .clone()
+            .unwrap_or_else(|| self.seeds[0].clone());
+        for _ in 0..MAX_REDIRECTS {
+            match op(self.conn(&addr)?) {
+                Err(KvsError::Moved { slot, addr: owner }) => {
+                    self.routes[slot as usize] = Some(owner.clone());
+                    addr = owner;
+                }
+                Err(KvsError::Ask { addr: owner, .. }) => {
+                    // the slot is being migrated, the routing table stays
+                    let client = self.conn(&owner)?;
+                    client.asking()?;
+                    return op(client);
+                }
+                Err(e @ KvsError::Io(_)) => {
+                    // reconnect on the next request
+                    self.conns.remove(&addr);
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+        Err(KvsError::Other(format!(
+            "too many redirects for slot {}",
+            slot
+        )))
+    }
+
+    /// Get the value of a key
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.call(&key.clone(), |client| client.get(key.clone()))
+    }
+
+    /// Set the value of a key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.call(&key.clone(), |client| {
+            client.set(key.clone(), value.clone())
+        })
+    }
+
+    /// Remove a key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.call(&key.clone(), |client| client.remove(key.clone()))
+    }
+
+    fn conn(&mut self, addr: &str) -> Result<&mut KvsClient> {
+        if !self.conns.contains_key(addr) {
+            let client = self.builder.connect(addr)?;
+            if !self.known.insert(addr.to_owned()) {
+                if let Some(metrics) = &self.builder.metrics {
+                    metrics.reconnected();
+                }
+            }
+            self.conns.insert(addr.to_owned(), client);
+        }
+        Ok(self.conns.get_mut(addr).unwrap())
+    }
+}
+
+// a time in milliseconds since the Unix epoch, as sent on the wire
+fn epoch_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
 }
@@ -0,0 +1,453 @@
+//! Static hash-slot sharding.
+//!
+//! The keyspace is split into [`SLOT_COUNT`] hash slots, each one served by
+//! a single node of the cluster. Nodes are started with the slots they own
+//! and gossip their view of the membership to each other, so any node can
+//! redirect a client to the owner of a key with a `MOVED` error and report
+//! the whole routing table through the `ClusterInfo` request.
+//!
+//! A slot is moved to another node while both keep serving traffic, see
+//! [`migrate_slot`]: the source answers with an `ASK` error for the keys it
+//! already handed over, and the target only serves them to a client asking
+//! for them explicitly, until the new owner is announced.
+
+#[cfg(feature = "client")]
+use crate::{protocol::SlotState, KvsClient, Result};
+
+/// Number of hash slots the keyspace is split into.
+pub const SLOT_COUNT: u16 = 16384;
+
+/// The hash slot of `key`.
+///
+/// This is the CRC16 (XMODEM) of the key modulo [`SLOT_COUNT`]. When the key
+/// contains a non-empty `{...}` hash tag, only the tag is hashed, so related
+/// keys like `{user1}.name` and `{user1}.email` land on the same node.
+pub fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    let hashed = match key.iter().position(|&b| b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % SLOT_COUNT
+}
+
+fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in buf {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Move `slot` from the node at `source_addr` to the one at `target_addr`,
+/// `batch` keys at a time, and return the number of keys moved.
+///
+/// The target is set importing and the source migrating first, so clients
+/// are redirected consistently while the keys are streamed; then the target
+/// is announced as the owner to both nodes, the rest of the cluster
+/// learning it through gossip.
+#[cfg(feature = "client")]
+pub fn migrate_slot(
+    source: &mut KvsClient,
+    target: &mut KvsClient,
+    source_addr: &str,
+    target_addr: &str,
+    slot: u16,
+    batch: usize,
+) -> Result<usize> {
+    target.set_slot(
+        slot,
+        SlotState::Importing {
+            from: source_addr.to_owned(),
+        },
+    )?;
+    source.set_slot(
+        slot,
+        SlotState::Migrating {
+            to: target_addr.to_owned(),
+        },
+    )?;
+
+    let mut moved = 0;
+    loop {
+        let keys = source.keys_in_slot(slot, batch.max(1))?;
+        if keys.is_empty() {
+            break;
+        }
+        moved += source.migrate(target_addr.to_owned(), keys)?;
+    }
+
+    let owner = SlotState::Node {
+        addr: target_addr.to_owned(),
+    };
+    target.set_slot(slot, owner.clone())?;
+    source.set_slot(slot, owner)?;
+    Ok(moved)
+}
+
+#[cfg(feature = "server")]
+pub use self::membership::Cluster;
+#[cfg(feature = "server")]
+pub(crate) use self::membership::{gossip, migrate_keys, Membership, Route};
+
+#[cfg(feature = "server")]
+mod membership {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::Write;
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use log::{debug, info};
+    use serde::Deserialize;
+    use serde_json::Deserializer;
+
+    use crate::protocol::{
+        ClusterInfo, ClusterInfoResponse, NodeInfo, Request, SetResponse, SlotRange, SlotState,
+    };
+    use crate::{KvsEngine, KvsError, Result};
+
+    const DEFAULT_GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+    // a node not heard of for this many gossip rounds is reported as down
+    const ALIVE_ROUNDS: u32 = 5;
+
+    /// Cluster settings of a server, see `KvsServer::cluster`.
+    pub struct Cluster {
+        addr: String,
+        slots: Vec<SlotRange>,
+        peers: Vec<String>,
+        gossip_interval: Duration,
+    }
+
+    impl Cluster {
+        /// A node reachable by the other nodes and the clients at `addr`,
+        /// which also identifies it, serving the given slots.
+        pub fn new(addr: impl Into<String>, slots: Vec<SlotRange>) -> Self {
+            Cluster {
+                addr: addr.into(),
+                slots,
+                peers: Vec::new(),
+                gossip_interval: DEFAULT_GOSSIP_INTERVAL,
+            }
+        }
+
+        /// Addresses of nodes to gossip with until the rest of the cluster
+        /// is learned from them.
+        pub fn peers(mut self, peers: Vec<String>) -> Self {
+            self.peers = peers;
+            self
+        }
+
+        /// How often the membership is exchanged with every known node.
+        pub fn gossip_interval(mut self, interval: Duration) -> Self {
+            self.gossip_interval = interval;
+            self
+        }
+    }
+
+    /// Where a request on a given slot is served.
+    pub enum Route {
+        Local,
+        Moved(String),
+        /// Served locally if the key is still here, by the given node
+        /// otherwise.
+        Migrating(String),
+        Unserved,
+    }
+
+    /// The view of the cluster of one node.
+    pub struct Membership {
+        me: String,
+        seeds: Vec<String>,
+        interval: Duration,
+        nodes: BTreeMap<String, Member>,
+        // slots being moved from this node, to the given node
+        migrating: BTreeMap<u16, String>,
+        // slots being moved to this node, from the given node
+        importing: BTreeMap<u16, String>,
+    }
+
+    struct Member {
+        info: NodeInfo,
+        last_seen: Option<Instant>,
+    }
+
+    impl Membership {
+        pub fn new(cluster: Cluster) -> Self {
+            let me = NodeInfo {
+                addr: cluster.addr.clone(),
+                slots: cluster.slots,
+                epoch: 1,
+                alive: true,
+            };
+            let mut nodes = BTreeMap::new();
+            nodes.insert(
+                cluster.addr.clone(),
+                Member {
+                    info: me,
+                    last_seen: None,
+                },
+            );
+            Membership {
+                me: cluster.addr,
+                seeds: cluster.peers,
+                interval: cluster.gossip_interval,
+                nodes,
+                migrating: BTreeMap::new(),
+                importing: BTreeMap::new(),
+            }
+        }
+
+        /// Address of this node.
+        pub fn me(&self) -> &str {
+            &self.me
+        }
+
+        /// Where to serve a request on `slot`; `asking` tells whether the
+        /// client was redirected here by the source of a migration.
+        pub fn route(&self, slot: u16, asking: bool) -> Route {
+            let owner = self
+                .nodes
+                .values()
+                .filter(|node| node.info.slots.iter().any(|range| range.contains(slot)))
+                .max_by_key(|node| node.info.epoch);
+            match owner {
+                Some(node) if node.info.addr == self.me => match self.migrating.get(&slot) {
+                    Some(target) => Route::Migrating(target.clone()),
+                    None => Route::Local,
+                },
+                _ if asking && self.importing.contains_key(&slot) => Route::Local,
+                Some(node) => Route::Moved(node.info.addr.clone()),
+                None => Route::Unserved,
+            }
+        }
+
+        pub fn set_slot(&mut self, slot: u16, state: SlotState) {
+            match state {
+                SlotState::Importing { from } => {
+                    self.importing.insert(slot, from);
+                }
+                SlotState::Migrating { to } => {
+                    self.migrating.insert(slot, to);
+                }
+                SlotState::Stable => {
+                    self.importing.remove(&slot);
+                    self.migrating.remove(&slot);
+                }
+                SlotState::Node { addr } => {
+                    self.importing.remove(&slot);
+                    self.migrating.remove(&slot);
+                    self.assign(slot, addr);
+                }
+            }
+        }
+
+        // Only the information about ourselves is authoritative, so a new
+        // epoch is taken for it, higher than any known one for the others
+        // to adopt it. Our copy of the new owner is fixed up in the
+        // meantime, its own announcement will replace it.
+        fn assign(&mut self, slot: u16, addr: String) {
+            let epoch = self.nodes.values().map(|node| node.info.epoch).max();
+            let epoch = epoch.unwrap_or(0) + 1;
+            for node in self.nodes.values_mut() {
+                let owns = node.info.addr == addr;
+                let changed = update_slots(&mut node.info.slots, slot, owns);
+                if changed && node.info.addr == self.me {
+                    node.info.epoch = epoch;
+                }
+            }
+            self.nodes.entry(addr.clone()).or_insert_with(|| Member {
+                info: NodeInfo {
+                    addr,
+                    slots: vec![SlotRange {
+                        start: slot,
+                        end: slot,
+                    }],
+                    epoch: 0,
+                    alive: false,
+                },
+                last_seen: None,
+            });
+            info!("Slot {} assigned, epoch {}", slot, epoch);
+        }
+
+        pub fn info(&self) -> ClusterInfo {
+            let timeout = self.interval * ALIVE_ROUNDS;
+            let nodes = self
+                .nodes
+                .values()
+                .map(|node| NodeInfo {
+                    alive: node.info.addr == self.me
+                        || node.last_seen.is_some_and(|seen| seen.elapsed() < timeout),
+                    ..node.info.clone()
+                })
+                .collect();
+            ClusterInfo {
+                nodes,
+                migrating: self.migrating.clone(),
+                importing: self.importing.clone(),
+            }
+        }
+
+        /// Merge the view of another node: for every node, the information
+        /// with the highest epoch wins. Nobody else knows better about us.
+        pub fn merge(&mut self, nodes: Vec<NodeInfo>) {
+            for info in nodes {
+                if info.addr == self.me {
+                    continue;
+                }
+                match self.nodes.get_mut(&info.addr) {
+                    Some(node) if node.info.epoch >= info.epoch => {}
+                    Some(node) => node.info = info,
+                    None => {
+                        info!("Cluster node {} joined", info.addr);
+                        self.nodes.insert(
+                            info.addr.clone(),
+                            Member {
+                                info,
+                                last_seen: None,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        fn seen(&mut self, addr: &str) {
+            if let Some(node) = self.nodes.get_mut(addr) {
+                node.last_seen = Some(Instant::now());
+            }
+        }
+
+        fn targets(&self) -> Vec<String> {
+            let mut targets: Vec<String> = self
+                .nodes
+                .keys()
+                .chain(self.seeds.iter())
+                .filter(|&addr| *addr != self.me)
+                .cloned()
+                .collect();
+            targets.sort();
+            targets.dedup();
+            targets
+        }
+    }
+
+    /// Exchange the membership with every known node, forever.
+    pub fn gossip(membership: Arc<Mutex<Membership>>) {
+        let interval = membership.lock().unwrap().interval;
+        loop {
+            thread::sleep(interval);
+            let (targets, view) = {
+                let membership = membership.lock().unwrap();
+                (membership.targets(), membership.info().nodes)
+            };
+            for target in targets {
+                match exchange(&target, &view, interval) {
+                    Ok(info) => {
+                        let mut membership = membership.lock().unwrap();
+                        membership.merge(info.nodes);
+                        membership.seen(&target);
+                    }
+                    Err(e) => debug!("Gossip with {} failed: {}", target, e),
+                }
+            }
+        }
+    }
+
+    fn exchange(target: &str, view: &[NodeInfo], timeout: Duration) -> Result<ClusterInfo> {
+        let addr = target
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| KvsError::Other(format!("cannot resolve {}", target)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let req = Request::Gossip {
+            nodes: view.to_vec(),
+        };
+        serde_json::to_writer(&mut stream, &req)?;
+        stream.flush()?;
+        let resp = ClusterInfoResponse::deserialize(&mut Deserializer::from_reader(&stream))?;
+        match resp {
+            ClusterInfoResponse::Ok(info) => Ok(info),
+            ClusterInfoResponse::Err(err) => Err(KvsError::Other(err)),
+        }
+    }
+
+    /// Hand `keys` over to the node at `target`, which must be importing
+    /// their slot, and return how many were moved. Every key is restored on
+    /// the target before being removed here, so it is never lost; requests
+    /// on it are served by the engine thread, which runs the migration, so
+    /// none can observe it in between.
+    pub fn migrate_keys<E: KvsEngine>(
+        engine: &mut E,
+        target: &str,
+        keys: Vec<String>,
+    ) -> Result<usize> {
+        let stream = TcpStream::connect(target)?;
+        let mut writer = &stream;
+        let mut reader = Deserializer::from_reader(&stream);
+        let mut moved = 0;
+        for key in keys {
+            let Some(dump) = engine.dump(key.clone())? else {
+                continue;
+            };
+            let reqs = [
+                Request::Asking,
+                Request::Restore {
+                    key: key.clone(),
+                    dump,
+                },
+            ];
+            for req in &reqs {
+                serde_json::to_writer(&mut writer, req)?;
+                writer.flush()?;
+                if let SetResponse::Err(err) = SetResponse::deserialize(&mut reader)? {
+                    return Err(KvsError::Other(format!(
+                        "migrating {} to {} failed: {}",
+                        key, target, err
+                    )));
+                }
+            }
+            engine.purge(key)?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    // add `slot` to `ranges` or remove it, returning whether they changed
+    fn update_slots(ranges: &mut Vec<SlotRange>, slot: u16, owns: bool) -> bool {
+        let mut slots: BTreeSet<u16> = ranges.iter().flat_map(|r| r.start..=r.end).collect();
+        let changed = if owns {
+            slots.insert(slot)
+        } else {
+            slots.remove(&slot)
+        };
+        if changed {
+            ranges.clear();
+            for slot in slots {
+                match ranges.last_mut() {
+                    Some(last) if last.end + 1 == slot => last.end = slot,
+                    _ => ranges.push(SlotRange {
+                        start: slot,
+                        end: slot,
+                    }),
+                }
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,146 @@
+//! Comparing two replicas of the same data, servers or data directories,
+//! key by key, to validate replication and backups.
+//!
+//! Every key is summed up by a checksum of everything stored under it, in
+//! every keyspace. Expiration times are left out but for whether the key
+//! expires, as the time left differs between two reads anyway.
+
+use serde::Serialize;
+
+use crate::{KeyDump, KvsEngine, Result};
+
+/// Scan size used to list the keys of a server.
+#[cfg(feature = "client")]
+const SCAN_BATCH: usize = 1000;
+
+/// One side of a diff.
+pub trait Replica {
+    /// Every key holding data, in any order.
+    fn keys(&mut self) -> Result<Vec<String>>;
+    /// Everything stored under a key, `None` if it holds no data.
+    fn dump(&mut self, key: String) -> Result<Option<KeyDump>>;
+}
+
+impl<E: KvsEngine> Replica for E {
+    fn keys(&mut self) -> Result<Vec<String>> {
+        KvsEngine::keys(self)
+    }
+
+    fn dump(&mut self, key: String) -> Result<Option<KeyDump>> {
+        KvsEngine::dump(self, key)
+    }
+}
+
+#[cfg(feature = "client")]
+impl Replica for crate::KvsClient {
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = Vec::new();
+        loop {
+            let page = self.scan(keys.last().cloned(), SCAN_BATCH)?;
+            if page.is_empty() {
+                return Ok(keys);
+            }
+            keys.extend(page);
+        }
+    }
+
+    fn dump(&mut self, key: String) -> Result<Option<KeyDump>> {
+        crate::KvsClient::dump(self, key)
+    }
+}
+
+/// A key and the checksum of its data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeySum {
+    /// The key
+    pub key: String,
+    /// Checksum of its data, see `checksum`
+    pub checksum: u64,
+}
+
+/// A key holding different data on each side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mismatch {
+    /// The key
+    pub key: String,
+    /// Checksum of its data on the left
+    pub left: u64,
+    /// Checksum of its data on the right
+    pub right: u64,
+}
+
+/// What differs between two replicas.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Diff {
+    /// Keys only the left replica holds
+    pub only_left: Vec<KeySum>,
+    /// Keys only the right replica holds
+    pub only_right: Vec<KeySum>,
+    /// Keys both hold, with different data
+    pub mismatched: Vec<Mismatch>,
+    /// Number of keys holding the same data on both sides
+    pub matched: u64,
+}
+
+impl Diff {
+    /// Whether both replicas hold the same data.
+    pub fn is_empty(&self) -> bool {
+        self.only_left.is_empty() && self.only_right.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Compare every key of `left` and `right`. Keys removed while the diff
+/// runs count as missing from their side.
+pub fn diff(left: &mut dyn Replica, right: &mut dyn Replica) -> Result<Diff> {
+    let mut report = Diff::default();
+    // each side may list its keys in its own order
+    let mut left_keys = left.keys()?;
+    left_keys.sort_unstable();
+    let mut right_keys = right.keys()?;
+    right_keys.sort_unstable();
+    let mut left_keys = left_keys.into_iter().peekable();
+    let mut right_keys = right_keys.into_iter().peekable();
+    loop {
+        let (in_left, in_right) = match (left_keys.peek(), right_keys.peek()) {
+            (None, None) => return Ok(report),
+            (Some(l), Some(r)) => (l <= r, r <= l),
+            (l, r) => (l.is_some(), r.is_some()),
+        };
+        let key = match in_left {
+            true => left_keys.next(),
+            false => right_keys.next(),
+        }
+        .unwrap();
+        if in_left && in_right {
+            right_keys.next();
+        }
+        let left_sum = match in_left {
+            true => left.dump(key.clone())?.as_ref().map(checksum),
+            false => None,
+        };
+        let right_sum = match in_right {
+            true => right.dump(key.clone())?.as_ref().map(checksum),
+            false => None,
+        };
+        match (left_sum, right_sum) {
+            (Some(left), Some(right)) if left == right => report.matched += 1,
+            (Some(left), Some(right)) => report.mismatched.push(Mismatch { key, left, right }),
+            (Some(checksum), None) => report.only_left.push(KeySum { key, checksum }),
+            (None, Some(checksum)) => report.only_right.push(KeySum { key, checksum }),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Checksum of everything stored under a key: the 64-bit FNV-1a hash of
+/// its dump, whether it expires standing in for its time to live.
+pub fn checksum(dump: &KeyDump) -> u64 {
+    let dump = KeyDump {
+        ttl_ms: dump.ttl_ms.map(|_| 0),
+        ..dump.clone()
+    };
+    let bytes = serde_json::to_vec(&dump).expect("a dump is always serializable");
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -0,0 +1,95 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+/// How often the free space is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watchdog of the free space of a data directory, refreshed by a thread
+/// of its own, so writes can be refused before the disk fills up and cuts
+/// a record short.
+pub struct DiskWatch {
+    dir: PathBuf,
+    threshold: u64,
+    // last free space seen, in bytes
+    free: Arc<AtomicU64>,
+}
+
+impl DiskWatch {
+    pub fn new(dir: PathBuf, threshold: u64) -> Self {
+        DiskWatch {
+            dir,
+            threshold,
+            free: Arc::new(AtomicU64::new(u64::MAX)),
+        }
+    }
+
+    /// Check the free space once, then keep checking it in the background.
+    pub fn start(&self) -> io::Result<()> {
+        self.free.store(free_space(&self.dir)?, Ordering::Relaxed);
+        let dir = self.dir.clone();
+        let threshold = self.threshold;
+        let free = Arc::clone(&self.free);
+        thread::spawn(move || loop {
+            thread::sleep(CHECK_INTERVAL);
+            let now = match free_space(&dir) {
+                Ok(now) => now,
+                Err(e) => {
+                    error!("checking free space of {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            let before = free.swap(now, Ordering::Relaxed);
+            if before >= threshold && now < threshold {
+                warn!(
+                    "{} bytes free on {}, below {}: refusing writes",
+                    now,
+                    dir.display(),
+                    threshold
+                );
+            } else if before < threshold && now >= threshold {
+                info!("{} bytes free on {}: accepting writes", now, dir.display());
+            }
+        });
+        Ok(())
+    }
+
+    /// The free space, in bytes, if below the threshold.
+    pub fn full(&self) -> Option<u64> {
+        Some(self.free.load(Ordering::Relaxed)).filter(|&free| free < self.threshold)
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+}
+
+/// Space available to unprivileged users on the filesystem of `dir`.
+#[cfg(unix)]
+fn free_space(dir: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: `statvfs` is plain data, filled in by the call below
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a C string and `stat` outlives the call
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space is only checked on unix",
+    ))
+}
@@ -0,0 +1,178 @@
+//! Encryption at rest of `KvStore`: the key a store is opened with, and
+//! the `encryption` file of its data directory checking it.
+//!
+//! Records are sealed along with their generation and offset, so a sealed
+//! record moved, replayed or swapped with another one on disk no longer
+//! opens.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+#[cfg(feature = "encryption")]
+use aes_gcm::{Aes256Gcm, Nonce};
+
+use crate::{KvsError, Result};
+
+/// Name of the file of an encrypted data directory checking the key it is
+/// opened with.
+pub const ENCRYPTION_FILE: &str = "encryption";
+
+// length of the nonce starting every sealed payload
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+// sealed into the encryption file, opening only with the right key
+const KEY_CHECK: &[u8] = b"kvs encryption key check";
+
+// starts the associated data of what the quarantine file seals, so it
+// cannot pass for the record it was taken from
+const QUARANTINE_AAD: &[u8] = b"quarantine";
+
+/// The key a `KvStore` encrypts its data with, see
+/// `KvStore::open_encrypted`: a 256-bit AES-GCM key, given as it is or
+/// asked of a callback once on open, e.g. fetching it from a key management
+/// service.
+///
+/// Nonces are random, 96 bits long: a key should seal at most about 2^32
+/// records over the life of the store, those rewritten by compaction
+/// included.
+#[cfg(feature = "encryption")]
+pub struct EncryptionConfig {
+    key: KeySource,
+}
+
+#[cfg(feature = "encryption")]
+enum KeySource {
+    Key([u8; 32]),
+    Provider(Box<dyn FnOnce() -> Result<[u8; 32]>>),
+}
+
+#[cfg(feature = "encryption")]
+impl EncryptionConfig {
+    /// Encrypt with `key`.
+    pub fn key(key: [u8; 32]) -> Self {
+        EncryptionConfig {
+            key: KeySource::Key(key),
+        }
+    }
+
+    /// Encrypt with the key `provider` returns, called once on open; its
+    /// errors fail the open.
+    pub fn provider(provider: impl FnOnce() -> Result<[u8; 32]> + 'static) -> Self {
+        EncryptionConfig {
+            key: KeySource::Provider(Box::new(provider)),
+        }
+    }
+
+    pub(crate) fn cipher(self) -> Result<Cipher> {
+        let key = match self.key {
+            KeySource::Key(key) => key,
+            KeySource::Provider(provider) => provider()?,
+        };
+        Ok(Cipher(Aes256Gcm::new(&key.into())))
+    }
+}
+
+/// Seals and opens the payloads of the records of encrypted generations:
+/// a random nonce, then the payload encrypted with AES-256-GCM and
+/// authenticated along with associated data, see `record_aad`.
+#[cfg(feature = "encryption")]
+pub(crate) struct Cipher(Aes256Gcm);
+
+/// Without the encryption feature, no store has a cipher.
+#[cfg(not(feature = "encryption"))]
+pub(crate) enum Cipher {}
+
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+impl Cipher {
+    pub(crate) fn seal(&self, payload: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let payload = Payload { msg: payload, aad };
+            let sealed = self
+                .0
+                .encrypt(&nonce, payload)
+                .map_err(|_| KvsError::Other("cannot encrypt a record".to_owned()))?;
+            let mut buf = nonce.to_vec();
+            buf.extend_from_slice(&sealed);
+            Ok(buf)
+        }
+        #[cfg(not(feature = "encryption"))]
+        match *self {}
+    }
+
+    // `None` when `sealed` was not sealed with this key and `aad`, or was
+    // altered
+    pub(crate) fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        {
+            if sealed.len() < NONCE_LEN {
+                return None;
+            }
+            let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+            let sealed = Payload { msg: sealed, aad };
+            self.0.decrypt(Nonce::from_slice(nonce), sealed).ok()
+        }
+        #[cfg(not(feature = "encryption"))]
+        match *self {}
+    }
+}
+
+// the associated data of the record at `offset` of generation `gen`,
+// binding it there
+pub(crate) fn record_aad(gen: u64, offset: u64) -> [u8; 16] {
+    let mut aad = [0; 16];
+    aad[..8].copy_from_slice(&gen.to_le_bytes());
+    aad[8..].copy_from_slice(&offset.to_le_bytes());
+    aad
+}
+
+// the associated data of what the quarantine file seals of the record at
+// `offset` of generation `gen`
+pub(crate) fn quarantine_aad(gen: u64, offset: u64) -> Vec<u8> {
+    [QUARANTINE_AAD, &record_aad(gen, offset)].concat()
+}
+
+/// Check the key `dir` is opened with against its encryption file: an
+/// encrypted directory takes its key. Returns whether encryption is being
+/// turned on, `dir` opened with a key for the first time and `writable`:
+/// the file is written by `enable` once the plaintext the directory holds
+/// beside its generations is dealt with.
+pub(crate) fn check(dir: &Path, cipher: Option<&Cipher>, writable: bool) -> Result<bool> {
+    let path = dir.join(ENCRYPTION_FILE);
+    let check = match fs::read(&path) {
+        Ok(check) => Some(check),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    match (check, cipher) {
+        (None, None) => Ok(false),
+        (Some(_), None) => Err(KvsError::Other(format!(
+            "{} is encrypted, to be opened with its key",
+            dir.display()
+        ))),
+        (Some(check), Some(cipher)) => {
+            match cipher.open(&check, &[]).filter(|plain| plain == KEY_CHECK) {
+                Some(_) => Ok(false),
+                None => Err(KvsError::Other(format!(
+                    "wrong encryption key for {}",
+                    dir.display()
+                ))),
+            }
+        }
+        (None, Some(_)) => Ok(writable),
+    }
+}
+
+/// Record `dir` as encrypted with `cipher`, see `check`.
+pub(crate) fn enable(dir: &Path, cipher: &Cipher) -> Result<()> {
+    let path = dir.join(ENCRYPTION_FILE);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, cipher.seal(KEY_CHECK, &[])?)?;
+    fs::File::open(&tmp)?.sync_all()?;
+    Ok(fs::rename(&tmp, &path)?)
+}
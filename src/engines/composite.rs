@@ -0,0 +1,339 @@
+use crate::KeyMeta;
+use crate::KvStore;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use crate::ValueType;
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// `CompositeStore` serves several `KvStore` data directories as one
+/// keyspace, each mounted under a key prefix like a file system under a
+/// mount point, so datasets kept apart are served together without
+/// merging their data.
+///
+/// A key goes to the store mounted under its longest prefix, stripped of
+/// it: with a store mounted under `legacy/`, `legacy/user:1` is `user:1`
+/// in that store. Keys of a store shadowed by a longer mount, like
+/// `legacy/x` in a store mounted under the empty prefix, are out of
+/// reach. Operations on a key under no mount fail with
+/// `KvsError::InvalidCommand`; mount a store under the empty prefix to
+/// catch every other key.
+///
+/// The stores keep their own logs: there is no LSN over the whole
+/// keyspace, so changes cannot be tracked, and `meta` reports none.
+#[derive(Default)]
+pub struct CompositeStore {
+    // by prefix
+    mounts: BTreeMap<String, KvStore>,
+}
+
+impl CompositeStore {
+    /// A keyspace with no store mounted yet, see `attach`.
+    pub fn new() -> CompositeStore {
+        CompositeStore::default()
+    }
+
+    /// Open the data directory of every mount, by prefix. Either every
+    /// store is opened, or none is: the stores opened before one failing
+    /// are closed again, and the error is returned with its directory.
+    pub fn open<P: Into<PathBuf>>(
+        mounts: impl IntoIterator<Item = (String, P)>,
+    ) -> Result<CompositeStore> {
+        let mut opened = BTreeMap::new();
+        for (prefix, dir) in mounts {
+            let dir = dir.into();
+            if opened.contains_key(&prefix) {
+                return Err(duplicate(&prefix));
+            }
+            let store = KvStore::open(&dir).map_err(|e| e.with_path(&dir))?;
+            opened.insert(prefix, store);
+        }
+        Ok(CompositeStore { mounts: opened })
+    }
+
+    /// Mount `store` under `prefix`, refused if a store is mounted there
+    /// already.
+    pub fn attach(&mut self, prefix: impl Into<String>, store: KvStore) -> Result<()> {
+        let prefix = prefix.into();
+        if self.mounts.contains_key(&prefix) {
+            return Err(duplicate(&prefix));
+        }
+        self.mounts.insert(prefix, store);
+        Ok(())
+    }
+
+    /// Unmount the store under `prefix` and hand it back, if any.
+    pub fn detach(&mut self, prefix: &str) -> Option<KvStore> {
+        self.mounts.remove(prefix)
+    }
+
+    /// The prefixes stores are mounted under, sorted.
+    pub fn prefixes(&self) -> impl Iterator<Item = &str> {
+        self.mounts.keys().map(String::as_str)
+    }
+
+    // the prefix of the mount serving `key`
+    fn mount_of(&self, key: &str) -> Option<&str> {
+        self.prefixes()
+            .filter(|prefix| key.starts_with(prefix))
+            .max_by_key(|prefix| prefix.len())
+    }
+
+    // the prefix of the mount serving `key`, and the key within its store
+    fn locate(&self, key: &str) -> Result<(String, String)> {
+        match self.mount_of(key) {
+            Some(prefix) => Ok((prefix.to_owned(), key[prefix.len()..].to_owned())),
+            None => Err(KvsError::InvalidCommand(format!(
+                "no store mounted over key {:?}",
+                key
+            ))),
+        }
+    }
+
+    // run `op` on the store serving `key`, with the key within it
+    fn on<T>(
+        &mut self,
+        key: &str,
+        op: impl FnOnce(&mut KvStore, String) -> Result<T>,
+    ) -> Result<T> {
+        let (prefix, key) = self.locate(key)?;
+        op(self.mounts.get_mut(&prefix).unwrap(), key)
+    }
+
+    // the keys of `inner`, found in the store under `prefix`, as seen from
+    // the composite, those shadowed by another mount left out
+    fn visible(&self, prefix: &str, inner: Vec<String>) -> Vec<String> {
+        inner
+            .into_iter()
+            .map(|key| format!("{}{}", prefix, key))
+            .filter(|key| self.mount_of(key) == Some(prefix))
+            .collect()
+    }
+
+    // the sum of `count` over every store
+    fn sum(&mut self, mut count: impl FnMut(&mut KvStore) -> Result<usize>) -> Result<usize> {
+        self.mounts.values_mut().map(&mut count).sum()
+    }
+}
+
+fn duplicate(prefix: &str) -> KvsError {
+    KvsError::InvalidCommand(format!("a store is mounted under {:?} already", prefix))
+}
+
+impl KvsEngine for CompositeStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.on(&key, |store, key| store.set(key, value))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.on(&key, |store, key| store.get(key))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.on(&key, |store, key| store.remove(key))
+    }
+
+    fn get_raw(&mut self, key: String) -> Result<Option<Box<RawValue>>> {
+        self.on(&key, |store, key| store.get_raw(key))
+    }
+
+    // one batch per store, for those batching reads
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut batches: BTreeMap<String, (Vec<usize>, Vec<String>)> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let (prefix, key) = self.locate(key)?;
+            let batch = batches.entry(prefix).or_default();
+            batch.0.push(i);
+            batch.1.push(key);
+        }
+        for (prefix, (positions, keys)) in batches {
+            let found = self.mounts.get_mut(&prefix).unwrap().get_many(keys)?;
+            for (i, value) in positions.into_iter().zip(found) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    fn undelete(&mut self, key: String) -> Result<()> {
+        self.on(&key, |store, key| store.undelete(key))
+    }
+
+    fn set_with_type(&mut self, key: String, value: String, value_type: ValueType) -> Result<()> {
+        self.on(&key, |store, key| {
+            store.set_with_type(key, value, value_type)
+        })
+    }
+
+    fn get_with_type(&mut self, key: String) -> Result<Option<(String, ValueType)>> {
+        self.on(&key, |store, key| store.get_with_type(key))
+    }
+
+    fn set_from_reader(
+        &mut self,
+        key: String,
+        value: &mut dyn Read,
+        value_type: ValueType,
+    ) -> Result<()> {
+        self.on(&key, |store, key| {
+            store.set_from_reader(key, value, value_type)
+        })
+    }
+
+    fn get_to_writer(&mut self, key: String, out: &mut dyn Write) -> Result<bool> {
+        self.on(&key, |store, key| store.get_to_writer(key, out))
+    }
+
+    fn get_raw_to_writer(&mut self, key: String, out: &mut dyn Write) -> Result<bool> {
+        self.on(&key, |store, key| store.get_raw_to_writer(key, out))
+    }
+
+    fn lpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        self.on(&key, |store, key| store.lpush(key, values))
+    }
+
+    fn rpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        self.on(&key, |store, key| store.rpush(key, values))
+    }
+
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.on(&key, |store, key| store.lrange(key, start, stop))
+    }
+
+    fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        self.on(&key, |store, key| store.lpop(key))
+    }
+
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.on(&key, |store, key| store.hset(key, field, value))
+    }
+
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        self.on(&key, |store, key| store.hget(key, field))
+    }
+
+    fn hgetall(&mut self, key: String) -> Result<BTreeMap<String, String>> {
+        self.on(&key, |store, key| store.hgetall(key))
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        self.on(&key, |store, key| store.hdel(key, field))
+    }
+
+    fn sadd(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        self.on(&key, |store, key| store.sadd(key, members))
+    }
+
+    fn srem(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        self.on(&key, |store, key| store.srem(key, members))
+    }
+
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        self.on(&key, |store, key| store.sismember(key, member))
+    }
+
+    fn smembers(&mut self, key: String) -> Result<BTreeSet<String>> {
+        self.on(&key, |store, key| store.smembers(key))
+    }
+
+    fn incr(&mut self, key: String, by: i64) -> Result<i64> {
+        self.on(&key, |store, key| store.incr(key, by))
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        self.on(&key, |store, key| store.expire(key, ttl))
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.on(&key, |store, key| store.ttl(key))
+    }
+
+    fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        self.on(&key, |store, key| store.touch(key, ttl))
+    }
+
+    fn set_at(&mut self, key: String, value: String, at: u64) -> Result<()> {
+        self.on(&key, |store, key| store.set_at(key, value, at))
+    }
+
+    fn remove_at(&mut self, key: String, at: u64) -> Result<()> {
+        self.on(&key, |store, key| store.remove_at(key, at))
+    }
+
+    fn run_scheduled(&mut self) -> Result<usize> {
+        self.sum(|store| store.run_scheduled())
+    }
+
+    fn sweep_expired(&mut self) -> Result<usize> {
+        self.sum(|store| store.sweep_expired())
+    }
+
+    // the counters of every store added up
+    fn metrics(&mut self) -> Result<BTreeMap<String, u64>> {
+        let mut metrics = BTreeMap::new();
+        for store in self.mounts.values_mut() {
+            for (name, value) in store.metrics()? {
+                *metrics.entry(name).or_default() += value;
+            }
+        }
+        metrics.insert("mounts".to_owned(), self.mounts.len() as u64);
+        Ok(metrics)
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.mounts.values_mut().try_for_each(KvStore::refresh)
+    }
+
+    // the LSN of a store means nothing to the composite
+    fn meta(&mut self, key: String) -> Result<Option<KeyMeta>> {
+        let meta = self.on(&key, |store, key| store.meta(key))?;
+        Ok(meta.map(|meta| KeyMeta { lsn: None, ..meta }))
+    }
+
+    fn get_field(&mut self, key: String, pointer: String) -> Result<Option<String>> {
+        self.on(&key, |store, key| store.get_field(key, pointer))
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.mounts.values_mut().try_for_each(KvStore::compact)
+    }
+
+    // sorted bytewise, whatever the order of the stores
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let prefixes: Vec<String> = self.mounts.keys().cloned().collect();
+        for prefix in prefixes {
+            let inner = self.mounts.get_mut(&prefix).unwrap().keys()?;
+            keys.extend(self.visible(&prefix, inner));
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let prefixes: Vec<String> = self.mounts.keys().cloned().collect();
+        for mount in prefixes {
+            let store = self.mounts.get_mut(&mount).unwrap();
+            let inner = if mount.starts_with(prefix) {
+                store.keys()?
+            } else if let Some(rest) = prefix.strip_prefix(mount.as_str()) {
+                store.scan_prefix(rest)?
+            } else {
+                continue;
+            };
+            keys.extend(self.visible(&mount, inner));
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn exists(&mut self, key: String) -> Result<bool> {
+        self.on(&key, |store, key| store.exists(key))
+    }
+}
@@ -0,0 +1,395 @@
+use crate::KeyMeta;
+use crate::KeyOrder;
+use crate::KvsEngine;
+use crate::PrefixUsage;
+use crate::Result;
+use crate::ValueType;
+use crate::{engines::decode_bytes, KvsError};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+/// Length of the nonce starting every encrypted value, in bytes.
+const NONCE_LEN: usize = 24;
+
+/// Hands out the key material of the prefixes an `EncryptedEngine`
+/// encrypts, e.g. from a key management service or the environment.
+pub trait KeyProvider {
+    /// Get the 256-bit key of `prefix`, one of the prefixes of the engine.
+    fn key(&self, prefix: &str) -> Result<[u8; 32]>;
+}
+
+/// Keys by prefix, held in memory.
+impl KeyProvider for BTreeMap<String, [u8; 32]> {
+    fn key(&self, prefix: &str) -> Result<[u8; 32]> {
+        self.get(prefix)
+            .copied()
+            .ok_or_else(|| KvsError::Other(format!("no key for prefix {:?}", prefix)))
+    }
+}
+
+/// `EncryptedEngine` encrypts the values of the keys under chosen
+/// prefixes before they reach `inner`, each prefix with its own key, so
+/// sensitive namespaces are encrypted without paying for it on the whole
+/// store. Keys under several prefixes use the longest one.
+///
+/// Values are sealed with XChaCha20-Poly1305 along with their type, bound
+/// to their key, and stored typed as `ValueType::Bytes`; list elements and
+/// hash values are sealed the same way. Keys themselves, expiry and set
+/// members stay in the clear: set members and counters, which the engine
+/// must compare or add up, are refused under encrypted prefixes.
+pub struct EncryptedEngine<E, K> {
+    inner: E,
+    provider: K,
+    prefixes: BTreeSet<String>,
+    ciphers: BTreeMap<String, XChaCha20Poly1305>,
+}
+
+impl<E: KvsEngine, K: KeyProvider> EncryptedEngine<E, K> {
+    /// Encrypt the values written to `inner` under the prefixes added with
+    /// `encrypt_prefix`, with the keys `provider` hands out.
+    pub fn new(inner: E, provider: K) -> Self {
+        EncryptedEngine {
+            inner,
+            provider,
+            prefixes: BTreeSet::new(),
+            ciphers: BTreeMap::new(),
+        }
+    }
+
+    /// Encrypt the values of the keys starting with `prefix`. Its key is
+    /// asked of the provider on first use.
+    pub fn encrypt_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.insert(prefix.into());
+        self
+    }
+
+    /// Take the wrapped engine back.
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    // the cipher of the longest prefix of `key`, if encrypted
+    fn cipher(&mut self, key: &str) -> Result<Option<XChaCha20Poly1305>> {
+        let Some(prefix) = self
+            .prefixes
+            .iter()
+            .filter(|prefix| key.starts_with(prefix.as_str()))
+            .max_by_key(|prefix| prefix.len())
+        else {
+            return Ok(None);
+        };
+        if let Some(cipher) = self.ciphers.get(prefix) {
+            return Ok(Some(cipher.clone()));
+        }
+        let cipher = XChaCha20Poly1305::new(&self.provider.key(prefix)?.into());
+        self.ciphers.insert(prefix.clone(), cipher.clone());
+        Ok(Some(cipher))
+    }
+
+    fn refuse_encrypted(&mut self, op: &str, key: &str) -> Result<()> {
+        match self.cipher(key)? {
+            Some(_) => Err(KvsError::InvalidCommand(format!(
+                "{} is not supported on encrypted keys",
+                op
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // the values of `key` as stored: sealed under an encrypted prefix,
+    // left as they are otherwise
+    fn seal_all(&mut self, key: &str, values: Vec<String>) -> Result<Vec<String>> {
+        match self.cipher(key)? {
+            Some(cipher) => values
+                .into_iter()
+                .map(|value| seal(&cipher, key, &value, ValueType::String))
+                .collect(),
+            None => Ok(values),
+        }
+    }
+
+    fn open_all(&mut self, key: &str, values: Vec<String>) -> Result<Vec<String>> {
+        match self.cipher(key)? {
+            Some(cipher) => values
+                .into_iter()
+                .map(|value| Ok(open(&cipher, key, &value)?.0))
+                .collect(),
+            None => Ok(values),
+        }
+    }
+}
+
+impl<E: KvsEngine, K: KeyProvider> KvsEngine for EncryptedEngine<E, K> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_with_type(key, value, ValueType::String)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_with_type(key)?.map(|(value, _)| value))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn undelete(&mut self, key: String) -> Result<()> {
+        self.inner.undelete(key)
+    }
+
+    fn set_with_type(&mut self, key: String, value: String, value_type: ValueType) -> Result<()> {
+        match self.cipher(&key)? {
+            Some(cipher) => {
+                value_type
+                    .validate(&value)
+                    .map_err(KvsError::InvalidValue)?;
+                let sealed = seal(&cipher, &key, &value, value_type)?;
+                self.inner.set_with_type(key, sealed, ValueType::Bytes)
+            }
+            None => self.inner.set_with_type(key, value, value_type),
+        }
+    }
+
+    fn get_with_type(&mut self, key: String) -> Result<Option<(String, ValueType)>> {
+        let Some(cipher) = self.cipher(&key)? else {
+            return self.inner.get_with_type(key);
+        };
+        match self.inner.get(key.clone())? {
+            Some(sealed) => Ok(Some(open(&cipher, &key, &sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn lpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        let values = self.seal_all(&key, values)?;
+        self.inner.lpush(key, values)
+    }
+
+    fn rpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        let values = self.seal_all(&key, values)?;
+        self.inner.rpush(key, values)
+    }
+
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let values = self.inner.lrange(key.clone(), start, stop)?;
+        self.open_all(&key, values)
+    }
+
+    fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.inner.lpop(key.clone())?;
+        Ok(self.open_all(&key, value.into_iter().collect())?.pop())
+    }
+
+    // hash values are bound to their field as well as their key
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        let value = match self.cipher(&key)? {
+            Some(cipher) => seal(&cipher, &field_aad(&key, &field), &value, ValueType::String)?,
+            None => value,
+        };
+        self.inner.hset(key, field, value)
+    }
+
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        let value = self.inner.hget(key.clone(), field.clone())?;
+        match (self.cipher(&key)?, value) {
+            (Some(cipher), Some(value)) => {
+                Ok(Some(open(&cipher, &field_aad(&key, &field), &value)?.0))
+            }
+            (_, value) => Ok(value),
+        }
+    }
+
+    fn hgetall(&mut self, key: String) -> Result<BTreeMap<String, String>> {
+        let hash = self.inner.hgetall(key.clone())?;
+        let Some(cipher) = self.cipher(&key)? else {
+            return Ok(hash);
+        };
+        hash.into_iter()
+            .map(|(field, value)| {
+                let (value, _) = open(&cipher, &field_aad(&key, &field), &value)?;
+                Ok((field, value))
+            })
+            .collect()
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        self.inner.hdel(key, field)
+    }
+
+    fn sadd(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        self.refuse_encrypted("sadd", &key)?;
+        self.inner.sadd(key, members)
+    }
+
+    fn srem(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        self.inner.srem(key, members)
+    }
+
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        self.inner.sismember(key, member)
+    }
+
+    fn smembers(&mut self, key: String) -> Result<BTreeSet<String>> {
+        self.inner.smembers(key)
+    }
+
+    fn incr(&mut self, key: String, by: i64) -> Result<i64> {
+        self.refuse_encrypted("incr", &key)?;
+        self.inner.incr(key, by)
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        self.inner.expire(key, ttl)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.inner.ttl(key)
+    }
+
+    fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        self.inner.touch(key, ttl)
+    }
+
+    // sealed now, the engine keeps the sealed value until it is due
+    fn set_at(&mut self, key: String, value: String, at: u64) -> Result<()> {
+        let value = match self.cipher(&key)? {
+            Some(cipher) => seal(&cipher, &key, &value, ValueType::String)?,
+            None => value,
+        };
+        self.inner.set_at(key, value, at)
+    }
+
+    fn remove_at(&mut self, key: String, at: u64) -> Result<()> {
+        self.inner.remove_at(key, at)
+    }
+
+    fn run_scheduled(&mut self) -> Result<usize> {
+        self.inner.run_scheduled()
+    }
+
+    fn sweep_expired(&mut self) -> Result<usize> {
+        self.inner.sweep_expired()
+    }
+
+    fn lsn(&mut self) -> Option<u64> {
+        self.inner.lsn()
+    }
+
+    fn metrics(&mut self) -> Result<BTreeMap<String, u64>> {
+        self.inner.metrics()
+    }
+
+    fn changed_since(&mut self, lsn: u64) -> Result<Vec<String>> {
+        self.inner.changed_since(lsn)
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.inner.refresh()
+    }
+
+    // the engine's, but for the size and type of the plaintext
+    fn meta(&mut self, key: String) -> Result<Option<KeyMeta>> {
+        let Some(mut meta) = self.inner.meta(key.clone())? else {
+            return Ok(None);
+        };
+        if self.cipher(&key)?.is_some() {
+            let Some((value, value_type)) = self.get_with_type(key)? else {
+                return Ok(None);
+            };
+            meta.size = value.len() as u64;
+            meta.value_type = value_type;
+        }
+        Ok(Some(meta))
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn key_order(&self) -> KeyOrder {
+        self.inner.key_order()
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn exists(&mut self, key: String) -> Result<bool> {
+        self.inner.exists(key)
+    }
+
+    // what the engine stores, ciphertext included
+    fn usage(&mut self, separator: String, depth: usize) -> Result<Vec<PrefixUsage>> {
+        self.inner.usage(separator, depth)
+    }
+}
+
+// the type tag leading the plaintext of a sealed value
+fn type_tag(value_type: ValueType) -> u8 {
+    match value_type {
+        ValueType::String => 0,
+        ValueType::Json => 1,
+        ValueType::Bytes => 2,
+        ValueType::Int => 3,
+    }
+}
+
+fn field_aad(key: &str, field: &str) -> String {
+    format!("{}\0{}", key, field)
+}
+
+// base64 of a fresh nonce followed by the ciphertext of the tagged value,
+// authenticated along with `aad`
+fn seal(
+    cipher: &XChaCha20Poly1305,
+    aad: &str,
+    value: &str,
+    value_type: ValueType,
+) -> Result<String> {
+    let mut plaintext = Vec::with_capacity(value.len() + 1);
+    plaintext.push(type_tag(value_type));
+    plaintext.extend_from_slice(value.as_bytes());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: &plaintext,
+        aad: aad.as_bytes(),
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| KvsError::Other(format!("cannot encrypt the value of {:?}", aad)))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(BASE64.encode(sealed))
+}
+
+fn open(cipher: &XChaCha20Poly1305, aad: &str, sealed: &str) -> Result<(String, ValueType)> {
+    let undecryptable = || KvsError::InvalidValue(format!("cannot decrypt the value of {:?}", aad));
+    let sealed = decode_bytes(sealed).map_err(|_| undecryptable())?;
+    if sealed.len() < NONCE_LEN {
+        return Err(undecryptable());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: aad.as_bytes(),
+    };
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| undecryptable())?;
+    let value_type = match plaintext.first() {
+        Some(0) => ValueType::String,
+        Some(1) => ValueType::Json,
+        Some(2) => ValueType::Bytes,
+        Some(3) => ValueType::Int,
+        _ => return Err(undecryptable()),
+    };
+    let value = String::from_utf8(plaintext[1..].to_vec())?;
+    Ok((value, value_type))
+}
@@ -1,276 +1,3429 @@
+use crate::engines::at_rest::{self, Cipher};
+#[cfg(feature = "compression")]
+use crate::engines::decode_bytes;
+#[cfg(feature = "compression")]
+use crate::engines::segment;
+use crate::engines::segment::GenFile;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::engines::uring::Ring;
+#[cfg(feature = "encryption")]
+use crate::engines::EncryptionConfig;
+use crate::engines::{
+    add_usage, check_key, list_range, migrate, EngineKind, ExpiredReads, KeyOrder, PrefixUsage,
+    ORDER_FILE,
+};
 use crate::errors::Result;
-use crate::{KvsEngine, KvsError};
+use crate::format::{
+    frame, is_encrypted, join_chunks, log_file_name, next_frame, parse_log_file_name,
+    verify_checksum, BinaryLog, Hint, IndexPos, KvLog, LogEncoding, ENCRYPTED_MAGIC,
+    FRAME_HEADER_LEN, HINT_EXTENSION, LOG_EXTENSION,
+};
+use crate::{KeyMeta, KvsEngine, KvsError, ValueType};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use serde_json::Deserializer;
+use siphasher::sip::SipHasher13;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs::{self, File};
+use std::hash::Hasher;
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
-use std::{collections::HashMap, path};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1MB
+pub(crate) const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024; // 1MB
+const DEFAULT_MAX_OPEN_FILES: usize = 1024;
+// keys longer than this are indexed by a digest
+const DIGEST_KEY_LEN: usize = 256;
+// the bits of an LSN holding the offset in its generation, the rest holding
+// the generation
+const LSN_OFFSET_BITS: u32 = 40;
+
+// the zstd level values and segments are compressed at, its default
+#[cfg(feature = "compression")]
+pub(super) const COMPRESSION_LEVEL: i32 = 3;
+
+/// Name of the file of the first data directory of a `KvStore` holding
+/// the malformed records replay came across, one JSON object per line
+/// with their generation, offset, the reason and the record itself. An
+/// encrypted store seals the reason and the record, marking the object
+/// `sealed`.
+pub const QUARANTINE_FILE: &str = "quarantine";
+
+/// Name of the file of the first data directory of a `KvStore` recording
+/// a compaction under way: `writing <gen>` while the compacted generation
+/// is written, then `done <gen>` until the generations below it are
+/// removed. An open cut short by a crash drops the incomplete generation,
+/// or the ones it replaces, rather than replaying both.
+pub const COMPACTION_FILE: &str = "compaction";
 
 /// The `KvStore` stores string key/value pairs.
 pub struct KvStore {
-    index: HashMap<String, IndexPos>,
-    reader: HashMap<u64, BufReaderWithPos<File>>,
-    writer: BufWriterWithPos<File>,
+    // position of the latest `Set` of every string key, sorted for range
+    // scans
+    index: BTreeMap<String, IndexPos>,
+    // position of the push record of every list element, head first
+    lists: HashMap<String, VecDeque<IndexPos>>,
+    // position of the latest `HSet` of every hash field
+    hashes: HashMap<String, HashMap<String, IndexPos>>,
+    // position of the `SAdd` of every set member
+    sets: HashMap<String, HashMap<String, IndexPos>>,
+    // expiration time of string keys having one, in ms since the Unix epoch
+    expires: HashMap<String, u64>,
+    // position of the last `Set` of removed string keys which can still be
+    // undeleted, along with the end of their trash window in ms
+    trash: HashMap<String, (IndexPos, u64)>,
+    // position of the `Schedule` record of every pending write, by the
+    // time it runs and its key
+    scheduled: BTreeMap<(u64, String), IndexPos>,
+    // the order keys are listed and scanned in, the index itself being
+    // sorted bytewise
+    order: KeyOrder,
+    config: Config,
+    // reads which found a string key expired, hiding it, deleting it, or
+    // dropping it from the index of a read-only store
+    expired_hidden: u64,
+    expired_deleted: u64,
+    expired_dropped: u64,
+    cache: Option<Cache>,
+    // batches reads when io_uring is available
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    ring: Option<Ring>,
+    // readers of the generation files, and where they are
+    readers: Readers,
+    // none when opened read-only
+    writer: Option<BufWriterWithPos<File>>,
+    // when opened read-only, every generation file and its length as of
+    // the last load
+    seen: Option<Vec<(u64, u64)>>,
+
+    // data directories, new generations going round-robin across them
+    dirs: Vec<path::PathBuf>,
+    // the cold directory, and the age in generations after which sealed
+    // generations move there
+    cold: Option<(path::PathBuf, u64)>,
+    current_gen: u64,
+    uncompacted: u64,
+    // compactions since the store was opened
+    compactions: u64,
+    // generations loaded from their hints rather than replayed on open
+    hinted: u64,
+    // malformed records skipped on open
+    quarantined: u64,
+    // number of live `PinGuard`s, compaction waiting for none to be left
+    pins: Arc<AtomicUsize>,
+}
+
+// the settings of a `KvStore`, as chosen by its builder methods
+#[derive(Clone, Copy)]
+struct Config {
+    trash_window: Option<Duration>,
+    expired_reads: ExpiredReads,
+    // the budget of the cache, if the store runs as one
+    cache_budget: Option<u64>,
+    // values longer than this many bytes are written as chunks
+    chunk_size: u64,
+    // values at least this many bytes long are written compressed
+    compress_above: Option<usize>,
+    // whether compaction writes its generation as a compressed segment
+    #[cfg(feature = "compression")]
+    compress_segments: bool,
+    // whether values are written along with the time
+    write_times: bool,
+    // the encoding new generations are written in
+    encoding: LogEncoding,
+}
+
+impl KvsEngine for KvStore {
+    /// Sets the value of a string key to a string.
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_with_type(key, value, ValueType::String)
+    }
+
+    /// Gets the string value of a given string key.
+    /// If the key does not exist, returns `None`.
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_with_type(key)?.map(|(value, _)| value))
+    }
+
+    /// Gets the value of a given string key as it is encoded in the log.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "disk.read", skip_all))]
+    fn get_raw(&mut self, key: String) -> Result<Option<Box<RawValue>>> {
+        let key = index_key(key);
+        if !self.index.contains_key(&key) || self.expired_on_read(&key)? {
+            return Ok(None);
+        }
+        let index_pos = self.index[&key];
+        let encoding = self.readers.encoding(index_pos.gen);
+        let mut buf = Vec::new();
+        let read = self.readers.get(index_pos.gen).and_then(|reader| {
+            if reader.pos != index_pos.pos {
+                reader.seek(SeekFrom::Start(index_pos.pos))?;
+            }
+            reader.read_record(encoding, &mut buf)
+        });
+        if let Err(e) = read {
+            return Err(e.with_op("get").with_key(&key).with_gen(index_pos.gen));
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.touch(&key);
+        }
+        // a value encoded as a string in a JSON line is served as is, an
+        // inline JSON document is encoded
+        let raw = match encoding {
+            LogEncoding::Json => verify_checksum(&buf).ok(),
+            LogEncoding::Binary => None,
+        };
+        if let Some(Ok(RawSet::Set { value })) = raw.map(serde_json::from_slice) {
+            return match value.get().starts_with('"') {
+                true => Ok(Some(value)),
+                false => Ok(Some(serde_json::value::to_raw_value(value.get())?)),
+            };
+        }
+        // a chunked value is joined, then encoded again
+        let log = Self::read_log(&mut self.readers, &index_pos)
+            .map_err(|e| e.with_op("get").with_key(&key))?;
+        match log {
+            KvLog::Set { value, .. } => Ok(Some(serde_json::value::to_raw_value(&value)?)),
+            log => Err(KvsError::Other(format!(
+                "expected a string value at {}:{}, found {:?}",
+                index_pos.gen, index_pos.pos, log
+            ))),
+        }
+    }
+
+    /// Sets the value of a string key, tagged with the type of its content.
+    fn set_with_type(&mut self, key: String, value: String, value_type: ValueType) -> Result<()> {
+        value_type
+            .validate(&value)
+            .map_err(KvsError::InvalidValue)?;
+        self.write_set(key, value, value_type, None)
+    }
+
+    /// Sets the value of a string key to the content read from `value`,
+    /// written as chunks as it is read. Typed values are read whole, to be
+    /// validated.
+    fn set_from_reader(
+        &mut self,
+        key: String,
+        value: &mut dyn Read,
+        value_type: ValueType,
+    ) -> Result<()> {
+        if !matches!(value_type, ValueType::String | ValueType::Bytes) {
+            let mut buf = Vec::new();
+            value.read_to_end(&mut buf)?;
+            let value = String::from_utf8(buf)
+                .map_err(|e| KvsError::InvalidValue(format!("not UTF-8: {}", e)))?;
+            return self.set_with_type(key, value, value_type);
+        }
+        self.write_value(key, value, value_type, None)
+    }
+
+    /// Writes the value of a string key to `out`, one chunk at a time.
+    fn get_to_writer(&mut self, key: String, out: &mut dyn Write) -> Result<bool> {
+        let key = index_key(key);
+        if !self.index.contains_key(&key) || self.expired_on_read(&key)? {
+            return Ok(false);
+        }
+        let index_pos = self.index[&key];
+        let encoding = self.readers.encoding(index_pos.gen);
+        let cipher = self.readers.cipher_of(index_pos.gen)?;
+        let reader = self.readers.get(index_pos.gen)?;
+        if reader.pos != index_pos.pos {
+            reader.seek(SeekFrom::Start(index_pos.pos))?;
+        }
+        loop {
+            let offset = reader.pos;
+            let mut buf = Vec::new();
+            if reader.read_record(encoding, &mut buf)? == 0 {
+                return Err(KvsError::Other(format!(
+                    "chunks without a manifest at {}:{}",
+                    index_pos.gen, index_pos.pos
+                )));
+            }
+            let log = decode_record(encoding, &buf, cipher.as_deref(), index_pos.gen, offset);
+            match log.and_then(decompress)? {
+                KvLog::Chunk { data, .. } => out.write_all(data.as_bytes())?,
+                KvLog::Set { value, .. } => {
+                    out.write_all(value.as_bytes())?;
+                    break;
+                }
+                KvLog::Manifest { .. } => break,
+                log => {
+                    return Err(KvsError::Other(format!(
+                        "expected a string value at {}:{}, found {:?}",
+                        index_pos.gen, index_pos.pos, log
+                    )))
+                }
+            }
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.touch(&key);
+        }
+        Ok(true)
+    }
+
+    /// Writes the value of a string key to `out` encoded as a JSON string:
+    /// as it is in the log for a single record, escaped one chunk at a time
+    /// for a value spanning more than a chunk.
+    fn get_raw_to_writer(&mut self, key: String, out: &mut dyn Write) -> Result<bool> {
+        let large = self
+            .index
+            .get(&index_key(key.clone()))
+            .is_some_and(|pos| pos.len > self.config.chunk_size);
+        if !large {
+            return match self.get_raw(key)? {
+                Some(value) => {
+                    out.write_all(value.get().as_bytes())?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            };
+        }
+        let mut escaped = JsonStringWriter {
+            inner: out,
+            started: false,
+        };
+        if !self.get_to_writer(key, &mut escaped)? {
+            return Ok(false);
+        }
+        escaped.finish()?;
+        Ok(true)
+    }
+
+    /// Gets the values of string keys in one pass over the index, their
+    /// records read in log order, in a single batch with io_uring.
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut found = Vec::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let key = index_key(key);
+            match self.index.get(&key).copied() {
+                Some(index_pos) if !self.expired_on_read(&key)? => {
+                    if let Some(cache) = &mut self.cache {
+                        cache.touch(&key);
+                    }
+                    found.push((i, index_pos));
+                }
+                _ => {}
+            }
+        }
+        found.sort_unstable_by_key(|(_, index_pos)| (index_pos.gen, index_pos.pos));
+        let positions: Vec<IndexPos> = found.iter().map(|&(_, index_pos)| index_pos).collect();
+        let logs = self
+            .read_records(&positions)
+            .map_err(|e| e.with_op("get_many"))?;
+        for ((i, _), log) in found.into_iter().zip(logs) {
+            if let KvLog::Set { value, .. } = log {
+                values[i] = Some(value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Gets the value of a given string key along with its type tag.
+    fn get_with_type(&mut self, key: String) -> Result<Option<(String, ValueType)>> {
+        let key = index_key(key);
+        if !self.index.contains_key(&key) || self.expired_on_read(&key)? {
+            return Ok(None);
+        }
+        let index_pos = self.index[&key];
+        let log = self
+            .read_records(&[index_pos])
+            .map_err(|e| e.with_op("get").with_key(&key).with_gen(index_pos.gen))?
+            .remove(0);
+        if let Some(cache) = &mut self.cache {
+            cache.touch(&key);
+        }
+        match log {
+            KvLog::Set {
+                value, value_type, ..
+            } => Ok(Some((value, value_type))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Removes a given string key from the store.
+    /// With a trash window, the value is kept to be undeleted.
+    fn remove(&mut self, full_key: String) -> Result<()> {
+        let key = index_key(full_key.clone());
+        if !self.index.contains_key(&key) || self.is_expired(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        let pos = match self.config.trash_window {
+            Some(window) => {
+                let until = now_ms() + window.as_millis() as u64;
+                let log = KvLog::Trash {
+                    key: full_key,
+                    until,
+                };
+                self.append_log_file(&log)?;
+                let pos = self.index.remove(&key).unwrap();
+                self.trash.insert(key.clone(), (pos, until));
+                pos
+            }
+            None => {
+                let log = KvLog::Remove { key: full_key };
+                self.append_log_file(&log)?;
+                self.index.remove(&key).unwrap()
+            }
+        };
+        self.expires.remove(&key);
+        if let Some(cache) = &mut self.cache {
+            cache.forget(&key, pos.len);
+        }
+        Ok(())
+    }
+
+    /// Restores the last value of a string key removed within the trash
+    /// window.
+    fn undelete(&mut self, key: String) -> Result<()> {
+        let Some(&(pos, until)) = self.trash.get(&index_key(key)) else {
+            return Err(KvsError::KeyNotFound);
+        };
+        if until <= now_ms() {
+            return Err(KvsError::KeyNotFound);
+        }
+        match Self::read_log(&mut self.readers, &pos)? {
+            KvLog::Set {
+                key,
+                value,
+                value_type,
+                expires_at,
+                ..
+            } => self.write_set(key, value, value_type, expires_at),
+            log => Err(KvsError::Other(format!(
+                "expected a string value at {}:{}, found {:?}",
+                pos.gen, pos.pos, log
+            ))),
+        }
+    }
+
+    /// Pushes values at the head of a list.
+    fn lpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        check_key(&key)?;
+        let list_key = index_key(key.clone());
+        for value in values {
+            let log = KvLog::LPush {
+                key: key.clone(),
+                value,
+            };
+            let pos = self.append_record(&log)?;
+            self.lists
+                .entry(list_key.clone())
+                .or_default()
+                .push_front(pos);
+        }
+        Ok(self.lists.get(&list_key).map_or(0, VecDeque::len))
+    }
+
+    /// Pushes values at the tail of a list.
+    fn rpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        check_key(&key)?;
+        let list_key = index_key(key.clone());
+        for value in values {
+            let log = KvLog::RPush {
+                key: key.clone(),
+                value,
+            };
+            let pos = self.append_record(&log)?;
+            self.lists
+                .entry(list_key.clone())
+                .or_default()
+                .push_back(pos);
+        }
+        Ok(self.lists.get(&list_key).map_or(0, VecDeque::len))
+    }
+
+    /// Gets the elements of a list between two inclusive indexes.
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let key = index_key(key);
+        let Some(list) = self.lists.get(&key) else {
+            return Ok(Vec::new());
+        };
+        let positions: Vec<IndexPos> = list
+            .range(list_range(list.len(), start, stop))
+            .copied()
+            .collect();
+        self.read_values(&positions)
+    }
+
+    /// Pops the head of a list.
+    fn lpop(&mut self, full_key: String) -> Result<Option<String>> {
+        let key = index_key(full_key.clone());
+        let Some(head) = self.lists.get(&key).and_then(|list| list.front()).copied() else {
+            return Ok(None);
+        };
+        let value = Self::read_value(&mut self.readers, &head)?;
+
+        let log = KvLog::LPop { key: full_key };
+        let old_pos = self.tail();
+        self.append_log_file(&log)?;
+        // both the popped element and the pop itself are stale now
+        self.uncompacted += head.len + self.tail() - old_pos;
+
+        let list = self.lists.get_mut(&key).unwrap();
+        list.pop_front();
+        if list.is_empty() {
+            self.lists.remove(&key);
+        }
+
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact_log()?;
+        }
+        Ok(Some(value))
+    }
+
+    /// Sets a field of a hash.
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        check_key(&key)?;
+        let log = KvLog::HSet {
+            key: key.clone(),
+            field: field.clone(),
+            value,
+        };
+        let pos = self.append_record(&log)?;
+        let hash = self.hashes.entry(index_key(key)).or_default();
+        if let Some(old) = hash.insert(field, pos) {
+            self.uncompacted += old.len;
+        }
+
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact_log()?;
+        }
+        Ok(())
+    }
+
+    /// Gets a field of a hash.
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        let key = index_key(key);
+        match self.hashes.get(&key).and_then(|hash| hash.get(&field)) {
+            Some(index_pos) => Ok(Some(Self::read_value(&mut self.readers, index_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets every field of a hash.
+    fn hgetall(&mut self, key: String) -> Result<BTreeMap<String, String>> {
+        let key = index_key(key);
+        let Some(hash) = self.hashes.get(&key) else {
+            return Ok(BTreeMap::new());
+        };
+        let (fields, positions): (Vec<String>, Vec<IndexPos>) = hash
+            .iter()
+            .map(|(field, index_pos)| (field.clone(), *index_pos))
+            .unzip();
+        let values = self.read_values(&positions)?;
+        Ok(fields.into_iter().zip(values).collect())
+    }
+
+    /// Removes a field of a hash.
+    fn hdel(&mut self, full_key: String, field: String) -> Result<()> {
+        let key = index_key(full_key.clone());
+        let Some(old) = self
+            .hashes
+            .get(&key)
+            .and_then(|hash| hash.get(&field))
+            .copied()
+        else {
+            return Err(KvsError::KeyNotFound);
+        };
+        let log = KvLog::HDel {
+            key: full_key,
+            field: field.clone(),
+        };
+        let old_pos = self.tail();
+        self.append_log_file(&log)?;
+        self.uncompacted += old.len + self.tail() - old_pos;
+
+        let hash = self.hashes.get_mut(&key).unwrap();
+        hash.remove(&field);
+        if hash.is_empty() {
+            self.hashes.remove(&key);
+        }
+
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact_log()?;
+        }
+        Ok(())
+    }
+
+    /// Adds members to a set.
+    fn sadd(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        check_key(&key)?;
+        let set_key = index_key(key.clone());
+        let mut added = 0;
+        for member in members {
+            if self
+                .sets
+                .get(&set_key)
+                .is_some_and(|set| set.contains_key(&member))
+            {
+                continue;
+            }
+            let log = KvLog::SAdd {
+                key: key.clone(),
+                member: member.clone(),
+            };
+            let pos = self.append_record(&log)?;
+            self.sets
+                .entry(set_key.clone())
+                .or_default()
+                .insert(member, pos);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Removes members from a set.
+    fn srem(&mut self, full_key: String, members: Vec<String>) -> Result<usize> {
+        let key = index_key(full_key.clone());
+        let mut removed = 0;
+        for member in members {
+            let Some(old) = self
+                .sets
+                .get(&key)
+                .and_then(|set| set.get(&member))
+                .copied()
+            else {
+                continue;
+            };
+            let log = KvLog::SRem {
+                key: full_key.clone(),
+                member: member.clone(),
+            };
+            let old_pos = self.tail();
+            self.append_log_file(&log)?;
+            self.uncompacted += old.len + self.tail() - old_pos;
+
+            let set = self.sets.get_mut(&key).unwrap();
+            set.remove(&member);
+            if set.is_empty() {
+                self.sets.remove(&key);
+            }
+            removed += 1;
+        }
+
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact_log()?;
+        }
+        Ok(removed)
+    }
+
+    /// Adds to the integer value of a string key.
+    fn incr(&mut self, key: String, by: i64) -> Result<i64> {
+        let (value, expires_at) = match self.get(key.clone())? {
+            Some(value) => {
+                let value = value
+                    .parse::<i64>()
+                    .map_err(|e| KvsError::InvalidValue(format!("not an integer: {}", e)))?;
+                (value, self.expires.get(&index_key(key.clone())).copied())
+            }
+            None => (0, None),
+        };
+        let value = value
+            .checked_add(by)
+            .ok_or_else(|| KvsError::InvalidValue("integer overflow".to_owned()))?;
+        self.write_set(key, value.to_string(), ValueType::Int, expires_at)?;
+        Ok(value)
+    }
+
+    /// Makes a string key expire after `ttl`, rewriting its record.
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        let key = index_key(key);
+        if !self.index.contains_key(&key) || self.is_expired(&key) {
+            return Ok(false);
+        }
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = now_ms().saturating_add(ttl);
+        let log = KvLog::Touch {
+            key: key.clone(),
+            expires_at: Some(expires_at),
+        };
+        self.uncompacted += self.append_record(&log)?.len;
+        self.expires.insert(key, expires_at);
+        Ok(true)
+    }
+
+    /// Makes a string key expire after `ttl` and marks it as recently used
+    /// in cache mode, appending a `Touch` record.
+    fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        let key = index_key(key);
+        if !self.expire(key.clone(), ttl)? {
+            return Ok(false);
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.touch(&key);
+        }
+        Ok(true)
+    }
+
+    /// Gets the time left before a string key expires.
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let key = index_key(key);
+        if !self.index.contains_key(&key) || self.is_expired(&key) {
+            return Ok(None);
+        }
+        Ok(self
+            .expires
+            .get(&key)
+            .map(|&at| Duration::from_millis(at.saturating_sub(now_ms()))))
+    }
+
+    /// Gets the size, type and expiration of a string value, where its
+    /// record ends in the log, and when it was written if the store records
+    /// write times.
+    fn meta(&mut self, key: String) -> Result<Option<KeyMeta>> {
+        let key = index_key(key);
+        if !self.index.contains_key(&key) || self.is_expired(&key) {
+            return Ok(None);
+        }
+        let index_pos = self.index[&key];
+        match self.read_records(&[index_pos])?.remove(0) {
+            KvLog::Set {
+                value,
+                value_type,
+                written_at,
+                ..
+            } => Ok(Some(KeyMeta {
+                size: value.len() as u64,
+                value_type,
+                ttl_ms: self.ttl(key)?.map(|ttl| ttl.as_millis() as u64),
+                written_at,
+                lsn: lsn_of(index_pos.gen, index_pos.pos + index_pos.len),
+            })),
+            log => Err(KvsError::Other(format!(
+                "expected a string value at {}:{}, found {:?}",
+                index_pos.gen, index_pos.pos, log
+            ))),
+        }
+    }
+
+    /// Records a write of a string key to run at `at`. Another write
+    /// scheduled on the key at the same time is replaced.
+    fn set_at(&mut self, key: String, value: String, at: u64) -> Result<()> {
+        self.schedule(key, at, Some(value))
+    }
+
+    /// Records a removal of a string key to run at `at`.
+    fn remove_at(&mut self, key: String, at: u64) -> Result<()> {
+        self.schedule(key, at, None)
+    }
+
+    /// Runs the due scheduled writes, each followed by an `Unschedule`
+    /// record. A store opened read-only leaves them to the one writing.
+    fn run_scheduled(&mut self) -> Result<usize> {
+        if self.writer.is_none() {
+            return Ok(0);
+        }
+        let now = now_ms();
+        let mut ran = 0;
+        while let Some((&(at, _), &index_pos)) = self
+            .scheduled
+            .first_key_value()
+            .filter(|((at, _), _)| *at <= now)
+        {
+            let (key, value) = match Self::read_log(&mut self.readers, &index_pos)? {
+                KvLog::Schedule { key, value, .. } => (key, value),
+                log => {
+                    return Err(KvsError::Other(format!(
+                        "expected a scheduled write at {}:{}, found {:?}",
+                        index_pos.gen, index_pos.pos, log
+                    )))
+                }
+            };
+            match value {
+                Some(value) => self.write_set(key.clone(), value, ValueType::String, None)?,
+                None => match self.remove(key.clone()) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                },
+            }
+            let key = index_key(key);
+            let old_pos = self.tail();
+            self.append_log_file(&KvLog::Unschedule {
+                key: key.clone(),
+                at,
+            })?;
+            self.scheduled.remove(&(at, key));
+            self.uncompacted += index_pos.len + self.tail() - old_pos;
+            ran += 1;
+        }
+
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact_log()?;
+        }
+        Ok(ran)
+    }
+
+    /// Rewrites the live records to a new generation and drops the old
+    /// ones, as done once enough of the log is stale.
+    fn compact(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        self.check_unpinned()?;
+        self.compact_log().map_err(|e| e.with_op("compact"))
+    }
+
+    /// Gets the position the log is written at: the generation in the high
+    /// 24 bits and the offset in it in the low 40. Compaction moves on to
+    /// newer generations, so it only ever increases. There is none once
+    /// either outgrows its bits.
+    fn lsn(&mut self) -> Option<u64> {
+        let writer = self.writer.as_ref()?;
+        lsn_of(self.current_gen, writer.pos)
+    }
+
+    /// Reports the log files and the stale bytes in them, compactions, and
+    /// the cache when in cache mode.
+    fn metrics(&mut self) -> Result<BTreeMap<String, u64>> {
+        let mut metrics = BTreeMap::from([
+            ("keys".to_owned(), self.index.len() as u64),
+            ("log_files".to_owned(), self.readers.paths.len() as u64),
+            (
+                "open_files".to_owned(),
+                (self.readers.open.len() + self.writer.is_some() as usize) as u64,
+            ),
+            ("uncompacted_bytes".to_owned(), self.uncompacted),
+            ("compactions".to_owned(), self.compactions),
+            ("hinted_generations".to_owned(), self.hinted),
+            ("quarantined_records".to_owned(), self.quarantined),
+            ("scheduled".to_owned(), self.scheduled.len() as u64),
+            ("expired_reads_hidden".to_owned(), self.expired_hidden),
+            ("expired_reads_deleted".to_owned(), self.expired_deleted),
+            ("expired_reads_dropped".to_owned(), self.expired_dropped),
+        ]);
+        if let Some(cache) = &self.cache {
+            metrics.insert("cache_used_bytes".to_owned(), cache.used);
+            metrics.insert("cache_evictions".to_owned(), cache.evictions);
+        }
+        Ok(metrics)
+    }
+
+    /// Lists the keys of the records written after `lsn`. Compaction drops
+    /// every generation before the one it writes, which holds all the live
+    /// data: from there on, every key is reported.
+    fn changed_since(&mut self, lsn: u64) -> Result<Vec<String>> {
+        let (since_gen, since_pos) = (lsn >> LSN_OFFSET_BITS, lsn & ((1 << LSN_OFFSET_BITS) - 1));
+        let mut gens: Vec<u64> = self.readers.paths.keys().copied().collect();
+        gens.sort_unstable();
+        // the first generation is 1, none was dropped while it is there
+        if let Some(&oldest) = gens.first().filter(|&&gen| gen > 1 && gen > since_gen) {
+            return Err(KvsError::LsnCompacted {
+                lsn,
+                oldest: lsn_of(oldest, 0).unwrap_or(u64::MAX),
+            });
+        }
+        let mut keys = BTreeSet::new();
+        for gen in gens.into_iter().filter(|&gen| gen >= since_gen) {
+            let encoding = self.readers.encoding(gen);
+            let cipher = self.readers.cipher_of(gen)?;
+            let mut reader = BufReader::new(GenFile::open(&self.readers.paths[&gen])?);
+            let header = encoding.header().len() as u64;
+            let mut pos = match gen == since_gen {
+                true => since_pos.max(header),
+                false => header,
+            };
+            reader.seek(SeekFrom::Start(pos))?;
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let offset = pos;
+                pos += read_record(&mut reader, encoding, &mut line)? as u64;
+                if line.is_empty() {
+                    break;
+                }
+                if encoding == LogEncoding::Json && line.trim_ascii().is_empty() {
+                    continue;
+                }
+                match decode_record(encoding, &line, cipher.as_deref(), gen, offset) {
+                    Ok(log) => keys.insert(log.key().to_owned()),
+                    // a record still being written by another process
+                    Err(_) if !encoding.is_whole(&line) => break,
+                    // quarantined on open
+                    Err(_) => continue,
+                };
+            }
+        }
+        // evictions and the like hold the digest of a long key, resolved
+        // only while the key still holds data
+        let mut changed = BTreeSet::new();
+        for key in keys {
+            let key = self.full_key(key)?;
+            if !key.starts_with('\0') {
+                changed.insert(key);
+            }
+        }
+        Ok(changed.into_iter().collect())
+    }
+
+    /// Loads the store again if opened read-only and its generation files
+    /// changed since the last load, which keeps serving the old data until
+    /// the new one is loaded whole.
+    fn refresh(&mut self) -> Result<()> {
+        let Some(seen) = &self.seen else {
+            return Ok(());
+        };
+        let cold = self.cold.as_ref().map(|(cold, _)| cold.clone());
+        let mut gen_paths = HashMap::new();
+        for dir in self
+            .dirs
+            .iter()
+            .map(|dir| dir.as_path())
+            .chain(cold.as_deref())
+        {
+            for gen in Self::get_sorted_gen_list(dir)? {
+                gen_paths.insert(gen, Self::log_file_path(dir, gen));
+            }
+        }
+        if Self::gen_lengths(&gen_paths)? == *seen {
+            return Ok(());
+        }
+        let mut store = Self::open_read_only(&self.dirs, cold.as_deref())?;
+        store.readers.set_max_open(self.readers.max_open);
+        store.cache = self
+            .config
+            .cache_budget
+            .map(|budget| Cache::new(budget, &store.index));
+        *self = KvStore {
+            config: self.config,
+            ..store
+        };
+        Ok(())
+    }
+
+    /// Gets every key holding data, in any keyspace.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let strings = self.index.keys().filter(|key| !self.is_expired(key));
+        let keys: BTreeSet<String> = strings
+            .chain(self.lists.keys())
+            .chain(self.hashes.keys())
+            .chain(self.sets.keys())
+            .cloned()
+            .collect();
+        let keys = keys
+            .into_iter()
+            .map(|key| self.full_key(key))
+            .collect::<Result<BTreeSet<String>>>()?;
+        let mut keys: Vec<String> = keys.into_iter().collect();
+        if self.order != KeyOrder::Bytewise {
+            self.order.sort(&mut keys);
+        }
+        Ok(keys)
+    }
+
+    fn key_order(&self) -> KeyOrder {
+        self.order
+    }
+
+    /// Reads the keys in `range` off the sorted index. Keys indexed by a
+    /// digest are sorted apart, their records giving them back whole. In
+    /// another order than bytewise, the range is looked for in the whole
+    /// index.
+    fn scan(&mut self, range: Range<String>) -> Result<impl Iterator<Item = (String, String)>> {
+        let order = self.order;
+        if order.cmp(&range.start, &range.end).is_ge() {
+            return Ok(Vec::new().into_iter());
+        }
+        let entries: Vec<IndexPos> = self
+            .index_in(&range)
+            .filter(|(key, _)| !self.is_expired(key))
+            .map(|(_, &index_pos)| index_pos)
+            .collect();
+        let mut pairs = Vec::new();
+        for index_pos in entries {
+            match Self::read_log(&mut self.readers, &index_pos)? {
+                KvLog::Set { key, value, .. } if order.contains(&range, &key) => {
+                    pairs.push((key, value))
+                }
+                KvLog::Set { .. } => {}
+                log => {
+                    return Err(KvsError::Other(format!(
+                        "expected a string value at {}:{}, found {:?}",
+                        index_pos.gen, index_pos.pos, log
+                    )))
+                }
+            }
+        }
+        pairs.sort_unstable_by(|(a, _), (b, _)| order.cmp(a, b));
+        Ok(pairs.into_iter())
+    }
+
+    /// Walks the sorted index from `prefix` for string keys, the other
+    /// keyspaces and keys indexed by a digest being filtered.
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let strings = self
+            .index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .chain(
+                self.index
+                    .range::<str, _>((Bound::Included("\0"), Bound::Excluded("\u{1}"))),
+            )
+            .map(|(key, _)| key)
+            .filter(|key| !self.is_expired(key));
+        let others = self
+            .lists
+            .keys()
+            .chain(self.hashes.keys())
+            .chain(self.sets.keys())
+            .filter(|key| key.starts_with(prefix) || key.starts_with('\0'));
+        let candidates: BTreeSet<String> = strings.chain(others).cloned().collect();
+        let mut keys = BTreeSet::new();
+        for key in candidates {
+            let key = self.full_key(key)?;
+            if key.starts_with(prefix) {
+                keys.insert(key);
+            }
+        }
+        let mut keys: Vec<String> = keys.into_iter().collect();
+        if self.order != KeyOrder::Bytewise {
+            self.order.sort(&mut keys);
+        }
+        Ok(keys)
+    }
+
+    /// Counts the keys by prefix along with the bytes of their live log
+    /// records, as kept in the index without reading the log.
+    fn usage(&mut self, separator: String, depth: usize) -> Result<Vec<PrefixUsage>> {
+        let mut bytes: HashMap<String, u64> = HashMap::new();
+        let strings = self
+            .index
+            .iter()
+            .filter(|(key, _)| !self.is_expired(key))
+            .map(|(key, pos)| (key, pos.len));
+        let lists = self
+            .lists
+            .iter()
+            .map(|(key, list)| (key, list.iter().map(|pos| pos.len).sum()));
+        let hashes = self
+            .hashes
+            .iter()
+            .chain(&self.sets)
+            .map(|(key, fields)| (key, fields.values().map(|pos| pos.len).sum()));
+        for (key, len) in strings.chain(lists).chain(hashes) {
+            *bytes.entry(key.clone()).or_default() += len;
+        }
+        let mut usage = BTreeMap::new();
+        for (key, len) in bytes {
+            let key = self.full_key(key)?;
+            add_usage(&mut usage, &key, &separator, depth, len as usize);
+        }
+        Ok(usage.into_values().collect())
+    }
+
+    /// Checks whether a member belongs to a set.
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        let key = index_key(key);
+        Ok(self
+            .sets
+            .get(&key)
+            .is_some_and(|set| set.contains_key(&member)))
+    }
+
+    /// Gets the members of a set.
+    fn smembers(&mut self, key: String) -> Result<BTreeSet<String>> {
+        let key = index_key(key);
+        Ok(self
+            .sets
+            .get(&key)
+            .map(|set| set.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+impl KvStore {
+    fn write_set(
+        &mut self,
+        key: String,
+        value: String,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        check_key(&key)?;
+        if value.len() as u64 > self.config.chunk_size {
+            return self.write_value(key, &mut value.as_bytes(), value_type, expires_at);
+        }
+        let old_pos = self.tail();
+        let log = KvLog::Set {
+            key: key.clone(),
+            value,
+            value_type,
+            expires_at,
+            written_at: self.config.write_times.then(now_ms),
+        };
+        self.append_log_file(&log)?;
+        self.index_value(key, old_pos, expires_at)
+    }
+
+    // write the value read from `value` as chunks of at most `chunk_size`
+    // bytes, cut on char boundaries, followed by their manifest; a value
+    // fitting in one chunk is written as a plain `Set`
+    fn write_value(
+        &mut self,
+        key: String,
+        value: &mut dyn Read,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        check_key(&key)?;
+        let not_utf8 = |e| KvsError::InvalidValue(format!("not UTF-8: {}", e));
+        let old_pos = self.tail();
+        let mut buf = Vec::new();
+        let mut chunked = false;
+        loop {
+            let missing = self.config.chunk_size + 1 - buf.len() as u64;
+            let eof = (value.take(missing).read_to_end(&mut buf)? as u64) < missing;
+            if eof && !chunked {
+                let log = KvLog::Set {
+                    key: key.clone(),
+                    value: String::from_utf8(buf).map_err(|e| not_utf8(e.utf8_error()))?,
+                    value_type,
+                    expires_at,
+                    written_at: self.config.write_times.then(now_ms),
+                };
+                self.append_log_file(&log)?;
+                break;
+            }
+            if buf.is_empty() {
+                let log = KvLog::Manifest {
+                    key: key.clone(),
+                    span: self.tail() - old_pos,
+                    value_type,
+                    expires_at,
+                    written_at: self.config.write_times.then(now_ms),
+                };
+                self.append_log_file(&log)?;
+                break;
+            }
+            let end = buf.len().min(self.config.chunk_size as usize);
+            // a chunk of at least 4 bytes holds at least one whole char
+            let data = match std::str::from_utf8(&buf[..end]) {
+                Ok(data) => data,
+                Err(e) if e.error_len().is_none() && !eof => {
+                    std::str::from_utf8(&buf[..e.valid_up_to()]).unwrap()
+                }
+                Err(e) => return Err(not_utf8(e)),
+            };
+            let log = KvLog::Chunk {
+                key: key.clone(),
+                data: data.to_owned(),
+            };
+            let len = data.len();
+            self.append_log_file(&log)?;
+            buf.drain(..len);
+            chunked = true;
+        }
+        self.index_value(key, old_pos, expires_at)
+    }
+
+    // index the value of `key` just written from `old_pos`
+    fn index_value(&mut self, key: String, old_pos: u64, expires_at: Option<u64>) -> Result<()> {
+        let key = index_key(key);
+        let cur_pos = self.tail();
+
+        match expires_at {
+            Some(at) => self.expires.insert(key.clone(), at),
+            None => self.expires.remove(&key),
+        };
+        // a new value supersedes the one in the trash
+        if let Some((old, _)) = self.trash.remove(&key) {
+            self.uncompacted += old.len;
+        }
+        let pos: IndexPos = (self.current_gen, old_pos..cur_pos).into();
+        let old = self.index.insert(key.clone(), pos);
+        if let Some(old) = old {
+            self.uncompacted += old.len;
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.forget(&key, old.map_or(0, |old| old.len));
+            cache.insert(&key, pos.len);
+            self.evict(&key)?;
+        }
+
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact_log()?;
+        }
+        Ok(())
+    }
+
+    fn schedule(&mut self, key: String, at: u64, value: Option<String>) -> Result<()> {
+        check_key(&key)?;
+        let log = KvLog::Schedule {
+            key: key.clone(),
+            at,
+            value,
+        };
+        let pos = self.append_record(&log)?;
+        if let Some(old) = self.scheduled.insert((at, index_key(key)), pos) {
+            self.uncompacted += old.len;
+        }
+
+        if self.uncompacted > COMPACTION_THRESHOLD {
+            self.compact_log()?;
+        }
+        Ok(())
+    }
+
+    // evict the least recently used string keys but `keep` until the cache
+    // fits its budget
+    fn evict(&mut self, keep: &str) -> Result<()> {
+        while let Some(key) = self.cache.as_ref().and_then(|cache| cache.victim(keep)) {
+            let record = self.append_record(&KvLog::Evict { key: key.clone() })?;
+            self.expires.remove(&key);
+            let len = self.index.remove(&key).map_or(0, |pos| pos.len);
+            // the evicted value and the record evicting it are both stale
+            self.uncompacted += len + record.len;
+            if let Some(cache) = &mut self.cache {
+                cache.forget(&key, len);
+                cache.evictions += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // the key indexed as `key`, read from a record of its data when it is
+    // a digest
+    fn full_key(&mut self, key: String) -> Result<String> {
+        if !key.starts_with('\0') {
+            return Ok(key);
+        }
+        let pos = self
+            .index
+            .get(&key)
+            .or_else(|| self.lists.get(&key).and_then(VecDeque::front))
+            .or_else(|| self.hashes.get(&key).and_then(|hash| hash.values().next()))
+            .or_else(|| self.sets.get(&key).and_then(|set| set.values().next()))
+            .copied();
+        match pos {
+            Some(pos) => Ok(Self::read_log(&mut self.readers, &pos)?.key().to_owned()),
+            None => Ok(key),
+        }
+    }
+
+    // the index entries of the string keys in `range` in the key order,
+    // along with every key indexed by a digest, whose record tells
+    fn index_in<'a, R: RangeBounds<String> + Clone + 'a>(
+        &'a self,
+        range: &'a R,
+    ) -> Box<dyn Iterator<Item = (&'a String, &'a IndexPos)> + 'a> {
+        let digests = self
+            .index
+            .range::<str, _>((Bound::Included("\0"), Bound::Excluded("\u{1}")));
+        match self.order {
+            KeyOrder::Bytewise => Box::new(
+                self.index
+                    .range(range.clone())
+                    .filter(|(key, _)| !key.starts_with('\0'))
+                    .chain(digests),
+            ),
+            order => Box::new(
+                self.index
+                    .iter()
+                    .filter(move |(key, _)| !key.starts_with('\0') && order.contains(range, key))
+                    .chain(digests),
+            ),
+        }
+    }
+
+    // expired keys stay in the index until the next compaction
+    fn is_expired(&self, key: &str) -> bool {
+        self.expires.get(key).is_some_and(|&at| at <= now_ms())
+    }
+
+    // whether a read finds `key` expired, then hidden or deleted as
+    // configured; a read-only store has no log to delete it in, and only
+    // drops it from its index, leaving the delete to the writer
+    fn expired_on_read(&mut self, key: &str) -> Result<bool> {
+        if !self.is_expired(key) {
+            return Ok(false);
+        }
+        match self.config.expired_reads {
+            ExpiredReads::Hide => self.expired_hidden += 1,
+            ExpiredReads::Delete if self.writer.is_none() => {
+                let len = self.drop_expired(key);
+                self.uncompacted += len;
+                self.expired_dropped += 1;
+            }
+            ExpiredReads::Delete => {
+                let full_key = self.full_key(key.to_owned())?;
+                let record = self.append_record(&KvLog::Remove { key: full_key })?;
+                // the expired value and the record removing it are both stale
+                let len = self.drop_expired(key);
+                self.uncompacted += len + record.len;
+                self.expired_deleted += 1;
+            }
+        }
+        Ok(true)
+    }
+
+    // drop the expired `key` from the index, returning the length of its
+    // record
+    fn drop_expired(&mut self, key: &str) -> u64 {
+        self.expires.remove(key);
+        let len = self.index.remove(key).map_or(0, |pos| pos.len);
+        if let Some(cache) = &mut self.cache {
+            cache.forget(key, len);
+        }
+        len
+    }
+
+    /// Keeps the value of removed string keys for `window`, during which
+    /// `undelete` restores them. Values are dropped for good by the first
+    /// compaction after their window closes.
+    pub fn trash_window(mut self, window: Duration) -> Self {
+        self.config.trash_window = Some(window);
+        self
+    }
+
+    /// Runs the store as a cache holding at most `budget` bytes of string
+    /// keys: once a write exceeds the budget, the least recently used
+    /// string keys are evicted, and the evictions recorded in the log.
+    /// Lists, hashes and sets are neither counted nor evicted.
+    ///
+    /// The size of a key is the size of its record in the log. Keys are
+    /// first ranked in the order they were written.
+    pub fn cache_budget(mut self, budget: u64) -> Self {
+        self.config.cache_budget = Some(budget);
+        self.cache = Some(Cache::new(budget, &self.index));
+        self
+    }
+
+    /// Writes string values longer than `size` bytes as several records of
+    /// at most `size` bytes, 1MB by default, so that neither writing nor
+    /// compacting a large value encodes or copies it whole at once. Chunks
+    /// hold at least 4 bytes, the longest UTF-8 char.
+    pub fn chunk_size(mut self, size: u64) -> Self {
+        self.config.chunk_size = size.max(4);
+        self
+    }
+
+    /// Compresses string values of at least `size` bytes with zstd before
+    /// writing them, when that shrinks them, and decompresses them on
+    /// reads. Compaction compresses the values written before. Values
+    /// written as chunks, see `chunk_size`, are left as they are.
+    #[cfg(feature = "compression")]
+    pub fn compress_above(mut self, size: usize) -> Self {
+        self.config.compress_above = Some(size);
+        self
+    }
+
+    /// Writes the generation compaction produces as a compressed segment:
+    /// the whole file is compressed with zstd, block by block, which takes
+    /// far less space than values compressed one by one, at the cost of
+    /// decompressing a block to read a record from it. Suits stores whose
+    /// data mostly lies in the compacted generation, and gets cold there.
+    #[cfg(feature = "compression")]
+    pub fn compress_segments(mut self, enabled: bool) -> Self {
+        self.config.compress_segments = enabled;
+        self
+    }
+
+    /// Deletes string keys found expired by reads right away rather than
+    /// hiding them until the next compaction, with `ExpiredReads::Delete`:
+    /// the read appends a remove record, as `remove` does. A read-only
+    /// store cannot write one, and only drops the key from its index.
+    pub fn expired_reads(mut self, policy: ExpiredReads) -> Self {
+        self.config.expired_reads = policy;
+        self
+    }
+
+    /// Records the time every string value is written along with it, as
+    /// reported by `meta`, which takes about 30 more bytes per value.
+    pub fn write_times(mut self, enabled: bool) -> Self {
+        self.config.write_times = enabled;
+        self
+    }
+
+    /// Writes new generations in `encoding`, from compaction on, the
+    /// current generation included as long as it holds no record. A store
+    /// otherwise keeps the encoding of its latest generation, JSON lines
+    /// for a new one. Binary records take about half the space, JSON lines
+    /// can be read with any text tool.
+    pub fn log_encoding(mut self, encoding: LogEncoding) -> Self {
+        if self.readers.cipher.is_some() {
+            return self;
+        }
+        self.config.encoding = encoding;
+        let current = self.readers.encoding(self.current_gen);
+        if self.writer.is_some()
+            && current != encoding
+            && self.tail() == current.header().len() as u64
+        {
+            if let Err(e) = self.restart_gen() {
+                log::warn!(
+                    "Writing generation {} as {}: {}",
+                    self.current_gen,
+                    current,
+                    e
+                );
+            }
+        }
+        self
+    }
+
+    // start the current generation again, holding no record, in the
+    // encoding of the store
+    fn restart_gen(&mut self) -> Result<()> {
+        fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&self.readers.paths[&self.current_gen])?;
+        self.writer = None;
+        self.writer = Some(Self::create_log_file(
+            &self.dirs,
+            self.current_gen,
+            self.config.encoding,
+            &mut self.readers,
+        )?);
+        Ok(())
+    }
+
+    /// Keeps at most `max` generation files open for reading, 1024 by
+    /// default, closing the least recently read ones and reopening them
+    /// when needed, so that a store with many generations does not run
+    /// out of file descriptors. The file being written is open besides.
+    pub fn max_open_files(mut self, max: usize) -> Self {
+        self.readers.set_max_open(max);
+        self
+    }
+
+    /// Compacts now if the log holds more than `ratio` times as many stale
+    /// bytes as live ones, returning whether it did, e.g. right after
+    /// opening, before serving traffic: a store left bloated by heavy
+    /// churn is otherwise replayed whole on every open until enough
+    /// writes trigger a compaction. A read-only store is left as is.
+    pub fn compact_if_bloated(&mut self, ratio: u64) -> Result<bool> {
+        if self.writer.is_none() || self.uncompacted == 0 {
+            return Ok(false);
+        }
+        let total: u64 = Self::gen_lengths(&self.readers.paths)?
+            .iter()
+            .map(|&(_, len)| len)
+            .sum();
+        let live = total.saturating_sub(self.uncompacted);
+        if self.uncompacted <= live.saturating_mul(ratio) {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+
+    /// Keep the generation files as they are until the guard is dropped,
+    /// for an external tool to copy them while the store keeps serving
+    /// writes, e.g. a filesystem-level backup.
+    ///
+    /// Compaction and tiering are postponed meanwhile, explicit ones
+    /// failing. Writes are still appended to the current generation: a
+    /// consistent copy takes every file listed by the guard up to the
+    /// length it lists.
+    pub fn pin_generations(&mut self) -> Result<PinGuard> {
+        self.pins.fetch_add(1, Ordering::SeqCst);
+        // released by the guard, also if listing the files fails
+        let mut guard = PinGuard {
+            pins: Arc::clone(&self.pins),
+            files: Vec::new(),
+        };
+        let mut gens: Vec<(&u64, &path::PathBuf)> = self.readers.paths.iter().collect();
+        gens.sort_unstable();
+        guard.files = gens
+            .into_iter()
+            .map(|(_, path)| Ok((path.clone(), fs::metadata(path)?.len())))
+            .collect::<Result<_>>()?;
+        Ok(guard)
+    }
+
+    fn check_unpinned(&self) -> Result<()> {
+        match self.pins.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            pins => Err(KvsError::Other(format!(
+                "generations are pinned by {} guards",
+                pins
+            ))),
+        }
+    }
+
+    /// Writes a snapshot of the store to `dest`, a data directory of its
+    /// own, for another process on the host to open with `open_read_only`,
+    /// e.g. to share a dataset between services, while this one keeps
+    /// serving writes.
+    ///
+    /// Sealed generations are hard linked, as nothing writes them again,
+    /// and the current one is copied up to its end. Where links cannot be
+    /// made, e.g. across filesystems, the live records are written
+    /// compacted instead. The snapshot is built next to `dest`, which must
+    /// not exist yet, and renamed to it once complete.
+    pub fn export_snapshot_dir(&mut self, dest: &path::Path) -> Result<()> {
+        if dest.exists() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dest.display()),
+            )));
+        }
+        let Some(name) = dest.file_name() else {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path must name a dir",
+            )));
+        };
+        let partial = dest.with_file_name(format!(".{}.partial", name.to_string_lossy()));
+        // left by an interrupted export
+        if partial.exists() {
+            fs::remove_dir_all(&partial)?;
+        }
+        fs::create_dir(&partial)?;
+        let exported = self
+            .write_snapshot(&partial)
+            .and_then(|()| Ok(fs::rename(&partial, dest)?));
+        if exported.is_err() {
+            let _ = fs::remove_dir_all(&partial);
+        }
+        exported.map_err(|e| e.with_op("export").with_path(dest))
+    }
+
+    fn write_snapshot(&mut self, dir: &path::Path) -> Result<()> {
+        // a store opened read-only stops at what it has loaded
+        let lengths = match &self.seen {
+            Some(seen) => seen.clone(),
+            None => Self::gen_lengths(&self.readers.paths)?,
+        };
+        let newest = lengths.last().map(|&(gen, _)| gen);
+        for &(gen, len) in &lengths {
+            let from = &self.readers.paths[&gen];
+            let to = Self::log_file_path(dir, gen);
+            if Some(gen) != newest {
+                if let Err(e) = fs::hard_link(from, &to) {
+                    log::info!(
+                        "Cannot link {} ({}), exporting a compacted snapshot",
+                        from.display(),
+                        e
+                    );
+                    return self.write_compacted_snapshot(dir);
+                }
+                continue;
+            }
+            let mut file = File::create(&to)?;
+            io::copy(&mut File::open(from)?.take(len), &mut file)?;
+            file.sync_all()?;
+        }
+        Self::write_snapshot_files(&self.dirs[0], dir)
+    }
+
+    fn write_compacted_snapshot(&mut self, dir: &path::Path) -> Result<()> {
+        for gen in Self::get_sorted_gen_list(dir)? {
+            fs::remove_file(Self::log_file_path(dir, gen))?;
+        }
+        let path = Self::log_file_path(dir, 1);
+        let mut writer = BufWriterWithPos::new(File::create(&path)?)?;
+        writer.write_all(self.readers.header(self.config.encoding))?;
+        self.write_live(&mut writer, 1, false)?;
+        writer.writer.get_ref().sync_all()?;
+        Self::write_snapshot_files(&self.dirs[0], dir)
+    }
+
+    // the format version, key order, encryption key check and engine marker
+    // of `src`, for the snapshot to be opened like it
+    fn write_snapshot_files(src: &path::Path, dir: &path::Path) -> Result<()> {
+        migrate::write_version(dir, migrate::read_version(src)?)?;
+        for file in [ORDER_FILE, at_rest::ENCRYPTION_FILE] {
+            if src.join(file).exists() {
+                fs::copy(src.join(file), dir.join(file))?;
+            }
+        }
+        EngineKind::Kvs.write_marker(dir)
+    }
+
+    /// Number of keys evicted in cache mode since the store was opened.
+    pub fn evictions(&self) -> u64 {
+        self.cache.as_ref().map_or(0, |cache| cache.evictions)
+    }
+
+    /// Streams the string keys in `range`, in order, along with their
+    /// values, into `sender`. A dedicated thread reads and decodes the
+    /// records, so the consumer processes a pair while the next ones are
+    /// read; a bounded channel keeps it at most that many pairs ahead.
+    ///
+    /// The scan sees the keys as of the call: the files it reads are opened
+    /// before returning, so later writes and compactions do not affect it.
+    /// The thread stops at the first error, sent along, or once the
+    /// receiver is dropped.
+    #[cfg(feature = "prefetch")]
+    pub fn scan_into<R: RangeBounds<String> + Clone>(
+        &mut self,
+        range: R,
+        sender: crossbeam_channel::Sender<Result<(String, String)>>,
+    ) -> Result<std::thread::JoinHandle<()>> {
+        let order = self.order;
+        let indexed: Vec<(String, IndexPos)> = self
+            .index_in(&range)
+            .filter(|(key, _)| !self.is_expired(key))
+            .map(|(key, &index_pos)| (key.clone(), index_pos))
+            .collect();
+        // keys indexed by a digest are read back whole, then kept if in range
+        let mut entries = Vec::with_capacity(indexed.len());
+        for (key, index_pos) in indexed {
+            let key = self.full_key(key)?;
+            if order.contains(&range, &key) {
+                entries.push((key, index_pos));
+            }
+        }
+        entries.sort_unstable_by(|(a, _), (b, _)| order.cmp(a, b));
+        // every file is kept open until the scan ends, whatever the cap
+        let mut files = Readers::new(HashMap::new());
+        files.encodings = self.readers.encodings.clone();
+        files.sealed = self.readers.sealed.clone();
+        files.cipher = self.readers.cipher.clone();
+        files.set_max_open(usize::MAX);
+        for (_, index_pos) in &entries {
+            if !files.open.contains_key(&index_pos.gen) {
+                let file = GenFile::open(&self.readers.paths[&index_pos.gen])?;
+                files.insert(index_pos.gen, BufReaderWithPos::new(file)?);
+            }
+        }
+
+        Ok(std::thread::spawn(move || {
+            for (key, index_pos) in entries {
+                let value = Self::read_log(&mut files, &index_pos).and_then(|log| match log {
+                    KvLog::Set { value, .. } => Ok((key, value)),
+                    log => Err(KvsError::Other(format!(
+                        "expected a string value at {}:{}, found {:?}",
+                        index_pos.gen, index_pos.pos, log
+                    ))),
+                });
+                let failed = value.is_err();
+                if sender.send(value).is_err() || failed {
+                    return;
+                }
+            }
+        }))
+    }
+
+    /// Opens a `KvStore` at a given path.
+    pub fn open(p: &path::Path) -> Result<KvStore> {
+        Self::open_dirs(&[p.to_path_buf()])
+    }
+
+    /// Opens a `KvStore` spread across several data directories, e.g. on
+    /// different disks. Existing generations are read wherever they are,
+    /// new ones are placed round-robin across `dirs`.
+    ///
+    /// The first directory is the main one, holding the engine marker; the
+    /// same directories must be given, in any order, on every open.
+    pub fn open_dirs(dirs: &[path::PathBuf]) -> Result<KvStore> {
+        Self::open_all(dirs, None, true, None, None)
+    }
+
+    /// Opens a `KvStore` over `dirs` like `open_dirs`, listing and scanning
+    /// keys in `order`. A new store records it, an existing one must have
+    /// been created in it; stores created without an order are bytewise.
+    pub fn open_ordered(dirs: &[path::PathBuf], order: KeyOrder) -> Result<KvStore> {
+        Self::open_all(dirs, None, true, Some(order), None)
+    }
+
+    /// Opens a `KvStore` over `dirs` like `open_dirs`, encrypted at rest
+    /// with the key of `config`: every record of the generations it writes
+    /// is sealed before reaching the disk, keys included, and compaction
+    /// writes no hints, which would hold them in the clear. The data
+    /// directory records a check of the key, so a wrong one fails the open
+    /// rather than reading garbage, and an encrypted directory is refused
+    /// without it.
+    ///
+    /// Each record is sealed along with its generation and offset, so one
+    /// moved or replayed elsewhere on disk fails to open as corrupt.
+    ///
+    /// Records written in the clear before stay readable, compaction
+    /// rewriting them encrypted. Turning encryption on also removes the
+    /// backups migrations left and seals the quarantine file, which hold
+    /// records in the clear. An encrypted store writes binary records,
+    /// whatever its `log_encoding`.
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted(dirs: &[path::PathBuf], config: EncryptionConfig) -> Result<KvStore> {
+        Self::open_all(dirs, None, true, None, Some(config.cipher()?))
+    }
+
+    /// The order the keys are listed and scanned in.
+    pub fn key_order(&self) -> KeyOrder {
+        self.order
+    }
+
+    /// Opens a `KvStore` over `dirs` and the `cold` directory, if any, for
+    /// reading only, e.g. while another process writes it. Nothing is
+    /// written, not even the format version: writes fail with
+    /// `KvsError::ReadOnly`, and `refresh` picks up the writes of the other
+    /// process. A record still being written is left for the next refresh.
+    pub fn open_read_only(dirs: &[path::PathBuf], cold: Option<&path::Path>) -> Result<KvStore> {
+        let mut store = Self::open_all(dirs, cold, false, None, None)?;
+        // kept to be read again, never tiered
+        store.cold = cold.map(|cold| (cold.to_path_buf(), u64::MAX));
+        Ok(store)
+    }
+
+    /// Opens a `KvStore` over `dirs` like `open_dirs`, with a cold tier:
+    /// sealed generations move to the `cold` directory, e.g. on a slower
+    /// and cheaper disk, once `after` newer generations exist. Reads follow
+    /// them there.
+    ///
+    /// Every compaction starts two generations, and drops all those before
+    /// it but the compacted one; `after = 1` moves the compacted generation
+    /// out right away.
+    pub fn open_tiered(dirs: &[path::PathBuf], cold: &path::Path, after: u64) -> Result<KvStore> {
+        let mut store = Self::open_all(dirs, Some(cold), true, None, None)?;
+        store.cold = Some((cold.to_path_buf(), after.max(1)));
+        store.tier_aged()?;
+        Ok(store)
+    }
+
+    /// Moves every sealed generation to the cold directory now, whatever
+    /// its age, returning how many moved.
+    pub fn tier(&mut self) -> Result<usize> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        self.check_unpinned()?;
+        let Some((cold, _)) = self.cold.clone() else {
+            return Err(KvsError::InvalidCommand(
+                "no cold directory configured".to_owned(),
+            ));
+        };
+        self.tier_before(&cold, self.current_gen)
+    }
+
+    // move the generations older than the configured age
+    fn tier_aged(&mut self) -> Result<usize> {
+        match self.cold.clone() {
+            Some((cold, after)) => {
+                self.tier_before(&cold, self.current_gen.saturating_sub(after - 1))
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn tier_before(&mut self, cold: &path::Path, end: u64) -> Result<usize> {
+        let mut gens: Vec<u64> = self
+            .readers
+            .paths
+            .iter()
+            .filter(|&(&gen, path)| gen < end && !path.starts_with(cold))
+            .map(|(&gen, _)| gen)
+            .collect();
+        gens.sort_unstable();
+        for &gen in &gens {
+            self.move_gen(gen, cold)?;
+        }
+        Ok(gens.len())
+    }
+
+    // copy then remove, as the cold directory is likely on another disk; a
+    // copy interrupted before its rename is ignored, one interrupted after
+    // is resolved on open
+    fn move_gen(&mut self, gen: u64, dir: &path::Path) -> Result<()> {
+        let from = self.readers.paths[&gen].clone();
+        let to = Self::log_file_path(dir, gen);
+        // hints go first, to be there once the generation is
+        if hint_path(&from).exists() {
+            let tmp = hint_path(&to).with_extension(format!("{}.tmp", HINT_EXTENSION));
+            fs::copy(hint_path(&from), &tmp)?;
+            fs::rename(&tmp, hint_path(&to))?;
+        }
+        let tmp = to.with_extension(format!("{}.tmp", LOG_EXTENSION));
+        fs::copy(&from, &tmp)?;
+        File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &to)?;
+        self.readers.close(gen);
+        self.readers.paths.insert(gen, to);
+        fs::remove_file(&from)?;
+        remove_hint(&from)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "disk.replay", skip_all)
+    )]
+    fn open_all(
+        dirs: &[path::PathBuf],
+        cold: Option<&path::Path>,
+        writable: bool,
+        order: Option<KeyOrder>,
+        cipher: Option<Cipher>,
+    ) -> Result<KvStore> {
+        if dirs.is_empty()
+            || dirs
+                .iter()
+                .map(|dir| dir.as_path())
+                .chain(cold)
+                .any(|dir| !dir.is_dir())
+        {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path must be a dir",
+            )));
+        }
+
+        if writable {
+            migrate::migrate(dirs, cold)?;
+        } else {
+            migrate::check(&dirs[0])?;
+        }
+        // turning encryption on, the plaintext beside the generations is
+        // sealed or removed before the directory is recorded as encrypted
+        let enabling = at_rest::check(&dirs[0], cipher.as_ref(), writable)?;
+        if let Some(cipher) = cipher.as_ref().filter(|_| enabling) {
+            for dir in dirs.iter().map(|dir| dir.as_path()).chain(cold) {
+                let removed = migrate::remove_backups(dir)?;
+                if removed > 0 {
+                    log::warn!(
+                        "Removed {} migration backups of {} held in the clear",
+                        removed,
+                        dir.display()
+                    );
+                }
+            }
+            Self::seal_quarantine(&dirs[0], cipher)?;
+            at_rest::enable(&dirs[0], cipher)?;
+        }
+
+        let mut indexes = Indexes::default();
+        let mut uncompacted: u64 = 0;
+        let mut gen_paths = HashMap::new();
+        for dir in dirs.iter().map(|dir| dir.as_path()).chain(cold) {
+            for gen in Self::get_sorted_gen_list(dir)? {
+                let file_path = Self::log_file_path(dir, gen);
+                if let Some(other) = gen_paths.insert(gen, file_path.clone()) {
+                    // a move to the cold tier cut short after its copy
+                    if Some(dir) == cold
+                        && fs::metadata(&other)?.len() == fs::metadata(&file_path)?.len()
+                    {
+                        remove_hint(&other)?;
+                        fs::remove_file(other)?;
+                        continue;
+                    }
+                    return Err(KvsError::Other(format!(
+                        "generation {} found twice: {} and {}",
+                        gen,
+                        other.display(),
+                        file_path.display()
+                    )));
+                }
+            }
+        }
+        // a compaction cut short left either its generation incomplete or
+        // the ones it replaces behind, not to be replayed along with it
+        if let Some(compaction) = Compaction::read(&dirs[0])? {
+            for gen in compaction.stale(gen_paths.keys().copied()) {
+                let path = gen_paths.remove(&gen).expect("listed generation");
+                if writable {
+                    remove_hint(&path)?;
+                    fs::remove_file(&path)?;
+                }
+            }
+            if writable {
+                Compaction::clear(&dirs[0])?;
+            }
+        }
+        let mut gen_list: Vec<u64> = gen_paths.keys().copied().collect();
+        gen_list.sort_unstable();
+        if gen_list.is_empty() && EngineKind::detect(&dirs[0])? == Some(EngineKind::Sled) {
+            return Err(KvsError::WrongEngine {
+                expected: EngineKind::Kvs,
+                found: EngineKind::Sled,
+            });
+        }
+        let seen = match writable {
+            true => None,
+            false => Some(Self::gen_lengths(&gen_paths)?),
+        };
+        let mut readers = Readers::new(gen_paths);
+        readers.cipher = cipher.map(Arc::new);
+        let mut hinted = 0;
+        let mut dead = Vec::new();
+        // new generations keep the encoding of the latest one
+        let mut encoding = LogEncoding::default();
+        for &gen in &gen_list {
+            let path = &readers.paths[&gen];
+            let replayed = GenFile::open(path)
+                .and_then(BufReaderWithPos::new)
+                .and_then(|mut reader| {
+                    let found = reader.detect_encoding()?;
+                    let (gen_encoding, sealed) = found.unwrap_or_default();
+                    let cipher = match sealed {
+                        true => Some(readers.cipher_of_sealed()?),
+                        false => None,
+                    };
+                    // hints describe a generation replayed from scratch
+                    if gen == gen_list[0] && Self::load_hint(gen, path, &mut indexes) {
+                        hinted += 1;
+                    } else {
+                        uncompacted += Self::replay_log_file(
+                            gen,
+                            gen_encoding,
+                            cipher.as_deref(),
+                            &mut reader,
+                            &mut indexes,
+                            &mut dead,
+                            !writable,
+                        )?;
+                    }
+                    Ok((reader, found))
+                });
+            let (reader, found) =
+                replayed.map_err(|e| e.with_op("open").with_gen(gen).with_path(path))?;
+            if let Some((found, sealed)) = found {
+                encoding = found;
+                readers.set_encoding(gen, found);
+                if sealed {
+                    readers.sealed.insert(gen);
+                }
+            }
+            readers.insert(gen, reader);
+        }
+        let quarantined = dead.len() as u64;
+        if !dead.is_empty() {
+            log::warn!(
+                "Skipped {} malformed records replaying {}",
+                quarantined,
+                dirs[0].display()
+            );
+            if writable {
+                Self::quarantine(&dirs[0], dead, readers.cipher.as_deref())?;
+            }
+        }
+
+        // an order can be picked as long as there are no keys to list
+        let fresh = indexes.index.is_empty()
+            && indexes.lists.is_empty()
+            && indexes.hashes.is_empty()
+            && indexes.sets.is_empty()
+            && indexes.trash.is_empty()
+            && indexes.scheduled.is_empty();
+        let order = KeyOrder::resolve(&dirs[0], order, fresh, writable)?;
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        // encrypted records are binary ones, sealed
+        if readers.cipher.is_some() {
+            encoding = LogEncoding::Binary;
+        }
+
+        let dirs = dirs.to_vec();
+        let writer = match writable {
+            true => Some(Self::create_log_file(
+                &dirs,
+                current_gen,
+                encoding,
+                &mut readers,
+            )?),
+            false => None,
+        };
+
+        let Indexes {
+            index,
+            lists,
+            hashes,
+            sets,
+            expires,
+            trash,
+            scheduled,
+        } = indexes;
+        Ok(KvStore {
+            index,
+            lists,
+            hashes,
+            sets,
+            expires,
+            trash,
+            scheduled,
+            order,
+            config: Config {
+                trash_window: None,
+                expired_reads: ExpiredReads::Hide,
+                cache_budget: None,
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                compress_above: None,
+                #[cfg(feature = "compression")]
+                compress_segments: false,
+                write_times: false,
+                encoding,
+            },
+            expired_hidden: 0,
+            expired_deleted: 0,
+            expired_dropped: 0,
+            cache: None,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            ring: Ring::new()
+                .map_err(|e| log::warn!("io_uring unavailable, reading with syscalls: {}", e))
+                .ok(),
+            readers,
+            writer,
+            seen,
+            dirs,
+            cold: None,
+            current_gen,
+            uncompacted,
+            compactions: 0,
+            hinted,
+            quarantined,
+            pins: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "disk.append", skip_all, fields(gen = self.current_gen))
+    )]
+    fn append_log_file(&mut self, log: &KvLog) -> Result<()> {
+        let record = encode_record(
+            log,
+            self.readers.encoding(self.current_gen),
+            self.readers.cipher_of(self.current_gen)?.as_deref(),
+            self.current_gen,
+            self.tail(),
+            self.config.compress_above,
+        )?;
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        writer
+            .write_all(&record)
+            .and_then(|()| writer.flush())
+            .map_err(|e| KvsError::from(e).with_gen(self.current_gen))
+    }
+
+    // where the next record is appended
+    fn tail(&self) -> u64 {
+        self.writer.as_ref().map_or(0, |writer| writer.pos)
+    }
+
+    // append a record and return where it lives
+    fn append_record(&mut self, log: &KvLog) -> Result<IndexPos> {
+        let old_pos = self.tail();
+        self.append_log_file(log)?;
+        Ok((self.current_gen, old_pos..self.tail()).into())
+    }
+
+    fn read_log(readers: &mut Readers, index_pos: &IndexPos) -> Result<KvLog> {
+        let encoding = readers.encoding(index_pos.gen);
+        let cipher = readers.cipher_of(index_pos.gen)?;
+        let reader = readers
+            .get(index_pos.gen)
+            .map_err(|e| e.with_gen(index_pos.gen))?;
+        if reader.pos != index_pos.pos {
+            reader
+                .seek(SeekFrom::Start(index_pos.pos))
+                .map_err(|e| KvsError::from(e).with_gen(index_pos.gen))?;
+        }
+        // chunks are decoded one at a time
+        let records = std::iter::from_fn(|| {
+            let offset = reader.pos;
+            let mut buf = Vec::new();
+            match reader.read_record(encoding, &mut buf) {
+                Ok(0) => None,
+                Ok(_) => Some(decode_record(
+                    encoding,
+                    &buf,
+                    cipher.as_deref(),
+                    index_pos.gen,
+                    offset,
+                )),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        join_chunks(records)
+            .and_then(decompress)
+            .map_err(|e| e.with_gen(index_pos.gen))
+    }
+
+    // copy the record at `index_pos`, or the chunks and manifest of a value,
+    // record by record to `writer`, the file of generation `gen` written in
+    // `encoding` and sealed if the store is encrypted, returning where it
+    // lives there; records are encoded again when their generation is in
+    // another encoding, or either generation is sealed, records being bound
+    // to where they live, or when they may hold a value to compress
+    fn copy_record(
+        readers: &mut Readers,
+        index_pos: &IndexPos,
+        writer: &mut BufWriterWithPos<File>,
+        gen: u64,
+        encoding: LogEncoding,
+        compress_above: Option<usize>,
+    ) -> Result<IndexPos> {
+        let from = readers.encoding(index_pos.gen);
+        let opener = readers.cipher_of(index_pos.gen)?;
+        let sealer = readers.cipher.clone();
+        let reader = readers.get(index_pos.gen)?;
+        if reader.pos != index_pos.pos {
+            reader.seek(SeekFrom::Start(index_pos.pos))?;
+        }
+        let pos = writer.pos;
+        let mut copied = 0;
+        while copied < index_pos.len {
+            let offset = reader.pos;
+            let mut buf = Vec::new();
+            match reader.read_record(from, &mut buf)? {
+                0 => break,
+                n => copied += n as u64,
+            }
+            // a record is longer than the value it holds
+            if from == encoding
+                && opener.is_none()
+                && sealer.is_none()
+                && compress_above.is_none_or(|above| buf.len() < above)
+            {
+                writer.write_all(&buf)?;
+                continue;
+            }
+            // the span of a manifest follows the chunks written before it
+            let log = match decode_record(from, &buf, opener.as_deref(), index_pos.gen, offset)? {
+                KvLog::Manifest {
+                    key,
+                    value_type,
+                    expires_at,
+                    written_at,
+                    ..
+                } => KvLog::Manifest {
+                    key,
+                    span: writer.pos - pos,
+                    value_type,
+                    expires_at,
+                    written_at,
+                },
+                log => log,
+            };
+            let record = encode_record(
+                &log,
+                encoding,
+                sealer.as_deref(),
+                gen,
+                writer.pos,
+                compress_above,
+            )?;
+            writer.write_all(&record)?;
+        }
+        Ok((gen, pos..writer.pos).into())
+    }
+
+    fn read_value(readers: &mut Readers, index_pos: &IndexPos) -> Result<String> {
+        Self::element_value(Self::read_log(readers, index_pos)?, index_pos)
+    }
+
+    fn element_value(log: KvLog, index_pos: &IndexPos) -> Result<String> {
+        match log {
+            KvLog::LPush { value, .. } | KvLog::RPush { value, .. } | KvLog::HSet { value, .. } => {
+                Ok(value)
+            }
+            log => Err(KvsError::Other(format!(
+                "expected a list element or hash field at {}:{}, found {:?}",
+                index_pos.gen, index_pos.pos, log
+            ))),
+        }
+    }
+
+    // read the records at `positions`, in order, in a single batch when
+    // io_uring is available
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "disk.read", skip_all, fields(records = positions.len()))
+    )]
+    fn read_records(&mut self, positions: &[IndexPos]) -> Result<Vec<KvLog>> {
+        // chunked values are longer than a chunk, and streamed instead;
+        // files closed over the cap of open ones are read with syscalls
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = self.ring.as_mut().filter(|_| {
+            positions.iter().all(|pos| {
+                pos.len <= self.config.chunk_size && self.readers.peek(pos.gen).is_some()
+            })
+        }) {
+            let reads: Vec<_> = positions
+                .iter()
+                .map(|index_pos| {
+                    let file = self.readers.peek(index_pos.gen).expect("reader not open");
+                    (file, index_pos.pos, index_pos.len as usize)
+                })
+                .collect();
+            return ring
+                .read_all(&reads)?
+                .iter()
+                .zip(positions)
+                .map(|(buf, index_pos)| {
+                    let encoding = self.readers.encoding(index_pos.gen);
+                    let cipher = self.readers.cipher_of(index_pos.gen)?;
+                    let mut rest = &buf[..];
+                    let mut offset = index_pos.pos;
+                    join_chunks(std::iter::from_fn(|| {
+                        let len = match encoding {
+                            LogEncoding::Json => rest
+                                .iter()
+                                .position(|&b| b == b'\n')
+                                .map_or(rest.len(), |end| end + 1),
+                            LogEncoding::Binary => {
+                                next_frame(rest).map_or(rest.len(), |(_, len)| len)
+                            }
+                        };
+                        let (record, tail) = rest.split_at(len);
+                        rest = tail;
+                        let at = offset;
+                        offset += len as u64;
+                        (!record.is_empty()).then(|| {
+                            decode_record(encoding, record, cipher.as_deref(), index_pos.gen, at)
+                        })
+                    }))
+                    .and_then(decompress)
+                })
+                .collect();
+        }
+        positions
+            .iter()
+            .map(|index_pos| Self::read_log(&mut self.readers, index_pos))
+            .collect()
+    }
+
+    fn read_values(&mut self, positions: &[IndexPos]) -> Result<Vec<String>> {
+        let logs = self.read_records(positions)?;
+        logs.into_iter()
+            .zip(positions)
+            .map(|(log, index_pos)| Self::element_value(log, index_pos))
+            .collect()
+    }
+
+    fn log_file_path(p: &path::Path, gen: u64) -> path::PathBuf {
+        p.join(log_file_name(gen))
+    }
+
+    fn get_sorted_gen_list(dir_path: &path::Path) -> Result<Vec<u64>> {
+        let mut gen_list: Vec<u64> = std::fs::read_dir(dir_path)?
+            .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+            .filter(|path| path.is_file())
+            .flat_map(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(parse_log_file_name)
+            })
+            .collect();
+        gen_list.sort_unstable();
+        Ok(gen_list)
+    }
+
+    // every generation file and its length, sorted
+    fn gen_lengths(gen_paths: &HashMap<u64, path::PathBuf>) -> Result<Vec<(u64, u64)>> {
+        let mut lengths = gen_paths
+            .iter()
+            .map(|(&gen, path)| Ok((gen, fs::metadata(path)?.len())))
+            .collect::<Result<Vec<_>>>()?;
+        lengths.sort_unstable();
+        Ok(lengths)
+    }
+
+    // create the file of generation `gen`, starting it with the header of
+    // `encoding`, or of encrypted records in an encrypted store
+    fn create_log_file(
+        dirs: &[path::PathBuf],
+        gen: u64,
+        encoding: LogEncoding,
+        readers: &mut Readers,
+    ) -> Result<BufWriterWithPos<File>> {
+        let dir = &dirs[(gen % dirs.len() as u64) as usize];
+        let file_path = readers
+            .paths
+            .entry(gen)
+            .or_insert_with(|| Self::log_file_path(dir, gen))
+            .clone();
+        let context = |e: KvsError| e.with_gen(gen).with_path(&file_path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&file_path)
+            .map_err(|e| context(e.into()))?;
+        let mut writer = BufWriterWithPos::new(file).map_err(context)?;
+        if writer.pos == 0 {
+            writer
+                .write_all(readers.header(encoding))
+                .and_then(|()| writer.flush())
+                .map_err(|e| context(e.into()))?;
+        }
+        let reader = GenFile::open(&file_path)
+            .and_then(BufReaderWithPos::new)
+            .map_err(context)?;
+        readers.set_encoding(gen, encoding);
+        if readers.cipher.is_some() {
+            readers.sealed.insert(gen);
+        }
+        readers.insert(gen, reader);
+        Ok(writer)
+    }
+
+    // a store opened read-only may see the last record half written by the
+    // process writing it, `torn_tail` skips it; other malformed records
+    // are skipped one by one and added to `dead`
+    fn replay_log_file(
+        gen: u64,
+        encoding: LogEncoding,
+        cipher: Option<&Cipher>,
+        reader: &mut BufReaderWithPos<GenFile>,
+        indexes: &mut Indexes,
+        dead: &mut Vec<DeadLetter>,
+        torn_tail: bool,
+    ) -> Result<u64> {
+        let Indexes {
+            index,
+            lists,
+            hashes,
+            sets,
+            expires,
+            trash,
+            scheduled,
+        } = indexes;
+        let mut uncompacted = 0;
+
+        // start right after the header
+        let mut pos = reader.seek(SeekFrom::Start(encoding.header().len() as u64))?;
+
+        // read and decode record by record
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let next = pos + reader.read_record(encoding, &mut line)? as u64;
+            let record = match encoding {
+                LogEncoding::Json => line.trim_ascii_end(),
+                LogEncoding::Binary => &line,
+            };
+            if record.is_empty() {
+                match next == pos {
+                    true => break,
+                    false => {
+                        pos = next;
+                        continue;
+                    }
+                }
+            }
+            let cur_pos = pos + record.len() as u64;
+            let mut log = match decode_record(encoding, &line, cipher, gen, pos) {
+                Ok(log) => log,
+                Err(_) if torn_tail && !encoding.is_whole(&line) => break,
+                // a torn tail is left to fsck, which repairs it
+                Err(e) if !encoding.is_whole(&line) => return Err(e),
+                Err(e) => {
+                    dead.push(DeadLetter {
+                        gen,
+                        offset: pos,
+                        reason: e.to_string(),
+                        record: String::from_utf8_lossy(record).into_owned(),
+                        sealed: false,
+                    });
+                    uncompacted += next - pos;
+                    pos = next;
+                    continue;
+                }
+            };
+            let key = log.key_mut();
+            *key = record_index_key(std::mem::take(key));
+            match log {
+                // garbage until its manifest shows up, which a write cut
+                // short never writes
+                KvLog::Chunk { .. } => uncompacted += next - pos,
+                KvLog::Manifest {
+                    key,
+                    span,
+                    expires_at,
+                    ..
+                } => {
+                    uncompacted = uncompacted.saturating_sub(span);
+                    match expires_at {
+                        Some(at) => expires.insert(key.clone(), at),
+                        None => expires.remove(&key),
+                    };
+                    if let Some((old_index, _)) = trash.remove(&key) {
+                        uncompacted += old_index.len;
+                    }
+                    let start = pos.saturating_sub(span);
+                    if let Some(old_index) = index.insert(key, (gen, start..cur_pos).into()) {
+                        uncompacted += old_index.len;
+                    }
+                }
+                KvLog::Set {
+                    key, expires_at, ..
+                }
+                | KvLog::CompressedSet {
+                    key, expires_at, ..
+                } => {
+                    match expires_at {
+                        Some(at) => expires.insert(key.clone(), at),
+                        None => expires.remove(&key),
+                    };
+                    if let Some((old_index, _)) = trash.remove(&key) {
+                        uncompacted += old_index.len;
+                    }
+                    // if key exists, 'insert' will return the old value.
+                    if let Some(old_index) = index.insert(key, (gen, pos..cur_pos).into()) {
+                        uncompacted += old_index.len;
+                    }
+                }
+                KvLog::Remove { key } | KvLog::Evict { key } => {
+                    expires.remove(&key);
+                    if let Some(old_index) = index.remove(&key) {
+                        uncompacted += old_index.len;
+                    }
+                    // NOTE: the remove log itself can be compacted.
+                    uncompacted += cur_pos - pos;
+                }
+                KvLog::Touch { key, expires_at } => {
+                    if index.contains_key(&key) {
+                        match expires_at {
+                            Some(at) => expires.insert(key, at),
+                            None => expires.remove(&key),
+                        };
+                    }
+                    // superseded by the next compaction
+                    uncompacted += cur_pos - pos;
+                }
+                KvLog::Trash { key, until } => {
+                    expires.remove(&key);
+                    // the value stays needed while it can be undeleted
+                    if let Some(old_index) = index.remove(&key) {
+                        trash.insert(key, (old_index, until));
+                    }
+                    uncompacted += cur_pos - pos;
+                }
+                KvLog::LPush { key, .. } => {
+                    lists
+                        .entry(key)
+                        .or_default()
+                        .push_front((gen, pos..cur_pos).into());
+                }
+                KvLog::RPush { key, .. } => {
+                    lists
+                        .entry(key)
+                        .or_default()
+                        .push_back((gen, pos..cur_pos).into());
+                }
+                KvLog::LPop { key } => {
+                    if let Some(list) = lists.get_mut(&key) {
+                        if let Some(head) = list.pop_front() {
+                            uncompacted += head.len;
+                        }
+                        if list.is_empty() {
+                            lists.remove(&key);
+                        }
+                    }
+                    uncompacted += cur_pos - pos;
+                }
+                KvLog::HSet { key, field, .. } => {
+                    let hash = hashes.entry(key).or_default();
+                    if let Some(old_index) = hash.insert(field, (gen, pos..cur_pos).into()) {
+                        uncompacted += old_index.len;
+                    }
+                }
+                KvLog::HDel { key, field } => {
+                    if let Some(hash) = hashes.get_mut(&key) {
+                        if let Some(old_index) = hash.remove(&field) {
+                            uncompacted += old_index.len;
+                        }
+                        if hash.is_empty() {
+                            hashes.remove(&key);
+                        }
+                    }
+                    uncompacted += cur_pos - pos;
+                }
+                KvLog::SAdd { key, member } => {
+                    let set = sets.entry(key).or_default();
+                    if let Some(old_index) = set.insert(member, (gen, pos..cur_pos).into()) {
+                        uncompacted += old_index.len;
+                    }
+                }
+                KvLog::SRem { key, member } => {
+                    if let Some(set) = sets.get_mut(&key) {
+                        if let Some(old_index) = set.remove(&member) {
+                            uncompacted += old_index.len;
+                        }
+                        if set.is_empty() {
+                            sets.remove(&key);
+                        }
+                    }
+                    uncompacted += cur_pos - pos;
+                }
+                KvLog::Schedule { key, at, .. } => {
+                    let pos = (gen, pos..cur_pos).into();
+                    if let Some(old_index) = scheduled.insert((at, key), pos) {
+                        uncompacted += old_index.len;
+                    }
+                }
+                KvLog::Unschedule { key, at } => {
+                    if let Some(old_index) = scheduled.remove(&(at, key)) {
+                        uncompacted += old_index.len;
+                    }
+                    uncompacted += cur_pos - pos;
+                }
+            }
+            pos = next;
+        }
+
+        Ok(uncompacted)
+    }
+
+    // seal the letters of the quarantine file of `dir` written in the
+    // clear with `cipher`, as encryption is turned on
+    fn seal_quarantine(dir: &path::Path, cipher: &Cipher) -> Result<()> {
+        let path = dir.join(QUARANTINE_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        let file = BufReader::new(File::open(&path)?);
+        for letter in Deserializer::from_reader(file).into_iter::<DeadLetter>() {
+            let mut line = serde_json::to_vec(&letter?.seal(cipher)?)?;
+            line.push(b'\n');
+            out.write_all(&line)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(fs::rename(&tmp, &path)?)
+    }
+
+    // append the records of `dead` to the quarantine file of `dir`, but
+    // those it already holds from an earlier open, sealed with `cipher` in
+    // an encrypted store
+    fn quarantine(dir: &path::Path, dead: Vec<DeadLetter>, cipher: Option<&Cipher>) -> Result<()> {
+        let path = dir.join(QUARANTINE_FILE);
+        let mut known = BTreeSet::new();
+        if path.exists() {
+            let file = BufReader::new(File::open(&path)?);
+            for letter in Deserializer::from_reader(file).into_iter::<DeadLetter>() {
+                let letter = letter?;
+                known.insert((letter.gen, letter.offset));
+            }
+        }
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        for letter in dead {
+            if known.contains(&(letter.gen, letter.offset)) {
+                continue;
+            }
+            let letter = match cipher {
+                Some(cipher) => letter.seal(cipher)?,
+                None => letter,
+            };
+            let mut line = serde_json::to_vec(&letter)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // load the indexes from the hints of generation `gen`, whose file is
+    // at `path`, if it has usable ones; `indexes` are left empty otherwise
+    fn load_hint(gen: u64, path: &path::Path, indexes: &mut Indexes) -> bool {
+        match Self::read_hint(gen, path, indexes) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                log::warn!(
+                    "Replaying generation {}, its hints are unusable: {}",
+                    gen,
+                    e
+                );
+                *indexes = Indexes::default();
+                false
+            }
+        }
+    }
 
-    path: path::PathBuf,
-    current_gen: u64,
-    uncompacted: u64,
-}
+    fn read_hint(gen: u64, path: &path::Path, indexes: &mut Indexes) -> Result<bool> {
+        let file = match File::open(hint_path(path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let len = GenFile::open(path)?.len()?;
+        let mut hints = Deserializer::from_reader(BufReader::new(file)).into_iter::<Hint>();
+        match hints.next().transpose()? {
+            Some(Hint::Header {
+                gen: hinted,
+                len: hinted_len,
+            }) if hinted == gen && hinted_len == len => {}
+            header => {
+                return Err(KvsError::Other(format!(
+                    "{:?} does not describe the {} bytes of the generation",
+                    header, len
+                )))
+            }
+        }
+        let Indexes {
+            index,
+            lists,
+            hashes,
+            sets,
+            expires,
+            trash,
+            scheduled,
+        } = indexes;
+        let check = |pos: IndexPos| match pos.gen == gen && pos.pos + pos.len <= len {
+            true => Ok(pos),
+            false => Err(KvsError::Other(format!(
+                "record out of the generation at {}:{}",
+                pos.gen, pos.pos
+            ))),
+        };
+        for hint in hints {
+            match hint? {
+                Hint::Header { .. } => {
+                    return Err(KvsError::Other("header repeated".to_owned()));
+                }
+                Hint::Set { key, pos } => {
+                    index.insert(key, check(pos)?);
+                }
+                // as for `Touch` records, only for keys holding a value
+                Hint::Expire { key, expires_at } => {
+                    if index.contains_key(&key) {
+                        expires.insert(key, expires_at);
+                    }
+                }
+                Hint::Trash { key, pos, until } => {
+                    trash.insert(key, (check(pos)?, until));
+                }
+                Hint::Push { key, pos } => {
+                    lists.entry(key).or_default().push_back(check(pos)?);
+                }
+                Hint::HSet { key, field, pos } => {
+                    hashes.entry(key).or_default().insert(field, check(pos)?);
+                }
+                Hint::SAdd { key, member, pos } => {
+                    sets.entry(key).or_default().insert(member, check(pos)?);
+                }
+                Hint::Schedule { key, at, pos } => {
+                    scheduled.insert((at, key), check(pos)?);
+                }
+            }
+        }
+        Ok(true)
+    }
 
-impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
-    /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let log = KvLog::Set {
-            key: key.clone(),
-            value,
+    // write the hints of generation `gen`, `len` bytes long, from the
+    // indexes, which must all point into it, as after compaction
+    fn write_hint(&self, gen: u64, len: u64) -> Result<()> {
+        let path = hint_path(&self.readers.paths[&gen]);
+        let tmp = path.with_extension(format!("{}.tmp", HINT_EXTENSION));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut write = |hint: Hint| -> Result<()> { Ok(writer.write_all(&hint.encode()?)?) };
+        write(Hint::Header { gen, len })?;
+        for (key, &pos) in &self.index {
+            write(Hint::Set {
+                key: key.clone(),
+                pos,
+            })?;
+        }
+        for (key, &expires_at) in &self.expires {
+            write(Hint::Expire {
+                key: key.clone(),
+                expires_at,
+            })?;
+        }
+        for (key, &(pos, until)) in &self.trash {
+            write(Hint::Trash {
+                key: key.clone(),
+                pos,
+                until,
+            })?;
+        }
+        for (key, list) in &self.lists {
+            for &pos in list {
+                write(Hint::Push {
+                    key: key.clone(),
+                    pos,
+                })?;
+            }
+        }
+        for (key, hash) in &self.hashes {
+            for (field, &pos) in hash {
+                write(Hint::HSet {
+                    key: key.clone(),
+                    field: field.clone(),
+                    pos,
+                })?;
+            }
+        }
+        for (key, set) in &self.sets {
+            for (member, &pos) in set {
+                write(Hint::SAdd {
+                    key: key.clone(),
+                    member: member.clone(),
+                    pos,
+                })?;
+            }
+        }
+        for ((at, key), &pos) in &self.scheduled {
+            write(Hint::Schedule {
+                key: key.clone(),
+                at: *at,
+                pos,
+            })?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "disk.compact", skip_all)
+    )]
+    fn compact_log(&mut self) -> Result<()> {
+        // postponed, the stale bytes keep counting until unpinned
+        if self.pins.load(Ordering::SeqCst) > 0 {
+            return Ok(());
+        }
+        // for example, if current_gen is 1, then compact_gen is 2 and new_gen is 3
+        // after compaction, new commands will be written to gen 3
+        // which means gen-2 is compacted and gen-3 is not.
+        let compact_gen = self.current_gen + 1;
+        Compaction::Writing(compact_gen).record(&self.dirs[0])?;
+        self.current_gen += 2;
+        self.writer = Some(Self::create_log_file(
+            &self.dirs,
+            self.current_gen,
+            self.config.encoding,
+            &mut self.readers,
+        )?);
+
+        // expired keys are dropped rather than copied
+        let now = now_ms();
+        let expires = &mut self.expires;
+        self.index
+            .retain(|key, _| expires.get(key).is_none_or(|&at| at > now));
+        expires.retain(|_, &mut at| at > now);
+        self.trash.retain(|_, &mut (_, until)| until > now);
+        if let Some(cache) = &mut self.cache {
+            cache.retain(&self.index);
+        }
+
+        // copy to compacted log file
+        let mut compact_writer = Self::create_log_file(
+            &self.dirs,
+            compact_gen,
+            self.config.encoding,
+            &mut self.readers,
+        )?;
+        self.write_live(&mut compact_writer, compact_gen, true)?;
+        let len = compact_writer.pos;
+        compact_writer.writer.get_ref().sync_all()?;
+        drop(compact_writer);
+        #[cfg(feature = "compression")]
+        if self.config.compress_segments {
+            // reopened as a segment when read next
+            self.readers.close(compact_gen);
+            segment::compress_file(&self.readers.paths[&compact_gen])
+                .map_err(|e| e.with_gen(compact_gen))?;
+        }
+        // the next open replays the generation without them; an encrypted
+        // store writes none, which would hold its keys in the clear
+        let hinted = match self.readers.cipher {
+            Some(_) => Ok(()),
+            None => self.write_hint(compact_gen, len),
         };
+        if let Err(e) = hinted {
+            log::warn!(
+                "Cannot write the hints of generation {}: {}",
+                compact_gen,
+                e
+            );
+        }
 
-        let old_pos = self.writer.pos;
-        self.append_log_file(&log)?;
-        let cur_pos = self.writer.pos;
+        // committed: from now on the old generations are dropped on open
+        // rather than replayed along with the compacted one
+        Compaction::Done(compact_gen).record(&self.dirs[0])?;
+        let stale = Compaction::Done(compact_gen).stale(self.readers.paths.keys().copied());
+        for gen in stale {
+            if let Some(path) = self.readers.remove(gen) {
+                remove_hint(&path)?;
+                fs::remove_file(&path)
+                    .map_err(|e| KvsError::from(e).with_gen(gen).with_path(path))?
+            }
+        }
+        Compaction::clear(&self.dirs[0])?;
+        self.tier_aged()?;
+
+        self.uncompacted = 0;
+        self.compactions += 1;
+        Ok(())
+    }
 
-        if let Some(old) = self
+    // write the live records to `writer`, the file of generation `gen`
+    // started in the encoding of the store; when `relocate`, the indexes
+    // are moved over to it, as by compaction
+    fn write_live(
+        &mut self,
+        writer: &mut BufWriterWithPos<File>,
+        gen: u64,
+        relocate: bool,
+    ) -> Result<()> {
+        let encoding = self.config.encoding;
+        let cipher = self.readers.cipher.clone();
+        let cipher = cipher.as_deref();
+        let hash_fields = self.hashes.values_mut().flat_map(HashMap::values_mut);
+        let set_members = self.sets.values_mut().flat_map(HashMap::values_mut);
+        for index_pos in self
             .index
-            .insert(key, (self.current_gen, old_pos..cur_pos).into())
+            .values_mut()
+            .chain(hash_fields)
+            .chain(set_members)
+            .chain(self.scheduled.values_mut())
         {
-            self.uncompacted += old.len;
+            let copied = Self::copy_record(
+                &mut self.readers,
+                index_pos,
+                writer,
+                gen,
+                encoding,
+                self.config.compress_above,
+            )?;
+            if relocate {
+                *index_pos = copied;
+            }
         }
-
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        // expiration times may have been touched since their value was set
+        for (key, &at) in &self.expires {
+            let log = KvLog::Touch {
+                key: key.clone(),
+                expires_at: Some(at),
+            };
+            writer.write_all(&encode_record(
+                &log, encoding, cipher, gen, writer.pos, None,
+            )?)?;
+        }
+        // trashed values are followed by their trash record, to stay trashed
+        for (key, (index_pos, until)) in self.trash.iter_mut() {
+            let copied = Self::copy_record(
+                &mut self.readers,
+                index_pos,
+                writer,
+                gen,
+                encoding,
+                self.config.compress_above,
+            )?;
+            if relocate {
+                *index_pos = copied;
+            }
+            let log = KvLog::Trash {
+                key: key.clone(),
+                until: *until,
+            };
+            writer.write_all(&encode_record(
+                &log, encoding, cipher, gen, writer.pos, None,
+            )?)?;
+        }
+        // lists are rewritten as tail pushes, so replaying them keeps the order
+        for list in self.lists.values_mut() {
+            for index_pos in list.iter_mut() {
+                // the pushes hold the full key, which may be indexed by digest
+                let log = match Self::read_log(&mut self.readers, index_pos)? {
+                    KvLog::LPush { key, value } | KvLog::RPush { key, value } => {
+                        KvLog::RPush { key, value }
+                    }
+                    log => {
+                        return Err(KvsError::Other(format!(
+                            "expected a list element at {}:{}, found {:?}",
+                            index_pos.gen, index_pos.pos, log
+                        )))
+                    }
+                };
+                let pos = writer.pos;
+                writer.write_all(&encode_record(
+                    &log, encoding, cipher, gen, writer.pos, None,
+                )?)?;
+                if relocate {
+                    *index_pos = (gen, pos..writer.pos).into();
+                }
+            }
         }
+        writer.flush()?;
         Ok(())
     }
+}
 
-    /// Gets the string value of a given string key.
-    /// If the key does not exist, returns `None`.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if !self.index.contains_key(&key) {
-            return Ok(None);
+/// Generation files of a `KvStore` kept from compaction, see
+/// `KvStore::pin_generations`. Dropping the guard releases them.
+pub struct PinGuard {
+    pins: Arc<AtomicUsize>,
+    files: Vec<(path::PathBuf, u64)>,
+}
+
+impl PinGuard {
+    /// Every generation file as of the pin, oldest first, with its length
+    /// then.
+    pub fn files(&self) -> &[(path::PathBuf, u64)] {
+        &self.files
+    }
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        self.pins.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// the byte budget of the cache mode, and the string keys from the least to
+// the most recently used
+struct Cache {
+    budget: u64,
+    used: u64,
+    evictions: u64,
+    ticks: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+    next: u64,
+}
+
+impl Cache {
+    fn new(budget: u64, index: &BTreeMap<String, IndexPos>) -> Cache {
+        let mut cache = Cache {
+            budget,
+            used: 0,
+            evictions: 0,
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+            next: 0,
+        };
+        let mut keys: Vec<(&String, &IndexPos)> = index.iter().collect();
+        keys.sort_by_key(|(_, pos)| (pos.gen, pos.pos));
+        for (key, pos) in keys {
+            cache.insert(key, pos.len);
         }
-        let index_pos = self.index.get(&key).unwrap();
-        let reader = self.reader.get_mut(&index_pos.gen).unwrap();
-        if let Err(e) = reader.seek(SeekFrom::Start(index_pos.pos)) {
-            return Err(KvsError::Io(e));
+        cache
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.get_mut(key) {
+            self.order.remove(tick);
+            *tick = self.next;
+            self.order.insert(self.next, key.to_owned());
+            self.next += 1;
         }
-        let mut buf = String::new();
-        reader.read_line(&mut buf)?;
-        let log = KvLog::deserialize(&buf)?;
-        match log {
-            KvLog::Set { value, .. } => Ok(Some(value)),
-            KvLog::Remove { .. } => Ok(None),
+    }
+
+    fn insert(&mut self, key: &str, len: u64) {
+        self.used += len;
+        self.ticks.insert(key.to_owned(), self.next);
+        self.order.insert(self.next, key.to_owned());
+        self.next += 1;
+    }
+
+    fn forget(&mut self, key: &str, len: u64) {
+        self.used -= len.min(self.used);
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
         }
     }
 
-    /// Removes a given string key from the store.
-    fn remove(&mut self, key: String) -> Result<()> {
-        if !self.index.contains_key(&key) {
-            return Err(KvsError::KeyNotFound);
+    // the key to evict next, if over budget
+    fn victim(&self, keep: &str) -> Option<String> {
+        if self.used <= self.budget {
+            return None;
         }
-        let log = KvLog::Remove { key: key.clone() };
-        self.append_log_file(&log)?;
-        self.index.remove(&key);
-        Ok(())
+        self.order.values().find(|key| *key != keep).cloned()
+    }
+
+    // drop the keys which left the index, e.g. expired during compaction
+    fn retain(&mut self, index: &BTreeMap<String, IndexPos>) {
+        self.ticks.retain(|key, _| index.contains_key(key));
+        self.order.retain(|_, key| index.contains_key(key));
+        self.used = self.ticks.keys().map(|key| index[key].len).sum();
     }
 }
 
-impl KvStore {
-    /// Opens a `KvStore` at a given path.
-    pub fn open(p: &path::Path) -> Result<KvStore> {
-        let file_path = p.to_path_buf();
-        if !p.is_dir() {
-            return Err(KvsError::Io(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "path must be a dir",
-            )));
+// the readers of the generation files, at most `max_open` of them open at
+// once: the least recently used is closed to open another, and reopened
+// when read again
+struct Readers {
+    // path of every generation file
+    paths: HashMap<u64, path::PathBuf>,
+    // encoding of the generation files holding binary records, the others
+    // holding JSON lines
+    encodings: HashMap<u64, LogEncoding>,
+    // the generation files holding encrypted records
+    sealed: HashSet<u64>,
+    // the cipher of an encrypted store, sealing the records of its new
+    // generations
+    cipher: Option<Arc<Cipher>>,
+    // open readers, along with the tick of their last use
+    open: HashMap<u64, (u64, BufReaderWithPos<GenFile>)>,
+    max_open: usize,
+    next: u64,
+}
+
+impl Readers {
+    fn new(paths: HashMap<u64, path::PathBuf>) -> Readers {
+        Readers {
+            paths,
+            encodings: HashMap::new(),
+            sealed: HashSet::new(),
+            cipher: None,
+            open: HashMap::new(),
+            max_open: DEFAULT_MAX_OPEN_FILES,
+            next: 0,
         }
+    }
 
-        let mut index: HashMap<String, IndexPos> = HashMap::new();
-        let mut reader_map: HashMap<u64, BufReaderWithPos<File>> = HashMap::new();
-        let mut uncompacted: u64 = 0;
-        let gen_list = Self::get_sorted_gen_list(p)?;
-        for &gen in &gen_list {
-            let file_path = Self::log_file_path(p, gen);
-            let mut reader = BufReaderWithPos::new(File::open(&file_path)?)?;
-            uncompacted += Self::replay_log_file(gen, &mut reader, &mut index)?;
-            reader_map.insert(gen, reader);
+    // the reader of `gen`, opened if closed
+    fn get(&mut self, gen: u64) -> Result<&mut BufReaderWithPos<GenFile>> {
+        if !self.open.contains_key(&gen) {
+            let path = self.paths.get(&gen).expect("generation not found");
+            let reader = GenFile::open(path)
+                .and_then(BufReaderWithPos::new)
+                .map_err(|e| e.with_path(path))?;
+            self.insert(gen, reader);
         }
+        let (tick, reader) = self.open.get_mut(&gen).unwrap();
+        *tick = self.next;
+        self.next += 1;
+        Ok(reader)
+    }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+    // the file of `gen` if open and not a compressed segment, left unused
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn peek(&self, gen: u64) -> Option<&File> {
+        self.open
+            .get(&gen)
+            .and_then(|(_, reader)| reader.reader.get_ref().plain())
+    }
+
+    fn insert(&mut self, gen: u64, reader: BufReaderWithPos<GenFile>) {
+        self.open.insert(gen, (self.next, reader));
+        self.next += 1;
+        self.close_over(self.max_open);
+    }
 
-        let writer = Self::create_log_file(&file_path, current_gen, &mut reader_map)?;
+    fn close(&mut self, gen: u64) {
+        self.open.remove(&gen);
+    }
 
-        Ok(KvStore {
-            index,
-            reader: reader_map,
-            writer,
-            path: file_path,
-            current_gen,
-            uncompacted,
+    // forget `gen`, returning its path
+    fn remove(&mut self, gen: u64) -> Option<path::PathBuf> {
+        self.close(gen);
+        self.encodings.remove(&gen);
+        self.sealed.remove(&gen);
+        self.paths.remove(&gen)
+    }
+
+    fn encoding(&self, gen: u64) -> LogEncoding {
+        self.encodings.get(&gen).copied().unwrap_or_default()
+    }
+
+    // the cipher of the records of `gen`, none when in the clear
+    fn cipher_of(&self, gen: u64) -> Result<Option<Arc<Cipher>>> {
+        match self.sealed.contains(&gen) {
+            true => self.cipher_of_sealed().map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn cipher_of_sealed(&self) -> Result<Arc<Cipher>> {
+        self.cipher.clone().ok_or_else(|| {
+            KvsError::Other("the generation is encrypted, to be read with its key".to_owned())
         })
     }
 
-    fn append_log_file(&mut self, log: &KvLog) -> Result<()> {
-        let serialized = log.serialize()?;
-        let log_line = format!("{}\n", serialized);
-        self.writer.write(log_line.as_bytes())?;
-        self.writer.flush()?;
-        Ok(())
+    // what a new generation in `encoding` starts with
+    fn header(&self, encoding: LogEncoding) -> &'static [u8] {
+        match self.cipher {
+            Some(_) => &ENCRYPTED_MAGIC,
+            None => encoding.header(),
+        }
     }
 
-    fn log_file_path(p: &path::Path, gen: u64) -> path::PathBuf {
-        p.join(format!("{}.log", gen))
+    fn set_encoding(&mut self, gen: u64, encoding: LogEncoding) {
+        match encoding {
+            LogEncoding::Json => self.encodings.remove(&gen),
+            encoding => self.encodings.insert(gen, encoding),
+        };
     }
 
-    fn get_sorted_gen_list(dir_path: &path::Path) -> Result<Vec<u64>> {
-        let mut gen_list: Vec<u64> = std::fs::read_dir(dir_path)?
-            .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
-            .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-            .flat_map(|path| {
-                path.file_name()
-                    .and_then(|name| name.to_str())
-                    .map(|name| name.trim_end_matches(".log"))
-                    .map(|name| name.parse::<u64>())
-            })
-            .flatten()
-            .collect();
-        gen_list.sort_unstable();
-        Ok(gen_list)
+    fn set_max_open(&mut self, max_open: usize) {
+        self.max_open = max_open.max(1);
+        self.close_over(self.max_open);
     }
 
-    fn create_log_file(
-        dir_path: &path::PathBuf,
-        gen: u64,
-        reader_map: &mut HashMap<u64, BufReaderWithPos<File>>,
-    ) -> Result<BufWriterWithPos<File>> {
-        let file_path = Self::log_file_path(dir_path, gen);
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&file_path)?;
-        let writer = BufWriterWithPos::new(file)?;
-        reader_map
-            .entry(gen)
-            .or_insert(BufReaderWithPos::new(File::open(&file_path)?)?);
-        Ok(writer)
+    // close the least recently used readers until at most `max` are open
+    fn close_over(&mut self, max: usize) {
+        while self.open.len() > max {
+            let (&gen, _) = self.open.iter().min_by_key(|(_, (tick, _))| *tick).unwrap();
+            self.open.remove(&gen);
+        }
     }
+}
 
-    fn replay_log_file(
-        gen: u64,
-        reader: &mut BufReaderWithPos<File>,
-        index: &mut HashMap<String, IndexPos>,
-    ) -> Result<u64> {
-        let mut uncompacted = 0;
+// writes the text written through it as a JSON string, quotes included
+// once something is written. Writes hold whole chars, as chunks do
+struct JsonStringWriter<'a> {
+    inner: &'a mut dyn Write,
+    started: bool,
+}
 
-        // reset pos to 0
-        let mut pos = reader.seek(SeekFrom::Start(0))?;
+impl JsonStringWriter<'_> {
+    // close the string, opened first if nothing was written
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.inner.write_all(b"\"")?;
+        }
+        self.inner.write_all(b"\"")
+    }
+}
 
-        // start read and deserialize
-        let mut stream = Deserializer::from_reader(reader).into_iter::<KvLog>();
-        while let Some(log) = stream.next() {
-            let cur_pos = stream.byte_offset() as u64;
-            match log? {
-                KvLog::Set { key, .. } => {
-                    // if key exists, 'insert' will return the old value.
-                    if let Some(old_index) = index.insert(key, (gen, pos..cur_pos).into()) {
-                        uncompacted += old_index.len;
-                    }
-                }
-                KvLog::Remove { key } => {
-                    if let Some(old_index) = index.remove(&key) {
-                        uncompacted += old_index.len;
-                    }
-                    // NOTE: the remove log itself can be compacted.
-                    uncompacted += cur_pos - pos;
-                }
-            }
-            // NOTE: we need to add 1 to cur_pos to include the '\n' character
-            pos = cur_pos + 1;
+impl Write for JsonStringWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text =
+            std::str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let escaped = serde_json::to_vec(text)?;
+        // the opening quote only once
+        let start = if self.started { 1 } else { 0 };
+        self.inner.write_all(&escaped[start..escaped.len() - 1])?;
+        self.started = true;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// the value of a `KvLog::Set` record, left encoded
+#[derive(Deserialize)]
+enum RawSet {
+    Set { value: Box<RawValue> },
+}
+
+// a malformed record of a generation, as written to the quarantine file
+#[derive(Serialize, Deserialize)]
+struct DeadLetter {
+    gen: u64,
+    offset: u64,
+    reason: String,
+    record: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    sealed: bool,
+}
+
+impl DeadLetter {
+    // the letter with its reason and record sealed with `cipher`, as the
+    // base64 of their sealed bytes; both may quote the record
+    fn seal(self, cipher: &Cipher) -> Result<DeadLetter> {
+        if self.sealed {
+            return Ok(self);
         }
+        let aad = at_rest::quarantine_aad(self.gen, self.offset);
+        Ok(DeadLetter {
+            reason: BASE64.encode(cipher.seal(self.reason.as_bytes(), &aad)?),
+            record: BASE64.encode(cipher.seal(self.record.as_bytes(), &aad)?),
+            sealed: true,
+            ..self
+        })
+    }
+}
 
-        Ok(uncompacted)
+// the in-memory state of a `KvStore`, as rebuilt by replaying the log
+#[derive(Default)]
+struct Indexes {
+    index: BTreeMap<String, IndexPos>,
+    lists: HashMap<String, VecDeque<IndexPos>>,
+    hashes: HashMap<String, HashMap<String, IndexPos>>,
+    sets: HashMap<String, HashMap<String, IndexPos>>,
+    expires: HashMap<String, u64>,
+    trash: HashMap<String, (IndexPos, u64)>,
+    scheduled: BTreeMap<(u64, String), IndexPos>,
+}
+
+// the key the maps of the store index `key` by: the key itself, or for a
+// key longer than `DIGEST_KEY_LEN` a digest of it starting with a NUL
+// character, which keeps long keys out of memory; the records holding the
+// data of a key keep it whole.
+//
+// Touch, evict and unschedule records and hint files hold the digest, so it
+// must never change: it is SipHash-1-3 with zero keys, fed the bytes std's
+// `DefaultHasher` used to be fed when it computed it
+fn index_key(key: String) -> String {
+    // a key starting with a NUL, refused on writes, is looked up by digest
+    // too, never to find the key whose digest it spells
+    if key.len() <= DIGEST_KEY_LEN && !key.starts_with('\0') {
+        return key;
     }
+    let digest = |seed: u8| {
+        let mut hasher = SipHasher13::new_with_keys(0, 0);
+        hasher.write_u8(seed);
+        hasher.write(key.as_bytes());
+        hasher.write_u8(0xff);
+        hasher.finish()
+    };
+    format!("\0{:016x}{:016x}", digest(0), digest(1))
+}
 
-    fn compact(&mut self) -> Result<()> {
-        // for example, if current_gen is 1, then compact_gen is 2 and new_gen is 3
-        // after compaction, new commands will be written to gen 3
-        // which means gen-2 is compacted and gen-3 is not.
-        let compact_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = Self::create_log_file(&self.path, self.current_gen, &mut self.reader)?;
+// the LSN of offset `pos` of generation `gen`, unless either outgrows its
+// bits, which would make LSNs overlap
+fn lsn_of(gen: u64, pos: u64) -> Option<u64> {
+    match gen >> (u64::BITS - LSN_OFFSET_BITS) == 0 && pos >> LSN_OFFSET_BITS == 0 {
+        true => Some(gen << LSN_OFFSET_BITS | pos),
+        false => None,
+    }
+}
 
-        // copy to compacted log file
-        let mut compact_writer = Self::create_log_file(&self.path, compact_gen, &mut self.reader)?;
-        for index_pos in self.index.values() {
-            let reader = self
-                .reader
-                .get_mut(&index_pos.gen)
-                .expect("reader not found");
-            if reader.pos != index_pos.pos {
-                reader.seek(SeekFrom::Start(index_pos.pos))?;
+// the key the maps of the store index the key of a record by: records
+// holding a digest already hold the index key
+fn record_index_key(key: String) -> String {
+    match key.starts_with('\0') {
+        true => key,
+        false => index_key(key),
+    }
+}
+
+// decode a record of generation `gen`, in `encoding` and sealed with
+// `cipher` if encrypted, read at `offset`, checking its checksum or that
+// it was sealed there
+pub(crate) fn decode_record(
+    encoding: LogEncoding,
+    record: &[u8],
+    cipher: Option<&Cipher>,
+    gen: u64,
+    offset: u64,
+) -> Result<KvLog> {
+    let corruption = |_| KvsError::Corruption { gen, offset };
+    match encoding {
+        LogEncoding::Json => {
+            let record = verify_checksum(record).map_err(corruption)?;
+            Ok(serde_json::from_slice(record)?)
+        }
+        LogEncoding::Binary => {
+            let Some((payload, _)) = next_frame(record) else {
+                return Err(KvsError::Other(format!(
+                    "record cut short at {}:{}",
+                    gen, offset
+                )));
+            };
+            let mut payload = payload.map_err(corruption)?;
+            let opened;
+            if let Some(cipher) = cipher {
+                opened = cipher
+                    .open(payload, &at_rest::record_aad(gen, offset))
+                    .ok_or(KvsError::Corruption { gen, offset })?;
+                payload = &opened;
             }
-            let mut buf = String::new();
-            reader.read_line(&mut buf)?;
-            compact_writer.write(buf.as_bytes())?;
+            let log = bincode::deserialize::<BinaryLog>(payload)
+                .map_err(|e| KvsError::Other(format!("Bad record at {}:{}: {}", gen, offset, e)))?;
+            Ok(log.into())
         }
-        compact_writer.flush()?;
+    }
+}
 
-        // remove old log files and update reader map
-        let should_removed_gens: Vec<u64> = self
-            .reader
-            .keys()
-            .filter(|&&k| k < compact_gen)
-            .cloned()
-            .collect();
-        for gen in should_removed_gens {
-            self.reader.remove(&gen);
-            fs::remove_file(Self::log_file_path(&self.path, gen))?
+// encode `log` as a record in `encoding`, sealed with `cipher` if given,
+// which takes binary records, to be written at `offset` of generation
+// `gen`, and compressed if it is the `Set` of a value at least
+// `compress_above` bytes long
+fn encode_record(
+    log: &KvLog,
+    encoding: LogEncoding,
+    cipher: Option<&Cipher>,
+    gen: u64,
+    offset: u64,
+    compress_above: Option<usize>,
+) -> Result<Vec<u8>> {
+    if let Some(log) = compress(log, compress_above)? {
+        return encode_record(&log, encoding, cipher, gen, offset, None);
+    }
+    match (encoding, cipher) {
+        (LogEncoding::Json, None) => Ok(log.encode()?),
+        (_, cipher) => {
+            let payload = bincode::serialize(&BinaryLog::from(log))
+                .map_err(|e| KvsError::Other(format!("Cannot encode a record: {}", e)))?;
+            match cipher {
+                Some(cipher) => Ok(frame(
+                    &cipher.seal(&payload, &at_rest::record_aad(gen, offset))?,
+                )),
+                None => Ok(frame(&payload)),
+            }
         }
+    }
+}
 
-        self.uncompacted = 0;
-        Ok(())
+// the `CompressedSet` standing for `log`, the `Set` of a value at least
+// `above` bytes long, unless compressing does not shrink the value
+#[cfg(feature = "compression")]
+fn compress(log: &KvLog, above: Option<usize>) -> Result<Option<KvLog>> {
+    let KvLog::Set {
+        key,
+        value,
+        value_type,
+        expires_at,
+        written_at,
+    } = log
+    else {
+        return Ok(None);
+    };
+    if above.is_none_or(|above| value.len() < above) {
+        return Ok(None);
+    }
+    let data = BASE64.encode(zstd::encode_all(value.as_bytes(), COMPRESSION_LEVEL)?);
+    if data.len() >= value.len() {
+        return Ok(None);
     }
+    Ok(Some(KvLog::CompressedSet {
+        key: key.clone(),
+        data,
+        value_type: *value_type,
+        expires_at: *expires_at,
+        written_at: *written_at,
+    }))
 }
 
-#[allow(dead_code)]
-struct IndexPos {
-    gen: u64,
-    pos: u64,
-    len: u64,
+#[cfg(not(feature = "compression"))]
+fn compress(_: &KvLog, _: Option<usize>) -> Result<Option<KvLog>> {
+    Ok(None)
+}
+
+// the `Set` a `CompressedSet` stands for, other records left as they are
+#[cfg(feature = "compression")]
+pub(crate) fn decompress(log: KvLog) -> Result<KvLog> {
+    let KvLog::CompressedSet {
+        key,
+        data,
+        value_type,
+        expires_at,
+        written_at,
+    } = log
+    else {
+        return Ok(log);
+    };
+    let value = String::from_utf8(zstd::decode_all(&decode_bytes(&data)?[..])?).map_err(|e| {
+        KvsError::Other(format!(
+            "compressed value of key {} is not UTF-8: {}",
+            key, e
+        ))
+    })?;
+    Ok(KvLog::Set {
+        key,
+        value,
+        value_type,
+        expires_at,
+        written_at,
+    })
 }
 
-impl From<(u64, Range<u64>)> for IndexPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
-        IndexPos {
-            gen,
-            pos: range.start,
-            len: range.end - range.start,
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress(log: KvLog) -> Result<KvLog> {
+    match log {
+        KvLog::CompressedSet { key, .. } => Err(KvsError::Other(format!(
+            "the value of key {} is compressed, which takes the compression feature",
+            key
+        ))),
+        log => Ok(log),
+    }
+}
+
+// read the record at the position of `reader` into `buf`: a line, or the
+// frame of a binary record, cut short by the end of the file if it is
+fn read_record<R: BufRead>(
+    reader: &mut R,
+    encoding: LogEncoding,
+    buf: &mut Vec<u8>,
+) -> io::Result<usize> {
+    match encoding {
+        LogEncoding::Json => reader.read_until(b'\n', buf),
+        LogEncoding::Binary => {
+            let start = buf.len();
+            reader
+                .by_ref()
+                .take(FRAME_HEADER_LEN as u64)
+                .read_to_end(buf)?;
+            if buf.len() - start == FRAME_HEADER_LEN {
+                let len = u32::from_le_bytes(buf[start..start + 4].try_into().unwrap());
+                reader.by_ref().take(len as u64).read_to_end(buf)?;
+            }
+            Ok(buf.len() - start)
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-enum KvLog {
-    Set { key: String, value: String },
-    Remove { key: String },
+// the hint file of the generation file at `path`
+fn hint_path(path: &path::Path) -> path::PathBuf {
+    path.with_extension(HINT_EXTENSION)
+}
+
+fn remove_hint(path: &path::Path) -> Result<()> {
+    match fs::remove_file(hint_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
 }
 
-impl KvLog {
-    fn serialize(&self) -> Result<String> {
-        Ok(serde_json::to_string(&self)?)
+// a compaction under way, as recorded in the compaction file
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compaction {
+    // the compacted generation is being written, incomplete
+    Writing(u64),
+    // the compacted generation is whole, the ones below it stale
+    Done(u64),
+}
+
+impl Compaction {
+    fn read(dir: &path::Path) -> Result<Option<Compaction>> {
+        let content = match fs::read_to_string(dir.join(COMPACTION_FILE)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let compaction = match content.trim().split_once(' ') {
+            Some(("writing", gen)) => gen.parse().ok().map(Compaction::Writing),
+            Some(("done", gen)) => gen.parse().ok().map(Compaction::Done),
+            _ => None,
+        };
+        compaction.map(Some).ok_or_else(|| {
+            KvsError::Other(format!(
+                "unreadable {} file in {}: {:?}",
+                COMPACTION_FILE,
+                dir.display(),
+                content
+            ))
+        })
+    }
+
+    // replace the compaction file of `dir` at once
+    fn record(self, dir: &path::Path) -> Result<()> {
+        let content = match self {
+            Compaction::Writing(gen) => format!("writing {}\n", gen),
+            Compaction::Done(gen) => format!("done {}\n", gen),
+        };
+        let path = dir.join(COMPACTION_FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        File::open(&tmp)?.sync_all()?;
+        Ok(fs::rename(&tmp, &path)?)
     }
 
-    fn deserialize(s: &str) -> Result<KvLog> {
-        Ok(serde_json::from_str(s)?)
+    fn clear(dir: &path::Path) -> Result<()> {
+        match fs::remove_file(dir.join(COMPACTION_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // the generations of `gens` the compaction leaves stale, ascending
+    fn stale(self, gens: impl Iterator<Item = u64>) -> Vec<u64> {
+        let mut stale: Vec<u64> = match self {
+            Compaction::Writing(compact_gen) => gens.filter(|&gen| gen == compact_gen).collect(),
+            Compaction::Done(compact_gen) => gens.filter(|&gen| gen < compact_gen).collect(),
+        };
+        stale.sort_unstable();
+        stale
     }
 }
 
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
 struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -278,7 +3431,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut inner: R) -> Result<Self> {
-        match inner.seek(SeekFrom::Current(0)) {
+        match inner.stream_position() {
             Ok(pos) => Ok(BufReaderWithPos {
                 reader: BufReader::new(inner),
                 pos,
@@ -287,8 +3440,17 @@ impl<R: Read + Seek> BufReaderWithPos<R> {
         }
     }
 
-    fn read_line(&mut self, buf: &mut String) -> Result<usize> {
-        match self.reader.read_line(buf) {
+    // the encoding of the file, going by its first bytes, and whether its
+    // records are encrypted, none when empty; the reader is left at the
+    // start
+    fn detect_encoding(&mut self) -> Result<Option<(LogEncoding, bool)>> {
+        self.seek(SeekFrom::Start(0))?;
+        let head = self.reader.fill_buf()?;
+        Ok((!head.is_empty()).then(|| (LogEncoding::detect(head), is_encrypted(head))))
+    }
+
+    fn read_record(&mut self, encoding: LogEncoding, buf: &mut Vec<u8>) -> Result<usize> {
+        match read_record(&mut self.reader, encoding, buf) {
             Ok(n) => {
                 self.pos += n as u64;
                 Ok(n)
@@ -329,7 +3491,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        match inner.seek(SeekFrom::Current(0)) {
+        match inner.stream_position() {
             Ok(pos) => Ok(BufWriterWithPos {
                 writer: BufWriter::new(inner),
                 pos,
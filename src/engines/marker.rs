@@ -0,0 +1,115 @@
+//! The `engine` marker file of a data directory, and detection of the
+//! engine from the data itself.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::format::parse_log_file_name;
+use crate::{KvsError, Result};
+
+/// Name of the marker file recording the engine of a data directory.
+pub const ENGINE_FILE: &str = "engine";
+
+// files and directories sled creates in its data directory
+const SLED_FILES: [&str; 3] = ["conf", "db", "blobs"];
+
+/// A storage engine, as recorded in the marker file of a data directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineKind {
+    /// The log-structured `KvStore`
+    Kvs,
+    /// The sled-backed `SledStore`
+    Sled,
+}
+
+impl EngineKind {
+    /// Read the marker file of `dir`. A missing marker is `None`, an
+    /// unknown engine in it is an error.
+    pub fn from_marker(dir: &Path) -> Result<Option<EngineKind>> {
+        match fs::read_to_string(dir.join(ENGINE_FILE)) {
+            Ok(content) => content
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|e| KvsError::Other(format!("Bad engine marker: {}", e))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record `self` as the engine of `dir`.
+    pub fn write_marker(self, dir: &Path) -> Result<()> {
+        fs::write(dir.join(ENGINE_FILE), self.to_string())?;
+        Ok(())
+    }
+
+    /// Tell the engine of `dir` from the files in it, ignoring the marker.
+    /// A directory without data of either engine is `None`, one with data
+    /// of both is an error.
+    pub fn detect(dir: &Path) -> Result<Option<EngineKind>> {
+        let (mut kvs, mut sled) = (false, false);
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            kvs |= parse_log_file_name(name).is_some();
+            sled |= SLED_FILES.contains(&name);
+        }
+        match (kvs, sled) {
+            (true, true) => Err(KvsError::Other(format!(
+                "{} holds data of both the kvs and sled engines",
+                dir.display()
+            ))),
+            (true, false) => Ok(Some(EngineKind::Kvs)),
+            (false, true) => Ok(Some(EngineKind::Sled)),
+            (false, false) => Ok(None),
+        }
+    }
+
+    /// Check that `dir` can be opened with the `self` engine, going by its
+    /// data first and its marker otherwise.
+    ///
+    /// A marker disagreeing with the data is reported as such, to be
+    /// repaired with `kvs-admin fix-engine`.
+    pub fn check(self, dir: &Path) -> Result<()> {
+        let found = EngineKind::detect(dir)?;
+        let marker = EngineKind::from_marker(dir)?;
+        if let (Some(marker), Some(found)) = (marker, found) {
+            if marker != found {
+                return Err(KvsError::EngineMarker { marker, found });
+            }
+        }
+        match found.or(marker) {
+            Some(found) if found != self => Err(KvsError::WrongEngine {
+                expected: self,
+                found,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            _ => Err(format!("Unknown engine: {}", s)),
+        }
+    }
+}
+
+impl Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => write!(f, "kvs"),
+            EngineKind::Sled => write!(f, "sled"),
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! The `format` file of a `KvStore` data directory, recording the version
+//! of its on-disk format, and the migrations bringing older directories up
+//! to date on open.
+//!
+//! Every migration step may rewrite the generation files one by one. The
+//! files are first copied to a `backup-v<from>` directory next to them,
+//! then each file is rewritten from its backup copy, so a step cut short is
+//! run again from scratch on the next open. Files are rewritten record by
+//! record, never held in memory whole. The version is recorded once every
+//! file is migrated; backups are left for the operator to remove.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::engines::kvs::DEFAULT_CHUNK_SIZE;
+use crate::format::{
+    append_checksum, hint_file_name, log_file_name, parse_log_file_name, verify_checksum, KvLog,
+    FORMAT_VERSION,
+};
+use crate::{KvsError, Result, ValueType};
+
+/// Name of the file recording the format version of a data directory.
+pub const FORMAT_FILE: &str = "format";
+
+/// Write the new content of a generation file to `out`, reading its current
+/// one from `path` as it goes.
+type Rewrite = fn(path: &Path, out: &mut dyn Write) -> Result<()>;
+
+/// A step from version `to - 1` to version `to`.
+struct Migration {
+    to: u32,
+    name: &'static str,
+    rewrite: Option<Rewrite>,
+}
+
+// in order, one per version
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 1,
+        // directories written before the format file existed hold the same
+        // records, they only get the version
+        name: "record the format version",
+        rewrite: None,
+    },
+    Migration {
+        to: 2,
+        // version 1 records are still valid, the version only keeps older
+        // releases from misreading chunk records
+        name: "allow chunked values",
+        rewrite: None,
+    },
+    Migration {
+        to: 3,
+        // likewise for scheduled writes
+        name: "allow scheduled writes",
+        rewrite: None,
+    },
+    Migration {
+        to: 4,
+        // and for JSON values written as is, older releases failing to
+        // decode them as strings
+        name: "allow raw JSON values",
+        rewrite: None,
+    },
+    Migration {
+        to: 5,
+        name: "encode bytes values in base64",
+        rewrite: Some(encode_bytes_values),
+    },
+    Migration {
+        to: 6,
+        name: "checksum records",
+        rewrite: Some(add_checksums),
+    },
+    Migration {
+        to: 7,
+        // JSON lines are still valid, the version keeps older releases from
+        // misreading binary generations
+        name: "allow binary generations",
+        rewrite: None,
+    },
+    Migration {
+        to: 8,
+        // records are untouched, the version keeps older releases from
+        // failing on compressed values
+        name: "allow compressed values",
+        rewrite: None,
+    },
+    Migration {
+        to: 9,
+        // and on compressed segments
+        name: "allow compressed segments",
+        rewrite: None,
+    },
+    Migration {
+        to: 10,
+        // and on encrypted generations
+        name: "allow encrypted generations",
+        rewrite: None,
+    },
+];
+
+/// Read the format version of the data directory `dir`. A directory
+/// without the file predates it, version 0.
+pub(crate) fn read_version(dir: &Path) -> Result<u32> {
+    match fs::read_to_string(dir.join(FORMAT_FILE)) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map_err(|e| KvsError::Other(format!("Bad format file: {}", e))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Bring the data spread across `dirs` and the `cold` tier up to the
+/// current format version, recorded in the first directory. Data written by
+/// a newer version is refused rather than misread.
+pub(crate) fn migrate(dirs: &[PathBuf], cold: Option<&Path>) -> Result<()> {
+    let mut version = read_version(&dirs[0])?;
+    if version > FORMAT_VERSION {
+        return Err(KvsError::FormatVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    let found = version;
+    for migration in MIGRATIONS.iter().filter(|m| m.to > found) {
+        info!(
+            "Migrating {} to format version {}: {}",
+            dirs[0].display(),
+            migration.to,
+            migration.name
+        );
+        if let Some(rewrite) = migration.rewrite {
+            for dir in dirs.iter().map(PathBuf::as_path).chain(cold) {
+                rewrite_dir(dir, version, rewrite)?;
+            }
+        }
+        version = migration.to;
+        write_version(&dirs[0], version)?;
+    }
+    // a fresh directory gets the version right away
+    if !dirs[0].join(FORMAT_FILE).exists() {
+        write_version(&dirs[0], FORMAT_VERSION)?;
+    }
+    Ok(())
+}
+
+/// Check that the data directory `dir` can be read without migrating it,
+/// for stores opened read-only. Every older version holds records this one
+/// still reads.
+pub(crate) fn check(dir: &Path) -> Result<()> {
+    let found = read_version(dir)?;
+    if found > FORMAT_VERSION {
+        return Err(KvsError::FormatVersion {
+            found,
+            supported: FORMAT_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Remove the backups migrations left in `dir`, returning how many there
+/// were.
+pub(crate) fn remove_backups(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_backup = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("backup-v"))
+            .is_some_and(|from| from.parse::<u32>().is_ok());
+        if is_backup && entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn rewrite_dir(dir: &Path, from: u32, rewrite: Rewrite) -> Result<()> {
+    let mut gens = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(gen) = name.to_str().and_then(parse_log_file_name) {
+            gens.push(gen);
+        }
+    }
+    // nothing to back up in a fresh directory
+    if gens.is_empty() {
+        return Ok(());
+    }
+    let backup = dir.join(format!("backup-v{}", from));
+    fs::create_dir_all(&backup)?;
+    // back up everything before touching anything; a backup left by an
+    // interrupted run is the original already
+    for &gen in &gens {
+        let saved = backup.join(log_file_name(gen));
+        if !saved.exists() {
+            let tmp = saved.with_extension("tmp");
+            fs::copy(dir.join(log_file_name(gen)), &tmp)?;
+            File::open(&tmp)?.sync_all()?;
+            fs::rename(&tmp, &saved)?;
+        }
+    }
+    for &gen in &gens {
+        // positions move, the hints would point astray
+        match fs::remove_file(dir.join(hint_file_name(gen))) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let path = dir.join(log_file_name(gen));
+        let tmp = path.with_extension("migrating");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        rewrite(&backup.join(log_file_name(gen)), &mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_version(dir: &Path, version: u32) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", FORMAT_FILE));
+    fs::write(&tmp, version.to_string())?;
+    fs::rename(&tmp, dir.join(FORMAT_FILE))?;
+    Ok(())
+}
+
+// call `f` with every line of the file at `path`, its newline included, and
+// where it starts
+fn for_each_line(path: &Path, mut f: impl FnMut(u64, &[u8]) -> Result<()>) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = Vec::new();
+    let mut offset = 0;
+    loop {
+        line.clear();
+        let len = reader.read_until(b'\n', &mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        f(offset, &line)?;
+        offset += len as u64;
+    }
+}
+
+// bytes values used to hold their bytes as text, they are encoded in place,
+// as chunks of `DEFAULT_CHUNK_SIZE` once encoded if they outgrow it. Other
+// records, and lines which do not decode, a torn tail among them, are
+// copied as they are.
+fn encode_bytes_values(path: &Path, out: &mut dyn Write) -> Result<()> {
+    // first find where the chunks of every bytes manifest lie, up to the
+    // manifest
+    let mut values: Vec<Range<u64>> = Vec::new();
+    // the chunks read since the last other record
+    let mut chunks: Vec<u64> = Vec::new();
+    for_each_line(path, |offset, line| {
+        match KvLog::decode(line) {
+            Ok(KvLog::Chunk { .. }) => {
+                chunks.push(offset);
+                return Ok(());
+            }
+            // its chunks are the records spanning right before it, others
+            // are left over by a write cut short
+            Ok(KvLog::Manifest {
+                span,
+                value_type: ValueType::Bytes,
+                ..
+            }) => {
+                let first = offset.saturating_sub(span);
+                let start = chunks.iter().find(|&&at| at >= first).unwrap_or(&offset);
+                values.push(*start..offset);
+            }
+            _ if line.trim_ascii().is_empty() => return Ok(()),
+            _ => {}
+        }
+        chunks.clear();
+        Ok(())
+    })?;
+
+    // then rewrite them
+    let mut values = values.into_iter().peekable();
+    let mut value: Option<Rechunk> = None;
+    for_each_line(path, |offset, line| {
+        while values.peek().is_some_and(|value| value.end < offset) {
+            values.next();
+        }
+        let newline = line.ends_with(b"\n");
+        match KvLog::decode(line) {
+            Ok(KvLog::Chunk { key, data })
+                if values.peek().is_some_and(|v| v.contains(&offset)) =>
+            {
+                value
+                    .get_or_insert_with(|| Rechunk::new(key))
+                    .push(data.as_bytes(), out)?;
+            }
+            Ok(KvLog::Manifest {
+                key,
+                value_type: ValueType::Bytes,
+                expires_at,
+                written_at,
+                ..
+            }) => {
+                let span = match value.take() {
+                    Some(value) => value.finish(out)?,
+                    None => 0,
+                };
+                let log = KvLog::Manifest {
+                    key,
+                    span,
+                    value_type: ValueType::Bytes,
+                    expires_at,
+                    written_at,
+                };
+                write_record(out, &log, newline)?;
+            }
+            Ok(KvLog::Set {
+                key,
+                value,
+                value_type: ValueType::Bytes,
+                expires_at,
+                written_at,
+            }) => {
+                let log = if value.len().div_ceil(3) * 4 > DEFAULT_CHUNK_SIZE as usize {
+                    let mut chunks = Rechunk::new(key.clone());
+                    chunks.push(value.as_bytes(), out)?;
+                    KvLog::Manifest {
+                        key,
+                        span: chunks.finish(out)?,
+                        value_type: ValueType::Bytes,
+                        expires_at,
+                        written_at,
+                    }
+                } else {
+                    KvLog::Set {
+                        key,
+                        value: BASE64.encode(value),
+                        value_type: ValueType::Bytes,
+                        expires_at,
+                        written_at,
+                    }
+                };
+                write_record(out, &log, newline)?;
+            }
+            _ => out.write_all(line)?,
+        }
+        Ok(())
+    })
+}
+
+// the chunks of a bytes value being encoded in base64, written as they
+// fill up
+struct Rechunk {
+    key: String,
+    // the bytes left over from the last multiple of 3, and the encoding of
+    // the ones before, not written yet
+    pending: Vec<u8>,
+    encoded: String,
+    // the size of the chunks written so far
+    span: u64,
+}
+
+impl Rechunk {
+    fn new(key: String) -> Rechunk {
+        Rechunk {
+            key,
+            pending: Vec::new(),
+            encoded: String::new(),
+            span: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8], out: &mut dyn Write) -> Result<()> {
+        self.pending.extend_from_slice(data);
+        let whole = self.pending.len() / 3 * 3;
+        BASE64.encode_string(&self.pending[..whole], &mut self.encoded);
+        self.pending.drain(..whole);
+        while self.encoded.len() >= DEFAULT_CHUNK_SIZE as usize {
+            let rest = self.encoded.split_off(DEFAULT_CHUNK_SIZE as usize);
+            let data = std::mem::replace(&mut self.encoded, rest);
+            self.write(data, out)?;
+        }
+        Ok(())
+    }
+
+    // write the last chunk and return the size of them all
+    fn finish(mut self, out: &mut dyn Write) -> Result<u64> {
+        BASE64.encode_string(&self.pending, &mut self.encoded);
+        if !self.encoded.is_empty() {
+            let data = std::mem::take(&mut self.encoded);
+            self.write(data, out)?;
+        }
+        Ok(self.span)
+    }
+
+    fn write(&mut self, data: String, out: &mut dyn Write) -> Result<()> {
+        let log = KvLog::Chunk {
+            key: self.key.clone(),
+            data,
+        };
+        self.span += write_record(out, &log, true)?;
+        Ok(())
+    }
+}
+
+// write a record as version 4 did, without a checksum, and return its size
+fn write_record(out: &mut dyn Write, log: &KvLog, newline: bool) -> Result<u64> {
+    let mut line = serde_json::to_vec(log)?;
+    if newline {
+        line.push(b'\n');
+    }
+    out.write_all(&line)?;
+    Ok(line.len() as u64)
+}
+
+// every line gets the checksum of its record. Lines grow, so manifests get
+// the new span of their chunks; lines which do not decode, a torn tail
+// among them, are copied as they are.
+fn add_checksums(path: &Path, out: &mut dyn Write) -> Result<()> {
+    // where every line started, before and after
+    let mut starts: Vec<(u64, u64)> = Vec::new();
+    let mut written = 0;
+    for_each_line(path, |start, line| {
+        starts.push((start, written));
+        let decoded = verify_checksum(line)
+            .ok()
+            .and_then(|record| Some((record, serde_json::from_slice(record).ok()?)));
+        let line = match decoded {
+            Some((record, log)) if line.ends_with(b"\n") => match log {
+                KvLog::Manifest {
+                    key,
+                    span,
+                    value_type,
+                    expires_at,
+                    written_at,
+                } => {
+                    let first = start.saturating_sub(span);
+                    let span = match starts.binary_search_by_key(&first, |&(old, _)| old) {
+                        Ok(i) => written - starts[i].1,
+                        Err(_) => span,
+                    };
+                    let log = KvLog::Manifest {
+                        key,
+                        span,
+                        value_type,
+                        expires_at,
+                        written_at,
+                    };
+                    log.encode()?
+                }
+                _ => {
+                    let mut line = record.to_vec();
+                    append_checksum(&mut line);
+                    line
+                }
+            },
+            _ => line.to_vec(),
+        };
+        out.write_all(&line)?;
+        written += line.len() as u64;
+        Ok(())
+    })
+}
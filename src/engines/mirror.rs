@@ -0,0 +1,361 @@
+use crate::KeyMeta;
+use crate::KeyOrder;
+use crate::KvsEngine;
+use crate::PrefixUsage;
+use crate::Result;
+use crate::ValueType;
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Debug, Display};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::time::Duration;
+
+/// `MirrorEngine` writes to two engines and reads from the first, to
+/// validate a migration from the `primary` engine to the `secondary` one,
+/// e.g. from kvs to sled, under production traffic before cutting over.
+///
+/// The primary is authoritative: writes go to it first and return its
+/// outcome. They are then applied to the secondary, whose errors, or
+/// results differing from the primary's, are logged and counted as
+/// divergences rather than failing the write. With `verify`, reads are
+/// checked against the secondary the same way; values streamed out with
+/// `get_to_writer` are not.
+pub struct MirrorEngine<P, S> {
+    primary: P,
+    secondary: S,
+    verify: bool,
+    divergences: u64,
+}
+
+impl<P: KvsEngine, S: KvsEngine> MirrorEngine<P, S> {
+    /// Mirror the writes to `primary` into `secondary`.
+    pub fn new(primary: P, secondary: S) -> Self {
+        MirrorEngine {
+            primary,
+            secondary,
+            verify: false,
+            divergences: 0,
+        }
+    }
+
+    /// Check every read against the secondary too, which doubles the cost
+    /// of reads.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Number of operations on which the secondary diverged from the
+    /// primary since the mirror was created.
+    pub fn divergences(&self) -> u64 {
+        self.divergences
+    }
+
+    /// Take the two engines back, e.g. to cut over to the secondary.
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+
+    // apply a write done on the primary to the secondary, which should
+    // answer the same
+    fn mirror<T: PartialEq + Debug>(
+        &mut self,
+        op: &str,
+        key: &str,
+        expected: T,
+        write: impl FnOnce(&mut S) -> Result<T>,
+    ) -> T {
+        match write(&mut self.secondary) {
+            Ok(found) if found == expected => {}
+            Ok(found) => self.diverged(op, key, format!("{:?}, not {:?}", found, expected)),
+            Err(e) => self.diverged(op, key, e),
+        }
+        expected
+    }
+
+    // check a read served by the primary against the secondary, if verifying
+    fn check<T: PartialEq + Debug>(
+        &mut self,
+        op: &str,
+        key: &str,
+        expected: T,
+        read: impl FnOnce(&mut S) -> Result<T>,
+    ) -> T {
+        match self.verify {
+            true => self.mirror(op, key, expected, read),
+            false => expected,
+        }
+    }
+
+    fn diverged(&mut self, op: &str, key: &str, found: impl Display) {
+        self.divergences += 1;
+        log::warn!("Mirror diverged on {} {:?}: secondary {}", op, key, found);
+    }
+}
+
+impl<P: KvsEngine, S: KvsEngine> KvsEngine for MirrorEngine<P, S> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.primary.set(key.clone(), value.clone())?;
+        self.mirror("set", &key, (), |s| s.set(key.clone(), value));
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.primary.get(key.clone())?;
+        Ok(self.check("get", &key, value, |s| s.get(key.clone())))
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let values = self.primary.get_many(keys.clone())?;
+        Ok(self.check("get_many", "", values, |s| s.get_many(keys)))
+    }
+
+    fn get_raw(&mut self, key: String) -> Result<Option<Box<RawValue>>> {
+        let raw = self.primary.get_raw(key.clone())?;
+        if self.verify {
+            // engines may escape the same string differently
+            let value = match &raw {
+                Some(raw) => Some(serde_json::from_str::<String>(raw.get())?),
+                None => None,
+            };
+            self.check("get", &key, value, |s| s.get(key.clone()));
+        }
+        Ok(raw)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.primary.remove(key.clone())?;
+        self.mirror("remove", &key, (), |s| s.remove(key.clone()));
+        Ok(())
+    }
+
+    fn undelete(&mut self, key: String) -> Result<()> {
+        self.primary.undelete(key.clone())?;
+        self.mirror("undelete", &key, (), |s| s.undelete(key.clone()));
+        Ok(())
+    }
+
+    fn set_with_type(&mut self, key: String, value: String, value_type: ValueType) -> Result<()> {
+        self.primary
+            .set_with_type(key.clone(), value.clone(), value_type)?;
+        self.mirror("set", &key, (), |s| {
+            s.set_with_type(key.clone(), value, value_type)
+        });
+        Ok(())
+    }
+
+    fn get_with_type(&mut self, key: String) -> Result<Option<(String, ValueType)>> {
+        let value = self.primary.get_with_type(key.clone())?;
+        Ok(self.check("get", &key, value, |s| s.get_with_type(key.clone())))
+    }
+
+    // read whole, to be written twice
+    fn set_from_reader(
+        &mut self,
+        key: String,
+        value: &mut dyn Read,
+        value_type: ValueType,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        value.read_to_end(&mut buf)?;
+        self.primary
+            .set_from_reader(key.clone(), &mut buf.as_slice(), value_type)?;
+        self.mirror("set", &key, (), |s| {
+            s.set_from_reader(key.clone(), &mut buf.as_slice(), value_type)
+        });
+        Ok(())
+    }
+
+    fn get_to_writer(&mut self, key: String, out: &mut dyn Write) -> Result<bool> {
+        self.primary.get_to_writer(key, out)
+    }
+
+    fn lpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        let len = self.primary.lpush(key.clone(), values.clone())?;
+        Ok(self.mirror("lpush", &key, len, |s| s.lpush(key.clone(), values)))
+    }
+
+    fn rpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        let len = self.primary.rpush(key.clone(), values.clone())?;
+        Ok(self.mirror("rpush", &key, len, |s| s.rpush(key.clone(), values)))
+    }
+
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let values = self.primary.lrange(key.clone(), start, stop)?;
+        Ok(self.check("lrange", &key, values, |s| {
+            s.lrange(key.clone(), start, stop)
+        }))
+    }
+
+    fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.primary.lpop(key.clone())?;
+        Ok(self.mirror("lpop", &key, value, |s| s.lpop(key.clone())))
+    }
+
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.primary
+            .hset(key.clone(), field.clone(), value.clone())?;
+        self.mirror("hset", &key, (), |s| s.hset(key.clone(), field, value));
+        Ok(())
+    }
+
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        let value = self.primary.hget(key.clone(), field.clone())?;
+        Ok(self.check("hget", &key, value, |s| s.hget(key.clone(), field)))
+    }
+
+    fn hgetall(&mut self, key: String) -> Result<BTreeMap<String, String>> {
+        let hash = self.primary.hgetall(key.clone())?;
+        Ok(self.check("hgetall", &key, hash, |s| s.hgetall(key.clone())))
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        self.primary.hdel(key.clone(), field.clone())?;
+        self.mirror("hdel", &key, (), |s| s.hdel(key.clone(), field));
+        Ok(())
+    }
+
+    fn sadd(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        let added = self.primary.sadd(key.clone(), members.clone())?;
+        Ok(self.mirror("sadd", &key, added, |s| s.sadd(key.clone(), members)))
+    }
+
+    fn srem(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        let removed = self.primary.srem(key.clone(), members.clone())?;
+        Ok(self.mirror("srem", &key, removed, |s| s.srem(key.clone(), members)))
+    }
+
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        let found = self.primary.sismember(key.clone(), member.clone())?;
+        Ok(self.check("sismember", &key, found, |s| {
+            s.sismember(key.clone(), member)
+        }))
+    }
+
+    fn smembers(&mut self, key: String) -> Result<BTreeSet<String>> {
+        let members = self.primary.smembers(key.clone())?;
+        Ok(self.check("smembers", &key, members, |s| s.smembers(key.clone())))
+    }
+
+    fn incr(&mut self, key: String, by: i64) -> Result<i64> {
+        let value = self.primary.incr(key.clone(), by)?;
+        Ok(self.mirror("incr", &key, value, |s| s.incr(key.clone(), by)))
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        let found = self.primary.expire(key.clone(), ttl)?;
+        Ok(self.mirror("expire", &key, found, |s| s.expire(key.clone(), ttl)))
+    }
+
+    // the time left differs by the time between both reads, only whether
+    // the key expires is checked
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let ttl = self.primary.ttl(key.clone())?;
+        self.check("ttl", &key, ttl.is_some(), |s| {
+            Ok(s.ttl(key.clone())?.is_some())
+        });
+        Ok(ttl)
+    }
+
+    fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        let found = self.primary.touch(key.clone(), ttl)?;
+        Ok(self.mirror("touch", &key, found, |s| s.touch(key.clone(), ttl)))
+    }
+
+    fn set_at(&mut self, key: String, value: String, at: u64) -> Result<()> {
+        self.primary.set_at(key.clone(), value.clone(), at)?;
+        self.mirror("set_at", &key, (), |s| s.set_at(key.clone(), value, at));
+        Ok(())
+    }
+
+    fn remove_at(&mut self, key: String, at: u64) -> Result<()> {
+        self.primary.remove_at(key.clone(), at)?;
+        self.mirror("remove_at", &key, (), |s| s.remove_at(key.clone(), at));
+        Ok(())
+    }
+
+    // both engines hold the same schedule, and run it on their own
+    fn run_scheduled(&mut self) -> Result<usize> {
+        let ran = self.primary.run_scheduled()?;
+        Ok(self.mirror("run_scheduled", "", ran, |s| s.run_scheduled()))
+    }
+
+    // engines sweep at their own pace, only failures diverge
+    fn sweep_expired(&mut self) -> Result<usize> {
+        let swept = self.primary.sweep_expired()?;
+        self.mirror("sweep_expired", "", (), |s| s.sweep_expired().map(drop));
+        Ok(swept)
+    }
+
+    // only the value is checked, the rest is the primary's own
+    fn meta(&mut self, key: String) -> Result<Option<KeyMeta>> {
+        let meta = self.primary.meta(key.clone())?;
+        let found = meta.as_ref().map(|meta| (meta.size, meta.value_type));
+        self.check("meta", &key, found, |s| {
+            Ok(s.meta(key.clone())?
+                .map(|meta| (meta.size, meta.value_type)))
+        });
+        Ok(meta)
+    }
+
+    fn lsn(&mut self) -> Option<u64> {
+        self.primary.lsn()
+    }
+
+    // the primary's, along with the divergences
+    fn metrics(&mut self) -> Result<BTreeMap<String, u64>> {
+        let mut metrics = self.primary.metrics()?;
+        metrics.insert("mirror_divergences".to_owned(), self.divergences);
+        Ok(metrics)
+    }
+
+    fn changed_since(&mut self, lsn: u64) -> Result<Vec<String>> {
+        self.primary.changed_since(lsn)
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.primary.refresh()?;
+        if let Err(e) = self.secondary.refresh() {
+            self.diverged("refresh", "", e);
+        }
+        Ok(())
+    }
+
+    // engines measure bytes their own way, only the primary is counted
+    fn usage(&mut self, separator: String, depth: usize) -> Result<Vec<PrefixUsage>> {
+        self.primary.usage(separator, depth)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.primary.compact()?;
+        if let Err(e) = self.secondary.compact() {
+            self.diverged("compact", "", e);
+        }
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let keys = self.primary.keys()?;
+        Ok(self.check("keys", "", keys, |s| s.keys()))
+    }
+
+    fn key_order(&self) -> KeyOrder {
+        self.primary.key_order()
+    }
+
+    // the primary's, reading every value twice would double the cost
+    fn scan(&mut self, range: Range<String>) -> Result<impl Iterator<Item = (String, String)>> {
+        self.primary.scan(range)
+    }
+
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let keys = self.primary.scan_prefix(prefix)?;
+        Ok(self.check("scan_prefix", prefix, keys, |s| s.scan_prefix(prefix)))
+    }
+
+    fn exists(&mut self, key: String) -> Result<bool> {
+        let found = self.primary.exists(key.clone())?;
+        Ok(self.check("exists", &key, found, |s| s.exists(key.clone())))
+    }
+}
@@ -1,4 +1,26 @@
-use crate::Result;
+use crate::{KvsError, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use crate::format::{LogEncoding, ValueType};
+
+/// The longest key accepted, in bytes. A key is repeated in every record
+/// about it, so a longer one would mostly bloat the log. Keys starting with
+/// a NUL byte are refused too, `KvStore` indexing long keys by a digest
+/// starting with one.
+pub const MAX_KEY_LEN: usize = 64 * 1024;
+
+/// The longest window `KvsEngine::rate` counts hits over.
+pub const MAX_RATE_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 /// The `KvsEngine` trait
 pub trait KvsEngine {
@@ -8,10 +30,735 @@ pub trait KvsEngine {
     fn get(&mut self, key: String) -> Result<Option<String>>;
     /// Remove a string key
     fn remove(&mut self, key: String) -> Result<()>;
+    /// Get the value of a string key encoded as a JSON string. Engines
+    /// storing values as JSON hand out the stored bytes, without decoding
+    /// and re-encoding them.
+    fn get_raw(&mut self, key: String) -> Result<Option<Box<RawValue>>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::value::to_raw_value(&value)?)),
+            None => Ok(None),
+        }
+    }
+    /// Write the value of a string key to `out` encoded as a JSON string,
+    /// as `get_raw` returns it, and return whether the key exists. Engines
+    /// storing large values in pieces encode it piece by piece; by default
+    /// it is read whole first.
+    fn get_raw_to_writer(&mut self, key: String, out: &mut dyn Write) -> Result<bool> {
+        match self.get_raw(key)? {
+            Some(value) => {
+                out.write_all(value.get().as_bytes())?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+    /// Get the values of string keys, `None` for the keys not found, in the
+    /// order of `keys`. By default, the keys are looked up one by one.
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+    /// Restore a string key removed within the trash window of the engine.
+    /// Engines without a trash window refuse.
+    fn undelete(&mut self, _key: String) -> Result<()> {
+        Err(KvsError::InvalidCommand(
+            "soft delete is not supported by this engine".to_owned(),
+        ))
+    }
+    /// Set the value of a string key, tagged with the type of its content.
+    /// The value is rejected if it does not match the type.
+    fn set_with_type(&mut self, key: String, value: String, value_type: ValueType) -> Result<()>;
+    /// Get the value of a string key along with its type tag.
+    /// Values set without a tag are reported as `ValueType::String`.
+    fn get_with_type(&mut self, key: String) -> Result<Option<(String, ValueType)>>;
+    /// Set the value of a string key to arbitrary bytes, stored typed as
+    /// `ValueType::Bytes`, encoded in base64.
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set_with_type(key, BASE64.encode(value), ValueType::Bytes)
+    }
+    /// Get the value of a string key as bytes: decoded from base64 for a
+    /// value typed as `ValueType::Bytes`, the UTF-8 text of the others.
+    fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        match self.get_with_type(key)? {
+            Some((value, ValueType::Bytes)) => Ok(Some(decode_bytes(&value)?)),
+            Some((value, _)) => Ok(Some(value.into_bytes())),
+            None => Ok(None),
+        }
+    }
+    /// Set the value of a string key to `value` serialized as JSON, stored
+    /// typed as `ValueType::Json`. Not available on `dyn KvsEngine`.
+    fn set_typed<T: Serialize>(&mut self, key: String, value: &T) -> Result<()>
+    where
+        Self: Sized,
+    {
+        self.set_with_type(key, serde_json::to_string(value)?, ValueType::Json)
+    }
+    /// Get the value of a string key deserialized from JSON, failing if it
+    /// does not hold a `T`. Not available on `dyn KvsEngine`.
+    fn get_typed<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>>
+    where
+        Self: Sized,
+    {
+        match self.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+    /// Set the value of a string key to the content read from `value`,
+    /// which must be UTF-8. Engines storing large values in pieces read it
+    /// piece by piece; by default it is read whole.
+    fn set_from_reader(
+        &mut self,
+        key: String,
+        value: &mut dyn Read,
+        value_type: ValueType,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        value.read_to_end(&mut buf)?;
+        let value = String::from_utf8(buf)
+            .map_err(|e| KvsError::InvalidValue(format!("not UTF-8: {}", e)))?;
+        self.set_with_type(key, value, value_type)
+    }
+    /// Write the value of a string key to `out` and return whether the key
+    /// exists. Engines storing large values in pieces write it piece by
+    /// piece; by default it is read whole first.
+    fn get_to_writer(&mut self, key: String, out: &mut dyn Write) -> Result<bool> {
+        match self.get(key)? {
+            Some(value) => {
+                out.write_all(value.as_bytes())?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Push values at the head of a list, one after the other, and return
+    /// the new length of the list. Lists live in their own keyspace, apart
+    /// from string keys.
+    fn lpush(&mut self, key: String, values: Vec<String>) -> Result<usize>;
+    /// Push values at the tail of a list and return the new length of the list.
+    fn rpush(&mut self, key: String, values: Vec<String>) -> Result<usize>;
+    /// Get the elements of a list between `start` and `stop`, both
+    /// inclusive. Negative indexes count from the tail, `-1` being the last
+    /// element. A missing list is empty.
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>>;
+    /// Pop the head of a list. If the list is empty, return `None`.
+    fn lpop(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Set a field of a hash. Like lists, hashes live in their own keyspace.
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()>;
+    /// Get a field of a hash. If the field does not exist, return `None`.
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>>;
+    /// Get every field of a hash. A missing hash is empty.
+    fn hgetall(&mut self, key: String) -> Result<BTreeMap<String, String>>;
+    /// Remove a field of a hash.
+    fn hdel(&mut self, key: String, field: String) -> Result<()>;
+
+    /// Add members to a set and return how many were not members yet.
+    /// Sets live in their own keyspace too.
+    fn sadd(&mut self, key: String, members: Vec<String>) -> Result<usize>;
+    /// Remove members from a set and return how many were members.
+    fn srem(&mut self, key: String, members: Vec<String>) -> Result<usize>;
+    /// Check whether `member` belongs to a set.
+    fn sismember(&mut self, key: String, member: String) -> Result<bool>;
+    /// Get the members of a set. A missing set is empty.
+    fn smembers(&mut self, key: String) -> Result<BTreeSet<String>>;
+
+    /// Add `by` to the integer value of a string key and return the result.
+    /// A missing key counts as 0; the value is tagged `ValueType::Int` and
+    /// keeps its expiration.
+    fn incr(&mut self, key: String, by: i64) -> Result<i64>;
+    /// Make a string key expire after `ttl`. Return whether the key exists.
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<bool>;
+    /// Get the time left before a string key expires, `None` if the key does
+    /// not exist or never expires.
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>>;
+    /// Make a string key expire after `ttl` and mark it as just used, for
+    /// cache-style workloads sliding the expiry of the keys they read.
+    /// Return whether the key exists.
+    ///
+    /// Engines recording expiry apart from values do so without rewriting
+    /// the value.
+    fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        self.expire(key, ttl)
+    }
+
+    /// Set a string key to `value` at `at`, in milliseconds since the Unix
+    /// epoch, e.g. to publish content at a future time. The engine keeps
+    /// the write until `run_scheduled` finds it due. Engines without a log
+    /// refuse.
+    fn set_at(&mut self, _key: String, _value: String, _at: u64) -> Result<()> {
+        Err(KvsError::InvalidCommand(
+            "scheduled writes are not supported by this engine".to_owned(),
+        ))
+    }
+    /// Remove a string key at `at`, like `set_at`. A key missing by then
+    /// is left alone.
+    fn remove_at(&mut self, _key: String, _at: u64) -> Result<()> {
+        Err(KvsError::InvalidCommand(
+            "scheduled writes are not supported by this engine".to_owned(),
+        ))
+    }
+    /// Run the scheduled writes which are due, oldest first, and return how
+    /// many ran.
+    fn run_scheduled(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+    /// Drop the string keys which expired, returning how many, for those
+    /// reads only hid. Engines sweeping them otherwise, e.g. on compaction,
+    /// drop none by default.
+    fn sweep_expired(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Get the log sequence number of the latest write: a number increasing
+    /// with every write, also across restarts, for clients to tell whether
+    /// a write is newer than another. Engines without a log have none.
+    fn lsn(&mut self) -> Option<u64> {
+        None
+    }
+
+    /// Get counters about the internals of the engine, like its compactions
+    /// or the files it keeps, by name, reported along with the statistics
+    /// of the server. Engines report none by default.
+    fn metrics(&mut self) -> Result<BTreeMap<String, u64>> {
+        Ok(BTreeMap::new())
+    }
+
+    /// Get the keys whose data changed after `lsn`, as returned by `lsn`,
+    /// sorted, for consumers to sync incrementally: those written or
+    /// removed since, in any keyspace. Keys may be reported without having
+    /// changed, e.g. after a compaction; keys which merely expired are not.
+    ///
+    /// Fails with `KvsError::LsnCompacted` once the engine no longer holds
+    /// the changes after `lsn`, and by default, for engines without a log.
+    fn changed_since(&mut self, _lsn: u64) -> Result<Vec<String>> {
+        Err(KvsError::InvalidCommand(
+            "change tracking is not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Pick up the writes another process made to the data, for engines
+    /// opened read-only next to it. Engines owning their data have nothing
+    /// to pick up.
+    fn refresh(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get what the engine knows of a string key: the size and type of its
+    /// value and the time left before it expires, and for engines keeping
+    /// a log, where and when the value was written. `None` if the key does
+    /// not exist.
+    fn meta(&mut self, key: String) -> Result<Option<KeyMeta>> {
+        let Some((value, value_type)) = self.get_with_type(key.clone())? else {
+            return Ok(None);
+        };
+        Ok(Some(KeyMeta {
+            size: value.len() as u64,
+            value_type,
+            ttl_ms: self.ttl(key)?.map(|ttl| ttl.as_millis() as u64),
+            ..KeyMeta::default()
+        }))
+    }
+
+    /// Get the part of a JSON value at `pointer`, a JSON pointer like
+    /// `/users/0/name` (RFC 6901), encoded as JSON, for clients to fetch one
+    /// field of a large document. The empty pointer is the whole document.
+    /// `None` if the key does not exist or holds nothing at `pointer`;
+    /// values not tagged `ValueType::Json` are refused.
+    fn get_field(&mut self, key: String, pointer: String) -> Result<Option<String>> {
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(KvsError::InvalidCommand(format!(
+                "JSON pointer {:?} must be empty or start with '/'",
+                pointer
+            )));
+        }
+        let Some((value, value_type)) = self.get_with_type(key)? else {
+            return Ok(None);
+        };
+        if value_type != ValueType::Json {
+            return Err(KvsError::InvalidValue(format!(
+                "{} value has no fields",
+                value_type
+            )));
+        }
+        if pointer.is_empty() {
+            return Ok(Some(value));
+        }
+        let document: serde_json::Value = serde_json::from_str(&value)?;
+        Ok(document.pointer(&pointer).map(|field| field.to_string()))
+    }
+
+    /// Reclaim the space taken by stale data now, rather than when the
+    /// engine would, e.g. at a quiet time or before backing the data up.
+    /// Does nothing by default.
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get every key holding data, in any keyspace, sorted: bytewise, or
+    /// in the `KeyOrder` of engines having one.
+    fn keys(&mut self) -> Result<Vec<String>>;
+
+    /// The order `keys` lists keys in, and scans follow. Bytewise by
+    /// default.
+    fn key_order(&self) -> KeyOrder {
+        KeyOrder::Bytewise
+    }
+
+    /// Get the string keys in `range` along with their values, sorted, to
+    /// list keys page by page: each page starts where the previous one
+    /// ended. Expired keys are left out. Both the range and the sort follow
+    /// the order of `keys`.
+    ///
+    /// The values are read by the call, which fails if any cannot be; a
+    /// narrower range keeps a page small. By default, every key is listed
+    /// and those in `range` read one by one. Not available on `dyn
+    /// KvsEngine`.
+    fn scan(&mut self, range: Range<String>) -> Result<impl Iterator<Item = (String, String)>>
+    where
+        Self: Sized,
+    {
+        let mut pairs = Vec::new();
+        for key in self.keys()? {
+            if !range.contains(&key) {
+                continue;
+            }
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs.into_iter())
+    }
+
+    /// Get every key starting with `prefix` holding data, in any keyspace,
+    /// sorted, e.g. every key of a user under `user:123:`. By default,
+    /// every key is listed and filtered.
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = self.keys()?;
+        keys.retain(|key| key.starts_with(prefix));
+        Ok(keys)
+    }
+
+    /// Check whether a key holds data in any keyspace.
+    fn exists(&mut self, key: String) -> Result<bool> {
+        Ok(self.get(key.clone())?.is_some()
+            || !self.lrange(key.clone(), 0, 0)?.is_empty()
+            || !self.hgetall(key.clone())?.is_empty()
+            || !self.smembers(key)?.is_empty())
+    }
+
+    /// List the immediate children of `prefix`, seeing keys as paths whose
+    /// components are split by `separator`, like `users/42/name` with `/`.
+    ///
+    /// Keys starting with `prefix` and holding no further separator are
+    /// listed as keys; the others are grouped by the "directory" up to and
+    /// including their next separator. An empty separator lists every key
+    /// starting with `prefix`.
+    fn list_children(&mut self, prefix: String, separator: String) -> Result<Children> {
+        let mut children = Children::default();
+        for key in self.scan_prefix(&prefix)? {
+            let Some(rest) = key.strip_prefix(prefix.as_str()) else {
+                continue;
+            };
+            match rest
+                .find(separator.as_str())
+                .filter(|_| !separator.is_empty())
+            {
+                Some(end) => {
+                    let dir = &key[..prefix.len() + end + separator.len()];
+                    // keys are sorted, so a directory's keys are contiguous
+                    if children.dirs.last().map(String::as_str) != Some(dir) {
+                        children.dirs.push(dir.to_owned());
+                    }
+                }
+                None => children.keys.push(key),
+            }
+        }
+        Ok(children)
+    }
+
+    /// Count the keys and the bytes they take up by prefix, to see which
+    /// namespace consumes the store. The prefix of a key runs up to and
+    /// including its `depth`-th `separator`, or its last one if it has
+    /// fewer.
+    ///
+    /// Bytes are those of the keys and their data, unless the engine
+    /// measures what it stores them in, like the log records of the kvs
+    /// engine.
+    fn usage(&mut self, separator: String, depth: usize) -> Result<Vec<PrefixUsage>> {
+        let mut usage = BTreeMap::new();
+        for key in self.keys()? {
+            if let Some(dump) = self.dump(key.clone())? {
+                add_usage(&mut usage, &key, &separator, depth, key.len() + dump.len());
+            }
+        }
+        Ok(usage.into_values().collect())
+    }
+
+    /// Capture everything stored under a key, in every keyspace, or `None`
+    /// if the key holds no data.
+    fn dump(&mut self, key: String) -> Result<Option<KeyDump>> {
+        let (value, value_type) = match self.get_with_type(key.clone())? {
+            Some((value, value_type)) => (Some(value), value_type),
+            None => (None, ValueType::String),
+        };
+        let dump = KeyDump {
+            value,
+            value_type,
+            ttl_ms: self.ttl(key.clone())?.map(|ttl| ttl.as_millis() as u64),
+            list: self.lrange(key.clone(), 0, -1)?,
+            hash: self.hgetall(key.clone())?,
+            set: self.smembers(key)?,
+        };
+        if dump.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(dump))
+    }
+
+    /// Replace everything stored under a key with a dump.
+    fn restore(&mut self, key: String, dump: KeyDump) -> Result<()> {
+        self.purge(key.clone())?;
+        if let Some(value) = dump.value {
+            self.set_with_type(key.clone(), value, dump.value_type)?;
+            if let Some(ttl) = dump.ttl_ms {
+                self.expire(key.clone(), Duration::from_millis(ttl))?;
+            }
+        }
+        if !dump.list.is_empty() {
+            self.rpush(key.clone(), dump.list)?;
+        }
+        for (field, value) in dump.hash {
+            self.hset(key.clone(), field, value)?;
+        }
+        if !dump.set.is_empty() {
+            self.sadd(key, dump.set.into_iter().collect())?;
+        }
+        Ok(())
+    }
+
+    /// Remove everything stored under a key, in every keyspace.
+    fn purge(&mut self, key: String) -> Result<()> {
+        match self.remove(key.clone()) {
+            Ok(()) | Err(KvsError::KeyNotFound) => {}
+            Err(e) => return Err(e),
+        }
+        while self.lpop(key.clone())?.is_some() {}
+        for field in self.hgetall(key.clone())?.into_keys() {
+            self.hdel(key.clone(), field)?;
+        }
+        let members = self.smembers(key.clone())?;
+        if !members.is_empty() {
+            self.srem(key, members.into_iter().collect())?;
+        }
+        Ok(())
+    }
+
+    /// Count one hit against a sliding-window rate limit of `limit` hits
+    /// per `window`.
+    ///
+    /// The first hit opens a window, the next ones opening right as the
+    /// previous one closes. A hit is counted along with those of the
+    /// current window and with those of the previous one, weighted by how
+    /// much of it the last `window` still covers, so a burst straddling
+    /// two windows cannot pass twice the limit. Refused hits count too.
+    ///
+    /// Both windows are stored under `key`, expiring once neither matters
+    /// anymore; a key holding another string value is refused. Since an
+    /// engine is only used through `&mut self`, the read and the write
+    /// cannot race with another hit.
+    ///
+    /// The window is a millisecond at least, `MAX_RATE_WINDOW` at most.
+    fn rate(&mut self, key: String, window: Duration, limit: u64) -> Result<Rate> {
+        if window.as_millis() == 0 || window > MAX_RATE_WINDOW {
+            return Err(KvsError::InvalidCommand(format!(
+                "rate window of {}ms, not within 1ms to {}ms",
+                window.as_millis(),
+                MAX_RATE_WINDOW.as_millis()
+            )));
+        }
+        let window = window.as_millis() as u64;
+        let now = now_ms();
+        let state = match self.get(key.clone())? {
+            Some(value) => Some(RateWindows::parse(&value).ok_or_else(|| {
+                KvsError::InvalidValue(format!("{:?} holds a value other than a rate", key))
+            })?),
+            None => None,
+        };
+        let mut state = match state {
+            Some(state) if state.start <= now => state,
+            _ => RateWindows {
+                start: now,
+                current: 0,
+                previous: 0,
+            },
+        };
+        let passed = (now - state.start) / window;
+        if passed > 0 {
+            state.previous = if passed == 1 { state.current } else { 0 };
+            state.current = 0;
+            state.start += passed * window;
+        }
+        state.current = state.current.saturating_add(1);
+        let elapsed = now - state.start;
+        // the weighted count, rounded up, at most the previous count
+        let weighted = (u128::from(state.previous) * u128::from(window - elapsed))
+            .div_ceil(u128::from(window)) as u64;
+        let count = state.current.saturating_add(weighted);
+
+        self.set(key.clone(), state.to_string())?;
+        self.expire(key, Duration::from_millis(state.start + 2 * window - now))?;
+        Ok(Rate {
+            allowed: count <= limit,
+            count,
+            reset_ms: window - elapsed,
+        })
+    }
+}
+
+/// Everything stored under a key, as captured by `KvsEngine::dump`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyDump {
+    /// The string value
+    pub value: Option<String>,
+    /// The type tag of the string value
+    pub value_type: ValueType,
+    /// Time left before the string value expires, in milliseconds
+    pub ttl_ms: Option<u64>,
+    /// The list elements, head first
+    pub list: Vec<String>,
+    /// The hash fields
+    pub hash: BTreeMap<String, String>,
+    /// The set members
+    pub set: BTreeSet<String>,
+}
+
+impl KeyDump {
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.list.is_empty() && self.hash.is_empty() && self.set.is_empty()
+    }
+
+    // bytes of data, keys of hash fields included
+    fn len(&self) -> usize {
+        self.value.as_ref().map_or(0, String::len)
+            + self.list.iter().map(String::len).sum::<usize>()
+            + self
+                .hash
+                .iter()
+                .map(|(f, v)| f.len() + v.len())
+                .sum::<usize>()
+            + self.set.iter().map(String::len).sum::<usize>()
+    }
+}
+
+/// What an engine knows of a string key, as told by `KvsEngine::meta`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyMeta {
+    /// Size of the value, in bytes
+    pub size: u64,
+    /// The type tag of the value
+    pub value_type: ValueType,
+    /// Time left before the key expires, in milliseconds
+    pub ttl_ms: Option<u64>,
+    /// When the value was written, in milliseconds since the Unix epoch, if
+    /// the engine records it
+    pub written_at: Option<u64>,
+    /// End of the record of the value in the log of the engine, if it has
+    /// one: the LSN reported for the write, until compaction moves it
+    pub lsn: Option<u64>,
+}
+
+/// The immediate children of a prefix, as listed by
+/// `KvsEngine::list_children`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Children {
+    /// The child "directories", sorted, each ending with the separator
+    pub dirs: Vec<String>,
+    /// The child keys, sorted
+    pub keys: Vec<String>,
+}
+
+/// The keys under a prefix and the bytes they take up, as counted by
+/// `KvsEngine::usage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrefixUsage {
+    /// The prefix, ending with the separator unless empty
+    pub prefix: String,
+    /// Number of keys under the prefix
+    pub keys: u64,
+    /// Bytes the keys take up
+    pub bytes: u64,
+}
+
+/// Count a key of `bytes` bytes in the usage of its prefix.
+pub(crate) fn add_usage(
+    usage: &mut BTreeMap<String, PrefixUsage>,
+    key: &str,
+    separator: &str,
+    depth: usize,
+    bytes: usize,
+) {
+    let end = match separator.is_empty() {
+        true => 0,
+        false => key
+            .match_indices(separator)
+            .take(depth)
+            .last()
+            .map_or(0, |(i, _)| i + separator.len()),
+    };
+    let entry = usage
+        .entry(key[..end].to_owned())
+        .or_insert_with(|| PrefixUsage {
+            prefix: key[..end].to_owned(),
+            ..PrefixUsage::default()
+        });
+    entry.keys += 1;
+    entry.bytes += bytes as u64;
+}
+
+/// Outcome of a hit counted by `KvsEngine::rate`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rate {
+    /// Whether the hit is within the limit
+    pub allowed: bool,
+    /// Number of hits over the last window, this one included, those of
+    /// the previous window weighted by how much of it is covered
+    pub count: u64,
+    /// Time left before the window closes, in milliseconds
+    pub reset_ms: u64,
+}
+
+// the hits counted by `KvsEngine::rate` in the current window, opened at
+// `start` in milliseconds since the Unix epoch, and in the previous one
+struct RateWindows {
+    start: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl RateWindows {
+    fn parse(value: &str) -> Option<RateWindows> {
+        let mut fields = value.split(' ').map(|field| field.parse().ok());
+        Some(RateWindows {
+            start: fields.next()??,
+            current: fields.next()??,
+            previous: fields.next()??,
+        })
+    }
+}
+
+impl Display for RateWindows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.start, self.current, self.previous)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// What a read does with a string key found expired but not swept yet,
+/// see `KvsEngine::sweep_expired`. The kvs engine hides them by default,
+/// the sled engine deletes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiredReads {
+    /// Report the key missing and leave it to the sweeper
+    Hide,
+    /// Delete the key right away, the read paying for the write. A
+    /// read-only kvs store only drops it from its index
+    Delete,
+}
+
+impl FromStr for ExpiredReads {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "hide" => Ok(ExpiredReads::Hide),
+            "delete" => Ok(ExpiredReads::Delete),
+            _ => Err(format!("Unknown expired reads policy: {}", s)),
+        }
+    }
+}
+
+impl Display for ExpiredReads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpiredReads::Hide => write!(f, "hide"),
+            ExpiredReads::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+// the bytes of a value typed as `ValueType::Bytes`
+pub(crate) fn decode_bytes(value: &str) -> Result<Vec<u8>> {
+    BASE64
+        .decode(value)
+        .map_err(|e| KvsError::InvalidValue(format!("not base64: {}", e)))
+}
+
+/// Refuse keys longer than `MAX_KEY_LEN`, or starting with a NUL byte.
+pub(crate) fn check_key(key: &str) -> Result<()> {
+    match key.len() {
+        len if len > MAX_KEY_LEN => Err(KvsError::KeyTooLong {
+            len,
+            max: MAX_KEY_LEN,
+        }),
+        _ if key.starts_with('\0') => Err(KvsError::InvalidCommand(
+            "keys cannot start with a NUL byte".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Resolve the inclusive, possibly negative, `start` and `stop` indexes of
+/// `lrange` against a list of `len` elements.
+pub(crate) fn list_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let resolve = |i: i64| if i < 0 { len + i } else { i };
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len - 1);
+    if start > stop {
+        return 0..0;
+    }
+    start as usize..stop as usize + 1
 }
 
+mod at_rest;
+mod composite;
+#[cfg(feature = "encryption")]
+mod encrypted;
 mod kvs;
+mod marker;
+mod migrate;
+mod mirror;
+mod order;
+mod segment;
+#[cfg(feature = "engine-sled")]
 mod sled;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 
-pub use kvs::KvStore;
+#[cfg(feature = "encryption")]
+pub use at_rest::EncryptionConfig;
+pub use at_rest::ENCRYPTION_FILE;
+pub use composite::CompositeStore;
+#[cfg(feature = "encryption")]
+pub use encrypted::{EncryptedEngine, KeyProvider};
+pub(crate) use kvs::decode_record;
+pub(crate) use kvs::decompress;
+pub use kvs::{KvStore, PinGuard, COMPACTION_FILE, QUARANTINE_FILE};
+pub use marker::{EngineKind, ENGINE_FILE};
+pub use migrate::FORMAT_FILE;
+pub use mirror::MirrorEngine;
+pub use order::{KeyOrder, ORDER_FILE};
+pub(crate) use segment::GenFile;
+#[cfg(feature = "engine-sled")]
 pub use sled::SledStore;
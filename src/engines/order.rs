@@ -0,0 +1,203 @@
+//! The order `KvStore` iterates its keys in, recorded in the `key-order`
+//! file of a data directory when it is not the default.
+
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{KvsError, Result};
+
+/// Name of the file recording the key order of a data directory.
+pub const ORDER_FILE: &str = "key-order";
+
+/// The order of the keys listed and scanned by a `KvStore`, ranges of keys
+/// included: a scan from `a` to `b` returns the keys from `a` to `b` in
+/// this order.
+///
+/// It is chosen when the store is created and recorded along with its
+/// data, later opens keeping it.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum KeyOrder {
+    /// Byte by byte, the order of `str`, the default
+    #[default]
+    Bytewise,
+    /// Byte by byte but for runs of ASCII digits, compared by their numeric
+    /// value, so `item:9` comes before `item:10`
+    Natural,
+    /// An order of the application, recorded by `name`: a store created
+    /// with it can only be opened with `KvStore::open_ordered` and an order
+    /// of the same name. Keys sharing a prefix should sort together for
+    /// `list_children` to group them.
+    Custom {
+        /// Name the order is recorded by
+        name: &'static str,
+        /// Comparison of two keys, a total order
+        cmp: fn(&str, &str) -> Ordering,
+    },
+}
+
+impl KeyOrder {
+    /// Compare two keys.
+    pub fn cmp(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            KeyOrder::Bytewise => a.cmp(b),
+            KeyOrder::Natural => natural_cmp(a, b),
+            KeyOrder::Custom { cmp, .. } => cmp(a, b),
+        }
+    }
+
+    /// Sort `keys` in this order.
+    pub fn sort(&self, keys: &mut [String]) {
+        match self {
+            KeyOrder::Bytewise => keys.sort_unstable(),
+            _ => keys.sort_unstable_by(|a, b| self.cmp(a, b)),
+        }
+    }
+
+    /// Check whether `key` falls within the bounds of `range`, in this
+    /// order.
+    pub fn contains<R: RangeBounds<String>>(&self, range: &R, key: &str) -> bool {
+        let after_start = match range.start_bound() {
+            Bound::Included(start) => self.cmp(start, key).is_le(),
+            Bound::Excluded(start) => self.cmp(start, key).is_lt(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.end_bound() {
+            Bound::Included(end) => self.cmp(key, end).is_le(),
+            Bound::Excluded(end) => self.cmp(key, end).is_lt(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// The name the order is recorded by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyOrder::Bytewise => "bytewise",
+            KeyOrder::Natural => "natural",
+            KeyOrder::Custom { name, .. } => name,
+        }
+    }
+
+    /// Read the name of the order recorded in `dir`. A directory without
+    /// the file is in the default order, `None`.
+    pub(crate) fn read_recorded(dir: &Path) -> Result<Option<String>> {
+        match fs::read_to_string(dir.join(ORDER_FILE)) {
+            Ok(content) => Ok(Some(content.trim().to_owned())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record `self` as the order of `dir`.
+    pub(crate) fn record(&self, dir: &Path) -> Result<()> {
+        fs::write(dir.join(ORDER_FILE), self.name())?;
+        Ok(())
+    }
+
+    /// The order of `dir` given the one it was opened with, if any: the
+    /// recorded one, which `order` must match, or `order` for a directory
+    /// without data yet, recorded when `writable`.
+    pub(crate) fn resolve(
+        dir: &Path,
+        order: Option<KeyOrder>,
+        fresh: bool,
+        writable: bool,
+    ) -> Result<KeyOrder> {
+        match (Self::read_recorded(dir)?, order) {
+            (None, Some(order)) if fresh => {
+                if writable && order != KeyOrder::Bytewise {
+                    order.record(dir)?;
+                }
+                Ok(order)
+            }
+            (None, None) => Ok(KeyOrder::Bytewise),
+            (Some(name), None) => name.parse().map_err(|_| {
+                KvsError::Other(format!(
+                    "{} is in the custom key order {}, to be opened with it",
+                    dir.display(),
+                    name
+                ))
+            }),
+            (recorded, Some(order)) => {
+                let recorded = recorded.unwrap_or_else(|| KeyOrder::Bytewise.name().to_owned());
+                match recorded == order.name() {
+                    true => Ok(order),
+                    false => Err(KvsError::Other(format!(
+                        "{} is in the {} key order, not {}",
+                        dir.display(),
+                        recorded,
+                        order.name()
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for KeyOrder {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Eq for KeyOrder {}
+
+impl FromStr for KeyOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "bytewise" => Ok(KeyOrder::Bytewise),
+            "natural" => Ok(KeyOrder::Natural),
+            _ => Err(format!("Unknown key order: {}", s)),
+        }
+    }
+}
+
+impl Display for KeyOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// compare runs of digits by value, then by length for equal values, so
+// that `01` and `1` are still told apart, and anything else bytewise
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (end_a, end_b) = (digits_end(a, i), digits_end(b, j));
+            let (digits_a, digits_b) = (trim_zeros(&a[i..end_a]), trim_zeros(&b[j..end_b]));
+            let ordering = digits_a
+                .len()
+                .cmp(&digits_b.len())
+                .then_with(|| digits_a.cmp(digits_b))
+                .then_with(|| (end_a - i).cmp(&(end_b - j)));
+            if ordering.is_ne() {
+                return ordering;
+            }
+            (i, j) = (end_a, end_b);
+            continue;
+        }
+        match a[i].cmp(&b[j]) {
+            Ordering::Equal => (i, j) = (i + 1, j + 1),
+            ordering => return ordering,
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+fn digits_end(s: &[u8], from: usize) -> usize {
+    from + s[from..].iter().take_while(|c| c.is_ascii_digit()).count()
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+    &digits[zeros..]
+}
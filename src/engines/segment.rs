@@ -0,0 +1,247 @@
+//! Generation files as `KvStore` reads them: plain, or compressed segments
+//! read block by block through their index, see `format`.
+
+#[cfg(feature = "compression")]
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(feature = "compression")]
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[cfg(feature = "compression")]
+use super::kvs::COMPRESSION_LEVEL;
+use crate::format::SEGMENT_MAGIC;
+#[cfg(feature = "compression")]
+use crate::format::{SegmentFooter, LOG_EXTENSION, SEGMENT_FOOTER_LEN};
+use crate::{KvsError, Result};
+
+// uncompressed length of the blocks segments are written in
+#[cfg(feature = "compression")]
+const BLOCK_LEN: u64 = 64 * 1024;
+
+/// A generation file, read as the generation it holds.
+pub(crate) enum GenFile {
+    Plain(File),
+    #[cfg(feature = "compression")]
+    Segment(Segment),
+}
+
+impl GenFile {
+    /// Open the generation file at `path`, whichever kind it is.
+    pub(crate) fn open(path: &Path) -> Result<GenFile> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; SEGMENT_MAGIC.len()];
+        let segment = match file.read_exact(&mut magic) {
+            Ok(()) => magic == SEGMENT_MAGIC,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e.into()),
+        };
+        file.rewind()?;
+        match segment {
+            false => Ok(GenFile::Plain(file)),
+            #[cfg(feature = "compression")]
+            true => Segment::open(file).map(GenFile::Segment),
+            #[cfg(not(feature = "compression"))]
+            true => Err(KvsError::Other(
+                "the generation is a compressed segment, which takes the compression feature"
+                    .to_owned(),
+            )),
+        }
+    }
+
+    /// The file itself, when its bytes are those of the generation.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub(crate) fn plain(&self) -> Option<&File> {
+        match self {
+            GenFile::Plain(file) => Some(file),
+            #[cfg(feature = "compression")]
+            GenFile::Segment(_) => None,
+        }
+    }
+
+    /// Whether the file is a compressed segment.
+    pub(crate) fn is_segment(&self) -> bool {
+        !matches!(self, GenFile::Plain(_))
+    }
+
+    /// Length of the generation, uncompressed.
+    pub(crate) fn len(&self) -> Result<u64> {
+        match self {
+            GenFile::Plain(file) => Ok(file.metadata()?.len()),
+            #[cfg(feature = "compression")]
+            GenFile::Segment(segment) => Ok(segment.footer.len),
+        }
+    }
+}
+
+impl Read for GenFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            GenFile::Plain(file) => file.read(buf),
+            #[cfg(feature = "compression")]
+            GenFile::Segment(segment) => segment.read(buf),
+        }
+    }
+}
+
+impl Seek for GenFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            GenFile::Plain(file) => file.seek(pos),
+            #[cfg(feature = "compression")]
+            GenFile::Segment(segment) => segment.seek(pos),
+        }
+    }
+}
+
+/// A compressed segment, read as the generation it holds.
+#[cfg(feature = "compression")]
+pub(crate) struct Segment {
+    file: File,
+    footer: SegmentFooter,
+    // offset of the frame of every block, then of the index ending the
+    // last one
+    frames: Vec<u64>,
+    pos: u64,
+    // the block decompressed last, by number
+    block: Option<(u64, Vec<u8>)>,
+}
+
+#[cfg(feature = "compression")]
+impl Segment {
+    fn open(mut file: File) -> Result<Segment> {
+        let size = file.seek(SeekFrom::End(0))?;
+        let damaged = || KvsError::Other("the compressed segment is damaged".to_owned());
+        if size < (SEGMENT_MAGIC.len() + SEGMENT_FOOTER_LEN) as u64 {
+            return Err(damaged());
+        }
+        let mut buf = [0; SEGMENT_FOOTER_LEN];
+        file.seek(SeekFrom::End(-(SEGMENT_FOOTER_LEN as i64)))?;
+        file.read_exact(&mut buf)?;
+        let footer = SegmentFooter::decode(&buf).ok_or_else(damaged)?;
+        let blocks = footer.blocks();
+        let end = blocks
+            .checked_mul(8)
+            .and_then(|index| index.checked_add(SEGMENT_FOOTER_LEN as u64))
+            .and_then(|tail| tail.checked_add(footer.index_at));
+        if end != Some(size) {
+            return Err(damaged());
+        }
+        let mut index = vec![0; blocks as usize * 8];
+        file.seek(SeekFrom::Start(footer.index_at))?;
+        file.read_exact(&mut index)?;
+        let mut frames: Vec<u64> = index
+            .chunks_exact(8)
+            .map(|offset| u64::from_le_bytes(offset.try_into().unwrap()))
+            .collect();
+        frames.push(footer.index_at);
+        if frames[0] != SEGMENT_MAGIC.len() as u64
+            || frames.windows(2).any(|pair| pair[0] >= pair[1])
+        {
+            return Err(damaged());
+        }
+        Ok(Segment {
+            file,
+            footer,
+            frames,
+            pos: 0,
+            block: None,
+        })
+    }
+
+    // the uncompressed bytes of block `n`
+    fn block(&mut self, n: u64) -> io::Result<&[u8]> {
+        if self.block.as_ref().is_none_or(|&(cached, _)| cached != n) {
+            let (start, end) = (self.frames[n as usize], self.frames[n as usize + 1]);
+            let mut frame = vec![0; (end - start) as usize];
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file.read_exact(&mut frame)?;
+            let expected = self
+                .footer
+                .block_len
+                .min(self.footer.len - n * self.footer.block_len);
+            let data = zstd::bulk::decompress(&frame, expected as usize)?;
+            if data.len() as u64 != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("block {} of the compressed segment is cut short", n),
+                ));
+            }
+            self.block = Some((n, data));
+        }
+        Ok(&self.block.as_ref().unwrap().1)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Read for Segment {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.footer.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.pos / self.footer.block_len;
+        let start = (self.pos - n * self.footer.block_len) as usize;
+        let block = &self.block(n)?[start..];
+        let len = block.len().min(buf.len());
+        buf[..len].copy_from_slice(&block[..len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Seek for Segment {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.footer.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the compressed segment",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Rewrite the plain generation file at `path` as a compressed segment,
+/// written next to it then renamed over it.
+#[cfg(feature = "compression")]
+pub(crate) fn compress_file(path: &Path) -> Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", LOG_EXTENSION));
+    let mut src = File::open(path)?;
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    out.write_all(&SEGMENT_MAGIC)?;
+    let mut at = SEGMENT_MAGIC.len() as u64;
+    let mut len = 0;
+    let mut frames = Vec::new();
+    let mut block = Vec::with_capacity(BLOCK_LEN as usize);
+    loop {
+        block.clear();
+        (&mut src).take(BLOCK_LEN).read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        let frame = zstd::bulk::compress(&block, COMPRESSION_LEVEL)?;
+        out.write_all(&frame)?;
+        frames.push(at);
+        at += frame.len() as u64;
+        len += block.len() as u64;
+    }
+    for offset in frames {
+        out.write_all(&offset.to_le_bytes())?;
+    }
+    let footer = SegmentFooter {
+        index_at: at,
+        len,
+        block_len: BLOCK_LEN,
+    };
+    out.write_all(&footer.encode())?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -1,21 +1,60 @@
+use crate::engines::{list_range, ExpiredReads};
 use crate::KvsEngine;
 use crate::KvsError;
 use crate::Result;
+use crate::ValueType;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// type tags of values not set as plain strings
+const VALUE_TYPES_TREE: &str = "value_types";
+// the tag of bytes values, held in base64. Those tagged `bytes` were written
+// before bytes values were encoded and hold their bytes as they are
+const BASE64_BYTES_TAG: &[u8] = b"bytes:base64";
+const RAW_BYTES_TAG: &[u8] = b"bytes";
+// lists, each stored as a JSON array under its key
+const LISTS_TREE: &str = "lists";
+// hash fields, each stored under its own entry, see `field_entry`
+const HASHES_TREE: &str = "hashes";
+// set members, each stored as an empty entry, see `field_entry`
+const SETS_TREE: &str = "sets";
+// expiration time of string keys having one, in ms since the Unix epoch,
+// stored big endian
+const EXPIRES_TREE: &str = "expires";
+// prefix of the trees of a namespace: its string values are stored in
+// `ns/NAME`, and its other trees in `ns/NAME/TREE`
+const NAMESPACE_PREFIX: &str = "ns/";
 
 /// `SledStore` is a key-value store using `sled` as the backend.
+///
+/// Clones share the database and may be used from several threads: the
+/// updates reading what they change, like `incr` or `lpush`, are applied
+/// with compare-and-swap and retried when they race.
+#[derive(Clone)]
 pub struct SledStore {
     db: sled::Db,
+    // the string values of the namespace
+    strings: sled::Tree,
+    namespace: Option<String>,
+    expired_reads: ExpiredReads,
+    // reads which found a string key expired, hiding and deleting it
+    expired_hidden: u64,
+    expired_deleted: u64,
 }
 
 impl KvsEngine for SledStore {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, value.into_bytes()).map(|_| ())?;
-        Ok(())
+        self.set_with_type(key, value, ValueType::String)
     }
 
     fn get(&mut self, key: String) -> Result<Option<String>> {
+        if self.expired_on_read(&key)? {
+            return Ok(None);
+        }
         Ok(self
-            .db
+            .strings
             .get(key)?
             .map(|ivec| ivec.as_ref().to_vec())
             .map(String::from_utf8)
@@ -23,7 +62,306 @@ impl KvsEngine for SledStore {
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        if self.drop_if_expired(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.strings.remove(&key)?.ok_or(KvsError::KeyNotFound)?;
+        self.value_types()?.remove(&key)?;
+        self.expires()?.remove(key)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn set_with_type(&mut self, key: String, value: String, value_type: ValueType) -> Result<()> {
+        value_type
+            .validate(&value)
+            .map_err(KvsError::InvalidValue)?;
+        let value_types = self.value_types()?;
+        match value_type {
+            ValueType::String => value_types.remove(&key)?,
+            ValueType::Bytes => value_types.insert(&key, BASE64_BYTES_TAG)?,
+            _ => value_types.insert(&key, value_type.to_string().into_bytes())?,
+        };
+        self.expires()?.remove(&key)?;
+        self.strings.insert(key, value.into_bytes()).map(|_| ())?;
+        Ok(())
+    }
+
+    fn get_with_type(&mut self, key: String) -> Result<Option<(String, ValueType)>> {
+        if self.expired_on_read(&key)? {
+            return Ok(None);
+        }
+        let value_type = match self.value_types()?.get(&key)? {
+            Some(tag) if tag == BASE64_BYTES_TAG => ValueType::Bytes,
+            // encoded on the way out, like the values written since
+            Some(tag) if tag == RAW_BYTES_TAG => {
+                let value = self.get(key)?.map(|value| BASE64.encode(value));
+                return Ok(value.map(|value| (value, ValueType::Bytes)));
+            }
+            Some(tag) => String::from_utf8(tag.to_vec())?.parse()?,
+            None => ValueType::String,
+        };
+        Ok(self.get(key)?.map(|value| (value, value_type)))
+    }
+
+    fn lpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        self.update_list(&key, |list| {
+            for value in &values {
+                list.insert(0, value.clone());
+            }
+            list.len()
+        })
+    }
+
+    fn rpush(&mut self, key: String, values: Vec<String>) -> Result<usize> {
+        self.update_list(&key, |list| {
+            list.extend(values.iter().cloned());
+            list.len()
+        })
+    }
+
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let mut list = self.load_list(&key)?;
+        let range = list_range(list.len(), start, stop);
+        Ok(list.drain(range).collect())
+    }
+
+    fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        self.update_list(&key, |list| match list.is_empty() {
+            true => None,
+            false => Some(list.remove(0)),
+        })
+    }
+
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.hashes()?
+            .insert(field_entry(&key, &field), value.into_bytes())?;
+        Ok(())
+    }
+
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        Ok(self
+            .hashes()?
+            .get(field_entry(&key, &field))?
+            .map(|ivec| String::from_utf8(ivec.to_vec()))
+            .transpose()?)
+    }
+
+    fn hgetall(&mut self, key: String) -> Result<BTreeMap<String, String>> {
+        let prefix = field_entry(&key, "");
+        let mut fields = BTreeMap::new();
+        for entry in self.hashes()?.scan_prefix(&prefix) {
+            let (entry, value) = entry?;
+            fields.insert(
+                String::from_utf8(entry[prefix.len()..].to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            );
+        }
+        Ok(fields)
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        self.hashes()?
+            .remove(field_entry(&key, &field))?
+            .ok_or(KvsError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn sadd(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        let sets = self.sets()?;
+        let mut added = 0;
+        for member in members {
+            if sets.insert(field_entry(&key, &member), &[])?.is_none() {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    fn srem(&mut self, key: String, members: Vec<String>) -> Result<usize> {
+        let sets = self.sets()?;
+        let mut removed = 0;
+        for member in members {
+            if sets.remove(field_entry(&key, &member))?.is_some() {
+                removed += 1;
+            }
+        }
+        self.db.flush()?;
+        Ok(removed)
+    }
+
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        Ok(self.sets()?.contains_key(field_entry(&key, &member))?)
+    }
+
+    fn smembers(&mut self, key: String) -> Result<BTreeSet<String>> {
+        let prefix = field_entry(&key, "");
+        let mut members = BTreeSet::new();
+        for entry in self.sets()?.scan_prefix(&prefix) {
+            let (entry, _) = entry?;
+            members.insert(String::from_utf8(entry[prefix.len()..].to_vec())?);
+        }
+        Ok(members)
+    }
+
+    // the expiration of the key is kept
+    fn incr(&mut self, key: String, by: i64) -> Result<i64> {
+        self.drop_if_expired(&key)?;
+        loop {
+            let current = self.strings.get(&key)?;
+            let value = match &current {
+                Some(value) => String::from_utf8(value.to_vec())?
+                    .parse::<i64>()
+                    .map_err(|e| KvsError::InvalidValue(format!("not an integer: {}", e)))?,
+                None => 0,
+            };
+            let value = value
+                .checked_add(by)
+                .ok_or_else(|| KvsError::InvalidValue("integer overflow".to_owned()))?;
+            let new = Some(value.to_string().into_bytes());
+            if self.strings.compare_and_swap(&key, current, new)?.is_ok() {
+                self.value_types()?
+                    .insert(&key, ValueType::Int.to_string().into_bytes())?;
+                return Ok(value);
+            }
+        }
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        // every expired key is dropped on the way
+        self.sweep_expired()?;
+        let mut keys = BTreeSet::new();
+        for tree in [&self.strings, &self.lists()?] {
+            for key in tree.iter().keys() {
+                keys.insert(String::from_utf8(key?.to_vec())?);
+            }
+        }
+        for tree in [self.hashes()?, self.sets()?] {
+            for entry in tree.iter().keys() {
+                keys.insert(entry_key(&entry?)?);
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        if self.drop_if_expired(&key)? || !self.strings.contains_key(&key)? {
+            return Ok(false);
+        }
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = now_ms().saturating_add(ttl);
+        self.expires()?.insert(key, &expires_at.to_be_bytes())?;
+        Ok(true)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        if self.drop_if_expired(&key)? {
+            return Ok(None);
+        }
+        Ok(self
+            .expires()?
+            .get(&key)?
+            .map(|at| Duration::from_millis(decode_ms(&at).saturating_sub(now_ms()))))
+    }
+
+    fn metrics(&mut self) -> Result<BTreeMap<String, u64>> {
+        Ok(BTreeMap::from([
+            ("size_on_disk_bytes".to_owned(), self.db.size_on_disk()?),
+            ("trees".to_owned(), self.db.tree_names().len() as u64),
+            ("string_keys".to_owned(), self.strings.len() as u64),
+            ("expired_reads_hidden".to_owned(), self.expired_hidden),
+            ("expired_reads_deleted".to_owned(), self.expired_deleted),
+        ]))
+    }
+
+    fn sweep_expired(&mut self) -> Result<usize> {
+        let now = now_ms();
+        let mut swept = 0;
+        for entry in self.expires()?.iter() {
+            let (key, at) = entry?;
+            if decode_ms(&at) <= now {
+                self.drop_string(&key)?;
+                swept += 1;
+            }
+        }
+        Ok(swept)
+    }
+
+    // UTF-8 sorts like its bytes, the range of keys is that of bytes
+    fn scan(&mut self, range: Range<String>) -> Result<impl Iterator<Item = (String, String)>> {
+        let mut pairs = Vec::new();
+        if range.is_empty() {
+            return Ok(pairs.into_iter());
+        }
+        for entry in self
+            .strings
+            .range(range.start.as_bytes()..range.end.as_bytes())
+        {
+            let (key, value) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            if !self.drop_if_expired(&key)? {
+                pairs.push((key, String::from_utf8(value.to_vec())?));
+            }
+        }
+        Ok(pairs.into_iter())
+    }
+
+    // hashes and sets are stored by key length first, and filtered
+    fn scan_prefix(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = BTreeSet::new();
+        for entry in self.strings.scan_prefix(prefix).keys() {
+            let key = String::from_utf8(entry?.to_vec())?;
+            if !self.drop_if_expired(&key)? {
+                keys.insert(key);
+            }
+        }
+        for key in self.lists()?.scan_prefix(prefix).keys() {
+            keys.insert(String::from_utf8(key?.to_vec())?);
+        }
+        for tree in [self.hashes()?, self.sets()?] {
+            for entry in tree.iter().keys() {
+                let key = entry_key(&entry?)?;
+                if key.starts_with(prefix) {
+                    keys.insert(key);
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn exists(&mut self, key: String) -> Result<bool> {
+        if !self.drop_if_expired(&key)? && self.strings.contains_key(&key)? {
+            return Ok(true);
+        }
+        let prefix = field_entry(&key, "");
+        Ok(self.lists()?.contains_key(&key)?
+            || self
+                .hashes()?
+                .scan_prefix(&prefix)
+                .next()
+                .transpose()?
+                .is_some()
+            || self
+                .sets()?
+                .scan_prefix(&prefix)
+                .next()
+                .transpose()?
+                .is_some())
+    }
+
+    // the fields and members of the key are removed in one batch per tree
+    fn purge(&mut self, key: String) -> Result<()> {
+        self.drop_string(key.as_bytes())?;
+        self.lists()?.remove(&key)?;
+        let prefix = field_entry(&key, "");
+        for tree in [self.hashes()?, self.sets()?] {
+            let mut batch = sled::Batch::default();
+            for entry in tree.scan_prefix(&prefix).keys() {
+                batch.remove(entry?);
+            }
+            tree.apply_batch(batch)?;
+        }
         self.db.flush()?;
         Ok(())
     }
@@ -32,6 +370,185 @@ impl KvsEngine for SledStore {
 impl SledStore {
     /// Create a new `SledStore` from a `sled::Db`.
     pub fn new(db: sled::Db) -> Self {
-        SledStore { db }
+        SledStore {
+            strings: (*db).clone(),
+            db,
+            namespace: None,
+            expired_reads: ExpiredReads::Delete,
+            expired_hidden: 0,
+            expired_deleted: 0,
+        }
+    }
+
+    /// Only hides string keys found expired by reads, with
+    /// `ExpiredReads::Hide`, leaving them to `sweep_expired` rather than
+    /// deleting them right away. Writes to an expired key still drop it.
+    pub fn expired_reads(mut self, policy: ExpiredReads) -> Self {
+        self.expired_reads = policy;
+        self
+    }
+
+    /// A store over the namespace `name` of the same database, whose keys
+    /// are apart from those of this store and of every other namespace.
+    /// Each namespace keeps its data in sled trees of its own. Names may
+    /// not be empty or hold a `/`.
+    pub fn namespace(&self, name: &str) -> Result<SledStore> {
+        if name.is_empty() || name.contains('/') {
+            return Err(KvsError::InvalidCommand(format!(
+                "invalid namespace {:?}",
+                name
+            )));
+        }
+        Ok(SledStore {
+            strings: self.db.open_tree(format!("{}{}", NAMESPACE_PREFIX, name))?,
+            db: self.db.clone(),
+            namespace: Some(name.to_owned()),
+            expired_reads: self.expired_reads,
+            expired_hidden: 0,
+            expired_deleted: 0,
+        })
+    }
+
+    /// The namespaces ever opened in the database, sorted.
+    pub fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for tree in self.db.tree_names() {
+            let Some(name) = tree.strip_prefix(NAMESPACE_PREFIX.as_bytes()) else {
+                continue;
+            };
+            if !name.contains(&b'/') {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // the tree `name` of the namespace
+    fn tree(&self, name: &str) -> Result<sled::Tree> {
+        Ok(match &self.namespace {
+            Some(namespace) => self
+                .db
+                .open_tree(format!("{}{}/{}", NAMESPACE_PREFIX, namespace, name))?,
+            None => self.db.open_tree(name)?,
+        })
+    }
+
+    fn value_types(&self) -> Result<sled::Tree> {
+        self.tree(VALUE_TYPES_TREE)
+    }
+
+    fn lists(&self) -> Result<sled::Tree> {
+        self.tree(LISTS_TREE)
+    }
+
+    fn hashes(&self) -> Result<sled::Tree> {
+        self.tree(HASHES_TREE)
+    }
+
+    fn sets(&self) -> Result<sled::Tree> {
+        self.tree(SETS_TREE)
+    }
+
+    fn expires(&self) -> Result<sled::Tree> {
+        self.tree(EXPIRES_TREE)
+    }
+
+    // whether a read finds `key` expired, then hidden or dropped as
+    // configured
+    fn expired_on_read(&mut self, key: &str) -> Result<bool> {
+        match self.expired_reads {
+            ExpiredReads::Hide => {
+                let expired = self
+                    .expires()?
+                    .get(key)?
+                    .is_some_and(|at| decode_ms(&at) <= now_ms());
+                self.expired_hidden += expired as u64;
+                Ok(expired)
+            }
+            ExpiredReads::Delete => {
+                let dropped = self.drop_if_expired(key)?;
+                self.expired_deleted += dropped as u64;
+                Ok(dropped)
+            }
+        }
+    }
+
+    // drop the string value of `key` if it expired, returning whether it did
+    fn drop_if_expired(&self, key: &str) -> Result<bool> {
+        match self.expires()?.get(key)? {
+            Some(at) if decode_ms(&at) <= now_ms() => {
+                self.drop_string(key.as_bytes())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn drop_string(&self, key: &[u8]) -> Result<()> {
+        self.strings.remove(key)?;
+        self.value_types()?.remove(key)?;
+        self.expires()?.remove(key)?;
+        Ok(())
     }
+
+    fn load_list(&self, key: &str) -> Result<Vec<String>> {
+        match self.lists()?.get(key)? {
+            Some(list) => Ok(serde_json::from_slice(&list)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // apply `update` to the list under `key`, again if another handle on
+    // the store changed the list meanwhile; an empty list is dropped, as if
+    // it never existed
+    fn update_list<T>(
+        &self,
+        key: &str,
+        mut update: impl FnMut(&mut Vec<String>) -> T,
+    ) -> Result<T> {
+        let lists = self.lists()?;
+        loop {
+            let current = lists.get(key)?;
+            let mut list: Vec<String> = match &current {
+                Some(list) => serde_json::from_slice(list)?,
+                None => Vec::new(),
+            };
+            let out = update(&mut list);
+            let new = match list.is_empty() {
+                true => None,
+                false => Some(serde_json::to_vec(&list)?),
+            };
+            if lists.compare_and_swap(key, current, new)?.is_ok() {
+                return Ok(out);
+            }
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn decode_ms(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().unwrap_or_default())
+}
+
+// Entry of `field` of hash `key`, or of a set member: the key length, then
+// the key, then the field, so the fields of a hash share a prefix and keys
+// cannot collide.
+fn field_entry(key: &str, field: &str) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(4 + key.len() + field.len());
+    entry.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    entry.extend_from_slice(key.as_bytes());
+    entry.extend_from_slice(field.as_bytes());
+    entry
+}
+
+// the hash or set key of an entry built by `field_entry`
+fn entry_key(entry: &[u8]) -> Result<String> {
+    let len = u32::from_be_bytes(entry[..4].try_into().unwrap()) as usize;
+    Ok(String::from_utf8(entry[4..4 + len].to_vec())?)
 }
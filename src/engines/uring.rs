@@ -0,0 +1,87 @@
+//! Batched positional reads through io_uring, for `KvStore`.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+
+/// Number of reads submitted with a single syscall.
+const ENTRIES: u32 = 64;
+
+/// An io_uring instance reading ranges of generation files.
+pub(crate) struct Ring {
+    ring: IoUring,
+}
+
+impl Ring {
+    /// Set up a ring. This fails on kernels without io_uring, or where it
+    /// is disabled, in which case reads go through the usual syscalls.
+    pub(crate) fn new() -> io::Result<Ring> {
+        Ok(Ring {
+            ring: IoUring::new(ENTRIES)?,
+        })
+    }
+
+    /// Read every `(file, offset, len)` range, submitting up to `ENTRIES`
+    /// reads per syscall.
+    pub(crate) fn read_all(&mut self, reads: &[(&File, u64, usize)]) -> io::Result<Vec<Vec<u8>>> {
+        let mut bufs: Vec<Vec<u8>> = reads.iter().map(|&(_, _, len)| vec![0; len]).collect();
+        for start in (0..reads.len()).step_by(ENTRIES as usize) {
+            let end = (start + ENTRIES as usize).min(reads.len());
+            for i in start..end {
+                let (file, offset, len) = reads[i];
+                let entry = opcode::Read::new(
+                    types::Fd(file.as_raw_fd()),
+                    bufs[i].as_mut_ptr(),
+                    len as u32,
+                )
+                .offset(offset)
+                .build()
+                .user_data(i as u64);
+                // SAFETY: the buffer is neither moved nor freed until the
+                // read completes, waited for below
+                unsafe { self.ring.submission().push(&entry) }
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            }
+
+            let submitted = end - start;
+            loop {
+                match self.ring.submit_and_wait(submitted) {
+                    Ok(_) => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        // reads may still be in flight: leak the buffers
+                        // rather than let the kernel write into freed memory
+                        std::mem::forget(bufs);
+                        return Err(e);
+                    }
+                }
+            }
+
+            // drain every completion before reporting an error, so none is
+            // left over for the next batch
+            let mut result = Ok(());
+            let completed: Vec<(usize, i32)> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+                .collect();
+            for (i, res) in completed {
+                let (file, offset, len) = reads[i];
+                if res < 0 {
+                    result = Err(io::Error::from_raw_os_error(-res));
+                } else if (res as usize) < len {
+                    // finish short reads the usual way
+                    let read = res as usize;
+                    if let Err(e) = file.read_exact_at(&mut bufs[i][read..], offset + read as u64) {
+                        result = Err(e);
+                    }
+                }
+            }
+            result?;
+        }
+        Ok(bufs)
+    }
+}
@@ -1,3 +1,9 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Keys longer than this many characters are cut short in error messages.
+const CONTEXT_KEY_LEN: usize = 64;
+
 /// Error types for kvs
 #[derive(Debug)]
 pub enum KvsError {
@@ -10,11 +16,291 @@ pub enum KvsError {
     /// Invalid command
     InvalidCommand(String),
     /// Sled error
+    #[cfg(feature = "engine-sled")]
     Sled(sled::Error),
     /// Utf8 error
     Utf8(std::string::FromUtf8Error),
+    /// The server only serves reads at the moment
+    ReadOnly,
+    /// The value does not match its declared type
+    InvalidValue(String),
+    /// The key belongs to a hash slot served by another node of the cluster
+    Moved {
+        /// The hash slot of the key
+        slot: u16,
+        /// Address of the node serving the slot
+        addr: String,
+    },
+    /// The slot of the key is being migrated and the key already moved to
+    /// another node, to be asked for this request only
+    Ask {
+        /// The hash slot of the key
+        slot: u16,
+        /// Address of the node the key moved to
+        addr: String,
+    },
+    /// The fencing token of the connection was superseded by a newer one
+    Fenced {
+        /// The token used by the connection
+        token: u64,
+        /// The latest token handed out by the server
+        current: u64,
+    },
+    /// The server refuses writes, its disk being almost full
+    DiskFull {
+        /// Free space on the disk of the data directory, in bytes
+        free: u64,
+        /// Free space writes need, in bytes
+        threshold: u64,
+    },
+    /// The server shares its data directory with others and another one,
+    /// holding the lease on it, serves writes
+    NotLeader {
+        /// Address of the server holding the lease, if known
+        leader: Option<String>,
+    },
+    /// The data directory holds data of another engine
+    WrongEngine {
+        /// The engine asked for
+        expected: crate::EngineKind,
+        /// The engine of the data
+        found: crate::EngineKind,
+    },
+    /// The engine marker of the data directory disagrees with its data
+    EngineMarker {
+        /// The engine recorded in the marker
+        marker: crate::EngineKind,
+        /// The engine of the data
+        found: crate::EngineKind,
+    },
+    /// The data directory was written by a newer release, in a format
+    /// this one cannot read
+    FormatVersion {
+        /// The format version of the data
+        found: u32,
+        /// The latest format version this release reads
+        supported: u32,
+    },
+    /// The key is longer than the engine accepts
+    KeyTooLong {
+        /// Length of the key, in bytes
+        len: usize,
+        /// Length of the longest key accepted, in bytes
+        max: usize,
+    },
+    /// The changes after an LSN were asked for, but compaction dropped
+    /// part of the log since
+    LsnCompacted {
+        /// The LSN asked for
+        lsn: u64,
+        /// The oldest LSN the log still holds the changes after
+        oldest: u64,
+    },
+    /// A record of the log does not match its checksum, damaged on disk
+    Corruption {
+        /// The generation of the file holding the record
+        gen: u64,
+        /// Offset of the record in the file
+        offset: u64,
+    },
+    /// The address of the server could not be resolved, or resolved to no
+    /// address
+    Resolve(std::io::Error),
     /// Other error
     Other(String),
+    /// An error of the engine, with where it happened
+    Context {
+        /// The error
+        source: Box<KvsError>,
+        /// Where it happened
+        context: ErrorContext,
+    },
+}
+
+/// Where an engine error happened: the operation, key, generation and file
+/// involved, those known. Attached with `KvsError::with_key` and the like.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The operation, e.g. `get` or `compact`
+    pub op: Option<&'static str>,
+    /// The key operated on
+    pub key: Option<String>,
+    /// The generation of the log read or written
+    pub gen: Option<u64>,
+    /// The file read or written
+    pub path: Option<PathBuf>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(op) = self.op {
+            parts.push(format!("op {}", op));
+        }
+        if let Some(key) = &self.key {
+            match key.char_indices().nth(CONTEXT_KEY_LEN) {
+                Some((end, _)) => parts.push(format!("key {:?}...", &key[..end])),
+                None => parts.push(format!("key {:?}", key)),
+            }
+        }
+        if let Some(gen) = self.gen {
+            parts.push(format!("generation {}", gen));
+        }
+        if let Some(path) = &self.path {
+            parts.push(format!("file {}", path.display()));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl KvsError {
+    /// Record the operation the error happened in, see `ErrorContext`.
+    /// Like the other `with_` methods, it only applies to errors of the
+    /// layers below the engine, IO, decoding and the like: the others are
+    /// returned as they are, to be matched on. What is already recorded is
+    /// kept, being the closest to the error.
+    pub fn with_op(self, op: &'static str) -> KvsError {
+        self.with_context(|context| {
+            context.op.get_or_insert(op);
+        })
+    }
+
+    /// Record the key the error happened on, see `with_op`.
+    pub fn with_key(self, key: &str) -> KvsError {
+        self.with_context(|context| {
+            context.key.get_or_insert_with(|| key.to_owned());
+        })
+    }
+
+    /// Record the generation of the log the error happened in, see
+    /// `with_op`.
+    pub fn with_gen(self, gen: u64) -> KvsError {
+        self.with_context(|context| {
+            context.gen.get_or_insert(gen);
+        })
+    }
+
+    /// Record the file the error happened in, see `with_op`.
+    pub fn with_path(self, path: impl Into<PathBuf>) -> KvsError {
+        self.with_context(|context| {
+            context.path.get_or_insert_with(|| path.into());
+        })
+    }
+
+    /// Where the error happened, if recorded.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            KvsError::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    fn with_context(self, add: impl FnOnce(&mut ErrorContext)) -> KvsError {
+        let (source, mut context) = match self {
+            KvsError::Context { source, context } => (source, context),
+            KvsError::Io(_) | KvsError::Serde(_) | KvsError::Utf8(_) | KvsError::Other(_) => {
+                (Box::new(self), ErrorContext::default())
+            }
+            #[cfg(feature = "engine-sled")]
+            KvsError::Sled(_) => (Box::new(self), ErrorContext::default()),
+            _ => return self,
+        };
+        add(&mut context);
+        KvsError::Context { source, context }
+    }
+
+    /// Rebuild an error from the message a server sent over the wire.
+    ///
+    /// Errors travel as their `Display` text so older clients can still
+    /// show them; the typed variants are recovered from the known messages,
+    /// dropping the context a server may append to them, the others keep it.
+    #[cfg(feature = "client")]
+    pub(crate) fn from_remote(remote: String) -> KvsError {
+        let message = crate::protocol::strip_error_context(&remote);
+        if message == KvsError::KeyNotFound.to_string() {
+            KvsError::KeyNotFound
+        } else if message == KvsError::ReadOnly.to_string() {
+            KvsError::ReadOnly
+        } else if let Some(reason) = message.strip_prefix("Invalid value: ") {
+            KvsError::InvalidValue(reason.to_owned())
+        } else if let Some((slot, addr)) = parse_redirect(message, "MOVED ") {
+            KvsError::Moved { slot, addr }
+        } else if let Some((slot, addr)) = parse_redirect(message, "ASK ") {
+            KvsError::Ask { slot, addr }
+        } else if let Some((token, current)) = parse_fenced(message) {
+            KvsError::Fenced { token, current }
+        } else if let Some((free, threshold)) = parse_disk_full(message) {
+            KvsError::DiskFull { free, threshold }
+        } else if let Some((len, max)) = parse_key_too_long(message) {
+            KvsError::KeyTooLong { len, max }
+        } else if let Some((lsn, oldest)) = parse_lsn_compacted(message) {
+            KvsError::LsnCompacted { lsn, oldest }
+        } else if let Some((gen, offset)) = parse_corruption(message) {
+            KvsError::Corruption { gen, offset }
+        } else if let Some(rest) = message.strip_prefix("Not the leader") {
+            KvsError::NotLeader {
+                leader: rest.strip_prefix(", writes go to ").map(str::to_owned),
+            }
+        } else {
+            KvsError::Other(remote)
+        }
+    }
+}
+
+// the slot and address of a `MOVED` or `ASK` message
+#[cfg(feature = "client")]
+fn parse_redirect(message: &str, prefix: &str) -> Option<(u16, String)> {
+    let (slot, addr) = message.strip_prefix(prefix)?.split_once(' ')?;
+    Some((slot.parse().ok()?, addr.to_owned()))
+}
+
+// the tokens of a `KvsError::Fenced` message
+#[cfg(feature = "client")]
+fn parse_fenced(message: &str) -> Option<(u64, u64)> {
+    let (token, current) = message
+        .strip_prefix("Stale fencing token ")?
+        .strip_suffix(')')?
+        .split_once(" (current ")?;
+    Some((token.parse().ok()?, current.parse().ok()?))
+}
+
+// the sizes of a `KvsError::DiskFull` message
+#[cfg(feature = "client")]
+fn parse_disk_full(message: &str) -> Option<(u64, u64)> {
+    let (free, threshold) = message
+        .strip_prefix("Disk almost full: ")?
+        .strip_suffix(" bytes")?
+        .split_once(" bytes free, writes need ")?;
+    Some((free.parse().ok()?, threshold.parse().ok()?))
+}
+
+// the lengths of a `KvsError::KeyTooLong` message
+#[cfg(feature = "client")]
+fn parse_key_too_long(message: &str) -> Option<(usize, usize)> {
+    let (len, max) = message
+        .strip_prefix("Key too long: ")?
+        .strip_suffix(" bytes")?
+        .split_once(" bytes, at most ")?;
+    Some((len.parse().ok()?, max.parse().ok()?))
+}
+
+// the LSNs of a `KvsError::LsnCompacted` message
+#[cfg(feature = "client")]
+fn parse_lsn_compacted(message: &str) -> Option<(u64, u64)> {
+    let (lsn, oldest) = message
+        .strip_prefix("LSN ")?
+        .split_once(" was compacted away, the log starts at ")?;
+    Some((lsn.parse().ok()?, oldest.parse().ok()?))
+}
+
+// where the record of a `KvsError::Corruption` message lies
+#[cfg(feature = "client")]
+fn parse_corruption(message: &str) -> Option<(u64, u64)> {
+    let (gen, offset) = message
+        .strip_prefix("Corrupt record at ")?
+        .strip_suffix(", not matching its checksum")?
+        .split_once(':')?;
+    Some((gen.parse().ok()?, offset.parse().ok()?))
 }
 
 impl From<std::io::Error> for KvsError {
@@ -35,6 +321,7 @@ impl From<String> for KvsError {
     }
 }
 
+#[cfg(feature = "engine-sled")]
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> KvsError {
         KvsError::Sled(err)
@@ -47,16 +334,66 @@ impl From<std::string::FromUtf8Error> for KvsError {
     }
 }
 
-impl std::fmt::Display for KvsError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             KvsError::Io(e) => write!(f, "IO error: {}", e),
             KvsError::Serde(e) => write!(f, "Serde error: {}", e),
             KvsError::KeyNotFound => write!(f, "Key not found"),
             KvsError::InvalidCommand(s) => write!(f, "Invalid command: {}", s),
+            #[cfg(feature = "engine-sled")]
             KvsError::Sled(e) => write!(f, "Sled error: {}", e),
             KvsError::Utf8(e) => write!(f, "Utf8 error: {}", e),
+            KvsError::ReadOnly => write!(f, "Server is read-only"),
+            KvsError::InvalidValue(s) => write!(f, "Invalid value: {}", s),
+            KvsError::Moved { slot, addr } => write!(f, "MOVED {} {}", slot, addr),
+            KvsError::Ask { slot, addr } => write!(f, "ASK {} {}", slot, addr),
+            KvsError::Fenced { token, current } => {
+                write!(f, "Stale fencing token {} (current {})", token, current)
+            }
+            KvsError::DiskFull { free, threshold } => write!(
+                f,
+                "Disk almost full: {} bytes free, writes need {} bytes",
+                free, threshold
+            ),
+            KvsError::NotLeader { leader: None } => write!(f, "Not the leader"),
+            KvsError::NotLeader {
+                leader: Some(leader),
+            } => write!(f, "Not the leader, writes go to {}", leader),
+            KvsError::WrongEngine { expected, found } => {
+                write!(
+                    f,
+                    "Data is {} data, cannot open it with {}",
+                    found, expected
+                )
+            }
+            KvsError::EngineMarker { marker, found } => write!(
+                f,
+                "Engine marker says {} but the data is {} data, \
+                 repair it with `kvs-admin fix-engine --actual {}`",
+                marker, found, found
+            ),
+            KvsError::FormatVersion { found, supported } => write!(
+                f,
+                "Data is in format version {}, this release reads up to {}",
+                found, supported
+            ),
+            KvsError::KeyTooLong { len, max } => {
+                write!(f, "Key too long: {} bytes, at most {} bytes", len, max)
+            }
+            KvsError::LsnCompacted { lsn, oldest } => write!(
+                f,
+                "LSN {} was compacted away, the log starts at {}",
+                lsn, oldest
+            ),
+            KvsError::Corruption { gen, offset } => write!(
+                f,
+                "Corrupt record at {}:{}, not matching its checksum",
+                gen, offset
+            ),
+            KvsError::Resolve(e) => write!(f, "Cannot resolve address: {}", e),
             KvsError::Other(s) => write!(f, "Unknown error: {}", s),
+            KvsError::Context { source, context } => write!(f, "{} ({})", source, context),
         }
     }
 }
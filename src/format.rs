@@ -0,0 +1,1153 @@
+//! The on-disk log format of `KvStore`, without any IO.
+//!
+//! A data directory holds generation files named `<gen>.log`. Each file is
+//! a sequence of records, one JSON object per line; replaying the
+//! generations in ascending order, the last record of a key wins.
+//!
+//! Every line ends with a tab and the CRC-32 of its record in 8 hex
+//! digits, checked whenever the record is read, so a damaged record is
+//! told apart from one that does not decode. Records written before
+//! checksums have none, and are read as they are. Lists,
+//! hashes and sets live in their own keyspaces; lists are rebuilt by
+//! applying their push and pop records in order, hashes field by field and
+//! sets member by member.
+//!
+//! A string key removed with a trash window gets a `Trash` record instead of
+//! a `Remove`: its last `Set` stays relevant until the window closes, so the
+//! key can be undeleted by writing that value again.
+//!
+//! The expiration time of a string key is set along with its value, and
+//! changed afterwards by `Touch` records, which spares rewriting the value.
+//!
+//! A large string value may be split across `Chunk` records, written one
+//! after the other and directly followed by a `Manifest` standing for the
+//! `Set` of the whole value. Chunks not followed by their manifest are left
+//! over by a write cut short, and ignored.
+//!
+//! The value of a `Set` typed as JSON is written as the document itself
+//! rather than as a string holding it, when that keeps it on one line and
+//! reads back the same bytes: JSON-heavy data is not escaped twice, and
+//! the fields of a document can be reached in the log. Decoding takes
+//! either form.
+//!
+//! A value typed as bytes holds its bytes encoded in standard, padded
+//! base64, since records are text.
+//!
+//! A large `Set` value may instead be compressed, in a `CompressedSet`
+//! holding the zstd frame of its UTF-8 bytes, encoded in base64. Reading
+//! it back takes a zstd decoder, which this module leaves to the reader.
+//!
+//! A write of a string key scheduled for later is kept in a `Schedule`
+//! record until it runs. Running it appends the write, then an
+//! `Unschedule` record retiring the schedule.
+//!
+//! A generation file may instead hold binary records, when it starts with
+//! `BINARY_MAGIC`: every record is then a frame made of the length of its
+//! payload and the CRC-32 of the payload, both 32-bit little-endian, then
+//! the payload, the `BinaryLog` of the record encoded with bincode 1 and
+//! its default options. Binary records are about half the size of JSON
+//! lines, and hold any value without escaping it. Both kinds of
+//! generations can live in the same directory, compaction rewriting the
+//! live records in the encoding the store writes.
+//!
+//! A store encrypted at rest writes generation files starting with
+//! `ENCRYPTED_MAGIC` instead, holding binary frames whose payload is
+//! sealed: a random 96-bit nonce, then the `BinaryLog` encrypted with
+//! AES-256-GCM. Decrypting them is left to the reader, along with the key.
+//!
+//! Compaction may instead write the generation as a compressed segment,
+//! for cold data taking less space than with values compressed one by
+//! one. The file then starts with `SEGMENT_MAGIC`, followed by the
+//! generation as it would be written otherwise, cut in blocks compressed
+//! into one zstd frame each; then the block index, the offset of the frame
+//! of every block in the file, 64-bit little-endian; then a
+//! `SegmentFooter`. Records are found at their offset in the generation
+//! uncompressed, which is what the index and hints point to.
+//!
+//! Compaction also writes a hint file, `<gen>.hint`, next to the
+//! generation it writes: one JSON object per line, a `Hint::Header` then
+//! where every live record of the generation lies. Opening the store loads
+//! the index from the hints of its oldest generation instead of replaying
+//! it. Hints are only an accelerator: a generation without them, or whose
+//! length differs from the one they describe, is replayed.
+//!
+//! This module only depends on `core`, `alloc`, `serde` and `serde_json`,
+//! so tools that cannot pull the full stack (wasm, embedded) can decode a
+//! log they read by their own means.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+use core::ops::Range;
+use core::str::FromStr;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::value::RawValue;
+
+/// Version of the format written by this release, recorded in the data
+/// directory. Bump it along with a migration from the previous one
+/// whenever the records change in a way older releases cannot read.
+pub const FORMAT_VERSION: u32 = 10;
+
+/// Extension of generation files.
+pub const LOG_EXTENSION: &str = "log";
+
+/// Extension of hint files.
+pub const HINT_EXTENSION: &str = "hint";
+
+/// A record of the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvLog {
+    /// `key` was set to `value`
+    #[serde(serialize_with = "serialize_set")]
+    Set {
+        /// The key
+        key: String,
+        /// The value, see `inline_json` for how JSON documents are written
+        #[serde(deserialize_with = "deserialize_value")]
+        value: String,
+        /// The type tag of the value, omitted for plain strings which keeps
+        /// those records readable by older versions
+        #[serde(default)]
+        value_type: ValueType,
+        /// When the key expires, in milliseconds since the Unix epoch
+        #[serde(default)]
+        expires_at: Option<u64>,
+        /// When the value was written, in milliseconds since the Unix
+        /// epoch, if the store records it
+        #[serde(default)]
+        written_at: Option<u64>,
+    },
+    /// `key` was removed
+    Remove {
+        /// The key
+        key: String,
+    },
+    /// `key` was removed, its last value being kept until `until`, in
+    /// milliseconds since the Unix epoch, to be undeleted
+    Trash {
+        /// The key
+        key: String,
+        /// When the value is dropped for good
+        until: u64,
+    },
+    /// `key` was evicted by a store in cache mode, to stay within its
+    /// byte budget
+    Evict {
+        /// The key
+        key: String,
+    },
+    /// The expiration time of string key `key` changed, its value staying
+    /// as last set
+    Touch {
+        /// The key
+        key: String,
+        /// When the key expires, in milliseconds since the Unix epoch,
+        /// `None` for never
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    /// A piece of the next value of string key `key`, see `Manifest`
+    Chunk {
+        /// The key
+        key: String,
+        /// The piece of the value
+        data: String,
+    },
+    /// `key` was set to the chunks right before this record, joined
+    Manifest {
+        /// The key
+        key: String,
+        /// Size of the chunk records as written, newlines or frame headers
+        /// included, in bytes
+        span: u64,
+        /// The type tag of the value
+        #[serde(default, skip_serializing_if = "ValueType::is_string")]
+        value_type: ValueType,
+        /// When the key expires, in milliseconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// When the value was written, in milliseconds since the Unix
+        /// epoch, if the store records it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+    },
+    /// `value` was pushed at the head of list `key`
+    LPush {
+        /// The key of the list
+        key: String,
+        /// The pushed element
+        value: String,
+    },
+    /// `value` was pushed at the tail of list `key`
+    RPush {
+        /// The key of the list
+        key: String,
+        /// The pushed element
+        value: String,
+    },
+    /// The head of list `key` was popped
+    LPop {
+        /// The key of the list
+        key: String,
+    },
+    /// `field` of hash `key` was set to `value`
+    HSet {
+        /// The key of the hash
+        key: String,
+        /// The field
+        field: String,
+        /// The value of the field
+        value: String,
+    },
+    /// `field` was removed from hash `key`
+    HDel {
+        /// The key of the hash
+        key: String,
+        /// The field
+        field: String,
+    },
+    /// `member` was added to set `key`
+    SAdd {
+        /// The key of the set
+        key: String,
+        /// The member
+        member: String,
+    },
+    /// `member` was removed from set `key`
+    SRem {
+        /// The key of the set
+        key: String,
+        /// The member
+        member: String,
+    },
+    /// String key `key` is to be set to `value` at `at`, in milliseconds
+    /// since the Unix epoch, or removed if there is no value
+    Schedule {
+        /// The key
+        key: String,
+        /// When the write runs
+        at: u64,
+        /// The value to set, `None` to remove the key
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+    },
+    /// The write scheduled on `key` at `at` ran
+    Unschedule {
+        /// The key
+        key: String,
+        /// When the write was scheduled
+        at: u64,
+    },
+    /// `key` was set to a value compressed with zstd, standing for the
+    /// `Set` of the value
+    CompressedSet {
+        /// The key
+        key: String,
+        /// The zstd frame of the value, encoded in standard, padded base64
+        data: String,
+        /// The type tag of the value
+        #[serde(default, skip_serializing_if = "ValueType::is_string")]
+        value_type: ValueType,
+        /// When the key expires, in milliseconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// When the value was written, in milliseconds since the Unix
+        /// epoch, if the store records it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written_at: Option<u64>,
+    },
+}
+
+impl KvLog {
+    /// The key the record is about.
+    pub fn key(&self) -> &str {
+        match self {
+            KvLog::Set { key, .. }
+            | KvLog::Remove { key }
+            | KvLog::Trash { key, .. }
+            | KvLog::Evict { key }
+            | KvLog::Touch { key, .. }
+            | KvLog::Chunk { key, .. }
+            | KvLog::Manifest { key, .. }
+            | KvLog::LPush { key, .. }
+            | KvLog::RPush { key, .. }
+            | KvLog::LPop { key }
+            | KvLog::HSet { key, .. }
+            | KvLog::HDel { key, .. }
+            | KvLog::SAdd { key, .. }
+            | KvLog::SRem { key, .. }
+            | KvLog::Schedule { key, .. }
+            | KvLog::Unschedule { key, .. }
+            | KvLog::CompressedSet { key, .. } => key,
+        }
+    }
+
+    /// The key the record is about, to be replaced.
+    pub(crate) fn key_mut(&mut self) -> &mut String {
+        match self {
+            KvLog::Set { key, .. }
+            | KvLog::Remove { key }
+            | KvLog::Trash { key, .. }
+            | KvLog::Evict { key }
+            | KvLog::Touch { key, .. }
+            | KvLog::Chunk { key, .. }
+            | KvLog::Manifest { key, .. }
+            | KvLog::LPush { key, .. }
+            | KvLog::RPush { key, .. }
+            | KvLog::LPop { key }
+            | KvLog::HSet { key, .. }
+            | KvLog::HDel { key, .. }
+            | KvLog::SAdd { key, .. }
+            | KvLog::SRem { key, .. }
+            | KvLog::Schedule { key, .. }
+            | KvLog::Unschedule { key, .. }
+            | KvLog::CompressedSet { key, .. } => key,
+        }
+    }
+
+    /// Encode the record as a line of the log, including its checksum and
+    /// the trailing newline.
+    pub fn encode(&self) -> serde_json::Result<Vec<u8>> {
+        let mut buf = serde_json::to_vec(self)?;
+        append_checksum(&mut buf);
+        Ok(buf)
+    }
+
+    /// Decode a record from a line of the log, checking its checksum if it
+    /// has one. Surrounding whitespace, including the trailing newline, is
+    /// ignored.
+    pub fn decode(line: &[u8]) -> serde_json::Result<KvLog> {
+        let record = verify_checksum(line).map_err(serde::de::Error::custom)?;
+        serde_json::from_slice(record)
+    }
+}
+
+/// The document to write in place of a `Set` value of type `value_type`,
+/// if it is written as is: a JSON document on one line, without
+/// surrounding whitespace, which is not a string itself, since decoding
+/// would take it for an encoded value.
+pub fn inline_json(value: &str, value_type: ValueType) -> Option<&RawValue> {
+    if value_type != ValueType::Json
+        || value.starts_with('"')
+        || value.trim() != value
+        || value.contains(['\n', '\r'])
+    {
+        return None;
+    }
+    serde_json::from_str(value).ok()
+}
+
+// the fields of a `Set`, its value written as is if it is inline JSON,
+// and those left at their default omitted
+fn serialize_set<S: Serializer>(
+    key: &str,
+    value: &str,
+    value_type: &ValueType,
+    expires_at: &Option<u64>,
+    written_at: &Option<u64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Value<'a> {
+        Inline(&'a RawValue),
+        Encoded(&'a str),
+    }
+
+    #[derive(Serialize)]
+    struct Set<'a> {
+        key: &'a str,
+        value: Value<'a>,
+        #[serde(skip_serializing_if = "ValueType::is_string")]
+        value_type: &'a ValueType,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: &'a Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        written_at: &'a Option<u64>,
+    }
+
+    Set {
+        key,
+        value: match inline_json(value, *value_type) {
+            Some(document) => Value::Inline(document),
+            None => Value::Encoded(value),
+        },
+        value_type,
+        expires_at,
+        written_at,
+    }
+    .serialize(serializer)
+}
+
+// a `Set` value, either encoded as a string or an inline JSON document
+fn deserialize_value<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    let raw = Box::<RawValue>::deserialize(deserializer)?;
+    match raw.get().starts_with('"') {
+        true => serde_json::from_str(raw.get()).map_err(serde::de::Error::custom),
+        false => Ok(String::from(raw.get())),
+    }
+}
+
+/// Join the records of a string value into a `Set`: either a `Set` alone,
+/// or chunks followed by their manifest. `records` is read up to the `Set`
+/// or the manifest, so the chunks can come straight from a file.
+pub fn join_chunks<E: From<serde_json::Error>>(
+    records: impl IntoIterator<Item = Result<KvLog, E>>,
+) -> Result<KvLog, E> {
+    let mut value = String::new();
+    let mut chunked = false;
+    for record in records {
+        match record? {
+            KvLog::Chunk { data, .. } => {
+                value.push_str(&data);
+                chunked = true;
+            }
+            KvLog::Manifest {
+                key,
+                value_type,
+                expires_at,
+                written_at,
+                ..
+            } => {
+                return Ok(KvLog::Set {
+                    key,
+                    value,
+                    value_type,
+                    expires_at,
+                    written_at,
+                })
+            }
+            log if !chunked => return Ok(log),
+            log => {
+                return Err(E::from(serde::de::Error::custom(format!(
+                    "chunks of key {} followed by {:?}",
+                    log.key(),
+                    log
+                ))))
+            }
+        }
+    }
+    Err(E::from(serde::de::Error::custom(
+        "chunks without a manifest",
+    )))
+}
+
+/// Length of the checksum ending a line: a tab and 8 hex digits.
+const CHECKSUM_LEN: usize = 9;
+
+// the CRC-32 of every byte value, for `crc32`
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => 0xedb8_8320 ^ (crc >> 1),
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC-32 (IEEE 802.3) of `bytes`, the checksum of records.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// A line of the log whose record does not match its checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumMismatch;
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("record does not match its checksum")
+    }
+}
+
+/// End the encoded record in `buf` with its checksum and a newline,
+/// making it a line of the log.
+pub fn append_checksum(buf: &mut Vec<u8>) {
+    let crc = crc32(buf);
+    buf.extend_from_slice(format!("\t{:08x}\n", crc).as_bytes());
+}
+
+/// Check the checksum ending a line of the log and return the record
+/// before it, trailing whitespace left out. A line without a checksum,
+/// written before them, is returned as it is.
+pub fn verify_checksum(line: &[u8]) -> Result<&[u8], ChecksumMismatch> {
+    let line = line.trim_ascii_end();
+    // a record ends with a brace, never with a tab and 8 characters
+    let Some(split) = line.len().checked_sub(CHECKSUM_LEN) else {
+        return Ok(line);
+    };
+    if line[split] != b'\t' {
+        return Ok(line);
+    }
+    let (record, checksum) = (&line[..split], &line[split + 1..]);
+    let expected = core::str::from_utf8(checksum)
+        .ok()
+        .and_then(|checksum| u32::from_str_radix(checksum, 16).ok());
+    match expected == Some(crc32(record)) {
+        true => Ok(record),
+        false => Err(ChecksumMismatch),
+    }
+}
+
+/// Starts every generation file holding binary records.
+pub const BINARY_MAGIC: [u8; 8] = *b"KVSBIN1\n";
+
+/// Starts every generation file holding encrypted binary records, as long
+/// as `BINARY_MAGIC`.
+pub const ENCRYPTED_MAGIC: [u8; 8] = *b"KVSENC1\n";
+
+/// Length of the header of a binary record: the length of its payload
+/// and its checksum.
+pub const FRAME_HEADER_LEN: usize = 8;
+
+/// How the records of a generation file are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogEncoding {
+    /// One JSON object per line, the default
+    #[default]
+    Json,
+    /// Length-prefixed frames holding a `BinaryLog` encoded with bincode,
+    /// after `BINARY_MAGIC`
+    Binary,
+}
+
+impl LogEncoding {
+    /// The encoding of a generation file starting with `head`, binary for
+    /// encrypted records too, framed alike. An empty file is taken for JSON
+    /// lines.
+    pub fn detect(head: &[u8]) -> LogEncoding {
+        match head.starts_with(&BINARY_MAGIC) || is_encrypted(head) {
+            true => LogEncoding::Binary,
+            false => LogEncoding::Json,
+        }
+    }
+
+    /// Whether `record`, read up to the end of the record or of the file,
+    /// was read whole: a line with its newline, or a frame as long as its
+    /// header says.
+    pub fn is_whole(&self, record: &[u8]) -> bool {
+        match self {
+            LogEncoding::Json => record.ends_with(b"\n"),
+            LogEncoding::Binary => next_frame(record).is_some(),
+        }
+    }
+
+    /// What a generation file in this encoding starts with, before its
+    /// first record.
+    pub fn header(&self) -> &'static [u8] {
+        match self {
+            LogEncoding::Json => &[],
+            LogEncoding::Binary => &BINARY_MAGIC,
+        }
+    }
+}
+
+impl FromStr for LogEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(LogEncoding::Json),
+            "binary" => Ok(LogEncoding::Binary),
+            _ => Err(format!("Unknown log encoding: {}", s)),
+        }
+    }
+}
+
+impl Display for LogEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogEncoding::Json => write!(f, "json"),
+            LogEncoding::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Whether the generation file starting with `head` holds encrypted
+/// records.
+pub fn is_encrypted(head: &[u8]) -> bool {
+    head.starts_with(&ENCRYPTED_MAGIC)
+}
+
+/// Frame `payload` as a binary record, its header first.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// The binary record at the start of `buf`, if `buf` holds it whole: its
+/// payload, checked against its checksum, and the length of the record.
+pub fn next_frame(buf: &[u8]) -> Option<(Result<&[u8], ChecksumMismatch>, usize)> {
+    let header = buf.get(..FRAME_HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let end = FRAME_HEADER_LEN.checked_add(len)?;
+    let payload = buf.get(FRAME_HEADER_LEN..end)?;
+    match crc32(payload) == crc {
+        true => Some((Ok(payload), end)),
+        false => Some((Err(ChecksumMismatch), end)),
+    }
+}
+
+/// Starts every generation file written as a compressed segment, and ends
+/// its footer.
+pub const SEGMENT_MAGIC: [u8; 8] = *b"KVSSEG1\n";
+
+/// Length of the footer ending a compressed segment.
+pub const SEGMENT_FOOTER_LEN: usize = 32;
+
+/// The end of a compressed segment, telling where its blocks lie: three
+/// 64-bit little-endian integers, then `SEGMENT_MAGIC` again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFooter {
+    /// Offset of the block index in the file
+    pub index_at: u64,
+    /// Length of the generation, uncompressed
+    pub len: u64,
+    /// Uncompressed length of every block but the last, which may be
+    /// shorter
+    pub block_len: u64,
+}
+
+impl SegmentFooter {
+    /// The footer as written at the end of the segment.
+    pub fn encode(&self) -> [u8; SEGMENT_FOOTER_LEN] {
+        let mut buf = [0; SEGMENT_FOOTER_LEN];
+        buf[..8].copy_from_slice(&self.index_at.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.len.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.block_len.to_le_bytes());
+        buf[24..].copy_from_slice(&SEGMENT_MAGIC);
+        buf
+    }
+
+    /// The footer `buf` ends with, if it does end with one.
+    pub fn decode(buf: &[u8]) -> Option<SegmentFooter> {
+        let start = buf.len().checked_sub(SEGMENT_FOOTER_LEN)?;
+        let footer = &buf[start..];
+        let field = |i: usize| u64::from_le_bytes(footer[i * 8..i * 8 + 8].try_into().unwrap());
+        (footer[24..] == SEGMENT_MAGIC && field(2) > 0).then(|| SegmentFooter {
+            index_at: field(0),
+            len: field(1),
+            block_len: field(2),
+        })
+    }
+
+    /// Number of blocks the generation is cut in.
+    pub fn blocks(&self) -> u64 {
+        self.len.div_ceil(self.block_len)
+    }
+}
+
+/// The payload of a binary record: the fields of a `KvLog`, every one of
+/// them written, since bincode does not describe what it encodes. The
+/// variants follow those of `KvLog`, and are never reordered.
+// documented by `KvLog`, which every variant mirrors
+#[allow(missing_docs)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinaryLog<'a> {
+    Set {
+        key: &'a str,
+        value: &'a str,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+        written_at: Option<u64>,
+    },
+    Remove {
+        key: &'a str,
+    },
+    Trash {
+        key: &'a str,
+        until: u64,
+    },
+    Evict {
+        key: &'a str,
+    },
+    Touch {
+        key: &'a str,
+        expires_at: Option<u64>,
+    },
+    Chunk {
+        key: &'a str,
+        data: &'a str,
+    },
+    Manifest {
+        key: &'a str,
+        span: u64,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+        written_at: Option<u64>,
+    },
+    LPush {
+        key: &'a str,
+        value: &'a str,
+    },
+    RPush {
+        key: &'a str,
+        value: &'a str,
+    },
+    LPop {
+        key: &'a str,
+    },
+    HSet {
+        key: &'a str,
+        field: &'a str,
+        value: &'a str,
+    },
+    HDel {
+        key: &'a str,
+        field: &'a str,
+    },
+    SAdd {
+        key: &'a str,
+        member: &'a str,
+    },
+    SRem {
+        key: &'a str,
+        member: &'a str,
+    },
+    Schedule {
+        key: &'a str,
+        at: u64,
+        #[serde(borrow)]
+        value: Option<&'a str>,
+    },
+    Unschedule {
+        key: &'a str,
+        at: u64,
+    },
+    CompressedSet {
+        key: &'a str,
+        data: &'a str,
+        value_type: ValueType,
+        expires_at: Option<u64>,
+        written_at: Option<u64>,
+    },
+}
+
+impl<'a> From<&'a KvLog> for BinaryLog<'a> {
+    fn from(log: &'a KvLog) -> Self {
+        match log {
+            KvLog::Set {
+                key,
+                value,
+                value_type,
+                expires_at,
+                written_at,
+            } => BinaryLog::Set {
+                key,
+                value,
+                value_type: *value_type,
+                expires_at: *expires_at,
+                written_at: *written_at,
+            },
+            KvLog::Remove { key } => BinaryLog::Remove { key },
+            KvLog::Trash { key, until } => BinaryLog::Trash { key, until: *until },
+            KvLog::Evict { key } => BinaryLog::Evict { key },
+            KvLog::Touch { key, expires_at } => BinaryLog::Touch {
+                key,
+                expires_at: *expires_at,
+            },
+            KvLog::Chunk { key, data } => BinaryLog::Chunk { key, data },
+            KvLog::Manifest {
+                key,
+                span,
+                value_type,
+                expires_at,
+                written_at,
+            } => BinaryLog::Manifest {
+                key,
+                span: *span,
+                value_type: *value_type,
+                expires_at: *expires_at,
+                written_at: *written_at,
+            },
+            KvLog::LPush { key, value } => BinaryLog::LPush { key, value },
+            KvLog::RPush { key, value } => BinaryLog::RPush { key, value },
+            KvLog::LPop { key } => BinaryLog::LPop { key },
+            KvLog::HSet { key, field, value } => BinaryLog::HSet { key, field, value },
+            KvLog::HDel { key, field } => BinaryLog::HDel { key, field },
+            KvLog::SAdd { key, member } => BinaryLog::SAdd { key, member },
+            KvLog::SRem { key, member } => BinaryLog::SRem { key, member },
+            KvLog::Schedule { key, at, value } => BinaryLog::Schedule {
+                key,
+                at: *at,
+                value: value.as_deref(),
+            },
+            KvLog::Unschedule { key, at } => BinaryLog::Unschedule { key, at: *at },
+            KvLog::CompressedSet {
+                key,
+                data,
+                value_type,
+                expires_at,
+                written_at,
+            } => BinaryLog::CompressedSet {
+                key,
+                data,
+                value_type: *value_type,
+                expires_at: *expires_at,
+                written_at: *written_at,
+            },
+        }
+    }
+}
+
+impl From<BinaryLog<'_>> for KvLog {
+    fn from(log: BinaryLog<'_>) -> Self {
+        match log {
+            BinaryLog::Set {
+                key,
+                value,
+                value_type,
+                expires_at,
+                written_at,
+            } => KvLog::Set {
+                key: key.into(),
+                value: value.into(),
+                value_type,
+                expires_at,
+                written_at,
+            },
+            BinaryLog::Remove { key } => KvLog::Remove { key: key.into() },
+            BinaryLog::Trash { key, until } => KvLog::Trash {
+                key: key.into(),
+                until,
+            },
+            BinaryLog::Evict { key } => KvLog::Evict { key: key.into() },
+            BinaryLog::Touch { key, expires_at } => KvLog::Touch {
+                key: key.into(),
+                expires_at,
+            },
+            BinaryLog::Chunk { key, data } => KvLog::Chunk {
+                key: key.into(),
+                data: data.into(),
+            },
+            BinaryLog::Manifest {
+                key,
+                span,
+                value_type,
+                expires_at,
+                written_at,
+            } => KvLog::Manifest {
+                key: key.into(),
+                span,
+                value_type,
+                expires_at,
+                written_at,
+            },
+            BinaryLog::LPush { key, value } => KvLog::LPush {
+                key: key.into(),
+                value: value.into(),
+            },
+            BinaryLog::RPush { key, value } => KvLog::RPush {
+                key: key.into(),
+                value: value.into(),
+            },
+            BinaryLog::LPop { key } => KvLog::LPop { key: key.into() },
+            BinaryLog::HSet { key, field, value } => KvLog::HSet {
+                key: key.into(),
+                field: field.into(),
+                value: value.into(),
+            },
+            BinaryLog::HDel { key, field } => KvLog::HDel {
+                key: key.into(),
+                field: field.into(),
+            },
+            BinaryLog::SAdd { key, member } => KvLog::SAdd {
+                key: key.into(),
+                member: member.into(),
+            },
+            BinaryLog::SRem { key, member } => KvLog::SRem {
+                key: key.into(),
+                member: member.into(),
+            },
+            BinaryLog::Schedule { key, at, value } => KvLog::Schedule {
+                key: key.into(),
+                at,
+                value: value.map(Into::into),
+            },
+            BinaryLog::Unschedule { key, at } => KvLog::Unschedule {
+                key: key.into(),
+                at,
+            },
+            BinaryLog::CompressedSet {
+                key,
+                data,
+                value_type,
+                expires_at,
+                written_at,
+            } => KvLog::CompressedSet {
+                key: key.into(),
+                data: data.into(),
+                value_type,
+                expires_at,
+                written_at,
+            },
+        }
+    }
+}
+
+/// Where a record the store still needs lives: the latest `Set` of a key,
+/// or its chunks and manifest, or `HSet` of a hash field, the push of a list element or the `SAdd` of
+/// a set member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexPos {
+    /// Generation of the file holding the record
+    pub gen: u64,
+    /// Offset of the record in the file
+    pub pos: u64,
+    /// Length of the record, in bytes
+    pub len: u64,
+}
+
+impl From<(u64, Range<u64>)> for IndexPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        IndexPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
+
+/// Name of the file holding generation `gen`.
+pub fn log_file_name(gen: u64) -> String {
+    format!("{}.{}", gen, LOG_EXTENSION)
+}
+
+/// Name of the hint file of generation `gen`.
+pub fn hint_file_name(gen: u64) -> String {
+    format!("{}.{}", gen, HINT_EXTENSION)
+}
+
+/// A line of a hint file: the state replaying its generation from scratch
+/// leads to, keyed by the keys the store indexes, a digest for long keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Hint {
+    /// The first line, describing the generation file
+    Header {
+        /// The generation
+        gen: u64,
+        /// Length of the generation file, in bytes
+        len: u64,
+    },
+    /// The latest `Set` or manifest of a string key
+    Set {
+        /// The key
+        key: String,
+        /// Where the record lies
+        pos: IndexPos,
+    },
+    /// When a string key expires, in milliseconds since the Unix epoch
+    Expire {
+        /// The key
+        key: String,
+        /// When it expires
+        expires_at: u64,
+    },
+    /// The last `Set` of a trashed string key and the end of its window
+    Trash {
+        /// The key
+        key: String,
+        /// Where the record lies
+        pos: IndexPos,
+        /// When the value is dropped for good
+        until: u64,
+    },
+    /// The push of a list element, the elements of a list coming head first
+    Push {
+        /// The key of the list
+        key: String,
+        /// Where the record lies
+        pos: IndexPos,
+    },
+    /// The latest `HSet` of a hash field
+    HSet {
+        /// The key of the hash
+        key: String,
+        /// The field
+        field: String,
+        /// Where the record lies
+        pos: IndexPos,
+    },
+    /// The `SAdd` of a set member
+    SAdd {
+        /// The key of the set
+        key: String,
+        /// The member
+        member: String,
+        /// Where the record lies
+        pos: IndexPos,
+    },
+    /// A pending scheduled write
+    Schedule {
+        /// The key written
+        key: String,
+        /// When the write runs, in milliseconds since the Unix epoch
+        at: u64,
+        /// Where the record lies
+        pos: IndexPos,
+    },
+}
+
+impl Hint {
+    /// Encode the hint as a line of a hint file, newline included.
+    pub fn encode(&self) -> serde_json::Result<Vec<u8>> {
+        let mut buf = serde_json::to_vec(self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+/// Generation held by a file named `name`, if it is a generation file.
+pub fn parse_log_file_name(name: &str) -> Option<u64> {
+    name.strip_suffix(LOG_EXTENSION)?
+        .strip_suffix('.')?
+        .parse()
+        .ok()
+}
+
+/// Iterate over the records of a generation file of JSON lines held in
+/// memory.
+///
+/// Every item is the byte range of the record in `buf` along with the
+/// decoded record. Iteration stops after the first record that fails to
+/// decode.
+pub fn records(buf: &[u8]) -> Records<'_> {
+    Records { buf, offset: 0 }
+}
+
+/// Iterator returned by [`records`].
+pub struct Records<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl Iterator for Records<'_> {
+    type Item = serde_json::Result<(Range<u64>, KvLog)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.offset < self.buf.len() && self.buf[self.offset].is_ascii_whitespace() {
+            self.offset += 1;
+        }
+        if self.offset == self.buf.len() {
+            return None;
+        }
+        let start = self.offset;
+        let rest = &self.buf[start..];
+        let line = match rest.iter().position(|&b| b == b'\n') {
+            Some(end) => &rest[..end],
+            None => rest,
+        };
+        match KvLog::decode(line) {
+            Ok(log) => {
+                self.offset = start + line.trim_ascii_end().len();
+                Some(Ok((start as u64..self.offset as u64, log)))
+            }
+            Err(e) => {
+                self.offset = self.buf.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The type of content a value holds, stored next to it so clients can
+/// render it appropriately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    /// Plain text, the default
+    #[default]
+    String,
+    /// A JSON document
+    Json,
+    /// Opaque binary data, held encoded in standard, padded base64
+    Bytes,
+    /// A signed 64-bit integer
+    Int,
+}
+
+impl ValueType {
+    /// Check that `value` is a valid content of this type, returning why
+    /// it is not otherwise.
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        match self {
+            ValueType::Json => {
+                serde_json::from_str::<serde_json::Value>(value)
+                    .map_err(|e| format!("not a JSON document: {}", e))?;
+            }
+            ValueType::Int => {
+                value
+                    .parse::<i64>()
+                    .map_err(|e| format!("not an integer: {}", e))?;
+            }
+            ValueType::Bytes => {
+                if !is_base64(value) {
+                    return Err("not base64".into());
+                }
+            }
+            ValueType::String => {}
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_string(&self) -> bool {
+        *self == ValueType::String
+    }
+}
+
+// whether `value` is standard base64, padded to a multiple of 4 characters
+fn is_base64(value: &str) -> bool {
+    let data = value.trim_end_matches('=');
+    value.len().is_multiple_of(4)
+        && value.len() - data.len() <= 2
+        && data
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+impl FromStr for ValueType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(ValueType::String),
+            "json" => Ok(ValueType::Json),
+            "bytes" => Ok(ValueType::Bytes),
+            "int" => Ok(ValueType::Int),
+            _ => Err(format!("Unknown value type: {}", s)),
+        }
+    }
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::String => write!(f, "string"),
+            ValueType::Json => write!(f, "json"),
+            ValueType::Bytes => write!(f, "bytes"),
+            ValueType::Int => write!(f, "int"),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Offline validation of a `KvStore` data directory.
+//!
+//! [`check`] reads every generation file without opening the store, which
+//! would start a new generation, and reports what it finds: records that do
+//! not decode, values not matching their type tag, empty generations left
+//! behind, and an engine marker disagreeing with the data. Asked to repair,
+//! it fixes what can be fixed without losing data:
+//!
+//! - a torn tail, the partial last record of a write cut short, is
+//!   truncated, which lets the store open again; in a binary generation,
+//!   a record whose length was damaged reads as one too;
+//! - empty generations other than the latest are removed;
+//! - a wrong engine marker is rewritten.
+//!
+//! Corrupt records followed by more data, and invalid values, are left to
+//! the operator. Opening the store skips corrupt records, copying them to
+//! its quarantine file, and compaction then drops them.
+
+use std::fs::{self, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::engines::{decode_record, decompress, GenFile};
+use crate::format::{
+    is_encrypted, log_file_name, next_frame, parse_log_file_name, records, KvLog, LogEncoding,
+};
+use crate::{EngineKind, KvsError, Result};
+
+/// What `check` found in a data directory.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    /// Number of generation files
+    pub generations: usize,
+    /// Number of records decoded
+    pub records: u64,
+    /// Problems found, repaired or not
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    /// Whether every problem found was repaired.
+    pub fn is_clean(&self) -> bool {
+        self.issues.iter().all(|issue| issue.repaired)
+    }
+}
+
+/// A problem found in a data directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    /// What kind of problem
+    pub kind: IssueKind,
+    /// Name of the file, in the data directory
+    pub file: String,
+    /// Offset of the faulty record in the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// Details, for humans
+    pub detail: String,
+    /// Whether `check` can repair it
+    pub repairable: bool,
+    /// Whether `check` repaired it
+    pub repaired: bool,
+}
+
+/// The kinds of problems `check` looks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// The last record of a generation is cut short
+    TornTail,
+    /// A record does not decode, with more data after it
+    Corrupt,
+    /// A value does not match its type tag
+    InvalidValue,
+    /// A generation other than the latest holds no record
+    EmptyGeneration,
+    /// The engine marker disagrees with the data
+    EngineMarker,
+}
+
+/// Validate the data directory `dir`, repairing what can be repaired
+/// without losing data if `repair` is set.
+pub fn check(dir: &Path, repair: bool) -> Result<Report> {
+    let mut report = Report::default();
+    check_marker(dir, repair, &mut report)?;
+
+    let mut gens: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            entry
+                .ok()?
+                .file_name()
+                .to_str()
+                .and_then(parse_log_file_name)
+        })
+        .collect();
+    gens.sort_unstable();
+    report.generations = gens.len();
+    let latest = gens.last().copied();
+
+    for gen in gens {
+        let file = log_file_name(gen);
+        let path = dir.join(&file);
+        let mut gen_file = GenFile::open(&path)?;
+        // a compressed segment is written whole then renamed, its records
+        // are never torn, nor truncated
+        let truncate_torn = repair && !gen_file.is_segment();
+        let mut buf = Vec::new();
+        gen_file.read_to_end(&mut buf)?;
+        let encoding = LogEncoding::detect(&buf);
+        if buf.len() == encoding.header().len() {
+            if Some(gen) != latest {
+                let mut issue = issue(IssueKind::EmptyGeneration, &file, None, "no record");
+                if repair {
+                    fs::remove_file(&path)?;
+                    issue.repaired = true;
+                }
+                report.issues.push(issue);
+            }
+            continue;
+        }
+
+        if encoding == LogEncoding::Binary {
+            check_frames(gen, &path, &buf, truncate_torn, &mut report)?;
+            continue;
+        }
+        let mut good_end = 0;
+        for record in records(&buf) {
+            match record {
+                Ok((range, log)) => {
+                    report.records += 1;
+                    good_end = range.end;
+                    check_value(&log, &file, range.start, &mut report);
+                }
+                Err(e) => {
+                    let start = next_record(&buf, good_end as usize);
+                    // a write cut short leaves a single partial line
+                    let torn = !buf[start..].trim_ascii_end().contains(&b'\n');
+                    let kind = if torn {
+                        IssueKind::TornTail
+                    } else {
+                        IssueKind::Corrupt
+                    };
+                    let mut issue = issue(kind, &file, Some(start as u64), &e.to_string());
+                    if torn && truncate_torn {
+                        truncate(&path, good_end)?;
+                        issue.repaired = true;
+                    }
+                    report.issues.push(issue);
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+// check the records of binary generation `gen`, read into `buf` from
+// `path`; a frame failing to decode is skipped by its length, and those of
+// an encrypted generation are only checked against their checksum, the
+// key being unknown
+fn check_frames(
+    gen: u64,
+    path: &Path,
+    buf: &[u8],
+    repair: bool,
+    report: &mut Report,
+) -> Result<()> {
+    let file = log_file_name(gen);
+    let sealed = is_encrypted(buf);
+    let mut offset = LogEncoding::Binary.header().len();
+    while offset < buf.len() {
+        let Some((_, len)) = next_frame(&buf[offset..]) else {
+            let mut issue = issue(
+                IssueKind::TornTail,
+                &file,
+                Some(offset as u64),
+                "record cut short",
+            );
+            if repair {
+                truncate(path, offset as u64)?;
+                issue.repaired = true;
+            }
+            report.issues.push(issue);
+            break;
+        };
+        let record = &buf[offset..offset + len];
+        let decoded = match (sealed, next_frame(record)) {
+            (true, Some((Ok(_), _))) => {
+                report.records += 1;
+                offset += len;
+                continue;
+            }
+            (true, _) => Err(KvsError::Corruption {
+                gen,
+                offset: offset as u64,
+            }),
+            (false, _) => decode_record(LogEncoding::Binary, record, None, gen, offset as u64),
+        };
+        match decoded {
+            Ok(log) => {
+                report.records += 1;
+                check_value(&log, &file, offset as u64, report);
+            }
+            Err(e) => report.issues.push(issue(
+                IssueKind::Corrupt,
+                &file,
+                Some(offset as u64),
+                &e.to_string(),
+            )),
+        }
+        offset += len;
+    }
+    Ok(())
+}
+
+// report a `Set` whose value does not match its type tag
+fn check_value(log: &KvLog, file: &str, offset: u64, report: &mut Report) {
+    // a compressed value is checked once decompressed, if this build can
+    let decompressed;
+    let log = match log {
+        KvLog::CompressedSet { .. } if !cfg!(feature = "compression") => return,
+        KvLog::CompressedSet { key, .. } => match decompress(log.clone()) {
+            Ok(set) => {
+                decompressed = set;
+                &decompressed
+            }
+            Err(e) => {
+                report.issues.push(issue(
+                    IssueKind::InvalidValue,
+                    file,
+                    Some(offset),
+                    &format!("key {}: {}", key, e),
+                ));
+                return;
+            }
+        },
+        log => log,
+    };
+    if let KvLog::Set {
+        key,
+        value,
+        value_type,
+        ..
+    } = log
+    {
+        if let Err(reason) = value_type.validate(value) {
+            report.issues.push(issue(
+                IssueKind::InvalidValue,
+                file,
+                Some(offset),
+                &format!("key {}: {}", key, reason),
+            ));
+        }
+    }
+}
+
+fn truncate(path: &Path, len: u64) -> Result<()> {
+    OpenOptions::new().write(true).open(path)?.set_len(len)?;
+    Ok(())
+}
+
+fn check_marker(dir: &Path, repair: bool, report: &mut Report) -> Result<()> {
+    let found = match EngineKind::detect(dir)? {
+        Some(EngineKind::Kvs) => EngineKind::Kvs,
+        Some(EngineKind::Sled) => {
+            return Err(KvsError::WrongEngine {
+                expected: EngineKind::Kvs,
+                found: EngineKind::Sled,
+            })
+        }
+        None => return Ok(()),
+    };
+    // an unreadable marker is as wrong as one naming another engine
+    let detail = match EngineKind::from_marker(dir) {
+        Ok(None) => return Ok(()),
+        Ok(Some(marker)) if marker == found => return Ok(()),
+        Ok(Some(marker)) => format!("marker says {}, data is {}", marker, found),
+        Err(e) => e.to_string(),
+    };
+    let mut issue = issue(IssueKind::EngineMarker, crate::ENGINE_FILE, None, &detail);
+    if repair {
+        found.write_marker(dir)?;
+        issue.repaired = true;
+    }
+    report.issues.push(issue);
+    Ok(())
+}
+
+fn issue(kind: IssueKind, file: &str, offset: Option<u64>, detail: &str) -> Issue {
+    Issue {
+        kind,
+        file: file.to_owned(),
+        offset,
+        detail: detail.to_owned(),
+        repairable: matches!(
+            kind,
+            IssueKind::TornTail | IssueKind::EmptyGeneration | IssueKind::EngineMarker
+        ),
+        repaired: false,
+    }
+}
+
+// skip the whitespace between two records
+fn next_record(buf: &[u8], from: usize) -> usize {
+    from + buf[from..]
+        .iter()
+        .take_while(|b| b.is_ascii_whitespace())
+        .count()
+}
@@ -0,0 +1,122 @@
+/// A compiled glob pattern matching keys, e.g. `user:*:settings`.
+///
+/// `*` matches any run of characters, `?` any single one, and `[...]` one
+/// of a set like `[abc]`, `[a-z]` or, negated, `[!0-9]`. A backslash makes
+/// the next character literal.
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+enum Token {
+    Literal(char),
+    Any,
+    Star,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, String> {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '[' => parse_class(&mut chars)?,
+                '\\' => Token::Literal(chars.next().ok_or("trailing backslash")?),
+                c => Token::Literal(c),
+            };
+            // consecutive stars match the same as one
+            if !matches!((tokens.last(), &token), (Some(Token::Star), Token::Star)) {
+                tokens.push(token);
+            }
+        }
+        Ok(Glob { tokens })
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut p, mut k) = (0, 0);
+        // where to resume after the last star: its token, and the first
+        // character it does not swallow yet
+        let mut star = None;
+        while k < key.len() {
+            match self.tokens.get(p) {
+                Some(Token::Star) => {
+                    star = Some((p, k));
+                    p += 1;
+                    continue;
+                }
+                Some(token) if token.matches(key[k]) => {
+                    p += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            // backtrack, letting the last star swallow one more character;
+            // never further, so matching stays linear in stars times length
+            match star {
+                Some((star_p, star_k)) => {
+                    star = Some((star_p, star_k + 1));
+                    p = star_p + 1;
+                    k = star_k + 1;
+                }
+                None => return false,
+            }
+        }
+        self.tokens[p..]
+            .iter()
+            .all(|token| matches!(token, Token::Star))
+    }
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Literal(literal) => *literal == c,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+        }
+    }
+}
+
+fn parse_class(chars: &mut std::str::Chars) -> Result<Token, String> {
+    let unterminated = || "unterminated character class".to_owned();
+    let mut negated = false;
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let mut c = chars.next().ok_or_else(unterminated)?;
+        match c {
+            '!' | '^' if first && !negated => {
+                negated = true;
+                continue;
+            }
+            // a leading `]` is part of the set
+            ']' if !first => break,
+            '\\' => c = chars.next().ok_or_else(unterminated)?,
+            _ => {}
+        }
+        first = false;
+        let mut lookahead = chars.clone();
+        let hi = match (lookahead.next(), lookahead.next()) {
+            (Some('-'), Some(hi)) if hi != ']' => {
+                chars.nth(1);
+                hi
+            }
+            _ => c,
+        };
+        if hi < c {
+            return Err(format!("invalid range {}-{}", c, hi));
+        }
+        ranges.push((c, hi));
+    }
+    Ok(Token::Class { negated, ranges })
+}
@@ -0,0 +1,69 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::protocol::HotKey;
+
+/// Top-k sketch of the most frequently accessed keys, using the
+/// Space-Saving algorithm.
+///
+/// At most `capacity` keys are tracked. When a new key shows up and the
+/// sketch is full, the least counted key is replaced and the newcomer
+/// inherits its count, recorded as the possible overestimation (`error`).
+/// Any key accessed more than `total / capacity` times is guaranteed to be
+/// tracked.
+pub struct HotKeys {
+    capacity: usize,
+    counters: HashMap<String, Counter>,
+    // ordered by count, to find the entry to evict
+    by_count: BTreeSet<(u64, String)>,
+}
+
+struct Counter {
+    count: u64,
+    error: u64,
+}
+
+impl HotKeys {
+    pub fn new(capacity: usize) -> Self {
+        HotKeys {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    /// Record one access to `key`.
+    pub fn record(&mut self, key: &str) {
+        if let Some(counter) = self.counters.get_mut(key) {
+            self.by_count.remove(&(counter.count, key.to_owned()));
+            counter.count += 1;
+            self.by_count.insert((counter.count, key.to_owned()));
+            return;
+        }
+
+        let mut counter = Counter { count: 1, error: 0 };
+        if self.counters.len() >= self.capacity {
+            let (min, evicted) = self.by_count.pop_first().expect("sketch is empty");
+            self.counters.remove(&evicted);
+            counter = Counter {
+                count: min + 1,
+                error: min,
+            };
+        }
+        self.by_count.insert((counter.count, key.to_owned()));
+        self.counters.insert(key.to_owned(), counter);
+    }
+
+    /// The `limit` most accessed keys, hottest first.
+    pub fn top(&self, limit: usize) -> Vec<HotKey> {
+        self.by_count
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|(count, key)| HotKey {
+                key: key.clone(),
+                count: *count,
+                error: self.counters[key].error,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// A lease on a data directory shared by several servers, e.g. on network
+/// storage, electing the one server which writes it.
+///
+/// The lease lives in a file naming its holder, the time it expires and
+/// its epoch. The holder renews it every third of its ttl; once it expires,
+/// any server may take it over under the next epoch, the late holder
+/// included. Servers writing it at once all read it back after a while, and
+/// only the last one written holds it. The holder considers it lost a
+/// quarter of the ttl before others may take it over, which leaves that
+/// much for the clocks of the hosts to disagree.
+pub struct Lease {
+    path: PathBuf,
+    holder: String,
+    ttl: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    // the epoch held, and until when it may be used
+    held: Option<(u64, Instant)>,
+    // the holder seen last
+    leader: Option<String>,
+}
+
+/// The content of the lease file.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    holder: String,
+    epoch: u64,
+    // in ms since the Unix epoch
+    expires_at: u64,
+}
+
+impl Lease {
+    /// Create a lease kept in the file at `path`, taken as `holder`, which
+    /// names this server to the others, e.g. its address. The lease
+    /// expires `ttl` after it was last renewed.
+    pub fn new(path: impl Into<PathBuf>, holder: impl Into<String>, ttl: Duration) -> Self {
+        Lease {
+            path: path.into(),
+            holder: holder.into(),
+            ttl,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Try to take the lease once, then keep renewing or taking it in the
+    /// background.
+    pub(crate) fn start(&self) -> Result<()> {
+        heartbeat(&self.path, &self.holder, self.ttl, &self.state)?;
+        let path = self.path.clone();
+        let holder = self.holder.clone();
+        let ttl = self.ttl;
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || loop {
+            thread::sleep(ttl / 3);
+            if let Err(e) = heartbeat(&path, &holder, ttl, &state) {
+                error!("renewing the lease {}: {}", path.display(), e);
+            }
+        });
+        Ok(())
+    }
+
+    /// The epoch of the lease, while this server holds it.
+    pub(crate) fn epoch(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state
+            .held
+            .filter(|&(_, until)| Instant::now() < until)
+            .map(|(epoch, _)| epoch)
+    }
+
+    /// The holder of the lease, as last seen.
+    pub(crate) fn leader(&self) -> Option<String> {
+        self.state.lock().unwrap().leader.clone()
+    }
+}
+
+// renew the lease if held, take it over if expired
+fn heartbeat(path: &Path, holder: &str, ttl: Duration, state: &Mutex<State>) -> Result<()> {
+    let started = Instant::now();
+    let held = state.lock().unwrap().held.map(|(epoch, _)| epoch);
+    let current = read(path)?;
+    let now = now_ms();
+    let epoch = match &current {
+        // renewed in time; once expired, it is taken over like any other
+        Some(record)
+            if record.holder == holder && Some(record.epoch) == held && record.expires_at > now =>
+        {
+            write(path, holder, record.epoch, ttl)?;
+            record.epoch
+        }
+        // held by another server, or by this one before a restart
+        Some(record) if record.expires_at > now => {
+            let mut state = state.lock().unwrap();
+            if state.held.take().is_some() {
+                warn!("Lost the lease to {}", record.holder);
+            }
+            state.leader = Some(record.holder.clone());
+            return Ok(());
+        }
+        _ => {
+            let epoch = current.as_ref().map_or(0, |record| record.epoch) + 1;
+            write(path, holder, epoch, ttl)?;
+            // others may have found it expired too, the last write wins
+            thread::sleep(ttl / 10);
+            match read(path)? {
+                Some(record) if record.holder == holder && record.epoch == epoch => {
+                    info!("Took the lease with epoch {}", epoch);
+                }
+                record => {
+                    let mut state = state.lock().unwrap();
+                    state.held = None;
+                    state.leader = record.map(|record| record.holder);
+                    return Ok(());
+                }
+            }
+            epoch
+        }
+    };
+    let mut state = state.lock().unwrap();
+    state.held = Some((epoch, started + ttl - ttl / 4));
+    state.leader = Some(holder.to_owned());
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Option<Record>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// replace the lease file at once, through a file of the holder's own
+fn write(path: &Path, holder: &str, epoch: u64, ttl: Duration) -> Result<()> {
+    let record = Record {
+        holder: holder.to_owned(),
+        epoch,
+        expires_at: now_ms() + ttl.as_millis() as u64,
+    };
+    let name: String = holder
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let tmp = path.with_extension(format!("{}.tmp", name));
+    fs::write(&tmp, serde_json::to_vec(&record)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before Unix epoch")
+        .as_millis() as u64
+}
@@ -2,16 +2,83 @@
 #![deny(missing_docs)]
 //! A simple key-value store.
 
+extern crate alloc;
+
+#[cfg(feature = "chaos")]
+mod chaos;
+#[cfg(feature = "client")]
 mod client;
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod cluster;
+pub mod diff;
+#[cfg(feature = "server")]
+mod diskwatch;
 mod engines;
 mod errors;
-mod protocol;
+pub mod format;
+pub mod fsck;
+#[cfg(feature = "server")]
+mod glob;
+#[cfg(feature = "server")]
+mod hotkeys;
+#[cfg(feature = "server")]
+mod lease;
+#[cfg(feature = "client")]
+pub mod metrics;
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod protocol;
+#[cfg(feature = "server")]
 mod server;
+pub mod trace;
 
-pub use client::KvsClient;
+#[cfg(feature = "chaos")]
+pub use chaos::Chaos;
+#[cfg(feature = "client")]
+pub use client::{ClientBuilder, ClusterClient, KvsClient};
+pub use engines::Children;
+pub use engines::CompositeStore;
+#[cfg(feature = "encryption")]
+pub use engines::EncryptedEngine;
+#[cfg(feature = "encryption")]
+pub use engines::EncryptionConfig;
+pub use engines::EngineKind;
+pub use engines::ExpiredReads;
+pub use engines::KeyDump;
+pub use engines::KeyMeta;
+pub use engines::KeyOrder;
+#[cfg(feature = "encryption")]
+pub use engines::KeyProvider;
 pub use engines::KvStore;
 pub use engines::KvsEngine;
+pub use engines::LogEncoding;
+pub use engines::MirrorEngine;
+pub use engines::PinGuard;
+pub use engines::PrefixUsage;
+pub use engines::Rate;
+#[cfg(feature = "engine-sled")]
 pub use engines::SledStore;
+pub use engines::ValueType;
+pub use engines::COMPACTION_FILE;
+pub use engines::ENCRYPTION_FILE;
+pub use engines::ENGINE_FILE;
+pub use engines::FORMAT_FILE;
+pub use engines::MAX_KEY_LEN;
+pub use engines::MAX_RATE_WINDOW;
+pub use engines::ORDER_FILE;
+pub use engines::QUARANTINE_FILE;
+pub use errors::ErrorContext;
 pub use errors::KvsError;
 pub use errors::Result;
+#[cfg(feature = "server")]
+pub use lease::Lease;
+#[cfg(feature = "client")]
+pub use metrics::ClientMetrics;
+#[cfg(feature = "client")]
+pub use metrics::OpStats;
+#[cfg(any(feature = "server", feature = "client"))]
+pub use protocol::{
+    ClientStats, ClusterInfo, Health, HotKey, NodeInfo, ServerStats, SlotRange, SlotState,
+    ValueFilter, WriteMeta,
+};
+#[cfg(feature = "server")]
 pub use server::KvsServer;
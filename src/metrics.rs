@@ -0,0 +1,223 @@
+//! Client-side statistics of the requests of `KvsClient`s: how long the
+//! responses took as seen by the application, per operation, how many
+//! failed, and how often `ClusterClient` had to connect again. Compared
+//! with the numbers of the server, they tell the network and client apart
+//! from the server itself.
+//!
+//! With the `prometheus` feature, `ClientMetrics` is a collector to
+//! register with the application's registry.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds of the latency buckets, in microseconds: from 100µs to
+/// 10s, requests slower than the last one counting in an extra bucket.
+pub const LATENCY_BUCKETS_US: [u64; 16] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+
+/// What the requests of one operation went through.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpStats {
+    /// Number of requests sent
+    pub count: u64,
+    /// Number of requests failed, by the server or on the way
+    pub errors: u64,
+    /// Total time waited for the responses, in microseconds
+    pub total_us: u64,
+    /// Number of requests answered within each bound of
+    /// `LATENCY_BUCKETS_US` but not the one before, then over the last one
+    pub buckets: Vec<u64>,
+}
+
+impl OpStats {
+    fn record(&mut self, elapsed: Duration, failed: bool) {
+        let us = elapsed.as_micros() as u64;
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS_US.len() + 1];
+        }
+        let bucket = LATENCY_BUCKETS_US.partition_point(|&bound| bound < us);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_us += us;
+        self.errors += failed as u64;
+    }
+
+    /// The latency `q` of the requests were answered within, `q` from 0 to
+    /// 1, as the bound of its bucket: `None` without any request, or past
+    /// the last bound.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BUCKETS_US
+                    .get(bucket)
+                    .map(|&us| Duration::from_micros(us));
+            }
+        }
+        None
+    }
+
+    /// The mean latency of the requests, `None` without any.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_micros(self.total_us / self.count))
+    }
+}
+
+/// Statistics of the requests of the clients built with it, see
+/// `ClientBuilder::metrics`. Clones share the same statistics, so one
+/// handle can gather those of a whole pool of connections.
+#[derive(Clone, Default)]
+pub struct ClientMetrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // by request name
+    ops: Mutex<BTreeMap<&'static str, OpStats>>,
+    reconnects: AtomicU64,
+    #[cfg(feature = "prometheus")]
+    prometheus: Prometheus,
+}
+
+impl ClientMetrics {
+    /// Statistics with nothing recorded yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The statistics of every operation so far, by request name, e.g.
+    /// `Get`.
+    pub fn ops(&self) -> BTreeMap<String, OpStats> {
+        let ops = self.inner.ops.lock().unwrap();
+        ops.iter()
+            .map(|(&op, stats)| (op.to_owned(), stats.clone()))
+            .collect()
+    }
+
+    /// Number of connections `ClusterClient` made again to a node, after
+    /// the one it had failed.
+    pub fn reconnects(&self) -> u64 {
+        self.inner.reconnects.load(Ordering::Relaxed)
+    }
+
+    // a request of `op` answered after `elapsed`, or failed on the way
+    pub(crate) fn record(&self, op: &'static str, elapsed: Duration, failed: bool) {
+        let mut ops = self.inner.ops.lock().unwrap();
+        ops.entry(op).or_default().record(elapsed, failed);
+        #[cfg(feature = "prometheus")]
+        self.inner.prometheus.record(op, elapsed, failed);
+    }
+
+    // a request of `op` answered with an error
+    pub(crate) fn failed(&self, op: &'static str) {
+        let mut ops = self.inner.ops.lock().unwrap();
+        ops.entry(op).or_default().errors += 1;
+        #[cfg(feature = "prometheus")]
+        self.inner.prometheus.errors.with_label_values(&[op]).inc();
+    }
+
+    pub(crate) fn reconnected(&self) {
+        self.inner.reconnects.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.inner.prometheus.reconnects.inc();
+    }
+}
+
+impl fmt::Debug for ClientMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientMetrics")
+            .field("ops", &self.ops())
+            .field("reconnects", &self.reconnects())
+            .finish()
+    }
+}
+
+// the same statistics, as Prometheus metrics
+#[cfg(feature = "prometheus")]
+struct Prometheus {
+    latency: prometheus::HistogramVec,
+    errors: prometheus::IntCounterVec,
+    reconnects: prometheus::IntCounter,
+}
+
+#[cfg(feature = "prometheus")]
+impl Default for Prometheus {
+    fn default() -> Self {
+        use prometheus::{HistogramOpts, Opts};
+
+        let buckets = LATENCY_BUCKETS_US
+            .iter()
+            .map(|&us| us as f64 / 1e6)
+            .collect();
+        // the names and labels are valid, creating them cannot fail
+        Prometheus {
+            latency: prometheus::HistogramVec::new(
+                HistogramOpts::new(
+                    "kvs_client_request_duration_seconds",
+                    "Time kvs clients waited for the responses of their requests",
+                )
+                .buckets(buckets),
+                &["op"],
+            )
+            .unwrap(),
+            errors: prometheus::IntCounterVec::new(
+                Opts::new(
+                    "kvs_client_request_errors_total",
+                    "Requests of kvs clients failed, by the server or on the way",
+                ),
+                &["op"],
+            )
+            .unwrap(),
+            reconnects: prometheus::IntCounter::new(
+                "kvs_client_reconnects_total",
+                "Connections kvs cluster clients made again to a node",
+            )
+            .unwrap(),
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Prometheus {
+    fn record(&self, op: &'static str, elapsed: Duration, failed: bool) {
+        self.latency
+            .with_label_values(&[op])
+            .observe(elapsed.as_secs_f64());
+        if failed {
+            self.errors.with_label_values(&[op]).inc();
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl prometheus::core::Collector for ClientMetrics {
+    fn desc(&self) -> Vec<&prometheus::core::Desc> {
+        let prometheus = &self.inner.prometheus;
+        let mut desc = prometheus.latency.desc();
+        desc.extend(prometheus.errors.desc());
+        desc.extend(prometheus.reconnects.desc());
+        desc
+    }
+
+    fn collect(&self) -> Vec<prometheus::proto::MetricFamily> {
+        let prometheus = &self.inner.prometheus;
+        let mut families = prometheus.latency.collect();
+        families.extend(prometheus.errors.collect());
+        families.extend(prometheus.reconnects.collect());
+        families
+    }
+}
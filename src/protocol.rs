@@ -1,26 +1,839 @@
+//! Wire messages exchanged between `KvsClient` and `KvsServer`.
+//!
+//! Every message is a JSON value written back to back on the connection.
+//! The encoding must stay readable by the previous release so mixed-version
+//! fleets keep working during a rolling upgrade:
+//!
+//! - enum tags are spelled out with `rename`, renaming a Rust variant must
+//!   never change the wire;
+//! - unknown fields are ignored, never add `deny_unknown_fields`;
+//! - new fields must be optional and carry `#[serde(default)]`;
+//! - the server answers a request it does not know with an `Err` instead of
+//!   dropping the connection.
+//!
+//! The exact bytes of a session are pinned in [`testvectors`], for other
+//! client implementations to check against.
+
+use crate::cluster::SLOT_COUNT;
+use crate::{Children, KeyDump, KeyMeta, PrefixUsage, Rate, ValueType};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "server")]
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::str::FromStr;
+
+pub mod testvectors;
 
 #[derive(Debug, Serialize, Deserialize)]
-pub enum Request {
+pub(crate) enum Request {
+    #[serde(rename = "Get")]
     Get { key: String },
-    Set { key: String, value: String },
+    #[serde(rename = "Set")]
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "ValueType::is_string")]
+        value_type: ValueType,
+        // answer with a `WriteMeta`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        meta: bool,
+    },
+    #[serde(rename = "Remove")]
     Remove { key: String },
+    #[serde(rename = "Stats")]
+    Stats,
+    #[serde(rename = "Clients")]
+    Clients,
+    #[serde(rename = "ReadOnly")]
+    ReadOnly { enabled: bool },
+    #[serde(rename = "HotKeys")]
+    HotKeys { limit: usize },
+    #[serde(rename = "GetWithType")]
+    GetWithType { key: String },
+    #[serde(rename = "LPush")]
+    LPush { key: String, values: Vec<String> },
+    #[serde(rename = "RPush")]
+    RPush { key: String, values: Vec<String> },
+    #[serde(rename = "LRange")]
+    LRange { key: String, start: i64, stop: i64 },
+    #[serde(rename = "LPop")]
+    LPop { key: String },
+    #[serde(rename = "HSet")]
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+    },
+    #[serde(rename = "HGet")]
+    HGet { key: String, field: String },
+    #[serde(rename = "HGetAll")]
+    HGetAll { key: String },
+    #[serde(rename = "HDel")]
+    HDel { key: String, field: String },
+    #[serde(rename = "SAdd")]
+    SAdd { key: String, members: Vec<String> },
+    #[serde(rename = "SRem")]
+    SRem { key: String, members: Vec<String> },
+    #[serde(rename = "SIsMember")]
+    SIsMember { key: String, member: String },
+    #[serde(rename = "SMembers")]
+    SMembers { key: String },
+    #[serde(rename = "Rate")]
+    Rate {
+        key: String,
+        window_ms: u64,
+        limit: u64,
+    },
+    #[serde(rename = "ClusterInfo")]
+    ClusterInfo,
+    #[serde(rename = "Gossip")]
+    Gossip { nodes: Vec<NodeInfo> },
+    #[serde(rename = "Asking")]
+    Asking,
+    #[serde(rename = "SetSlot")]
+    SetSlot { slot: u16, state: SlotState },
+    #[serde(rename = "GetKeysInSlot")]
+    GetKeysInSlot { slot: u16, count: usize },
+    #[serde(rename = "Migrate")]
+    Migrate { target: String, keys: Vec<String> },
+    #[serde(rename = "Restore")]
+    Restore { key: String, dump: KeyDump },
+    #[serde(rename = "Scan")]
+    Scan {
+        after: Option<String>,
+        count: usize,
+        // a glob the keys must match
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+        // what the string values of the keys must match
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<ValueFilter>,
+    },
+    #[serde(rename = "Dump")]
+    Dump { key: String },
+    #[serde(rename = "ListChildren")]
+    ListChildren { prefix: String, separator: String },
+    #[serde(rename = "Undelete")]
+    Undelete { key: String },
+    #[serde(rename = "Fence")]
+    Fence,
+    #[serde(rename = "UseFence")]
+    UseFence { token: u64 },
+    #[serde(rename = "Upgrade")]
+    Upgrade,
+    // an upload in pieces: begin, chunks, each acknowledged so the server
+    // never queues more than one, then commit
+    #[serde(rename = "SetBegin")]
+    SetBegin { key: String },
+    #[serde(rename = "SetChunk")]
+    SetChunk { data: String },
+    #[serde(rename = "SetCommit")]
+    SetCommit,
+    // answered with `GetStreamResponse` frames
+    #[serde(rename = "GetStream")]
+    GetStream { key: String },
+    #[serde(rename = "Meta")]
+    Meta { key: String },
+    #[serde(rename = "Usage")]
+    Usage { separator: String, depth: usize },
+    #[serde(rename = "NextId")]
+    NextId { sequence: String },
+    #[serde(rename = "SetAt")]
+    SetAt {
+        key: String,
+        value: String,
+        at_ms: u64,
+    },
+    #[serde(rename = "RemoveAt")]
+    RemoveAt { key: String, at_ms: u64 },
+    // answered with `ExportResponse` frames
+    #[serde(rename = "Export")]
+    Export { since_lsn: u64 },
+    #[serde(rename = "Ping")]
+    Ping,
+    #[serde(rename = "GetField")]
+    GetField { key: String, pointer: String },
+    #[serde(rename = "ScanPrefix")]
+    ScanPrefix { prefix: String },
+    #[serde(rename = "GetMany")]
+    GetMany { keys: Vec<String> },
+    #[serde(rename = "Compact")]
+    Compact,
+}
+
+/// Size of the pieces of a value streamed with `SetChunk` requests or
+/// `GetStreamResponse::Chunk` frames, in bytes.
+pub(crate) const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl Request {
+    /// The command name, as spelled on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "Get",
+            Request::Set { .. } => "Set",
+            Request::Remove { .. } => "Remove",
+            Request::Stats => "Stats",
+            Request::Clients => "Clients",
+            Request::ReadOnly { .. } => "ReadOnly",
+            Request::HotKeys { .. } => "HotKeys",
+            Request::GetWithType { .. } => "GetWithType",
+            Request::LPush { .. } => "LPush",
+            Request::RPush { .. } => "RPush",
+            Request::LRange { .. } => "LRange",
+            Request::LPop { .. } => "LPop",
+            Request::HSet { .. } => "HSet",
+            Request::HGet { .. } => "HGet",
+            Request::HGetAll { .. } => "HGetAll",
+            Request::HDel { .. } => "HDel",
+            Request::SAdd { .. } => "SAdd",
+            Request::SRem { .. } => "SRem",
+            Request::SIsMember { .. } => "SIsMember",
+            Request::SMembers { .. } => "SMembers",
+            Request::Rate { .. } => "Rate",
+            Request::ClusterInfo => "ClusterInfo",
+            Request::Gossip { .. } => "Gossip",
+            Request::Asking => "Asking",
+            Request::SetSlot { .. } => "SetSlot",
+            Request::GetKeysInSlot { .. } => "GetKeysInSlot",
+            Request::Migrate { .. } => "Migrate",
+            Request::Restore { .. } => "Restore",
+            Request::Scan { .. } => "Scan",
+            Request::Dump { .. } => "Dump",
+            Request::ListChildren { .. } => "ListChildren",
+            Request::Undelete { .. } => "Undelete",
+            Request::Fence => "Fence",
+            Request::UseFence { .. } => "UseFence",
+            Request::Upgrade => "Upgrade",
+            Request::SetBegin { .. } => "SetBegin",
+            Request::SetChunk { .. } => "SetChunk",
+            Request::SetCommit => "SetCommit",
+            Request::GetStream { .. } => "GetStream",
+            Request::NextId { .. } => "NextId",
+            Request::Meta { .. } => "Meta",
+            Request::Usage { .. } => "Usage",
+            Request::SetAt { .. } => "SetAt",
+            Request::RemoveAt { .. } => "RemoveAt",
+            Request::Export { .. } => "Export",
+            Request::Ping => "Ping",
+            Request::GetField { .. } => "GetField",
+            Request::ScanPrefix { .. } => "ScanPrefix",
+            Request::GetMany { .. } => "GetMany",
+            Request::Compact => "Compact",
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Request {
+    /// The key the request operates on, if any.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::GetWithType { key }
+            | Request::LPush { key, .. }
+            | Request::RPush { key, .. }
+            | Request::LRange { key, .. }
+            | Request::LPop { key }
+            | Request::HSet { key, .. }
+            | Request::HGet { key, .. }
+            | Request::HGetAll { key }
+            | Request::HDel { key, .. }
+            | Request::SAdd { key, .. }
+            | Request::SRem { key, .. }
+            | Request::SIsMember { key, .. }
+            | Request::SMembers { key }
+            | Request::Rate { key, .. }
+            | Request::Restore { key, .. }
+            | Request::Dump { key }
+            | Request::Undelete { key }
+            | Request::SetBegin { key }
+            | Request::GetStream { key }
+            | Request::Meta { key }
+            | Request::GetField { key, .. }
+            | Request::SetAt { key, .. }
+            | Request::RemoveAt { key, .. }
+            | Request::NextId { sequence: key } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Whether the request modifies the stored data.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::Remove { .. }
+                | Request::LPush { .. }
+                | Request::RPush { .. }
+                | Request::LPop { .. }
+                | Request::HSet { .. }
+                | Request::HDel { .. }
+                | Request::SAdd { .. }
+                | Request::SRem { .. }
+                | Request::Rate { .. }
+                | Request::Migrate { .. }
+                | Request::Restore { .. }
+                | Request::Undelete { .. }
+                | Request::SetBegin { .. }
+                | Request::SetChunk { .. }
+                | Request::SetCommit
+                | Request::NextId { .. }
+                | Request::SetAt { .. }
+                | Request::RemoveAt { .. }
+        )
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
+pub(crate) enum GetResponse {
+    #[serde(rename = "Ok")]
     Ok(Option<String>),
+    #[serde(rename = "Err")]
     Err(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub enum SetResponse {
+pub(crate) enum GetManyResponse {
+    #[serde(rename = "Ok")]
+    Ok(Vec<Option<String>>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum GetWithTypeResponse {
+    #[serde(rename = "Ok")]
+    Ok(Option<TypedValue>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum SetResponse {
+    #[serde(rename = "Ok")]
     Ok(()),
+    #[serde(rename = "Err")]
     Err(String),
 }
 
+// an older server answers a `Set` asking for metadata with a `SetResponse`,
+// its `null` reads as no metadata
 #[derive(Debug, Serialize, Deserialize)]
-pub enum RemoveResponse {
+pub(crate) enum SetMetaResponse {
+    #[serde(rename = "Ok")]
+    Ok(Option<WriteMeta>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// Answers a `GetStream`: the value in `Chunk` frames, then whether the key
+/// exists, or an error, which may follow some chunks.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum GetStreamResponse {
+    #[serde(rename = "Chunk")]
+    Chunk(String),
+    #[serde(rename = "Ok")]
+    Ok(bool),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// Answers an `Export`: a `Key` frame for every key changed since the LSN,
+/// then the LSN to export from next time, or an error, which may follow
+/// some keys.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ExportResponse {
+    #[serde(rename = "Key")]
+    Key {
+        key: String,
+        // `None` for a key holding no data anymore
+        dump: Option<KeyDump>,
+    },
+    #[serde(rename = "Ok")]
+    Ok(Option<u64>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum RemoveResponse {
+    #[serde(rename = "Ok")]
     Ok(()),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// Answers with a count: the new length of the list for `LPush` and
+/// `RPush`, the number of added or removed members for `SAdd` and `SRem`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum CountResponse {
+    #[serde(rename = "Ok")]
+    Ok(usize),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum LRangeResponse {
+    #[serde(rename = "Ok")]
+    Ok(Vec<String>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum HGetAllResponse {
+    #[serde(rename = "Ok")]
+    Ok(BTreeMap<String, String>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum SIsMemberResponse {
+    #[serde(rename = "Ok")]
+    Ok(bool),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum SMembersResponse {
+    #[serde(rename = "Ok")]
+    Ok(BTreeSet<String>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum RateResponse {
+    #[serde(rename = "Ok")]
+    Ok(Rate),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// Answers `ClusterInfo`, and `Gossip` with the view of the receiving node.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ClusterInfoResponse {
+    #[serde(rename = "Ok")]
+    Ok(ClusterInfo),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum KeysResponse {
+    #[serde(rename = "Ok")]
+    Ok(Vec<String>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum DumpResponse {
+    #[serde(rename = "Ok")]
+    Ok(Option<KeyDump>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum MetaResponse {
+    #[serde(rename = "Ok")]
+    Ok(Option<KeyMeta>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum UsageResponse {
+    #[serde(rename = "Ok")]
+    Ok(Vec<PrefixUsage>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ChildrenResponse {
+    #[serde(rename = "Ok")]
+    Ok(Children),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// Answers with a number: the fencing token for `Fence`, the ID for
+/// `NextId`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum TokenResponse {
+    #[serde(rename = "Ok")]
+    Ok(u64),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum StatsResponse {
+    #[serde(rename = "Ok")]
+    Ok(ServerStats),
+    #[serde(rename = "Err")]
     Err(String),
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum PingResponse {
+    #[serde(rename = "Ok")]
+    Ok(Health),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ClientsResponse {
+    #[serde(rename = "Ok")]
+    Ok(Vec<ClientStats>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum HotKeysResponse {
+    #[serde(rename = "Ok")]
+    Ok(Vec<HotKey>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// A `GetResponse` whose value is written as the engine stored it, already
+/// encoded as a JSON string, to spare decoding and re-encoding large values.
+/// The server writes found values through `KvsEngine::get_raw_to_writer`
+/// right after the start of the `Ok` variant, streaming chunked ones.
+#[cfg(feature = "server")]
+#[derive(Debug, Serialize)]
+pub(crate) enum RawGetResponse {
+    #[serde(rename = "Ok")]
+    Ok(Option<Box<RawValue>>),
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// Sent back for a request the server failed to decode, e.g. a command
+/// introduced by a newer client.
+#[cfg(feature = "server")]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum ErrorResponse {
+    #[serde(rename = "Err")]
+    Err(String),
+}
+
+/// Marks the context a server appends to its error messages, see
+/// `KvsServer::error_context`.
+#[cfg(any(feature = "client", feature = "server"))]
+const ERROR_CONTEXT: &str = " (kvs ";
+
+/// How much of the key is echoed back in the context of an error.
+#[cfg(feature = "server")]
+const ERROR_CONTEXT_KEY_LEN: usize = 64;
+
+/// The context of an error answering the command `op` on `key`: the version
+/// of the server, the command, and the key, truncated and escaped so that it
+/// fits on one log line.
+#[cfg(feature = "server")]
+pub(crate) fn error_context(op: &str, key: Option<&str>) -> String {
+    let mut context = format!("{}{}, {}", ERROR_CONTEXT, env!("CARGO_PKG_VERSION"), op);
+    if let Some(key) = key {
+        let mut echo: String = key
+            .chars()
+            .take(ERROR_CONTEXT_KEY_LEN)
+            .flat_map(char::escape_debug)
+            .collect();
+        if key.chars().nth(ERROR_CONTEXT_KEY_LEN).is_some() {
+            echo.push_str("...");
+        }
+        context.push_str(&format!(" key \"{}\"", echo));
+    }
+    context.push(')');
+    context
+}
+
+/// The message of an error without the context appended by the server.
+#[cfg(feature = "client")]
+pub(crate) fn strip_error_context(message: &str) -> &str {
+    message
+        .find(ERROR_CONTEXT)
+        .map_or(message, |at| &message[..at])
+}
+
+/// A response which may be an error, whose message the server appends its
+/// context to.
+#[cfg(feature = "server")]
+pub(crate) trait ErrorMessage {
+    fn error_mut(&mut self) -> Option<&mut String>;
+}
+
+#[cfg(feature = "server")]
+macro_rules! error_message {
+    ($($resp:ident),* $(,)?) => {
+        $(
+            impl ErrorMessage for $resp {
+                fn error_mut(&mut self) -> Option<&mut String> {
+                    match self {
+                        $resp::Err(message) => Some(message),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "server")]
+error_message!(
+    GetResponse,
+    GetManyResponse,
+    GetWithTypeResponse,
+    SetResponse,
+    SetMetaResponse,
+    GetStreamResponse,
+    ExportResponse,
+    RemoveResponse,
+    CountResponse,
+    LRangeResponse,
+    HGetAllResponse,
+    SIsMemberResponse,
+    SMembersResponse,
+    RateResponse,
+    ClusterInfoResponse,
+    KeysResponse,
+    DumpResponse,
+    MetaResponse,
+    UsageResponse,
+    ChildrenResponse,
+    TokenResponse,
+    StatsResponse,
+    PingResponse,
+    ClientsResponse,
+    HotKeysResponse,
+    RawGetResponse,
+);
+
+#[cfg(feature = "server")]
+impl ErrorMessage for ErrorResponse {
+    fn error_mut(&mut self) -> Option<&mut String> {
+        let ErrorResponse::Err(message) = self;
+        Some(message)
+    }
+}
+
+/// Scheduling statistics reported by a running server.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerStats {
+    /// Number of currently open connections
+    pub connections: u64,
+    /// Total number of requests served
+    pub requests: u64,
+    /// Number of requests received but not yet served
+    pub queued: u64,
+    /// Number of times a connection used up its budget and yielded to others
+    pub yields: u64,
+    /// Longest time a request has waited in the queue, in microseconds
+    pub max_wait_us: u64,
+    /// Whether the server currently rejects writes
+    pub read_only: bool,
+    /// Epoch of the lease on the shared data directory, while this server
+    /// holds it and serves writes
+    pub epoch: Option<u64>,
+    /// Counters about the internals of the engine, see
+    /// `KvsEngine::metrics`
+    pub engine: BTreeMap<String, u64>,
+}
+
+/// Whether a server takes new traffic, as answered to a ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Health {
+    /// Serving
+    #[serde(rename = "ok")]
+    Ok,
+    /// Shutting down: requests are still served, but load balancers should
+    /// stop routing new connections to the server
+    #[serde(rename = "draining")]
+    Draining,
+}
+
+impl Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Health::Ok => "ok",
+            Health::Draining => "draining",
+        })
+    }
+}
+
+/// What the server reports about a write, see `KvsClient::set_with_meta`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WriteMeta {
+    /// Time from receiving the request to answering it, queueing included,
+    /// in microseconds
+    pub server_time_us: u64,
+    /// Position of the write in the log of the engine, increasing with every
+    /// write, if the engine has one; see `KvsEngine::lsn`
+    pub lsn: Option<u64>,
+    /// Address of the cluster node which served the write, in cluster mode
+    pub node: Option<String>,
+}
+
+/// Traffic counters of all connections coming from one client IP.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientStats {
+    /// IP address of the client
+    pub ip: String,
+    /// Number of currently open connections
+    pub connections: u64,
+    /// Total number of requests received
+    pub requests: u64,
+    /// Total size of the received requests, in bytes
+    pub bytes_in: u64,
+    /// Total size of the sent responses, in bytes
+    pub bytes_out: u64,
+    /// Number of requests received per command
+    pub ops: BTreeMap<String, u64>,
+}
+
+/// A frequently accessed key, as estimated by the server's top-k sketch.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotKey {
+    /// The key
+    pub key: String,
+    /// Estimated number of accesses
+    pub count: u64,
+    /// Upper bound of the overestimation of `count`
+    pub error: u64,
+}
+
+/// The membership of a cluster, as seen by one of its nodes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterInfo {
+    /// Every known node, the answering one included
+    pub nodes: Vec<NodeInfo>,
+    /// Slots the answering node is moving to another node, with the target
+    pub migrating: BTreeMap<u16, String>,
+    /// Slots the answering node is receiving from another node, with the
+    /// source
+    pub importing: BTreeMap<u16, String>,
+}
+
+/// A filter on the string values of the keys of a scan, applied by the
+/// server. Keys holding no string value never match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueFilter {
+    /// The value contains this substring
+    #[serde(rename = "Contains")]
+    Contains(String),
+    /// The value matches this regular expression, in the syntax of the
+    /// `regex` crate, which runs in time linear in the value
+    #[serde(rename = "Regex")]
+    Regex(String),
+}
+
+/// A node of a cluster.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeInfo {
+    /// Address of the node, which also identifies it
+    pub addr: String,
+    /// Hash slots served by the node
+    pub slots: Vec<SlotRange>,
+    /// Version of the information, the highest one wins when views differ
+    pub epoch: u64,
+    /// Whether the node answered gossip recently
+    pub alive: bool,
+}
+
+/// A change of the state of a hash slot on one node, see `migrate_slot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SlotState {
+    /// The slot is being moved to this node from the node at `from`
+    #[serde(rename = "Importing")]
+    Importing {
+        /// Address of the source node
+        from: String,
+    },
+    /// The slot is being moved from this node to the node at `to`
+    #[serde(rename = "Migrating")]
+    Migrating {
+        /// Address of the target node
+        to: String,
+    },
+    /// The slot is now served by the node at `addr`, ending any migration
+    #[serde(rename = "Node")]
+    Node {
+        /// Address of the owner
+        addr: String,
+    },
+    /// Abort any migration of the slot
+    #[serde(rename = "Stable")]
+    Stable,
+}
+
+/// An inclusive range of hash slots, written `start-end`, or `slot` for a
+/// single one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SlotRange {
+    /// First slot of the range
+    pub start: u16,
+    /// Last slot of the range
+    pub end: u16,
+}
+
+impl SlotRange {
+    /// Whether `slot` is in the range.
+    pub fn contains(&self, slot: u16) -> bool {
+        self.start <= slot && slot <= self.end
+    }
+}
+
+impl FromStr for SlotRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse = |slot: &str| match slot.trim().parse::<u16>() {
+            Ok(slot) if slot < SLOT_COUNT => Ok(slot),
+            _ => Err(format!("Invalid slot: {}", slot)),
+        };
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if start > end {
+            return Err(format!("Invalid slot range: {}", s));
+        }
+        Ok(SlotRange { start, end })
+    }
+}
+
+impl Display for SlotRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TypedValue {
+    pub(crate) value: String,
+    #[serde(default)]
+    pub(crate) value_type: ValueType,
+}
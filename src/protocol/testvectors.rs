@@ -0,0 +1,198 @@
+//! Canonical encodings of requests and the server's answers to them, for
+//! checking other client implementations against the bytes the Rust server
+//! expects.
+//!
+//! The vectors form one session: replayed in order on a single connection
+//! to a server with an empty store, every request gets exactly the encoded
+//! response next to it.
+
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+
+use serde_json::value::RawValue;
+use serde_json::{Deserializer, Value};
+
+use super::Request;
+use crate::{KvsError, Result};
+
+/// A request as encoded on the wire, and the server's answer to it.
+#[derive(Debug, Clone, Copy)]
+pub struct Vector {
+    /// What the vector covers
+    pub name: &'static str,
+    /// The canonical encoding of the request
+    pub request: &'static str,
+    /// The encoding of the response, as sent by the server
+    pub response: &'static str,
+}
+
+/// The vectors, in session order.
+pub const VECTORS: &[Vector] = &[
+    Vector {
+        name: "set",
+        request: r#"{"Set":{"key":"key1","value":"value1"}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "get",
+        request: r#"{"Get":{"key":"key1"}}"#,
+        response: r#"{"Ok":"value1"}"#,
+    },
+    Vector {
+        name: "get missing key",
+        request: r#"{"Get":{"key":"missing"}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "set with type",
+        request: r#"{"Set":{"key":"key2","value":"42","value_type":"int"}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "set invalid value",
+        request: r#"{"Set":{"key":"key3","value":"forty-two","value_type":"int"}}"#,
+        response: r#"{"Err":"Invalid value: not an integer: invalid digit found in string"}"#,
+    },
+    Vector {
+        name: "get with type",
+        request: r#"{"GetWithType":{"key":"key2"}}"#,
+        response: r#"{"Ok":{"value":"42","value_type":"int"}}"#,
+    },
+    Vector {
+        name: "remove",
+        request: r#"{"Remove":{"key":"key1"}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "remove missing key",
+        request: r#"{"Remove":{"key":"key1"}}"#,
+        response: r#"{"Err":"Key not found"}"#,
+    },
+    Vector {
+        name: "lpush",
+        request: r#"{"LPush":{"key":"list","values":["a","b"]}}"#,
+        response: r#"{"Ok":2}"#,
+    },
+    Vector {
+        name: "rpush",
+        request: r#"{"RPush":{"key":"list","values":["c"]}}"#,
+        response: r#"{"Ok":3}"#,
+    },
+    Vector {
+        name: "lrange",
+        request: r#"{"LRange":{"key":"list","start":0,"stop":-1}}"#,
+        response: r#"{"Ok":["b","a","c"]}"#,
+    },
+    Vector {
+        name: "lpop",
+        request: r#"{"LPop":{"key":"list"}}"#,
+        response: r#"{"Ok":"b"}"#,
+    },
+    Vector {
+        name: "hset",
+        request: r#"{"HSet":{"key":"hash","field":"f1","value":"v1"}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "hget",
+        request: r#"{"HGet":{"key":"hash","field":"f1"}}"#,
+        response: r#"{"Ok":"v1"}"#,
+    },
+    Vector {
+        name: "hgetall",
+        request: r#"{"HGetAll":{"key":"hash"}}"#,
+        response: r#"{"Ok":{"f1":"v1"}}"#,
+    },
+    Vector {
+        name: "sadd",
+        request: r#"{"SAdd":{"key":"set","members":["x","y","x"]}}"#,
+        response: r#"{"Ok":2}"#,
+    },
+    Vector {
+        name: "sismember",
+        request: r#"{"SIsMember":{"key":"set","member":"y"}}"#,
+        response: r#"{"Ok":true}"#,
+    },
+    Vector {
+        name: "smembers",
+        request: r#"{"SMembers":{"key":"set"}}"#,
+        response: r#"{"Ok":["x","y"]}"#,
+    },
+    Vector {
+        name: "scan",
+        request: r#"{"Scan":{"after":null,"count":10}}"#,
+        response: r#"{"Ok":["hash","key2","list","set"]}"#,
+    },
+    Vector {
+        name: "set begin",
+        request: r#"{"SetBegin":{"key":"big"}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "set chunk",
+        request: r#"{"SetChunk":{"data":"hello "}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "set last chunk",
+        request: r#"{"SetChunk":{"data":"world"}}"#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "set commit",
+        request: r#""SetCommit""#,
+        response: r#"{"Ok":null}"#,
+    },
+    Vector {
+        name: "get streamed value",
+        request: r#"{"Get":{"key":"big"}}"#,
+        response: r#"{"Ok":"hello world"}"#,
+    },
+];
+
+/// Check that `encoded`, a request sent by another client for `vector`,
+/// is the request of the vector. Field order and whitespace are free, as
+/// the server decodes them alike.
+pub fn check_request(vector: &Vector, encoded: &[u8]) -> Result<()> {
+    let request: Request = serde_json::from_slice(encoded)
+        .map_err(|e| mismatch(vector, format!("the server cannot decode it: {}", e)))?;
+    let canonical = serde_json::to_string(&request)?;
+    if canonical != vector.request {
+        return Err(mismatch(vector, format!("it decodes as {}", canonical)));
+    }
+    Ok(())
+}
+
+/// Check that `encoded`, a response received for `vector`, is the response
+/// of the vector, up to field order and whitespace.
+pub fn check_response(vector: &Vector, encoded: &[u8]) -> Result<()> {
+    let expected: Value = serde_json::from_str(vector.response)?;
+    let response: Value = serde_json::from_slice(encoded)?;
+    if response != expected {
+        return Err(mismatch(vector, format!("the response is {}", response)));
+    }
+    Ok(())
+}
+
+/// Replay every vector on one connection to the server at `addr`, whose
+/// store must be empty, checking the exact bytes of each response.
+pub fn replay(addr: &str) -> Result<()> {
+    let mut writer = TcpStream::connect(addr)?;
+    let reader = BufReader::new(writer.try_clone()?);
+    let mut responses = Deserializer::from_reader(reader).into_iter::<Box<RawValue>>();
+    for vector in VECTORS {
+        writer.write_all(vector.request.as_bytes())?;
+        writer.flush()?;
+        let response = responses
+            .next()
+            .ok_or_else(|| mismatch(vector, "the server closed the connection".to_owned()))??;
+        if response.get() != vector.response {
+            return Err(mismatch(vector, format!("the response is {}", response)));
+        }
+    }
+    Ok(())
+}
+
+fn mismatch(vector: &Vector, reason: String) -> KvsError {
+    KvsError::Other(format!("vector {:?}: {}", vector.name, reason))
+}
@@ -1,83 +1,1713 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::io::Seek;
 use std::io::Write;
+use std::net::IpAddr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::ops::Range;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use log::debug;
 use log::error;
+use log::info;
+use log::warn;
 use serde_json::Deserializer;
+use serde_json::Value;
 
+#[cfg(feature = "chaos")]
+use crate::chaos::{Chaos, Fault};
+use crate::cluster;
+use crate::cluster::Cluster;
+use crate::cluster::Membership;
+use crate::cluster::Route;
+use crate::diskwatch::DiskWatch;
+use crate::engines::check_key;
+use crate::glob::Glob;
+use crate::hotkeys::HotKeys;
+use crate::lease::Lease;
+use crate::protocol;
+use crate::protocol::ChildrenResponse;
+use crate::protocol::ClientStats;
+use crate::protocol::ClientsResponse;
+use crate::protocol::ClusterInfoResponse;
+use crate::protocol::CountResponse;
+use crate::protocol::DumpResponse;
+use crate::protocol::ErrorMessage;
+use crate::protocol::ErrorResponse;
+use crate::protocol::ExportResponse;
+use crate::protocol::GetManyResponse;
 use crate::protocol::GetResponse;
+use crate::protocol::GetStreamResponse;
+use crate::protocol::GetWithTypeResponse;
+use crate::protocol::HGetAllResponse;
+use crate::protocol::Health;
+use crate::protocol::HotKeysResponse;
+use crate::protocol::KeysResponse;
+use crate::protocol::LRangeResponse;
+use crate::protocol::MetaResponse;
+use crate::protocol::PingResponse;
+use crate::protocol::RateResponse;
+use crate::protocol::RawGetResponse;
 use crate::protocol::RemoveResponse;
 use crate::protocol::Request;
+use crate::protocol::SIsMemberResponse;
+use crate::protocol::SMembersResponse;
+use crate::protocol::ServerStats;
+use crate::protocol::SetMetaResponse;
 use crate::protocol::SetResponse;
+use crate::protocol::StatsResponse;
+use crate::protocol::TokenResponse;
+use crate::protocol::TypedValue;
+use crate::protocol::UsageResponse;
+use crate::protocol::ValueFilter;
+use crate::protocol::WriteMeta;
+use crate::protocol::STREAM_CHUNK_SIZE;
+use crate::trace::TraceEvent;
+use crate::trace::Tracer;
 use crate::KvsEngine;
+use crate::KvsError;
 use crate::Result;
+use crate::ValueType;
+
+/// Default number of requests served from one connection before yielding.
+const DEFAULT_BUDGET: usize = 32;
+
+/// Default time a scan may spend filtering values.
+const DEFAULT_SCAN_BUDGET: Duration = Duration::from_millis(500);
+
+/// Largest compiled program of a value filter regex, in bytes.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Default number of IDs of a sequence leased at once.
+const DEFAULT_ID_BLOCK: u64 = 100;
+
+/// Default time given to connections to close after an upgrade.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time a server shutting down reports draining to pings before it
+/// stops accepting connections.
+const DEFAULT_DRAIN_DELAY: Duration = Duration::from_secs(5);
+
+/// How often a server sharing its data directory checks the lease, and
+/// picks up the writes of the leader while following.
+const LEASE_CHECK: Duration = Duration::from_millis(100);
+
+/// Commands of the admin console.
+const CONSOLE_HELP: &str = "\
+stats                 counters of the server
+clients               traffic of every client IP
+compact               compact the data directory now
+readonly on|off       refuse or accept writes
+loglevel [LEVEL]      show or set the log level, e.g. debug
+shutdown              report draining, then drain connections and exit
+quit                  close the console
+";
+
+/// How often the scheduled writes which are due are run.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the string keys which expired are swept, for engines whose
+/// reads only hide them.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Starts the server replacing this one, handing it the listener.
+type UpgradeHook = Box<dyn FnMut(&TcpListener) -> Result<()> + Send>;
+
+/// Opens the engine again, for writing or for reading only.
+type OpenHook<E> = Box<dyn FnMut(bool) -> Result<E> + Send>;
+
+struct Leased<E> {
+    lease: Lease,
+    open: OpenHook<E>,
+    // the epoch the engine was opened for writing under, none while following
+    epoch: Option<u64>,
+    next_check: Instant,
+}
 
 /// The server of key-value store.
+///
+/// Every connection gets its own reader thread which decodes requests and
+/// queues them for the engine thread. The engine thread serves connections
+/// round-robin, at most `budget` requests at a time, so one client pipelining
+/// thousands of requests cannot starve the others.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    budget: usize,
+    scan_budget: Duration,
+    read_only: bool,
+    error_context: bool,
+    disk: Option<DiskWatch>,
+    lease: Option<Leased<E>>,
+    hot_keys: Option<HotKeys>,
+    // IDs leased from every sequence and not handed out yet
+    sequences: HashMap<String, Range<u64>>,
+    id_block: u64,
+    cluster: Option<Arc<Mutex<Membership>>>,
+    // the latest fencing token handed out, and where it is kept
+    fence: u64,
+    fence_file: Option<PathBuf>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
+    upgrade: Option<UpgradeHook>,
+    drain_timeout: Duration,
+    drain_delay: Duration,
+    // when a server shutting down stops accepting connections
+    shutdown_at: Option<Instant>,
+    // where the admin console listens
+    admin_socket: Option<PathBuf>,
+    // where to trace the sampled keys and at which rate, until started
+    trace: Option<(PathBuf, f64)>,
+    tracer: Option<Tracer>,
+    // kept to be handed over on an upgrade, until then
+    listener: Option<TcpListener>,
+    // set once the listener is handed over, stops accepting connections
+    draining: Arc<AtomicBool>,
+    drain_deadline: Option<Instant>,
+    // when the scheduled writes are run next, and the expired keys swept
+    next_sweep: Instant,
+    next_expiry_sweep: Instant,
+    conns: HashMap<u64, Connection>,
+    ready: VecDeque<u64>,
+    stats: ServerStats,
+    clients: HashMap<IpAddr, ClientStats>,
+}
+
+enum Event {
+    Connected {
+        id: u64,
+        addr: SocketAddr,
+        stream: TcpStream,
+    },
+    Request {
+        id: u64,
+        req: Incoming,
+        size: u64,
+        received: Instant,
+    },
+    Closed {
+        id: u64,
+    },
+    // a command typed on the admin console, and where to send its output
+    Console {
+        line: String,
+        reply: Sender<String>,
+    },
+}
+
+/// A decoded request, or why it could not be decoded.
+type Incoming = std::result::Result<Request, String>;
+
+struct Pending {
+    req: Incoming,
+    size: u64,
+    received: Instant,
+}
+
+struct Upload {
+    key: String,
+    file: BufWriter<File>,
+}
+
+struct Connection {
+    addr: SocketAddr,
+    writer: BufWriter<TcpStream>,
+    pending: VecDeque<Pending>,
+    closed: bool,
+    // the next request was redirected here with `KvsError::Ask`
+    asking: bool,
+    // the fencing token writes of the connection are checked against
+    fence: Option<u64>,
+    // the value being uploaded in pieces, kept in a temporary file
+    upload: Option<Upload>,
+    // open as long as the connection, the parent of its requests
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 /// Implement the server of key-value store.
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a new server with the given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            budget: DEFAULT_BUDGET,
+            scan_budget: DEFAULT_SCAN_BUDGET,
+            read_only: false,
+            error_context: false,
+            disk: None,
+            lease: None,
+            hot_keys: None,
+            sequences: HashMap::new(),
+            id_block: DEFAULT_ID_BLOCK,
+            cluster: None,
+            fence: 0,
+            fence_file: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            upgrade: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            drain_delay: DEFAULT_DRAIN_DELAY,
+            shutdown_at: None,
+            admin_socket: None,
+            trace: None,
+            tracer: None,
+            listener: None,
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_deadline: None,
+            next_sweep: Instant::now(),
+            next_expiry_sweep: Instant::now(),
+            conns: HashMap::new(),
+            ready: VecDeque::new(),
+            stats: ServerStats::default(),
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Set how many requests are served from one connection before the
+    /// server yields to the next connection with pending requests.
+    pub fn budget(mut self, budget: usize) -> Self {
+        self.budget = budget.max(1);
+        self
+    }
+
+    /// Set how long one Scan request may spend reading and filtering
+    /// values, so a costly filter over a large store cannot hold the engine
+    /// thread. A scan running out of it returns the keys matched so far.
+    pub fn scan_budget(mut self, budget: Duration) -> Self {
+        self.scan_budget = budget;
+        self
+    }
+
+    /// Start the server in read-only mode, rejecting every write with
+    /// `KvsError::ReadOnly`, compactions included, so the data directory
+    /// can be backed up. It can be switched back at runtime.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Append the version of the server, the command and an echo of its
+    /// key to the error messages sent back, e.g. `Key not found (kvs 0.1.0,
+    /// Remove key "user:42")`, so that the logs of a client sharing the
+    /// server with other services tell what failed. Clients of this release
+    /// drop it to recover the typed errors, older ones only see it in the
+    /// message, hence off by default.
+    pub fn error_context(mut self, error_context: bool) -> Self {
+        self.error_context = error_context;
+        self
+    }
+
+    /// Refuse writes and compactions with `KvsError::DiskFull` while the
+    /// disk of `dir`, the data directory, has less than `bytes` free, so
+    /// that it never fills up halfway through a record. Reads are still
+    /// served.
+    pub fn min_free_space(mut self, dir: impl Into<PathBuf>, bytes: u64) -> Self {
+        self.disk = Some(DiskWatch::new(dir.into(), bytes));
+        self
+    }
+
+    /// Share the data directory with other servers under `lease`: only the
+    /// server holding it serves writes, the others refuse them with
+    /// `KvsError::NotLeader` and serve reads, refreshing their engine to
+    /// pick up the writes of the leader.
+    ///
+    /// The engine given to `new` is the one of a follower, opened read-only.
+    /// `open(true)` opens it for writing once the lease is taken, and
+    /// `open(false)` read-only again once it is lost.
+    pub fn lease(
+        mut self,
+        lease: Lease,
+        open: impl FnMut(bool) -> Result<E> + Send + 'static,
+    ) -> Self {
+        self.lease = Some(Leased {
+            lease,
+            open: Box::new(open),
+            epoch: None,
+            next_check: Instant::now(),
+        });
+        self
+    }
+
+    /// Track the most accessed keys in a sketch holding `capacity` keys,
+    /// queried with the HotKeys request.
+    pub fn hot_keys(mut self, capacity: usize) -> Self {
+        self.hot_keys = Some(HotKeys::new(capacity));
+        self
+    }
+
+    /// Lease IDs of a sequence `size` at a time: `NextId` writes the end
+    /// of a block to the store once, then hands out its IDs from memory.
+    /// The IDs left in a block when the server stops are skipped.
+    pub fn id_block(mut self, size: u64) -> Self {
+        self.id_block = size.max(1);
+        self
+    }
+
+    /// Join a cluster: serve only the keys of the slots of this node,
+    /// redirecting the others with `KvsError::Moved`, and gossip the
+    /// membership with the other nodes.
+    pub fn cluster(mut self, cluster: Cluster) -> Self {
+        self.cluster = Some(Arc::new(Mutex::new(Membership::new(cluster))));
+        self
+    }
+
+    /// Inject faults while serving requests, to test how clients cope
+    /// with a slow or failing server.
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Keep the latest fencing token in the file at `path`, so tokens keep
+    /// increasing across restarts. Without it, tokens start over from 1.
+    pub fn fence_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.fence_file = Some(path.into());
+        self
+    }
+
+    /// Serve the Upgrade request by handing the listener to `hook`, which
+    /// starts the server taking over, e.g. a new binary inheriting the
+    /// socket. This server then stops accepting connections and returns
+    /// from `run` once the established ones are closed.
+    pub fn upgrade(
+        mut self,
+        hook: impl FnMut(&TcpListener) -> Result<()> + Send + 'static,
+    ) -> Self {
+        self.upgrade = Some(Box::new(hook));
+        self
+    }
+
+    /// Set how long connections established before an upgrade are served,
+    /// before they are shut down.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set how long a server asked to shut down answers pings with
+    /// `Health::Draining` while still accepting connections, for load
+    /// balancers to stop routing to it, before it drains its connections
+    /// like after an upgrade and exits.
+    pub fn drain_delay(mut self, delay: Duration) -> Self {
+        self.drain_delay = delay;
+        self
+    }
+
+    /// Serve a text console on a Unix socket at `path`, readable and
+    /// writable by the user running the server only, e.g. through `nc -U`
+    /// or `socat`. It takes one command per line, `help` listing them, and
+    /// stays reachable when the port of the server is firewalled.
+    #[cfg(unix)]
+    pub fn admin_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.admin_socket = Some(path.into());
+        self
+    }
+
+    /// Append a trace of the gets, sets and removals of `sample_rate` of
+    /// the string keys, between 0 and 1, to the file at `path`, for
+    /// `kvs-admin analyze-trace` to recommend a cache size. See `trace`.
+    pub fn trace(mut self, path: impl Into<PathBuf>, sample_rate: f64) -> Self {
+        self.trace = Some((path.into(), sample_rate));
+        self
     }
 
     /// Run the server with the given address.
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.serve(stream) {
-                        error!("starting server error: {}", e);
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.run_on(TcpListener::bind(addr)?)
+    }
+
+    /// Run the server on a listener bound already, e.g. one handed over by
+    /// the server it replaces.
+    pub fn run_on(mut self, listener: TcpListener) -> Result<()> {
+        if let Some(path) = self.fence_file.as_ref().filter(|path| path.exists()) {
+            self.fence = std::fs::read_to_string(path)?
+                .trim()
+                .parse()
+                .map_err(|e| KvsError::Other(format!("invalid fence file: {}", e)))?;
+        }
+        if self.upgrade.is_some() {
+            self.listener = Some(listener.try_clone()?);
+        }
+        if let Some(disk) = &self.disk {
+            disk.start()?;
+        }
+        if let Some(leased) = &self.lease {
+            leased.lease.start()?;
+        }
+        if let Some((path, sample_rate)) = self.trace.take() {
+            self.tracer = Some(Tracer::open(&path, sample_rate)?);
+            info!("Tracing {} of the keys to {}", sample_rate, path.display());
+        }
+        let (tx, rx) = mpsc::channel();
+        let draining = Arc::clone(&self.draining);
+        #[cfg(unix)]
+        if let Some(path) = &self.admin_socket {
+            let console = bind_console(path)?;
+            let tx = tx.clone();
+            thread::spawn(move || accept_console(console, tx));
+        }
+        thread::spawn(move || accept(listener, tx, draining));
+        if let Some(membership) = &self.cluster {
+            let membership = Arc::clone(membership);
+            thread::spawn(move || cluster::gossip(membership));
+        }
+
+        loop {
+            self.follow_lease();
+            self.sweep();
+            if self.shutdown_at.is_some_and(|at| Instant::now() >= at) {
+                info!("Drain delay over, draining connections");
+                self.shutdown_at = None;
+                self.draining.store(true, Ordering::Relaxed);
+                self.drain_deadline = Some(Instant::now() + self.drain_timeout);
+            }
+            if let Some(deadline) = self.drain_deadline {
+                if self.conns.is_empty() {
+                    info!("Connections drained, exiting");
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    warn!(
+                        "Drain timeout, shutting down {} connections",
+                        self.conns.len()
+                    );
+                    for conn in self.conns.values() {
+                        let _ = conn.writer.get_ref().shutdown(Shutdown::Both);
                     }
+                    return Ok(());
                 }
-                Err(e) => error!("connection failed: {}", e),
+            }
+            // block only when there is nothing left to serve
+            if self.ready.is_empty() {
+                let mut timeout = self.next_sweep.saturating_duration_since(Instant::now());
+                if let Some(deadline) = self.drain_deadline.or(self.shutdown_at) {
+                    timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+                }
+                if self.lease.is_some() {
+                    timeout = timeout.min(LEASE_CHECK);
+                }
+                match rx.recv_timeout(timeout) {
+                    Ok(event) => self.handle_event(event),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            }
+            while let Ok(event) = rx.try_recv() {
+                self.handle_event(event);
+            }
+            if let Some(id) = self.ready.pop_front() {
+                self.serve_turn(id);
             }
         }
-        Ok(())
     }
 
-    fn serve(&mut self, conn: TcpStream) -> Result<()> {
-        let cli_addr = conn.peer_addr()?;
-        let reader = BufReader::new(&conn);
-        let mut writer = BufWriter::new(&conn);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+    // reopen the engine once the lease is taken or lost, and refresh it
+    // meanwhile while following
+    fn follow_lease(&mut self) {
+        let Some(leased) = &mut self.lease else {
+            return;
+        };
+        if Instant::now() < leased.next_check {
+            return;
+        }
+        leased.next_check = Instant::now() + LEASE_CHECK;
+        let epoch = leased.lease.epoch();
+        if epoch == leased.epoch {
+            if epoch.is_none() {
+                if let Err(e) = self.engine.refresh() {
+                    warn!("Refreshing the engine: {}", e);
+                }
+            }
+            return;
+        }
+        match (leased.open)(epoch.is_some()) {
+            Ok(engine) => {
+                self.engine = engine;
+                leased.epoch = epoch;
+                // the blocks were leased under another epoch
+                self.sequences.clear();
+                match epoch {
+                    Some(epoch) => info!("Leading with epoch {}, serving writes", epoch),
+                    None => info!(
+                        "Following {}, serving reads",
+                        leased.lease.leader().unwrap_or_default()
+                    ),
+                }
+            }
+            Err(e) => error!("Reopening the engine: {}", e),
+        }
+    }
+
+    // the hash of `key` if traced
+    fn sample(&self, key: &str) -> Option<u64> {
+        self.tracer.as_ref().and_then(|tracer| tracer.sample(key))
+    }
+
+    // trace an event, giving up on the trace once it cannot be written
+    fn trace_event(&mut self, event: TraceEvent) {
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.record(&event) {
+                error!("Writing the trace, tracing stopped: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+
+    // run the scheduled writes which are due, and sweep the expired keys
+    // every so often, unless writes are refused
+    fn sweep(&mut self) {
+        if Instant::now() < self.next_sweep {
+            return;
+        }
+        self.next_sweep = Instant::now() + SWEEP_INTERVAL;
+        // scheduled writes and expiry deletes are refused like client writes
+        if self.disk_writes_refused().is_some() {
+            return;
+        }
+        match self.engine.run_scheduled() {
+            Ok(0) => {}
+            Ok(ran) => debug!("Ran {} scheduled writes", ran),
+            Err(e) => error!("Running scheduled writes: {}", e),
+        }
+        if Instant::now() < self.next_expiry_sweep {
+            return;
+        }
+        self.next_expiry_sweep = Instant::now() + EXPIRY_SWEEP_INTERVAL;
+        match self.engine.sweep_expired() {
+            Ok(0) => {}
+            Ok(swept) => debug!("Swept {} expired keys", swept),
+            Err(e) => error!("Sweeping expired keys: {}", e),
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Connected { id, addr, stream } => {
+                debug!("Connection {} established from {}", id, addr);
+                let client = self
+                    .clients
+                    .entry(addr.ip())
+                    .or_insert_with(|| ClientStats {
+                        ip: addr.ip().to_string(),
+                        ..ClientStats::default()
+                    });
+                client.connections += 1;
+                self.conns.insert(
+                    id,
+                    Connection {
+                        addr,
+                        writer: BufWriter::new(stream),
+                        pending: VecDeque::new(),
+                        closed: false,
+                        asking: false,
+                        fence: None,
+                        upload: None,
+                        #[cfg(feature = "tracing")]
+                        span: tracing::info_span!("connection", id, %addr),
+                    },
+                );
+            }
+            Event::Request {
+                id,
+                req,
+                size,
+                received,
+            } => {
+                if let Some(conn) = self.conns.get_mut(&id) {
+                    if conn.pending.is_empty() {
+                        self.ready.push_back(id);
+                    }
+                    conn.pending.push_back(Pending {
+                        req,
+                        size,
+                        received,
+                    });
+                }
+            }
+            Event::Console { line, reply } => {
+                let _ = reply.send(self.console(&line));
+            }
+            Event::Closed { id } => {
+                if let Some(conn) = self.conns.get_mut(&id) {
+                    if conn.pending.is_empty() {
+                        let conn = self.conns.remove(&id).unwrap();
+                        self.release(id, conn);
+                    } else {
+                        // serve what is already queued before dropping it
+                        conn.closed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn serve_turn(&mut self, id: u64) {
+        let Some(mut conn) = self.conns.remove(&id) else {
+            return;
+        };
+
+        let mut result = Ok(());
+        for _ in 0..self.budget {
+            let Some(pending) = conn.pending.pop_front() else {
+                break;
+            };
+            let wait = pending.received.elapsed().as_micros() as u64;
+            self.stats.max_wait_us = self.stats.max_wait_us.max(wait);
+            self.stats.requests += 1;
+            result = self.serve(&mut conn, pending);
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() {
+            result = conn.writer.flush().map_err(Into::into);
+        }
+
+        if let Err(e) = result {
+            error!("serving {} error: {}", conn.addr, e);
+            let _ = conn.writer.get_ref().shutdown(Shutdown::Both);
+            self.release(id, conn);
+            return;
+        }
+        if !conn.pending.is_empty() {
+            if !self.ready.is_empty() {
+                self.stats.yields += 1;
+            }
+            self.ready.push_back(id);
+        } else if conn.closed {
+            self.release(id, conn);
+            return;
+        }
+        self.conns.insert(id, conn);
+    }
+
+    fn release(&mut self, id: u64, conn: Connection) {
+        debug!("Connection {} from {} closed", id, conn.addr);
+        if let Some(client) = self.clients.get_mut(&conn.addr.ip()) {
+            client.connections -= 1;
+        }
+    }
+
+    fn serve(&mut self, conn: &mut Connection, pending: Pending) -> Result<()> {
+        let asking = std::mem::take(&mut conn.asking);
+        let fence = conn.fence;
+        let writer = &mut conn.writer;
+        let cli_addr = conn.addr;
+        let received = pending.received;
+        let req = pending.req;
+
+        let client = self
+            .clients
+            .get_mut(&cli_addr.ip())
+            .expect("client not found");
+        client.requests += 1;
+        client.bytes_in += pending.size;
+        let op = req.as_ref().map_or("Unknown", Request::name);
+        *client.ops.entry(op.to_owned()).or_default() += 1;
+        let context = self
+            .error_context
+            .then(|| protocol::error_context(op, req.as_ref().ok().and_then(Request::key)));
+        #[cfg(feature = "tracing")]
+        let _request =
+            tracing::info_span!(parent: &conn.span, "request", op, size = pending.size).entered();
 
         macro_rules! send_resp {
             ($resp:expr) => {{
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
+                let mut resp = $resp;
+                if let (Some(context), Some(message)) = (&context, resp.error_mut()) {
+                    message.push_str(context);
+                }
+                // serialized straight into the connection buffer, large
+                // values are never held twice
+                let mut out = CountingWriter {
+                    inner: &mut *writer,
+                    count: 0,
+                };
+                serde_json::to_writer(&mut out, &resp)?;
+                let sent = out.count;
+                if let Some(client) = self.clients.get_mut(&cli_addr.ip()) {
+                    client.bytes_out += sent;
+                }
                 debug!("Response sent to {}: {:?}", cli_addr, resp);
             }};
         }
 
-        for req in req_reader {
-            let req = req?;
-            debug!("Receive request from {}: {:?}", cli_addr, req);
-            match req {
-                Request::Get { key } => send_resp!(match self.engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
-                }),
-                Request::Set { key, value } => send_resp!(match self.engine.set(key, value) {
+        debug!("Receive request from {}: {:?}", cli_addr, req);
+        #[cfg(feature = "chaos")]
+        match self.chaos.as_mut().and_then(Chaos::inject) {
+            Some(Fault::Drop) => {
+                return Err(KvsError::Other(
+                    "connection dropped by chaos mode".to_owned(),
+                ));
+            }
+            Some(Fault::Error) => {
+                send_resp!(ErrorResponse::Err(
+                    "Fault injected by chaos mode".to_owned()
+                ));
+                return Ok(());
+            }
+            None => {}
+        }
+        let req = match req {
+            Ok(req) => req,
+            Err(e) => {
+                send_resp!(ErrorResponse::Err(e));
+                return Ok(());
+            }
+        };
+        if let Some(Err(e)) = req.key().map(check_key) {
+            send_resp!(ErrorResponse::Err(e.to_string()));
+            return Ok(());
+        }
+        if let (Some(hot_keys), Some(key)) = (self.hot_keys.as_mut(), req.key()) {
+            hot_keys.record(key);
+        }
+        if let (Some(membership), Some(key)) = (&self.cluster, req.key()) {
+            let slot = cluster::key_slot(key);
+            let route = membership.lock().unwrap().route(slot, asking);
+            match route {
+                Route::Local => {}
+                Route::Migrating(addr) => match self.engine.exists(key.to_owned()) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        send_resp!(ErrorResponse::Err(KvsError::Ask { slot, addr }.to_string()));
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        send_resp!(ErrorResponse::Err(e.to_string()));
+                        return Ok(());
+                    }
+                },
+                Route::Moved(addr) => {
+                    send_resp!(ErrorResponse::Err(
+                        KvsError::Moved { slot, addr }.to_string()
+                    ));
+                    return Ok(());
+                }
+                Route::Unserved => {
+                    send_resp!(ErrorResponse::Err(format!(
+                        "Slot {} is not served by any node",
+                        slot
+                    )));
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(leased) = self.lease.as_ref().filter(|_| req.is_write()) {
+            if leased.epoch.is_none() || leased.lease.epoch() != leased.epoch {
+                let leader = leased.lease.leader();
+                send_resp!(SetResponse::Err(KvsError::NotLeader { leader }.to_string()));
+                return Ok(());
+            }
+        }
+        // compaction rewrites the data directory without writing data
+        if req.is_write() || matches!(req, Request::Compact) {
+            if let Some(e) = self.disk_writes_refused() {
+                // every write response shares the shape of `SetResponse`
+                send_resp!(SetResponse::Err(e.to_string()));
+                return Ok(());
+            }
+        }
+        if let Some(token) = fence.filter(|&token| token < self.fence && req.is_write()) {
+            let current = self.fence;
+            send_resp!(SetResponse::Err(
+                KvsError::Fenced { token, current }.to_string()
+            ));
+            return Ok(());
+        }
+        #[cfg(feature = "tracing")]
+        let _engine = tracing::info_span!("engine", op).entered();
+        match req {
+            // the value is written by the engine right into the response,
+            // which is only opened once it starts
+            Request::Get { key } => {
+                let sampled = self.sample(&key);
+                let mut out = PrefixWriter {
+                    inner: CountingWriter {
+                        inner: &mut *writer,
+                        count: 0,
+                    },
+                    prefix: Some(RAW_GET_PREFIX),
+                };
+                let found = self.engine.get_raw_to_writer(key, &mut out);
+                let opened = out.prefix.is_none();
+                if opened {
+                    out.write_all(b"}")?;
+                }
+                if let Some(client) = self.clients.get_mut(&cli_addr.ip()) {
+                    client.bytes_out += out.inner.count;
+                }
+                if let (Some(key), Ok(found)) = (sampled, &found) {
+                    // the escaped JSON string, less its quotes, is close enough
+                    let framing = RAW_GET_PREFIX.len() as u64 + 3;
+                    let size = found.then(|| out.inner.count.saturating_sub(framing));
+                    self.trace_event(TraceEvent::Get { key, size });
+                }
+                match (found, opened) {
+                    (Ok(_), true) => {}
+                    (Ok(_), false) => send_resp!(RawGetResponse::Ok(None)),
+                    (Err(e), false) => send_resp!(RawGetResponse::Err(format!("{}", e))),
+                    // cut short, the response cannot be completed
+                    (Err(e), true) => return Err(e),
+                }
+            }
+            Request::Set {
+                key,
+                value,
+                value_type,
+                meta,
+            } => {
+                let sampled = self.sample(&key).map(|key| (key, value.len() as u64));
+                let result = self.engine.set_with_type(key, value, value_type);
+                if let (Some((key, size)), Ok(())) = (sampled, &result) {
+                    self.trace_event(TraceEvent::Set { key, size });
+                }
+                match (result, meta) {
+                    (Ok(_), false) => send_resp!(SetResponse::Ok(())),
+                    (Err(e), false) => send_resp!(SetResponse::Err(format!("{}", e))),
+                    (Ok(_), true) => send_resp!(SetMetaResponse::Ok(Some(WriteMeta {
+                        server_time_us: received.elapsed().as_micros() as u64,
+                        lsn: self.engine.lsn(),
+                        node: self.cluster.as_ref().map(|membership| membership
+                            .lock()
+                            .unwrap()
+                            .me()
+                            .to_owned()),
+                    }))),
+                    (Err(e), true) => send_resp!(SetMetaResponse::Err(format!("{}", e))),
+                }
+            }
+            Request::GetWithType { key } => {
+                let sampled = self.sample(&key);
+                let value = self.engine.get_with_type(key);
+                if let (Some(key), Ok(value)) = (sampled, &value) {
+                    let size = value.as_ref().map(|(value, _)| value.len() as u64);
+                    self.trace_event(TraceEvent::Get { key, size });
+                }
+                send_resp!(match value {
+                    Ok(value) => GetWithTypeResponse::Ok(
+                        value.map(|(value, value_type)| TypedValue { value, value_type }),
+                    ),
+                    Err(e) => GetWithTypeResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Remove { key } => {
+                let sampled = self.sample(&key);
+                let result = self.engine.remove(key);
+                if let (Some(key), Ok(())) = (sampled, &result) {
+                    self.trace_event(TraceEvent::Remove { key });
+                }
+                send_resp!(match result {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Undelete { key } => send_resp!(match self.engine.undelete(key) {
+                Ok(_) => RemoveResponse::Ok(()),
+                Err(e) => RemoveResponse::Err(format!("{}", e)),
+            }),
+            Request::SetAt { key, value, at_ms } => {
+                send_resp!(match self.engine.set_at(key, value, at_ms) {
                     Ok(_) => SetResponse::Ok(()),
                     Err(e) => SetResponse::Err(format!("{}", e)),
-                }),
-                Request::Remove { key } => send_resp!(match self.engine.remove(key) {
+                })
+            }
+            Request::RemoveAt { key, at_ms } => {
+                send_resp!(match self.engine.remove_at(key, at_ms) {
                     Ok(_) => RemoveResponse::Ok(()),
                     Err(e) => RemoveResponse::Err(format!("{}", e)),
-                }),
-            };
+                })
+            }
+            Request::LPush { key, values } => send_resp!(match self.engine.lpush(key, values) {
+                Ok(len) => CountResponse::Ok(len),
+                Err(e) => CountResponse::Err(format!("{}", e)),
+            }),
+            Request::RPush { key, values } => send_resp!(match self.engine.rpush(key, values) {
+                Ok(len) => CountResponse::Ok(len),
+                Err(e) => CountResponse::Err(format!("{}", e)),
+            }),
+            Request::LRange { key, start, stop } => {
+                send_resp!(match self.engine.lrange(key, start, stop) {
+                    Ok(values) => LRangeResponse::Ok(values),
+                    Err(e) => LRangeResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::LPop { key } => send_resp!(match self.engine.lpop(key) {
+                Ok(value) => GetResponse::Ok(value),
+                Err(e) => GetResponse::Err(format!("{}", e)),
+            }),
+            Request::HSet { key, field, value } => {
+                send_resp!(match self.engine.hset(key, field, value) {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::HGet { key, field } => send_resp!(match self.engine.hget(key, field) {
+                Ok(value) => GetResponse::Ok(value),
+                Err(e) => GetResponse::Err(format!("{}", e)),
+            }),
+            Request::GetField { key, pointer } => {
+                send_resp!(match self.engine.get_field(key, pointer) {
+                    Ok(field) => GetResponse::Ok(field),
+                    Err(e) => GetResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::HGetAll { key } => send_resp!(match self.engine.hgetall(key) {
+                Ok(fields) => HGetAllResponse::Ok(fields),
+                Err(e) => HGetAllResponse::Err(format!("{}", e)),
+            }),
+            Request::HDel { key, field } => send_resp!(match self.engine.hdel(key, field) {
+                Ok(_) => RemoveResponse::Ok(()),
+                Err(e) => RemoveResponse::Err(format!("{}", e)),
+            }),
+            Request::SAdd { key, members } => send_resp!(match self.engine.sadd(key, members) {
+                Ok(added) => CountResponse::Ok(added),
+                Err(e) => CountResponse::Err(format!("{}", e)),
+            }),
+            Request::SRem { key, members } => send_resp!(match self.engine.srem(key, members) {
+                Ok(removed) => CountResponse::Ok(removed),
+                Err(e) => CountResponse::Err(format!("{}", e)),
+            }),
+            Request::SIsMember { key, member } => {
+                send_resp!(match self.engine.sismember(key, member) {
+                    Ok(is_member) => SIsMemberResponse::Ok(is_member),
+                    Err(e) => SIsMemberResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::SMembers { key } => send_resp!(match self.engine.smembers(key) {
+                Ok(members) => SMembersResponse::Ok(members),
+                Err(e) => SMembersResponse::Err(format!("{}", e)),
+            }),
+            Request::Rate {
+                key,
+                window_ms,
+                limit,
+            } => {
+                let window = Duration::from_millis(window_ms);
+                send_resp!(match self.engine.rate(key, window, limit) {
+                    Ok(rate) => RateResponse::Ok(rate),
+                    Err(e) => RateResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::ClusterInfo => send_resp!(match &self.cluster {
+                Some(membership) => ClusterInfoResponse::Ok(membership.lock().unwrap().info()),
+                None => ClusterInfoResponse::Err("Cluster mode is disabled".to_owned()),
+            }),
+            Request::Gossip { nodes } => send_resp!(match &self.cluster {
+                Some(membership) => {
+                    let mut membership = membership.lock().unwrap();
+                    membership.merge(nodes);
+                    ClusterInfoResponse::Ok(membership.info())
+                }
+                None => ClusterInfoResponse::Err("Cluster mode is disabled".to_owned()),
+            }),
+            Request::Asking => {
+                conn.asking = true;
+                send_resp!(SetResponse::Ok(()))
+            }
+            Request::SetSlot { slot, state } => send_resp!(match &self.cluster {
+                Some(membership) => {
+                    membership.lock().unwrap().set_slot(slot, state);
+                    SetResponse::Ok(())
+                }
+                None => SetResponse::Err("Cluster mode is disabled".to_owned()),
+            }),
+            Request::GetKeysInSlot { slot, count } => send_resp!(match self.engine.keys() {
+                Ok(keys) => KeysResponse::Ok(
+                    keys.into_iter()
+                        .filter(|key| cluster::key_slot(key) == slot)
+                        .take(count)
+                        .collect(),
+                ),
+                Err(e) => KeysResponse::Err(format!("{}", e)),
+            }),
+            Request::Migrate { target, keys } => {
+                send_resp!(
+                    match cluster::migrate_keys(&mut self.engine, &target, keys) {
+                        Ok(moved) => CountResponse::Ok(moved),
+                        Err(e) => CountResponse::Err(format!("{}", e)),
+                    }
+                )
+            }
+            Request::Restore { key, dump } => send_resp!(match self.engine.restore(key, dump) {
+                Ok(_) => SetResponse::Ok(()),
+                Err(e) => SetResponse::Err(format!("{}", e)),
+            }),
+            Request::Scan {
+                after,
+                count,
+                pattern,
+                value,
+            } => {
+                let glob = pattern.as_deref().map(Glob::new).transpose();
+                let value = value.as_ref().map(ValueMatcher::new).transpose();
+                // the cursor is compared in the order the keys are listed in
+                let order = self.engine.key_order();
+                let after = |key: &String| {
+                    after
+                        .as_ref()
+                        .is_none_or(|after| order.cmp(key, after).is_gt())
+                };
+                send_resp!(match (glob, value, self.engine.keys()) {
+                    (Ok(glob), Ok(None), Ok(keys)) => KeysResponse::Ok(
+                        keys.into_iter()
+                            .filter(after)
+                            .filter(|key| glob.as_ref().is_none_or(|glob| glob.matches(key)))
+                            .take(count)
+                            .collect(),
+                    ),
+                    (Ok(glob), Ok(Some(value)), Ok(keys)) => {
+                        let keys = keys
+                            .into_iter()
+                            .filter(after)
+                            .filter(|key| glob.as_ref().is_none_or(|glob| glob.matches(key)));
+                        match self.scan_values(keys, &value, count) {
+                            Ok(keys) => KeysResponse::Ok(keys),
+                            Err(e) => KeysResponse::Err(format!("{}", e)),
+                        }
+                    }
+                    (Err(e), _, _) => KeysResponse::Err(format!("Invalid pattern: {}", e)),
+                    (_, Err(e), _) => KeysResponse::Err(format!("Invalid value filter: {}", e)),
+                    (_, _, Err(e)) => KeysResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Meta { key } => send_resp!(match self.engine.meta(key) {
+                Ok(meta) => MetaResponse::Ok(meta),
+                Err(e) => MetaResponse::Err(format!("{}", e)),
+            }),
+            Request::Dump { key } => send_resp!(match self.engine.dump(key) {
+                Ok(dump) => DumpResponse::Ok(dump),
+                Err(e) => DumpResponse::Err(format!("{}", e)),
+            }),
+            Request::Usage { separator, depth } => {
+                send_resp!(match self.engine.usage(separator, depth) {
+                    Ok(usage) => UsageResponse::Ok(usage),
+                    Err(e) => UsageResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::ListChildren { prefix, separator } => {
+                send_resp!(match self.engine.list_children(prefix, separator) {
+                    Ok(children) => ChildrenResponse::Ok(children),
+                    Err(e) => ChildrenResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::GetMany { keys } => {
+                if let Some(e) = keys.iter().find_map(|key| check_key(key).err()) {
+                    send_resp!(GetManyResponse::Err(e.to_string()));
+                    return Ok(());
+                }
+                // keys are not redirected one by one, all of them must be
+                // served here
+                if let Some(membership) = &self.cluster {
+                    let membership = membership.lock().unwrap();
+                    let remote = keys.iter().find(|key| {
+                        !matches!(
+                            membership.route(cluster::key_slot(key), false),
+                            Route::Local
+                        )
+                    });
+                    if let Some(key) = remote {
+                        send_resp!(GetManyResponse::Err(format!(
+                            "Key {:?} of GetMany is not served by this node",
+                            key
+                        )));
+                        return Ok(());
+                    }
+                }
+                send_resp!(match self.engine.get_many(keys) {
+                    Ok(values) => GetManyResponse::Ok(values),
+                    Err(e) => GetManyResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::ScanPrefix { prefix } => send_resp!(match self.engine.scan_prefix(&prefix) {
+                Ok(keys) => KeysResponse::Ok(keys),
+                Err(e) => KeysResponse::Err(format!("{}", e)),
+            }),
+            Request::Ping => send_resp!(PingResponse::Ok(self.health())),
+            Request::Compact => send_resp!(match self.engine.compact() {
+                Ok(()) => {
+                    info!("Compacted on request of {}", conn.addr);
+                    SetResponse::Ok(())
+                }
+                Err(e) => SetResponse::Err(format!("{}", e)),
+            }),
+            Request::Stats => {
+                let mut stats = self.current_stats();
+                stats.queued += conn.pending.len() as u64;
+                send_resp!(match self.engine.metrics() {
+                    Ok(engine) => StatsResponse::Ok(ServerStats { engine, ..stats }),
+                    Err(e) => StatsResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::ReadOnly { enabled } => {
+                if self.read_only != enabled {
+                    info!(
+                        "Read-only mode switched {}",
+                        if enabled { "on" } else { "off" }
+                    );
+                }
+                self.read_only = enabled;
+                send_resp!(SetResponse::Ok(()))
+            }
+            Request::Upgrade => {
+                let result = match (&mut self.upgrade, &self.listener) {
+                    _ if self.drain_deadline.is_some() => {
+                        Err(KvsError::Other("already upgrading".to_owned()))
+                    }
+                    _ if self.shutdown_at.is_some() => {
+                        Err(KvsError::Other("shutting down".to_owned()))
+                    }
+                    (Some(hook), Some(listener)) => hook(listener),
+                    _ => Err(KvsError::Other("upgrade is not configured".to_owned())),
+                };
+                send_resp!(match result {
+                    Ok(()) => {
+                        info!("Listener handed over, draining connections");
+                        self.listener = None;
+                        self.draining.store(true, Ordering::Relaxed);
+                        self.drain_deadline = Some(Instant::now() + self.drain_timeout);
+                        SetResponse::Ok(())
+                    }
+                    Err(e) => SetResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Fence => {
+                let token = self.fence + 1;
+                let saved = match &self.fence_file {
+                    Some(path) => std::fs::write(path, token.to_string()),
+                    None => Ok(()),
+                };
+                send_resp!(match saved {
+                    Ok(()) => {
+                        info!("Fencing token {} handed out", token);
+                        self.fence = token;
+                        TokenResponse::Ok(token)
+                    }
+                    Err(e) => TokenResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::UseFence { token } => send_resp!(if token > self.fence {
+                SetResponse::Err(format!("Unknown fencing token {}", token))
+            } else {
+                conn.fence = Some(token);
+                SetResponse::Ok(())
+            }),
+            Request::SetBegin { key } => send_resp!(match tempfile::tempfile() {
+                Ok(file) => {
+                    conn.upload = Some(Upload {
+                        key,
+                        file: BufWriter::new(file),
+                    });
+                    SetResponse::Ok(())
+                }
+                Err(e) => SetResponse::Err(format!("{}", e)),
+            }),
+            Request::SetChunk { data } => send_resp!(match &mut conn.upload {
+                Some(upload) => match upload.file.write_all(data.as_bytes()) {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(e) => {
+                        conn.upload = None;
+                        SetResponse::Err(format!("{}", e))
+                    }
+                },
+                None => SetResponse::Err("No upload in progress".to_owned()),
+            }),
+            Request::SetCommit => send_resp!(match conn.upload.take() {
+                Some(Upload { key, file }) => {
+                    let committed = file
+                        .into_inner()
+                        .map_err(|e| KvsError::Io(e.into_error()))
+                        .and_then(|mut file| {
+                            file.rewind()?;
+                            let mut value = BufReader::new(file);
+                            self.engine
+                                .set_from_reader(key, &mut value, ValueType::String)
+                        });
+                    match committed {
+                        Ok(()) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(format!("{}", e)),
+                    }
+                }
+                None => SetResponse::Err("No upload in progress".to_owned()),
+            }),
+            Request::GetStream { key } => {
+                let mut frames = FrameWriter {
+                    inner: CountingWriter {
+                        inner: &mut *writer,
+                        count: 0,
+                    },
+                    buf: Vec::new(),
+                };
+                let found = self
+                    .engine
+                    .get_to_writer(key, &mut frames)
+                    .and_then(|found| Ok(frames.finish().map(|_| found)?));
+                if let Some(client) = self.clients.get_mut(&cli_addr.ip()) {
+                    client.bytes_out += frames.inner.count;
+                }
+                send_resp!(match found {
+                    Ok(found) => GetStreamResponse::Ok(found),
+                    Err(e) => GetStreamResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Export { since_lsn } => {
+                let lsn = self.engine.lsn();
+                match self.engine.changed_since(since_lsn) {
+                    Ok(keys) => {
+                        let mut failed = None;
+                        for key in keys {
+                            match self.engine.dump(key.clone()) {
+                                Ok(dump) => send_resp!(ExportResponse::Key { key, dump }),
+                                Err(e) => {
+                                    failed = Some(e);
+                                    break;
+                                }
+                            }
+                        }
+                        send_resp!(match failed {
+                            None => ExportResponse::Ok(lsn),
+                            Some(e) => ExportResponse::Err(format!("{}", e)),
+                        })
+                    }
+                    Err(e) => send_resp!(ExportResponse::Err(format!("{}", e))),
+                }
+            }
+            Request::NextId { sequence } => send_resp!(match self.next_id(sequence) {
+                Ok(id) => TokenResponse::Ok(id),
+                Err(e) => TokenResponse::Err(format!("{}", e)),
+            }),
+            Request::HotKeys { limit } => send_resp!(match &self.hot_keys {
+                Some(hot_keys) => HotKeysResponse::Ok(hot_keys.top(limit)),
+                None => HotKeysResponse::Err("Hot key tracking is disabled".to_owned()),
+            }),
+            Request::Clients => {
+                let mut clients: Vec<ClientStats> = self.clients.values().cloned().collect();
+                clients.sort_by_key(|c| Reverse(c.requests));
+                send_resp!(ClientsResponse::Ok(clients))
+            }
+        };
+
+        Ok(())
+    }
+
+    // the next ID of `sequence`, leasing a new block once the last one is
+    // used up; the integer value of the key is the end of the last block
+    fn next_id(&mut self, sequence: String) -> Result<u64> {
+        let lease = self.sequences.entry(sequence.clone()).or_insert(0..0);
+        if lease.is_empty() {
+            let block = self.id_block.min(i64::MAX as u64) as i64;
+            let end = self.engine.incr(sequence, block)?;
+            let end = u64::try_from(end)
+                .map_err(|_| KvsError::InvalidValue(format!("negative sequence: {}", end)))?;
+            *lease = end.saturating_sub(block as u64) + 1..end + 1;
+        }
+        let id = lease.start;
+        lease.start += 1;
+        Ok(id)
+    }
+
+    // keep the keys whose string value matches, until `count` are found or
+    // the scan budget runs out
+    fn scan_values(
+        &mut self,
+        keys: impl Iterator<Item = String>,
+        value: &ValueMatcher,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        let deadline = Instant::now() + self.scan_budget;
+        let mut found = Vec::new();
+        for key in keys {
+            if found.len() >= count {
+                break;
+            }
+            if Instant::now() >= deadline {
+                if found.is_empty() {
+                    return Err(KvsError::Other(format!(
+                        "Value filter ran over the scan budget of {:?} before a match, \
+                         narrow the pattern",
+                        self.scan_budget
+                    )));
+                }
+                // a short page, the client goes on after its last key
+                break;
+            }
+            if let Some(v) = self.engine.get(key.clone())? {
+                if value.matches(&v) {
+                    found.push(key);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    // run a command of the admin console, returning its output
+    fn console(&mut self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => String::new(),
+            ["help"] => CONSOLE_HELP.to_owned(),
+            ["stats"] => {
+                let stats = ServerStats {
+                    // no connection is being served
+                    connections: self.conns.len() as u64,
+                    ..self.current_stats()
+                };
+                let mut out = format!(
+                    "connections: {}\nrequests: {}\nqueued: {}\nyields: {}\n\
+                     max_wait_us: {}\nread_only: {}\n",
+                    stats.connections,
+                    stats.requests,
+                    stats.queued,
+                    stats.yields,
+                    stats.max_wait_us,
+                    stats.read_only
+                );
+                if let Some(epoch) = stats.epoch {
+                    out += &format!("epoch: {}\n", epoch);
+                }
+                match self.engine.metrics() {
+                    Ok(metrics) => {
+                        for (name, value) in metrics {
+                            out += &format!("engine.{}: {}\n", name, value);
+                        }
+                    }
+                    Err(e) => out += &format!("ERR {}\n", e),
+                }
+                out
+            }
+            ["clients"] => {
+                let mut clients: Vec<&ClientStats> = self.clients.values().collect();
+                clients.sort_by_key(|c| Reverse(c.requests));
+                clients
+                    .iter()
+                    .map(|c| {
+                        format!(
+                            "{} connections={} requests={} bytes_in={} bytes_out={}\n",
+                            c.ip, c.connections, c.requests, c.bytes_in, c.bytes_out
+                        )
+                    })
+                    .collect()
+            }
+            ["compact"] => match self
+                .disk_writes_refused()
+                .map_or_else(|| self.engine.compact(), Err)
+            {
+                Ok(()) => {
+                    info!("Compacted from the admin console");
+                    "OK\n".to_owned()
+                }
+                Err(e) => format!("ERR {}\n", e),
+            },
+            ["readonly", mode @ ("on" | "off")] => {
+                self.read_only = *mode == "on";
+                info!("Read-only mode switched {} from the admin console", mode);
+                "OK\n".to_owned()
+            }
+            ["loglevel"] => format!("{}\n", log::max_level()),
+            ["loglevel", level] => match level.parse::<log::LevelFilter>() {
+                Ok(level) => {
+                    log::set_max_level(level);
+                    info!("Log level set to {} from the admin console", level);
+                    "OK\n".to_owned()
+                }
+                Err(_) => format!("ERR unknown log level {}\n", level),
+            },
+            ["shutdown"] if self.health() == Health::Draining => {
+                "ERR already draining\n".to_owned()
+            }
+            ["shutdown"] => {
+                info!(
+                    "Shutdown asked from the admin console, draining in {:?}",
+                    self.drain_delay
+                );
+                self.shutdown_at = Some(Instant::now() + self.drain_delay);
+                "OK\n".to_owned()
+            }
+            _ => format!("ERR unknown command {:?}, try help\n", line.trim()),
+        }
+    }
+
+    // why the data directory must not be written to now, if so: in
+    // read-only mode, e.g. while it is backed up, or short of free space
+    fn disk_writes_refused(&self) -> Option<KvsError> {
+        if self.read_only {
+            return Some(KvsError::ReadOnly);
+        }
+        let disk = self.disk.as_ref()?;
+        disk.full().map(|free| KvsError::DiskFull {
+            free,
+            threshold: disk.threshold(),
+        })
+    }
+
+    fn health(&self) -> Health {
+        match self.shutdown_at.is_some() || self.drain_deadline.is_some() {
+            true => Health::Draining,
+            false => Health::Ok,
+        }
+    }
+
+    fn current_stats(&self) -> ServerStats {
+        ServerStats {
+            // the connection being served is taken out of the map meanwhile
+            connections: self.conns.len() as u64 + 1,
+            queued: self.conns.values().map(|c| c.pending.len() as u64).sum(),
+            read_only: self.read_only,
+            epoch: self.lease.as_ref().and_then(|leased| leased.epoch),
+            ..self.stats.clone()
+        }
+    }
+}
+
+fn accept(listener: TcpListener, tx: Sender<Event>, draining: Arc<AtomicBool>) {
+    for (id, stream) in (0..).zip(listener.incoming()) {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("connection failed: {}", e);
+                continue;
+            }
+        };
+        let conn = stream
+            .peer_addr()
+            .and_then(|addr| Ok((addr, stream.try_clone()?)));
+        match conn {
+            Ok((addr, writer)) => {
+                let event = Event::Connected {
+                    id,
+                    addr,
+                    stream: writer,
+                };
+                if tx.send(event).is_err() {
+                    return;
+                }
+                let tx = tx.clone();
+                thread::spawn(move || read_requests(id, stream, tx));
+            }
+            Err(e) => error!("connection failed: {}", e),
+        }
+        // the listener belongs to the next server now; a connection
+        // accepted meanwhile is still served while draining
+        if draining.load(Ordering::Relaxed) {
+            return;
+        }
+    }
+}
+
+// replace a socket left behind by a previous run, and keep others out
+#[cfg(unix)]
+fn bind_console(path: &std::path::Path) -> Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Admin console listening on {}", path.display());
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn accept_console(listener: UnixListener, tx: Sender<Event>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let tx = tx.clone();
+                thread::spawn(move || serve_console(stream, tx));
+            }
+            Err(e) => error!("admin console connection failed: {}", e),
+        }
+    }
+}
+
+// pass every line to the engine thread and write back its output
+#[cfg(unix)]
+fn serve_console(stream: UnixStream, tx: Sender<Event>) {
+    use std::io::BufRead;
+
+    let mut writer = &stream;
+    for line in BufReader::new(&stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim() == "quit" {
+            return;
+        }
+        let (reply, output) = mpsc::channel();
+        if tx.send(Event::Console { line, reply }).is_err() {
+            return;
+        }
+        let Ok(output) = output.recv() else {
+            return;
+        };
+        if writer.write_all(output.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn read_requests(id: u64, stream: TcpStream, tx: Sender<Event>) {
+    // decode into a `Value` first, so a request this server does not know
+    // yet gets an error response instead of breaking the whole stream.
+    let mut reader = Deserializer::from_reader(BufReader::new(&stream)).into_iter::<Value>();
+    let mut offset = 0;
+    while let Some(value) = reader.next() {
+        match value {
+            Ok(value) => {
+                let req = serde_json::from_value::<Request>(value)
+                    .map_err(|e| format!("Unsupported request: {}", e));
+                let event = Event::Request {
+                    id,
+                    req,
+                    size: (reader.byte_offset() - offset) as u64,
+                    received: Instant::now(),
+                };
+                offset = reader.byte_offset();
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("reading request error: {}", e);
+                break;
+            }
         }
+    }
+    let _ = tx.send(Event::Closed { id });
+}
+
+// a compiled `ValueFilter`
+enum ValueMatcher {
+    Contains(String),
+    Regex(regex::Regex),
+}
+
+impl ValueMatcher {
+    fn new(filter: &ValueFilter) -> std::result::Result<ValueMatcher, regex::Error> {
+        Ok(match filter {
+            ValueFilter::Contains(s) => ValueMatcher::Contains(s.clone()),
+            // the `regex` crate never backtracks, matching is linear in the
+            // value; bounding the program keeps compiling cheap too
+            ValueFilter::Regex(re) => ValueMatcher::Regex(
+                regex::RegexBuilder::new(re)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .build()?,
+            ),
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatcher::Contains(s) => value.contains(s.as_str()),
+            ValueMatcher::Regex(re) => re.is_match(value),
+        }
+    }
+}
+
+// counts the bytes written through it
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// how a `RawGetResponse::Ok` holding a value starts
+const RAW_GET_PREFIX: &[u8] = b"{\"Ok\":";
+
+// writes `prefix` ahead of the first bytes written through it
+struct PrefixWriter<W: Write> {
+    inner: W,
+    prefix: Option<&'static [u8]>,
+}
+
+impl<W: Write> Write for PrefixWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(prefix) = self.prefix {
+            self.inner.write_all(prefix)?;
+            self.prefix = None;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
 
+// cuts what is written through it into `GetStreamResponse::Chunk` frames of
+// at most `STREAM_CHUNK_SIZE` bytes, on char boundaries
+struct FrameWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn send(&mut self, end: usize, last: bool) -> std::io::Result<()> {
+        let end = match std::str::from_utf8(&self.buf[..end]) {
+            Ok(_) => end,
+            Err(e) if e.error_len().is_none() && !last => e.valid_up_to(),
+            Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        };
+        let data = String::from_utf8(self.buf.drain(..end).collect()).unwrap();
+        serde_json::to_writer(&mut self.inner, &GetStreamResponse::Chunk(data))?;
         Ok(())
     }
+
+    // send what is left
+    fn finish(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send(self.buf.len(), true)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while self.buf.len() >= STREAM_CHUNK_SIZE {
+            self.send(STREAM_CHUNK_SIZE, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
@@ -0,0 +1,304 @@
+//! Sampled traces of the reads and writes a server serves, and their
+//! analysis into the working set of the traffic and the hit rate an LRU
+//! cache of a given size would get, to size the cache of a store.
+//!
+//! Keys are sampled by a hash of theirs: every access to a sampled key is
+//! traced, and none to the others, so the trace behaves like the whole
+//! traffic over a smaller keyspace. A cache of `size` bytes over every key
+//! then gets about the hit rate of a cache of `size * rate` bytes over the
+//! sampled ones. Keys are only recorded by that hash.
+//!
+//! A trace is a file of JSON events, one per line. Every server writing to
+//! it starts with a `Start` event giving its sample rate. Events carry the
+//! time they were traced at, for `kvs-bench replay` to play the traffic
+//! again at its pace; traces written before that have none.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// An event of a trace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum TraceEvent {
+    /// A server started tracing
+    Start {
+        /// Share of the keys traced
+        sample_rate: f64,
+    },
+    /// A string key was read
+    Get {
+        /// Hash of the key
+        key: u64,
+        /// Size of its value in bytes, `None` if the key does not exist
+        size: Option<u64>,
+    },
+    /// A string key was written
+    Set {
+        /// Hash of the key
+        key: u64,
+        /// Size of its value in bytes
+        size: u64,
+    },
+    /// A string key was removed
+    Remove {
+        /// Hash of the key
+        key: u64,
+    },
+}
+
+/// An event of a trace along with when it was traced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimedEvent {
+    /// When the event was traced, in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at_ms: Option<u64>,
+    /// The event
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+/// Writes the events of the sampled keys to a trace file.
+pub struct Tracer {
+    // hashes below this are sampled
+    threshold: u64,
+    out: LineWriter<File>,
+}
+
+impl Tracer {
+    /// Append to the trace at `path`, sampling `sample_rate` of the keys,
+    /// between 0 and 1.
+    pub fn open(path: &Path, sample_rate: f64) -> Result<Tracer> {
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(KvsError::InvalidCommand(format!(
+                "sample rate must be between 0 and 1, not {}",
+                sample_rate
+            )));
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut tracer = Tracer {
+            threshold: (sample_rate * u64::MAX as f64) as u64,
+            out: LineWriter::new(file),
+        };
+        tracer.record(&TraceEvent::Start { sample_rate })?;
+        Ok(tracer)
+    }
+
+    /// The hash of `key` if it is sampled.
+    pub fn sample(&self, key: &str) -> Option<u64> {
+        Some(key_hash(key)).filter(|&hash| hash < self.threshold)
+    }
+
+    /// Write an event to the trace, timed now.
+    pub fn record(&mut self, event: &TraceEvent) -> Result<()> {
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let timed = TimedEvent {
+            at_ms: Some(at_ms),
+            event: event.clone(),
+        };
+        serde_json::to_writer(&mut self.out, &timed)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// The hash keys are sampled and recorded by: the 64-bit FNV-1a hash of the
+/// key, mixed so that similar keys spread over the whole range. It stays
+/// the same across releases, for traces written by several of them.
+pub fn key_hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// What `analyze` found in a trace, scaled up to the whole traffic.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TraceReport {
+    /// Share of the keys traced
+    pub sample_rate: f64,
+    /// Number of events traced
+    pub events: u64,
+    /// Number of reads of existing keys traced, those a cache may serve
+    pub gets: u64,
+    /// Estimated number of distinct keys read or written
+    pub keys: u64,
+    /// Estimated size of their latest values, in bytes
+    pub bytes: u64,
+    /// Hit rate of an LRU cache by size, smallest first
+    pub curve: Vec<CachePoint>,
+}
+
+/// The hit rate of an LRU cache of a given size.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CachePoint {
+    /// Size of the cache, in bytes of values
+    pub cache_bytes: u64,
+    /// Share of the reads of existing keys it serves, between 0 and 1
+    pub hit_rate: f64,
+}
+
+/// Read the events of the trace at `path`.
+pub fn read(path: &Path) -> Result<Vec<TraceEvent>> {
+    read_lines(path)
+}
+
+/// Read the events of the trace at `path` along with their times.
+pub fn read_timed(path: &Path) -> Result<Vec<TimedEvent>> {
+    read_lines(path)
+}
+
+fn read_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Replay `events` against LRU caches of each of `sizes` bytes, or of
+/// fractions of the working set if none is given.
+///
+/// A read of a key the cache misses brings the key in, a write updates it,
+/// and a removal drops it. Reads of missing keys are left out of the hit
+/// rate, no cache serving them.
+pub fn analyze(events: &[TraceEvent], sizes: &[u64]) -> Result<TraceReport> {
+    let mut report = TraceReport::default();
+    let mut latest: HashMap<u64, u64> = HashMap::new();
+    for event in events {
+        report.events += 1;
+        match *event {
+            TraceEvent::Start { sample_rate } => {
+                if report.sample_rate != 0.0 && report.sample_rate != sample_rate {
+                    return Err(KvsError::InvalidCommand(format!(
+                        "trace mixes sample rates {} and {}",
+                        report.sample_rate, sample_rate
+                    )));
+                }
+                report.sample_rate = sample_rate;
+            }
+            TraceEvent::Get {
+                key,
+                size: Some(size),
+            }
+            | TraceEvent::Set { key, size } => {
+                if matches!(event, TraceEvent::Get { .. }) {
+                    report.gets += 1;
+                }
+                latest.insert(key, size);
+            }
+            TraceEvent::Get { size: None, .. } | TraceEvent::Remove { .. } => {}
+        }
+    }
+    if report.sample_rate == 0.0 {
+        return Err(KvsError::InvalidCommand(
+            "trace holds no sampled key".to_owned(),
+        ));
+    }
+    let scale = |n: u64| (n as f64 / report.sample_rate).round() as u64;
+    report.keys = scale(latest.len() as u64);
+    report.bytes = scale(latest.values().sum());
+
+    let mut sizes = match sizes.is_empty() {
+        true => [32, 16, 8, 4, 2, 1]
+            .iter()
+            .map(|fraction| report.bytes / fraction)
+            .collect(),
+        false => sizes.to_vec(),
+    };
+    sizes.sort_unstable();
+    sizes.dedup();
+    for cache_bytes in sizes {
+        let budget = (cache_bytes as f64 * report.sample_rate) as u64;
+        let hits = simulate(events, budget);
+        report.curve.push(CachePoint {
+            cache_bytes,
+            hit_rate: match report.gets {
+                0 => 0.0,
+                gets => hits as f64 / gets as f64,
+            },
+        });
+    }
+    Ok(report)
+}
+
+// the number of reads an LRU cache of `budget` bytes serves
+fn simulate(events: &[TraceEvent], budget: u64) -> u64 {
+    let mut cache = Lru {
+        budget,
+        ..Lru::default()
+    };
+    let mut hits = 0;
+    for event in events {
+        match *event {
+            TraceEvent::Get {
+                key,
+                size: Some(size),
+            } => {
+                if cache.contains(key) {
+                    hits += 1;
+                }
+                cache.put(key, size);
+            }
+            TraceEvent::Set { key, size } => cache.put(key, size),
+            TraceEvent::Remove { key } => cache.remove(key),
+            TraceEvent::Get { size: None, .. } | TraceEvent::Start { .. } => {}
+        }
+    }
+    hits
+}
+
+// the keys of a simulated cache, from the least to the most recently used
+#[derive(Default)]
+struct Lru {
+    budget: u64,
+    used: u64,
+    // tick of the last use of every key, and the size of its value
+    keys: HashMap<u64, (u64, u64)>,
+    order: BTreeMap<u64, u64>,
+    next: u64,
+}
+
+impl Lru {
+    fn contains(&self, key: u64) -> bool {
+        self.keys.contains_key(&key)
+    }
+
+    // use `key`, bringing it in, then evict until within budget
+    fn put(&mut self, key: u64, size: u64) {
+        self.remove(key);
+        // a value larger than the cache is never kept
+        if size > self.budget {
+            return;
+        }
+        self.keys.insert(key, (self.next, size));
+        self.order.insert(self.next, key);
+        self.next += 1;
+        self.used += size;
+        while self.used > self.budget {
+            let (_, victim) = self.order.pop_first().expect("cache over budget is empty");
+            let (_, size) = self.keys.remove(&victim).unwrap();
+            self.used -= size;
+        }
+    }
+
+    fn remove(&mut self, key: u64) {
+        if let Some((tick, size)) = self.keys.remove(&key) {
+            self.order.remove(&tick);
+            self.used -= size;
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use kvs::{Chaos, KvStore, KvsClient, KvsError, KvsServer};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start_server(addr: &'static str, chaos: Chaos) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).chaos(chaos).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+    temp_dir
+}
+
+#[test]
+fn latency_delays_every_request() {
+    let addr = "127.0.0.1:4029";
+    let _dir = start_server(addr, Chaos::new().latency(Duration::from_millis(50)));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let start = Instant::now();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn errors_are_injected() {
+    let addr = "127.0.0.1:4030";
+    let _dir = start_server(addr, Chaos::new().error_rate(1.0));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for _ in 0..3 {
+        match client.set("key".to_owned(), "value".to_owned()) {
+            Err(KvsError::Other(message)) => assert!(message.contains("chaos")),
+            other => panic!("expected an injected error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn connections_are_dropped() {
+    let addr = "127.0.0.1:4031";
+    let _dir = start_server(addr, Chaos::new().drop_rate(1.0));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.get("key".to_owned()).is_err());
+}
+
+#[test]
+fn error_rate_fails_some_requests() {
+    let addr = "127.0.0.1:4032";
+    let _dir = start_server(addr, Chaos::new().error_rate(0.5).seed(42));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut outcomes = Vec::new();
+    for _ in 0..64 {
+        outcomes.push(client.get("key".to_owned()).is_ok());
+    }
+    // some requests fail, not all of them
+    assert!(outcomes.iter().any(|ok| *ok));
+    assert!(outcomes.iter().any(|ok| !*ok));
+}
@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +158,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +180,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +200,185 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// A marker disagreeing with the data keeps the server down until
+// `kvs-admin fix-engine` repairs it from the data.
+#[test]
+fn cli_fix_engine_marker() {
+    let temp_dir = TempDir::new().unwrap();
+    kvs::KvStore::open(temp_dir.path()).unwrap();
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4036"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("fix-engine --actual kvs"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["fix-engine", "--actual", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("fix-engine")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Engine marker set to kvs"));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "kvs"
+    );
+}
+
+// `kvs-admin --max-duration-secs` stops a command out of time with status
+// 75, and lets one within it complete
+#[test]
+fn admin_cli_max_duration() {
+    let temp_dir = TempDir::new().unwrap();
+    kvs::KvStore::open(temp_dir.path()).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["--max-duration-secs", "0", "fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(75)
+        .stderr(contains("Out of time"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["fsck", "--max-duration-secs", "60"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+// `kvs-server selftest` should exercise a data directory, leaving its data
+// alone, and refuse one a server is using
+#[test]
+fn server_cli_selftest() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    kvs::KvsEngine::set(&mut store, "key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["selftest", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("fsck     ok").and(contains("self-test passed")));
+    let mut store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        kvs::KvsEngine::keys(&mut store).unwrap(),
+        vec!["key".to_owned()]
+    );
+    drop(store);
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4066"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("selftest")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("in use by a running server"));
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
+// `kvs-bench replay` should play the requests of a trace at its pace, sped
+// up, against a server
+#[test]
+fn bench_cli_replay() {
+    let temp_dir = TempDir::new().unwrap();
+    let trace = temp_dir.path().join("trace");
+    fs::write(
+        &trace,
+        concat!(
+            r#"{"at_ms":1000,"op":"start","sample_rate":1.0}"#,
+            "\n",
+            r#"{"at_ms":1000,"op":"set","key":1,"size":5}"#,
+            "\n",
+            r#"{"at_ms":1500,"op":"get","key":1,"size":5}"#,
+            "\n",
+            r#"{"at_ms":2000,"op":"remove","key":2}"#,
+            "\n",
+        ),
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4068"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let start = std::time::Instant::now();
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["replay", "--addr", "127.0.0.1:4068", "--speed", "4"])
+        .arg(&trace)
+        .assert()
+        .success()
+        .stdout(contains("Replayed 3 requests").and(contains("0 errors")));
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4068", "get", "replay:0000000000000001"])
+        .assert()
+        .success()
+        .stdout("xxxxx\n");
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value1"])
+        .args(["--addr", addr, "set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +386,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +394,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key1", "value2"])
+        .args(["--addr", addr, "set", "key1", "value2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +402,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +410,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +418,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key2"])
+        .args(["--addr", addr, "rm", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +426,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "set", "key2", "value3"])
+        .args(["--addr", addr, "set", "key2", "value3"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +434,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "rm", "key1"])
+        .args(["--addr", addr, "rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +447,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key2"])
+        .args(["--addr", addr, "get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["--addr", addr, "get", "key1"])
+        .args(["--addr", addr, "get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +485,77 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-client` should take and show keys and values in hex or base64
+#[test]
+fn cli_key_value_formats() {
+    let addr = "127.0.0.1:4055";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(["--addr", addr]).args(args);
+        client.assert()
+    };
+    // "key\t1" and "héllo"
+    client(&[
+        "--key-format",
+        "hex",
+        "--value-format",
+        "base64",
+        "set",
+        "6b65790931",
+        "aMOpbGxv",
+    ])
+    .success();
+    client(&["get", "key\t1"]).success().stdout("héllo\n");
+    client(&[
+        "--key-format",
+        "base64",
+        "--value-format",
+        "hex",
+        "get",
+        "a2V5CTE=",
+    ])
+    .success()
+    .stdout("68c3a96c6c6f\n");
+    client(&["--key-format", "hex", "keys"])
+        .success()
+        .stdout("6b65790931\n");
+    client(&["--key-format", "hex", "get", "6b6"])
+        .failure()
+        .stderr(contains("not hexadecimal"));
+    client(&["--value-format", "base64", "set", "key2", "/w=="])
+        .failure()
+        .stderr(contains("not UTF-8"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `completions` and `man` should print a completion script and a man page
+// without a server to talk to
+#[test]
+fn cli_completions_and_man() {
+    for bin in ["kvs-client", "kvs-server"] {
+        Command::cargo_bin(bin)
+            .unwrap()
+            .args(["completions", "bash"])
+            .assert()
+            .success()
+            .stdout(contains(format!("-o default {}", bin)));
+        Command::cargo_bin(bin)
+            .unwrap()
+            .arg("man")
+            .assert()
+            .success()
+            .stdout(contains(".TH"));
+    }
+}
@@ -0,0 +1,183 @@
+use kvs::cluster::{self, key_slot, Cluster};
+use kvs::{ClusterClient, KvStore, KvsClient, KvsError, KvsServer, SlotRange, SlotState};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_node(addr: &'static str, slots: &str, peer: &str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let slots = vec![slots.parse().unwrap()];
+    let cluster = Cluster::new(addr, slots)
+        .peers(vec![peer.to_owned()])
+        .gossip_interval(Duration::from_millis(100));
+    thread::spawn(move || KvsServer::new(store).cluster(cluster).run(addr).unwrap());
+    temp_dir
+}
+
+#[test]
+fn key_slots() {
+    // same values as the Redis cluster specification
+    assert_eq!(key_slot("foo"), 12182);
+    assert_eq!(key_slot("bar"), 5061);
+    assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+}
+
+#[test]
+fn slot_ranges() {
+    let range: SlotRange = "100-200".parse().unwrap();
+    assert!(range.contains(100) && range.contains(200) && !range.contains(201));
+    assert_eq!(range.to_string(), "100-200");
+    assert_eq!("42".parse::<SlotRange>().unwrap().to_string(), "42");
+    assert!("200-100".parse::<SlotRange>().is_err());
+    assert!("0-16384".parse::<SlotRange>().is_err());
+}
+
+#[test]
+fn nodes_gossip_and_redirect() {
+    let (first, second) = ("127.0.0.1:4022", "127.0.0.1:4023");
+    let _first_dir = start_node(first, "0-8191", second);
+    let _second_dir = start_node(second, "8192-16383", first);
+    thread::sleep(Duration::from_millis(800));
+
+    // both nodes learned about each other
+    let mut client = KvsClient::connect(first).unwrap();
+    let info = client.cluster_info().unwrap();
+    assert_eq!(info.nodes.len(), 2);
+    assert!(info.nodes.iter().all(|node| node.alive));
+
+    // "foo" lives on the second node
+    match client.set("foo".to_owned(), "value".to_owned()) {
+        Err(KvsError::Moved { slot, addr }) => {
+            assert_eq!(slot, 12182);
+            assert_eq!(addr, second);
+        }
+        other => panic!("expected a redirect, got {:?}", other),
+    }
+    client.set("bar".to_owned(), "value".to_owned()).unwrap();
+
+    let mut cluster = ClusterClient::connect(vec![first.to_owned()]).unwrap();
+    for i in 0..100 {
+        cluster
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    for i in 0..100 {
+        assert_eq!(
+            cluster.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    cluster.remove("bar".to_owned()).unwrap();
+    assert_eq!(cluster.get("bar".to_owned()).unwrap(), None);
+
+    // every node only holds the keys of its own slots
+    let mut direct = KvsClient::connect(second).unwrap();
+    let local = (0..100)
+        .map(|i| format!("key{}", i))
+        .filter(|key| key_slot(key) >= 8192)
+        .collect::<Vec<_>>();
+    assert!(!local.is_empty());
+    for key in local {
+        assert!(direct.get(key).unwrap().is_some());
+    }
+}
+
+#[test]
+fn cluster_info_disabled_by_default() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.cluster_info().is_err());
+    client.set("foo".to_owned(), "value".to_owned()).unwrap();
+}
+
+#[test]
+fn slot_migration_redirects_with_ask() {
+    let (source, target) = ("127.0.0.1:4025", "127.0.0.1:4026");
+    let _source_dir = start_node(source, "0-16382", target);
+    let _target_dir = start_node(target, "16383", source);
+    thread::sleep(Duration::from_millis(800));
+
+    // "foo" and "{foo}.bar" share slot 12182, served by the source
+    let mut from = KvsClient::connect(source).unwrap();
+    let mut to = KvsClient::connect(target).unwrap();
+    from.set("foo".to_owned(), "value".to_owned()).unwrap();
+    from.lpush("{foo}.bar".to_owned(), vec!["a".to_owned(), "b".to_owned()])
+        .unwrap();
+
+    to.set_slot(
+        12182,
+        SlotState::Importing {
+            from: source.to_owned(),
+        },
+    )
+    .unwrap();
+    from.set_slot(
+        12182,
+        SlotState::Migrating {
+            to: target.to_owned(),
+        },
+    )
+    .unwrap();
+    assert_eq!(from.cluster_info().unwrap().migrating[&12182], target);
+    assert_eq!(to.cluster_info().unwrap().importing[&12182], source);
+    assert_eq!(from.keys_in_slot(12182, 10).unwrap().len(), 2);
+
+    // a moved key is redirected for this request only, the rest is served
+    assert_eq!(
+        from.migrate(target.to_owned(), vec!["foo".to_owned()])
+            .unwrap(),
+        1
+    );
+    match from.get("foo".to_owned()) {
+        Err(KvsError::Ask { slot, addr }) => {
+            assert_eq!(slot, 12182);
+            assert_eq!(addr, target);
+        }
+        other => panic!("expected an ASK redirect, got {:?}", other),
+    }
+    assert_eq!(
+        from.lrange("{foo}.bar".to_owned(), 0, -1).unwrap(),
+        vec!["b".to_owned(), "a".to_owned()]
+    );
+
+    // the target only serves it when asked explicitly
+    assert!(matches!(
+        to.get("foo".to_owned()),
+        Err(KvsError::Moved { .. })
+    ));
+    to.asking().unwrap();
+    assert_eq!(to.get("foo".to_owned()).unwrap(), Some("value".to_owned()));
+    assert!(matches!(
+        to.get("foo".to_owned()),
+        Err(KvsError::Moved { .. })
+    ));
+
+    let mut cluster = ClusterClient::connect(vec![source.to_owned()]).unwrap();
+    assert_eq!(
+        cluster.get("foo".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    // finishing the migration moves the remaining keys and the ownership
+    let moved = cluster::migrate_slot(&mut from, &mut to, source, target, 12182, 1).unwrap();
+    assert_eq!(moved, 1);
+    assert!(from.cluster_info().unwrap().migrating.is_empty());
+    assert!(matches!(
+        from.get("foo".to_owned()),
+        Err(KvsError::Moved { .. })
+    ));
+    assert_eq!(
+        to.lrange("{foo}.bar".to_owned(), 0, -1).unwrap(),
+        vec!["b".to_owned(), "a".to_owned()]
+    );
+    assert_eq!(
+        cluster.get("foo".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
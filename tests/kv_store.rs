@@ -1,4 +1,21 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::format::FORMAT_VERSION;
+use kvs::format::{self, KvLog};
+#[cfg(feature = "encryption")]
+use kvs::EncryptedEngine;
+#[cfg(feature = "engine-sled")]
+use kvs::SledStore;
+use kvs::{
+    fsck, CompositeStore, ExpiredReads, KeyOrder, KvStore, KvsEngine, KvsError, LogEncoding,
+    MirrorEngine, Result, ValueType, COMPACTION_FILE, FORMAT_FILE, MAX_KEY_LEN, MAX_RATE_WINDOW,
+    ORDER_FILE, QUARANTINE_FILE,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +141,2686 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should keep the type tag of a value across reopens and reject values
+// not matching their type
+#[test]
+fn value_types() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_type("key2".to_owned(), r#"{"a":1}"#.to_owned(), ValueType::Json)?;
+    store.set_with_type("key3".to_owned(), "42".to_owned(), ValueType::Int)?;
+    assert!(store
+        .set_with_type("key4".to_owned(), "forty-two".to_owned(), ValueType::Int)
+        .is_err());
+    assert!(store
+        .set_with_type("key4".to_owned(), "{".to_owned(), ValueType::Json)
+        .is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_type("key1".to_owned())?,
+        Some(("value1".to_owned(), ValueType::String))
+    );
+    assert_eq!(
+        store.get_with_type("key2".to_owned())?,
+        Some((r#"{"a":1}"#.to_owned(), ValueType::Json))
+    );
+    assert_eq!(store.get("key3".to_owned())?, Some("42".to_owned()));
+    assert_eq!(store.get_with_type("key4".to_owned())?, None);
+
+    // overwriting with a plain string drops the tag
+    store.set("key2".to_owned(), "plain".to_owned())?;
+    assert_eq!(
+        store.get_with_type("key2".to_owned())?,
+        Some(("plain".to_owned(), ValueType::String))
+    );
+
+    Ok(())
+}
+
+// Should write JSON values as is in the log, and read them back byte for
+// byte, encoding those that would not round-trip
+#[test]
+fn raw_json_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let inline = r#"{"b":1,"a":[1,2.50,"x\"y"]}"#;
+    let others = [" {\"a\": 1}", "[1,\n2]", r#""text""#, "{\"a\": 1}"];
+    store.set_with_type("inline".to_owned(), inline.to_owned(), ValueType::Json)?;
+    for (i, value) in others.iter().enumerate() {
+        store.set_with_type(format!("key{}", i), value.to_string(), ValueType::Json)?;
+    }
+
+    let log = fs::read_to_string(temp_dir.path().join(format::log_file_name(1)))?;
+    assert!(log.contains(&format!("\"value\":{},", inline)));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_with_type("inline".to_owned())?,
+        Some((inline.to_owned(), ValueType::Json))
+    );
+    let raw = store.get_raw("inline".to_owned())?.unwrap();
+    assert_eq!(serde_json::from_str::<String>(raw.get())?, inline);
+    for (i, value) in others.iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.to_string()));
+    }
+
+    store.compact()?;
+    assert_eq!(store.get("inline".to_owned())?, Some(inline.to_owned()));
+    Ok(())
+}
+
+// Should be able to decode the log files of a store without opening it
+#[test]
+fn decode_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_type("key2".to_owned(), "42".to_owned(), ValueType::Int)?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap();
+        if format::parse_log_file_name(name).is_none() {
+            continue;
+        }
+        let buf = fs::read(&path)?;
+        for record in format::records(&buf) {
+            let (range, log) = record?;
+            assert_eq!(
+                KvLog::decode(&buf[range.start as usize..range.end as usize])?,
+                log
+            );
+            logs.push(log);
+        }
+    }
+
+    assert_eq!(
+        logs,
+        vec![
+            KvLog::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+                value_type: ValueType::String,
+                expires_at: None,
+                written_at: None,
+            },
+            KvLog::Set {
+                key: "key2".to_owned(),
+                value: "42".to_owned(),
+                value_type: ValueType::Int,
+                expires_at: None,
+                written_at: None,
+            },
+            KvLog::Remove {
+                key: "key1".to_owned()
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// A compaction cut short by a crash leaves its generation incomplete, or
+// the generations it replaces behind: the next open drops one or the
+// other rather than replaying lists twice
+#[test]
+fn compaction_cut_short() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gens = || -> Vec<u64> {
+        let mut gens: Vec<u64> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter_map(|entry| format::parse_log_file_name(entry.ok()?.file_name().to_str()?))
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.rpush("list".to_owned(), vec!["b".to_owned(), "c".to_owned()])?;
+    store.lpush("list".to_owned(), vec!["a".to_owned(), "z".to_owned()])?;
+    store.lpop("list".to_owned())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let old: Vec<(PathBuf, Vec<u8>)> = gens()
+        .into_iter()
+        .map(|gen| {
+            let path = temp_dir.path().join(format::log_file_name(gen));
+            let content = fs::read(&path).unwrap();
+            (path, content)
+        })
+        .collect();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+    assert!(!temp_dir.path().join(COMPACTION_FILE).exists());
+    let compact_gen = gens()[0];
+    assert!(old.iter().all(|(path, _)| !path.exists()));
+    let compacted = fs::read(temp_dir.path().join(format::log_file_name(compact_gen)))?;
+
+    let reopened = |state: &str| -> Result<()> {
+        for (path, content) in &old {
+            fs::write(path, content)?;
+        }
+        fs::write(
+            temp_dir.path().join(COMPACTION_FILE),
+            format!("{} {}\n", state, compact_gen),
+        )?;
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.lrange("list".to_owned(), 0, -1)?, vec!["a", "b", "c"]);
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+        assert!(!temp_dir.path().join(COMPACTION_FILE).exists());
+        Ok(())
+    };
+
+    // crashed removing the generations the compacted one replaces
+    reopened("done")?;
+    assert!(old.iter().all(|(path, _)| !path.exists()));
+
+    // crashed halfway through writing the compacted generation
+    let compact_path = temp_dir.path().join(format::log_file_name(compact_gen));
+    fs::write(&compact_path, &compacted[..compacted.len() / 2])?;
+    reopened("writing")?;
+    assert!(!compact_path.exists());
+    assert!(old.iter().all(|(path, _)| path.exists()));
+    Ok(())
+}
+
+// Should keep lists apart from string keys and rebuild them on reopen
+#[test]
+fn lists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.rpush("list".to_owned(), vec!["b".to_owned(), "c".to_owned()])?,
+        2
+    );
+    assert_eq!(
+        store.lpush("list".to_owned(), vec!["a".to_owned(), "z".to_owned()])?,
+        4
+    );
+    store.set("list".to_owned(), "string".to_owned())?;
+    assert_eq!(store.lpop("list".to_owned())?, Some("z".to_owned()));
+    assert_eq!(store.lpop("missing".to_owned())?, None);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("list".to_owned())?, Some("string".to_owned()));
+    assert_eq!(store.lrange("list".to_owned(), 0, -1)?, vec!["a", "b", "c"]);
+    assert_eq!(store.lrange("list".to_owned(), 1, 1)?, vec!["b"]);
+    assert_eq!(store.lrange("list".to_owned(), -2, 10)?, vec!["b", "c"]);
+    assert!(store.lrange("list".to_owned(), 2, 1)?.is_empty());
+    assert!(store.lrange("missing".to_owned(), 0, -1)?.is_empty());
+
+    for _ in 0..3 {
+        store.lpop("list".to_owned())?;
+    }
+    assert_eq!(store.lpop("list".to_owned())?, None);
+    Ok(())
+}
+
+// Pops leave stale records behind, compaction must keep the remaining
+// elements in order, readable before and after a reopen
+#[test]
+fn list_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1000);
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.rpush("list".to_owned(), vec!["first".to_owned()])?;
+    for i in 0..2000 {
+        store.lpush("list".to_owned(), vec![format!("{}{}", i, value)])?;
+        store.lpop("list".to_owned())?;
+    }
+    store.rpush("list".to_owned(), vec!["last".to_owned()])?;
+    store.lpush("list".to_owned(), vec!["head".to_owned()])?;
+
+    // the first generation is gone once compacted
+    assert!(!temp_dir.path().join(format::log_file_name(1)).exists());
+    let expected = vec!["head", "first", "last"];
+    assert_eq!(store.lrange("list".to_owned(), 0, -1)?, expected);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.lrange("list".to_owned(), 0, -1)?, expected);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should update hash fields one by one and keep them across compactions
+// and reopens
+#[test]
+fn hashes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1000);
+
+    store.hset("user".to_owned(), "name".to_owned(), "alice".to_owned())?;
+    store.hset("user".to_owned(), "city".to_owned(), "paris".to_owned())?;
+    store.hset("user".to_owned(), "tmp".to_owned(), "1".to_owned())?;
+    for i in 0..2000 {
+        store.hset(
+            "user".to_owned(),
+            "visits".to_owned(),
+            format!("{}{}", i, value),
+        )?;
+    }
+    store.hdel("user".to_owned(), "tmp".to_owned())?;
+    assert!(matches!(
+        store.hdel("user".to_owned(), "tmp".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(!temp_dir.path().join(format::log_file_name(1)).exists());
+
+    for _ in 0..2 {
+        assert_eq!(
+            store.hget("user".to_owned(), "name".to_owned())?,
+            Some("alice".to_owned())
+        );
+        assert_eq!(store.hget("user".to_owned(), "tmp".to_owned())?, None);
+        assert_eq!(store.hget("other".to_owned(), "name".to_owned())?, None);
+        let fields = store.hgetall("user".to_owned())?;
+        assert_eq!(
+            fields.keys().collect::<Vec<_>>(),
+            vec!["city", "name", "visits"]
+        );
+        assert_eq!(fields["visits"], format!("1999{}", value));
+        assert!(store.hgetall("other".to_owned())?.is_empty());
+
+        drop(store);
+        store = KvStore::open(temp_dir.path())?;
+    }
+    Ok(())
+}
+
+// Should deduplicate set members and keep them across compactions and
+// reopens
+#[test]
+fn sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let member = "x".repeat(1000);
+
+    let tags = |tags: &[&str]| tags.iter().map(|&t| t.to_owned()).collect::<Vec<_>>();
+    assert_eq!(store.sadd("tags".to_owned(), tags(&["a", "b", "a"]))?, 2);
+    assert_eq!(store.sadd("tags".to_owned(), tags(&["b", "c"]))?, 1);
+    for i in 0..2000 {
+        store.sadd("tags".to_owned(), vec![format!("{}{}", i, member)])?;
+        store.srem("tags".to_owned(), vec![format!("{}{}", i, member)])?;
+    }
+    assert_eq!(store.srem("tags".to_owned(), tags(&["c", "d"]))?, 1);
+    assert!(!temp_dir.path().join(format::log_file_name(1)).exists());
+
+    for _ in 0..2 {
+        assert!(store.sismember("tags".to_owned(), "a".to_owned())?);
+        assert!(!store.sismember("tags".to_owned(), "c".to_owned())?);
+        assert!(!store.sismember("other".to_owned(), "a".to_owned())?);
+        let members = store.smembers("tags".to_owned())?;
+        assert_eq!(members.into_iter().collect::<Vec<_>>(), tags(&["a", "b"]));
+        assert!(store.smembers("other".to_owned())?.is_empty());
+
+        drop(store);
+        store = KvStore::open(temp_dir.path())?;
+    }
+    Ok(())
+}
+
+// Should expire keys, also across reopens, and keep the expiration of
+// counters when incrementing them
+#[test]
+fn expiring_counters() -> Result<()> {
+    expiring_counters_with(KvStore::open)
+}
+
+#[cfg(feature = "engine-sled")]
+#[test]
+fn expiring_counters_sled() -> Result<()> {
+    // without the background flusher, the lock is released on drop
+    let config = |path: &Path| sled::Config::new().path(path).flush_every_ms(None);
+    expiring_counters_with(|path| Ok(SledStore::new(config(path).open()?)))
+}
+
+// Reads should hide or delete keys found expired as configured, counting
+// both, the sweep dropping the hidden ones
+#[test]
+fn expired_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    expired_reads_on(&mut store, false)?;
+    // hidden, left to compaction
+    assert_eq!(store.metrics()?["keys"], 2);
+    let mut store = store.expired_reads(ExpiredReads::Delete);
+    expired_reads_on(&mut store, true)?;
+    assert_eq!(store.metrics()?["keys"], 1);
+    drop(store);
+    // deleted in the log by the read
+    let buf = fs::read(temp_dir.path().join(format::log_file_name(1)))?;
+    let removed: Vec<String> = format::records(&buf)
+        .filter_map(|record| match record.unwrap().1 {
+            KvLog::Remove { key } => Some(key),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(removed, vec!["gone"]);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    #[cfg(feature = "engine-sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = sled::Config::new()
+            .path(temp_dir.path())
+            .flush_every_ms(None);
+        let store = SledStore::new(config.open()?);
+        let mut hiding = store.clone().expired_reads(ExpiredReads::Hide);
+        expired_reads_on(&mut hiding, false)?;
+        assert_eq!(hiding.metrics()?["string_keys"], 2);
+        assert_eq!(hiding.sweep_expired()?, 1);
+        assert_eq!(hiding.metrics()?["string_keys"], 1);
+        expired_reads_on(&mut store.expired_reads(ExpiredReads::Delete), true)?;
+    }
+    Ok(())
+}
+
+fn expired_reads_on<E: KvsEngine>(store: &mut E, deletes: bool) -> Result<()> {
+    let before = store.metrics()?;
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.expire("gone".to_owned(), Duration::from_millis(0))?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.get_with_type("gone".to_owned())?, None);
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+
+    let after = store.metrics()?;
+    let counted = |name: &str| after[name] - before[name];
+    match deletes {
+        // once deleted, the key is simply missing
+        true => assert_eq!(
+            (
+                counted("expired_reads_hidden"),
+                counted("expired_reads_deleted")
+            ),
+            (0, 1)
+        ),
+        false => assert_eq!(
+            (
+                counted("expired_reads_hidden"),
+                counted("expired_reads_deleted")
+            ),
+            (2, 0)
+        ),
+    }
+    Ok(())
+}
+
+// Namespaces of a sled database should keep their keys apart, and clones
+// used from several threads should not lose updates
+#[cfg(feature = "engine-sled")]
+#[test]
+fn sled_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = sled::Config::new()
+        .path(temp_dir.path())
+        .flush_every_ms(None);
+    let mut store = SledStore::new(config.open()?);
+    let mut users = store.namespace("users")?;
+    assert!(store.namespace("a/b").is_err());
+
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "users".to_owned())?;
+    users.rpush("list".to_owned(), vec!["a".to_owned()])?;
+    users.hset("hash".to_owned(), "f".to_owned(), "v".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    assert_eq!(store.keys()?, vec!["key".to_owned()]);
+    assert_eq!(users.keys()?, vec!["hash", "key", "list"]);
+    assert_eq!(store.namespaces()?, vec!["users".to_owned()]);
+
+    assert!(users.exists("hash".to_owned())?);
+    assert!(!store.exists("hash".to_owned())?);
+    users.purge("hash".to_owned())?;
+    assert!(!users.exists("hash".to_owned())?);
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mut users = users.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    users.incr("hits".to_owned(), 1)?;
+                    users.rpush("log".to_owned(), vec![i.to_string()])?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(users.get("hits".to_owned())?, Some("200".to_owned()));
+    assert_eq!(users.lrange("log".to_owned(), 0, -1)?.len(), 200);
+    Ok(())
+}
+
+// every engine expires keys the same way
+fn expiring_counters_with<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    assert_eq!(store.incr("hits".to_owned(), 2)?, 2);
+    assert_eq!(store.incr("hits".to_owned(), -3)?, -1);
+    assert_eq!(
+        store.get_with_type("hits".to_owned())?,
+        Some(("-1".to_owned(), ValueType::Int))
+    );
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert!(store.incr("name".to_owned(), 1).is_err());
+    assert!(!store.expire("missing".to_owned(), Duration::from_secs(1))?);
+    assert_eq!(store.ttl("hits".to_owned())?, None);
+
+    assert!(store.expire("hits".to_owned(), Duration::from_millis(300))?);
+    assert!(store.expire("name".to_owned(), Duration::from_secs(60))?);
+    store.incr("hits".to_owned(), 1)?;
+    assert!(store.ttl("hits".to_owned())? <= Some(Duration::from_millis(300)));
+
+    drop(store);
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("0".to_owned()));
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("hits".to_owned())?, None);
+    assert_eq!(store.ttl("hits".to_owned())?, None);
+    assert!(matches!(
+        store.remove("hits".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.incr("hits".to_owned(), 1)?, 1);
+    assert_eq!(store.ttl("hits".to_owned())?, None);
+    assert_eq!(store.get("name".to_owned())?, Some("kvs".to_owned()));
+    assert!(store.ttl("name".to_owned())? > Some(Duration::from_secs(50)));
+
+    // setting a key again clears its expiry, expired keys are not listed
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert_eq!(store.ttl("name".to_owned())?, None);
+    assert!(store.expire("hits".to_owned(), Duration::from_millis(100))?);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.keys()?, ["name"]);
+    assert!(!store.exists("hits".to_owned())?);
+    Ok(())
+}
+
+// Should slide the expiry of a key with a small record, keeping it across
+// reopening and compaction
+#[test]
+fn touch_without_rewriting_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(10_000);
+    store.set("page".to_owned(), value.clone())?;
+    assert!(!store.touch("missing".to_owned(), Duration::from_secs(1))?);
+
+    let log_size = || -> u64 {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let before = log_size();
+    for _ in 0..10 {
+        assert!(store.touch("page".to_owned(), Duration::from_secs(60))?);
+    }
+    assert!(log_size() - before < 1000);
+    assert!(store.ttl("page".to_owned())? > Some(Duration::from_secs(50)));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("page".to_owned())? > Some(Duration::from_secs(50)));
+    assert!(store.touch("page".to_owned(), Duration::from_secs(1))?);
+
+    // enough writes to compact, the touched expiry survives it
+    for iter in 0..2000 {
+        store.set("filler".to_owned(), format!("{:01000}", iter))?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("page".to_owned())?, Some(value));
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(store.get("page".to_owned())?, None);
+    Ok(())
+}
+
+// Should allow `limit` hits per window, weigh in the hits of the previous
+// window as the window slides, and forget them once it slid past them
+#[test]
+fn rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let window = Duration::from_millis(500);
+
+    for count in 1..=3 {
+        let rate = store.rate("api".to_owned(), window, 3)?;
+        assert!(rate.allowed);
+        assert_eq!(rate.count, count);
+        assert!(rate.reset_ms <= 500);
+    }
+    let rate = store.rate("api".to_owned(), window, 3)?;
+    assert!(!rate.allowed);
+    assert_eq!(rate.count, 4);
+
+    // a fixed window would allow it, the previous hits still weigh 90%
+    thread::sleep(Duration::from_millis(550));
+    let rate = store.rate("api".to_owned(), window, 3)?;
+    assert!(!rate.allowed);
+    assert!(rate.count > 3);
+    assert!(rate.reset_ms > 200);
+
+    thread::sleep(Duration::from_millis(1100));
+    let rate = store.rate("api".to_owned(), window, 3)?;
+    assert!(rate.allowed);
+    assert_eq!(rate.count, 1);
+
+    // windows out of bounds, and keys holding other values, are refused
+    for window in [Duration::ZERO, MAX_RATE_WINDOW * 2, Duration::MAX] {
+        assert!(matches!(
+            store.rate("api".to_owned(), window, 3),
+            Err(KvsError::InvalidCommand(_))
+        ));
+    }
+    store.set("name".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.rate("name".to_owned(), window, 3),
+        Err(KvsError::InvalidValue(_))
+    ));
+    assert_eq!(store.get("name".to_owned())?, Some("value".to_owned()));
+
+    // counts saturate rather than overflow
+    let start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+        - 100;
+    store.set(
+        "full".to_owned(),
+        format!("{} {} {}", start, u64::MAX, u64::MAX),
+    )?;
+    let rate = store.rate("full".to_owned(), MAX_RATE_WINDOW, 3)?;
+    assert!(!rate.allowed);
+    assert_eq!(rate.count, u64::MAX);
+    Ok(())
+}
+
+// Should list the immediate children of a prefix, grouping deeper keys by
+// directory
+#[test]
+fn list_children() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key in [
+        "users/1/name",
+        "users/1/email",
+        "users/2/name",
+        "users/count",
+    ] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.rpush("users/queue".to_owned(), vec!["1".to_owned()])?;
+    store.set("usersettings".to_owned(), "value".to_owned())?;
+
+    let children = store.list_children("users/".to_owned(), "/".to_owned())?;
+    assert_eq!(children.dirs, vec!["users/1/", "users/2/"]);
+    assert_eq!(children.keys, vec!["users/count", "users/queue"]);
+
+    let children = store.list_children("".to_owned(), "/".to_owned())?;
+    assert_eq!(children.dirs, vec!["users/"]);
+    assert_eq!(children.keys, vec!["usersettings"]);
+
+    // other separators, or none at all
+    let children = store.list_children("users".to_owned(), "::".to_owned())?;
+    assert!(children.dirs.is_empty());
+    assert_eq!(children.keys.len(), 6);
+    let children = store.list_children("users/1/".to_owned(), "".to_owned())?;
+    assert_eq!(children.keys, vec!["users/1/email", "users/1/name"]);
+    Ok(())
+}
+
+// Should keep removed values for the trash window, across reopening and
+// compaction, then drop them
+#[test]
+fn undelete_within_trash_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let window = Duration::from_millis(500);
+    let mut store = KvStore::open(temp_dir.path())?.trash_window(window);
+
+    store.set_with_type("key1".to_owned(), "42".to_owned(), ValueType::Int)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.keys()?.contains(&"key1".to_owned()));
+
+    // a new value supersedes the trashed one
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?.trash_window(window);
+    store.undelete("key1".to_owned())?;
+    assert_eq!(
+        store.get_with_type("key1".to_owned())?,
+        Some(("42".to_owned(), ValueType::Int))
+    );
+    store.undelete("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert!(matches!(
+        store.undelete("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // the window closes
+    store.remove("key2".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+    assert!(matches!(
+        store.undelete("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Should never trash values without a trash window
+#[test]
+fn remove_without_trash_window() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.undelete("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Should carry trashed values over compaction
+#[test]
+fn trash_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let window = Duration::from_secs(60);
+    let mut store = KvStore::open(temp_dir.path())?.trash_window(window);
+
+    store.set("kept".to_owned(), "value".to_owned())?;
+    store.remove("kept".to_owned())?;
+    let value = "x".repeat(1000);
+    for _ in 0..1500 {
+        store.set("filler".to_owned(), value.clone())?;
+    }
+    assert!(!temp_dir.path().join(format::log_file_name(1)).exists());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?.trash_window(window);
+    assert_eq!(store.get("kept".to_owned())?, None);
+    store.undelete("kept".to_owned())?;
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should write hints along with compaction and load every keyspace from
+// them on open, replaying the generation when they are unusable
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let window = Duration::from_secs(60);
+    let long_key = "k".repeat(300);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let mut store = KvStore::open(temp_dir.path())?.trash_window(window);
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.set(long_key.clone(), "long".to_owned())?;
+    store.set("expiring".to_owned(), "value".to_owned())?;
+    store.expire("expiring".to_owned(), Duration::from_secs(60))?;
+    store.set("trashed".to_owned(), "value".to_owned())?;
+    store.remove("trashed".to_owned())?;
+    store.rpush("list".to_owned(), vec!["b".to_owned(), "c".to_owned()])?;
+    store.lpush("list".to_owned(), vec!["a".to_owned()])?;
+    store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+    store.sadd("set".to_owned(), vec!["member".to_owned()])?;
+    store.set_at("later".to_owned(), "value".to_owned(), now + 60_000)?;
+    store.compact()?;
+    assert!(temp_dir.path().join(format::hint_file_name(2)).exists());
+    // written after the hints, replayed
+    store.set("after".to_owned(), "value".to_owned())?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get(long_key.clone())?, Some("long".to_owned()));
+        assert!(store.ttl("expiring".to_owned())?.is_some());
+        assert_eq!(store.lrange("list".to_owned(), 0, -1)?, vec!["a", "b", "c"]);
+        assert_eq!(
+            store.hget("hash".to_owned(), "field".to_owned())?,
+            Some("value".to_owned())
+        );
+        assert!(store.sismember("set".to_owned(), "member".to_owned())?);
+        assert_eq!(store.get("after".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.metrics()?["scheduled"], 1);
+        assert_eq!(store.get("trashed".to_owned())?, None);
+        Ok(())
+    };
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?.trash_window(window);
+    assert_eq!(store.metrics()?["hinted_generations"], 1);
+    check(&mut store)?;
+    store.undelete("trashed".to_owned())?;
+    store.remove("trashed".to_owned())?;
+
+    // hints not matching the generation are ignored
+    drop(store);
+    fs::write(
+        temp_dir.path().join(format::hint_file_name(2)),
+        "{\"Header\":{\"gen\":2,\"len\":1}}\n",
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?.trash_window(window);
+    assert_eq!(store.metrics()?["hinted_generations"], 0);
+    check(&mut store)?;
+    store.undelete("trashed".to_owned())?;
+
+    // compaction drops the hints along with their generation
+    store.compact()?;
+    assert!(!temp_dir.path().join(format::hint_file_name(2)).exists());
+    assert!(temp_dir.path().join(format::hint_file_name(6)).exists());
+    Ok(())
+}
+
+// Should read long lists and large hashes, whose elements are fetched in
+// batches
+#[test]
+fn batched_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let values: Vec<String> = (0..300).map(|i| format!("value{}", i)).collect();
+    store.rpush("list".to_owned(), values.clone())?;
+    for i in 0..150 {
+        store.hset("hash".to_owned(), format!("field{}", i), format!("{}", i))?;
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.lrange("list".to_owned(), 0, -1)?, values);
+    assert_eq!(
+        store.lrange("list".to_owned(), 100, 101)?,
+        &values[100..102]
+    );
+    let hash = store.hgetall("hash".to_owned())?;
+    assert_eq!(hash.len(), 150);
+    assert_eq!(hash["field42"], "42");
+    Ok(())
+}
+
+// Should hand out values encoded as JSON strings, as stored in the log
+#[test]
+fn get_raw_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "line \"one\"\nline\ttwo é ✓".to_owned();
+    store.set("key1".to_owned(), value.clone())?;
+    let raw = store.get_raw("key1".to_owned())?.unwrap();
+    assert_eq!(serde_json::from_str::<String>(raw.get())?, value);
+    assert!(store.get_raw("key2".to_owned())?.is_none());
+    Ok(())
+}
+
+// Should split long values into chunks, cut on char boundaries, and read
+// them back whole across reopening and compaction
+#[test]
+fn chunked_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?.chunk_size(100);
+
+    let value = "line \"one\" é ✓\n".repeat(50);
+    store.set("big".to_owned(), value.clone())?;
+    let json = format!("[{}]", vec!["1"; 100].join(","));
+    store.set_with_type("json".to_owned(), json.clone(), ValueType::Json)?;
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+    let raw = store.get_raw("big".to_owned())?.unwrap();
+    assert_eq!(serde_json::from_str::<String>(raw.get())?, value);
+    let mut streamed = Vec::new();
+    assert!(store.get_raw_to_writer("big".to_owned(), &mut streamed)?);
+    assert_eq!(streamed, raw.get().as_bytes());
+
+    let buf = fs::read(temp_dir.path().join(format::log_file_name(1)))?;
+    let logs: Vec<KvLog> = format::records(&buf).map(|r| r.unwrap().1).collect();
+    assert!(logs.len() > 10);
+    assert!(logs.iter().all(|log| match log {
+        KvLog::Chunk { data, .. } => data.len() <= 100 + 2,
+        log => matches!(log, KvLog::Manifest { .. }),
+    }));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?.chunk_size(100);
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+    let filler = "x".repeat(1000);
+    for _ in 0..1500 {
+        store.set("filler".to_owned(), filler.clone())?;
+    }
+    assert!(!temp_dir.path().join(format::log_file_name(1)).exists());
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(value));
+    assert_eq!(
+        store.get_with_type("json".to_owned())?,
+        Some((json, ValueType::Json))
+    );
+    assert_eq!(store.get("filler".to_owned())?, Some(filler));
+    Ok(())
+}
+
+// Should evict the least recently used keys over the cache budget, and keep
+// them evicted across reopening
+#[test]
+fn cache_budget_evicts_least_recently_used() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // room for three of these values, not four
+    let value = "x".repeat(1000);
+    let mut store = KvStore::open(temp_dir.path())?.cache_budget(3500);
+
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), value.clone())?;
+    store.set("key3".to_owned(), value.clone())?;
+    assert_eq!(store.evictions(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+
+    store.set("key4".to_owned(), value.clone())?;
+    assert_eq!(store.evictions(), 1);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+
+    // freed room is reused without evicting
+    store.remove("key3".to_owned())?;
+    store.set("key5".to_owned(), value.clone())?;
+    assert_eq!(store.evictions(), 1);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.keys()?, vec!["key1", "key4", "key5"]);
+    Ok(())
+}
+
+// Should evict keys already over the budget on the next write
+#[test]
+fn cache_budget_applies_to_existing_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "x".repeat(1000);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?.cache_budget(2500);
+    store.set("key5".to_owned(), value.clone())?;
+    assert_eq!(store.evictions(), 4);
+    assert_eq!(store.keys()?, vec!["key4", "key5"]);
+    Ok(())
+}
+
+// Should compact the values evicted by cache churn, keeping the data
+// directory around the budget
+#[test]
+fn cache_churn_compacts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "x".repeat(1000);
+    let mut store = KvStore::open(temp_dir.path())?.cache_budget(10_000);
+    for i in 0..20_000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let metrics = store.metrics()?;
+    assert!(metrics["compactions"] > 0);
+    assert!(metrics["uncompacted_bytes"] > 0);
+
+    let size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(size < 4 * 1024 * 1024, "{} bytes on disk", size);
+    assert_eq!(store.keys()?.len(), 9);
+    Ok(())
+}
+
+// Should report a torn tail that keeps the store from opening, and repair it
+// without losing the records before it
+#[test]
+fn fsck_repairs_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let gen = fs::read_dir(temp_dir.path())?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| fs::metadata(path).map(|m| m.len() > 0).unwrap_or(false))
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .unwrap();
+    let mut file = fs::OpenOptions::new().append(true).open(&gen)?;
+    file.write_all(br#"{"Set":{"key":"key3","val"#)?;
+    drop(file);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = fsck::check(temp_dir.path(), false)?;
+    assert_eq!(report.records, 2);
+    assert_eq!(report.issues.len(), 1);
+    assert_eq!(report.issues[0].kind, fsck::IssueKind::TornTail);
+    assert!(!report.is_clean());
+
+    assert!(fsck::check(temp_dir.path(), true)?.is_clean());
+    assert!(fsck::check(temp_dir.path(), false)?.issues.is_empty());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should leave a corrupt record followed by more data to the operator
+#[test]
+fn fsck_reports_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+         {\"Set\":{\"key\":\"key2\" garbage\n\
+         {\"Set\":{\"key\":\"key3\",\"value\":\"x\",\"value_type\":\"int\"}}\n",
+    )?;
+
+    let report = fsck::check(temp_dir.path(), true)?;
+    assert_eq!(report.issues.len(), 1);
+    assert_eq!(report.issues[0].kind, fsck::IssueKind::Corrupt);
+    assert!(!report.issues[0].repaired);
+    assert!(!report.is_clean());
+    Ok(())
+}
+
+// Should quarantine malformed records amid good ones on open, once, and
+// keep replaying the rest
+#[test]
+fn quarantine_malformed_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join(format::log_file_name(1)))?
+        .write_all(
+            b"{\"Set\":{\"key\":\"key2\" garbage\n\
+              {\"Bogus\":{}}\n\
+              {\"Set\":{\"key\":\"key3\",\"value\":\"value3\"}}\n",
+        )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.metrics()?["quarantined_records"], 2);
+    let quarantined = || -> Result<Vec<serde_json::Value>> {
+        let content = fs::read_to_string(temp_dir.path().join(QUARANTINE_FILE))?;
+        Ok(content
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<_>>()?)
+    };
+    let letters = quarantined()?;
+    assert_eq!(letters.len(), 2);
+    assert_eq!(letters[0]["gen"], 1);
+    assert_eq!(letters[0]["record"], "{\"Set\":{\"key\":\"key2\" garbage");
+    assert_eq!(letters[1]["record"], "{\"Bogus\":{}}");
+    assert!(letters[1]["offset"].as_u64() > letters[0]["offset"].as_u64());
+
+    // found again on the next open, but quarantined once
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics()?["quarantined_records"], 2);
+    assert_eq!(quarantined()?.len(), 2);
+
+    // compaction leaves them out of the log
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics()?["quarantined_records"], 0);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(quarantined()?.len(), 2);
+    Ok(())
+}
+
+// Should checksum every record, failing reads of a damaged one, and add
+// checksums to directories written without them
+#[test]
+fn record_checksums() -> Result<()> {
+    assert_eq!(format::crc32(b"123456789"), 0xcbf4_3926);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let log = temp_dir.path().join(format::log_file_name(1));
+    let content = fs::read(&log)?;
+    assert!(content
+        .split_inclusive(|&b| b == b'\n')
+        .all(|line| format::verify_checksum(line).unwrap().len() + 10 == line.len()));
+
+    // a bit flipped under the open store
+    let damaged = content.windows(6).position(|w| w == b"value1").unwrap();
+    let mut bytes = content.clone();
+    bytes[damaged] ^= 0x01;
+    fs::write(&log, &bytes)?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::Corruption { gen: 1, offset: 0 })
+    ));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // and quarantined on open
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.metrics()?["quarantined_records"], 1);
+    let quarantine = fs::read_to_string(temp_dir.path().join(QUARANTINE_FILE))?;
+    assert!(quarantine.contains("Corrupt record at 1:0"));
+    drop(store);
+
+    // chunks written before checksums, their manifest spanning them
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let chunks = "{\"Chunk\":{\"key\":\"big\",\"data\":\"abc\"}}\n\
+                  {\"Chunk\":{\"key\":\"big\",\"data\":\"def\"}}\n";
+    fs::write(
+        temp_dir.path().join(format::log_file_name(1)),
+        format!(
+            "{}{{\"Manifest\":{{\"key\":\"big\",\"span\":{}}}}}\n",
+            chunks,
+            chunks.len()
+        ),
+    )?;
+    fs::write(temp_dir.path().join(FORMAT_FILE), "5")?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some("abcdef".to_owned()));
+    let migrated = fs::read(temp_dir.path().join(format::log_file_name(1)))?;
+    assert!(migrated
+        .split_inclusive(|&b| b == b'\n')
+        .all(|line| format::verify_checksum(line).unwrap().len() + 10 == line.len()));
+    Ok(())
+}
+
+// Should write binary generations when asked, read them back along with
+// JSON ones, and rewrite the live records in the chosen encoding on
+// compaction
+#[test]
+fn binary_log_encoding() -> Result<()> {
+    let fill = |store: &mut KvStore| -> Result<()> {
+        store.set("key1".to_owned(), "line\nbreak\t\"quoted\"".to_owned())?;
+        store.set_with_type("doc".to_owned(), "{\"a\":1}".to_owned(), ValueType::Json)?;
+        store.set("big".to_owned(), "x".repeat(100))?;
+        store.rpush("list".to_owned(), vec!["a".to_owned(), "b".to_owned()])?;
+        store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+        store.sadd("set".to_owned(), vec!["member".to_owned()])?;
+        store.expire("key1".to_owned(), Duration::from_secs(3600))?;
+        Ok(())
+    };
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("line\nbreak\t\"quoted\"".to_owned())
+        );
+        assert_eq!(
+            store.get_raw("doc".to_owned())?.unwrap().get(),
+            "\"{\\\"a\\\":1}\""
+        );
+        assert_eq!(store.get("big".to_owned())?, Some("x".repeat(100)));
+        let mut out = Vec::new();
+        assert!(store.get_to_writer("big".to_owned(), &mut out)?);
+        assert_eq!(out, "x".repeat(100).into_bytes());
+        assert_eq!(store.lrange("list".to_owned(), 0, -1)?, vec!["a", "b"]);
+        assert_eq!(
+            store.hget("hash".to_owned(), "field".to_owned())?,
+            Some("value".to_owned())
+        );
+        assert!(store.smembers("set".to_owned())?.contains("member"));
+        assert!(store.ttl("key1".to_owned())?.is_some());
+        Ok(())
+    };
+    let starts_binary = |dir: &Path, gen: u64| -> bool {
+        fs::read(dir.join(format::log_file_name(gen)))
+            .unwrap()
+            .starts_with(&format::BINARY_MAGIC)
+    };
+
+    // binary records are smaller than JSON lines
+    let json_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(json_dir.path())?.chunk_size(16);
+    fill(&mut store)?;
+    drop(store);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?
+        .chunk_size(16)
+        .log_encoding(LogEncoding::Binary);
+    fill(&mut store)?;
+    check(&mut store)?;
+    assert!(starts_binary(temp_dir.path(), 1));
+    let len = |dir: &Path| {
+        fs::metadata(dir.join(format::log_file_name(1)))
+            .unwrap()
+            .len()
+    };
+    assert!(len(temp_dir.path()) < len(json_dir.path()));
+    drop(store);
+
+    // the encoding is kept by new generations, and fsck reads them
+    let mut store = KvStore::open(temp_dir.path())?.chunk_size(16);
+    check(&mut store)?;
+    assert!(starts_binary(temp_dir.path(), 2));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.changed_since(2 << 40)?, vec!["key2".to_owned()]);
+    drop(store);
+    let report = fsck::check(temp_dir.path(), false)?;
+    assert!(report.issues.is_empty());
+    assert_eq!(report.generations, 2);
+
+    // a JSON directory is rewritten in binary by compaction, and back
+    let mut store = KvStore::open(json_dir.path())?
+        .chunk_size(16)
+        .log_encoding(LogEncoding::Binary);
+    store.compact()?;
+    check(&mut store)?;
+    assert!(starts_binary(json_dir.path(), 3));
+    drop(store);
+    let mut store = KvStore::open(json_dir.path())?.log_encoding(LogEncoding::Json);
+    check(&mut store)?;
+    store.compact()?;
+    check(&mut store)?;
+    assert!(!starts_binary(json_dir.path(), 6));
+    drop(store);
+    let mut store = KvStore::open(json_dir.path())?;
+    check(&mut store)?;
+    drop(store);
+
+    // a damaged binary record fails its checksum
+    let log = temp_dir.path().join(format::log_file_name(2));
+    let mut bytes = fs::read(&log)?;
+    let damaged = bytes.windows(6).position(|w| w == b"value2").unwrap();
+    bytes[damaged] ^= 0x01;
+    fs::write(&log, &bytes)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.metrics()?["quarantined_records"], 1);
+    Ok(())
+}
+
+// Should spread generations across data directories and read them back from
+// wherever they are, through compaction
+#[test]
+fn spread_across_dirs() -> Result<()> {
+    let temp_dirs: Vec<TempDir> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let dirs: Vec<PathBuf> = temp_dirs.iter().map(|dir| dir.path().to_owned()).collect();
+    let gens_in = |dir: &Path| {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                format::parse_log_file_name(name.to_str().unwrap()).is_some()
+            })
+            .count()
+    };
+
+    for round in 0..3 {
+        let mut store = KvStore::open_dirs(&dirs)?;
+        store.set(format!("key{}", round), format!("value{}", round))?;
+    }
+    assert!(dirs.iter().all(|dir| gens_in(dir) > 0));
+
+    // enough overwrites to compact
+    let mut store = KvStore::open_dirs(&dirs)?;
+    for iter in 0..2000 {
+        store.set("key0".to_owned(), format!("{:01000}", iter))?;
+    }
+    drop(store);
+
+    // the directories may be given in any order
+    let mut reversed = dirs.clone();
+    reversed.reverse();
+    let mut store = KvStore::open_dirs(&reversed)?;
+    assert_eq!(
+        store.get("key0".to_owned())?,
+        Some(format!("{:01000}", 1999))
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(dirs.iter().map(|dir| gens_in(dir)).sum::<usize>() <= 4);
+    Ok(())
+}
+
+// Should move sealed generations to the cold tier, on age or on demand, and
+// keep reading them from there
+#[test]
+fn cold_tier() -> Result<()> {
+    let hot = TempDir::new().expect("unable to create temporary working directory");
+    let cold = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = vec![hot.path().to_owned()];
+    let gens_in = |dir: &Path| {
+        let mut gens: Vec<u64> = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| format::parse_log_file_name(entry.unwrap().file_name().to_str()?))
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+
+    for round in 1..=3 {
+        let mut store = KvStore::open_tiered(&dirs, cold.path(), 2)?;
+        store.set(format!("key{}", round), format!("value{}", round))?;
+    }
+    // generation 1 is two generations behind generation 3, generation 2
+    // only one
+    assert_eq!(gens_in(hot.path()), vec![2, 3]);
+    assert_eq!(gens_in(cold.path()), vec![1]);
+
+    let mut store = KvStore::open_tiered(&dirs, cold.path(), 2)?;
+    assert_eq!(gens_in(cold.path()), vec![1, 2]);
+    assert_eq!(store.tier()?, 1);
+    assert_eq!(gens_in(hot.path()), vec![4]);
+    for round in 1..=3 {
+        assert_eq!(
+            store.get(format!("key{}", round))?,
+            Some(format!("value{}", round))
+        );
+    }
+
+    // without the cold directory, its generations are missing
+    drop(store);
+    let mut store = KvStore::open(hot.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(store.tier(), Err(KvsError::InvalidCommand(_))));
+    Ok(())
+}
+
+// Should stream a range of keys in order from a reader thread, long keys
+// indexed by digest included, unaffected by later writes
+#[cfg(feature = "prefetch")]
+#[test]
+fn scan_into_channel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    store.remove("key050".to_owned())?;
+    let long = format!("key045{}", "x".repeat(300));
+    store.set(long.clone(), "long".to_owned())?;
+    store.set(format!("key090{}", "x".repeat(300)), "out".to_owned())?;
+
+    let (sender, receiver) = crossbeam_channel::bounded(4);
+    let reader = store.scan_into("key040".to_owned().."key060".to_owned(), sender)?;
+    store.set("key045".to_owned(), "changed".to_owned())?;
+    let pairs: Vec<(String, String)> = receiver.iter().collect::<Result<_>>()?;
+    reader.join().unwrap();
+
+    assert_eq!(pairs.len(), 20);
+    assert_eq!(pairs[0], ("key040".to_owned(), "value40".to_owned()));
+    assert_eq!(pairs[5], ("key045".to_owned(), "value45".to_owned()));
+    assert_eq!(pairs[6], (long, "long".to_owned()));
+    assert!(pairs.iter().all(|(key, _)| key != "key050"));
+    assert_eq!(pairs[19].0, "key059");
+
+    // the reader stops once the receiver is gone
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let reader = store.scan_into(.., sender)?;
+    drop(receiver);
+    reader.join().unwrap();
+    Ok(())
+}
+
+// Should record the format version, migrate directories predating it and
+// refuse data written in a newer format
+#[test]
+fn format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let format_file = temp_dir.path().join(FORMAT_FILE);
+    assert_eq!(
+        fs::read_to_string(&format_file)?,
+        FORMAT_VERSION.to_string()
+    );
+    // a fresh directory has nothing to migrate
+    assert!(!fs::read_dir(temp_dir.path())?.any(|entry| entry
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .starts_with("backup-")));
+
+    // a directory from before the format file
+    fs::remove_file(&format_file)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    assert_eq!(
+        fs::read_to_string(&format_file)?,
+        FORMAT_VERSION.to_string()
+    );
+
+    fs::write(&format_file, (FORMAT_VERSION + 1).to_string())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::FormatVersion { found, supported })
+            if found == FORMAT_VERSION + 1 && supported == FORMAT_VERSION
+    ));
+    Ok(())
+}
+
+// A store opened read-only should write nothing and pick up the writes of
+// the store writing the directory on refresh, keeping its settings
+#[test]
+fn read_only_follows_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = [temp_dir.path().to_owned()];
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key".to_owned(), "value".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(&dirs, None)?.expired_reads(ExpiredReads::Delete);
+    assert_eq!(reader.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        reader.set("key".to_owned(), "other".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    writer.set("key".to_owned(), "newer".to_owned())?;
+    writer.set("gone".to_owned(), "value".to_owned())?;
+    writer.expire("gone".to_owned(), Duration::from_millis(0))?;
+    // half a record, as seen while the writer appends it
+    let gen = fs::read_dir(temp_dir.path())?
+        .filter_map(|entry| format::parse_log_file_name(entry.ok()?.file_name().to_str()?))
+        .max()
+        .unwrap();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join(format::log_file_name(gen)))?
+        .write_all(br#"{"Set":{"key":"torn","#)?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key".to_owned())?, Some("newer".to_owned()));
+    assert_eq!(reader.get("torn".to_owned())?, None);
+    assert_eq!(reader.get("gone".to_owned())?, None);
+    assert_eq!(reader.metrics()?["expired_reads_dropped"], 1);
+    assert_eq!(reader.metrics()?["expired_reads_deleted"], 0);
+    Ok(())
+}
+
+// A mirror should apply every write to both engines, and count where the
+// secondary disagrees with the primary
+#[test]
+fn mirror_writes_both_engines() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let secondary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut secondary = KvStore::open(secondary_dir.path())?;
+    secondary.set("stale".to_owned(), "value".to_owned())?;
+    let mut mirror = MirrorEngine::new(KvStore::open(primary_dir.path())?, secondary).verify(true);
+
+    mirror.set("key".to_owned(), "value".to_owned())?;
+    mirror.rpush("list".to_owned(), vec!["a".to_owned(), "b".to_owned()])?;
+    mirror.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+    assert_eq!(mirror.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(mirror.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(mirror.divergences(), 0);
+
+    // missing from the primary, the secondary still has it
+    assert_eq!(mirror.get("stale".to_owned())?, None);
+    assert_eq!(mirror.divergences(), 1);
+    assert!(matches!(
+        mirror.remove("stale".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    let (_, mut secondary) = mirror.into_inner();
+    assert_eq!(secondary.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(secondary.lrange("list".to_owned(), 0, -1)?, vec!["a", "b"]);
+    assert_eq!(
+        secondary.hget("hash".to_owned(), "field".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(secondary.get("counter".to_owned())?, Some("5".to_owned()));
+    Ok(())
+}
+
+// Should route every key to the store mounted under its longest prefix,
+// list the keys each mount serves, and open all the stores or none
+#[test]
+fn composite_mounts() -> Result<()> {
+    let legacy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let current_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut legacy = KvStore::open(legacy_dir.path())?;
+    legacy.set("user:1".to_owned(), "old".to_owned())?;
+    drop(legacy);
+    let mut current = KvStore::open(current_dir.path())?;
+    current.set("legacy/hidden".to_owned(), "shadowed".to_owned())?;
+    drop(current);
+
+    let mut store = CompositeStore::open([
+        ("legacy/".to_owned(), legacy_dir.path()),
+        (String::new(), current_dir.path()),
+    ])?;
+    assert_eq!(
+        store.get("legacy/user:1".to_owned())?,
+        Some("old".to_owned())
+    );
+    store.set("legacy/user:2".to_owned(), "new".to_owned())?;
+    store.set("user:3".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        store.get_many(vec![
+            "user:3".to_owned(),
+            "legacy/user:2".to_owned(),
+            "legacy/hidden".to_owned(),
+        ])?,
+        vec![Some("value".to_owned()), Some("new".to_owned()), None]
+    );
+    assert_eq!(
+        store.keys()?,
+        vec!["legacy/user:1", "legacy/user:2", "user:3"]
+    );
+    assert_eq!(store.scan_prefix("legacy/user:")?.len(), 2);
+    assert_eq!(store.scan_prefix("user")?, vec!["user:3"]);
+
+    let mut legacy = store.detach("legacy/").unwrap();
+    assert_eq!(legacy.get("user:2".to_owned())?, Some("new".to_owned()));
+    assert_eq!(
+        store.get("legacy/hidden".to_owned())?,
+        Some("shadowed".to_owned())
+    );
+    assert!(matches!(
+        store.attach("", legacy),
+        Err(KvsError::InvalidCommand(_))
+    ));
+
+    let mut partial = CompositeStore::new();
+    partial.attach("a/", KvStore::open(legacy_dir.path())?)?;
+    assert!(matches!(
+        partial.get("b/key".to_owned()),
+        Err(KvsError::InvalidCommand(_))
+    ));
+
+    // a file in the way of the second directory fails the whole open
+    let file = legacy_dir.path().join("not-a-directory");
+    fs::write(&file, "")?;
+    drop(partial);
+    assert!(CompositeStore::open([
+        ("a/".to_owned(), legacy_dir.path().to_owned()),
+        ("b/".to_owned(), file.join("store")),
+    ])
+    .is_err());
+    Ok(())
+}
+
+// Should encrypt the values under the chosen prefixes with their own key,
+// keeping their type, and leave the other keys alone
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys = BTreeMap::from([
+        ("secret:".to_owned(), [1; 32]),
+        ("pii:".to_owned(), [2; 32]),
+    ]);
+    let mut store = EncryptedEngine::new(KvStore::open(temp_dir.path())?, keys.clone())
+        .encrypt_prefix("secret:")
+        .encrypt_prefix("pii:");
+
+    store.set("secret:token".to_owned(), "hunter2".to_owned())?;
+    store.set_with_type(
+        "pii:user".to_owned(),
+        r#"{"name":"Ann"}"#.to_owned(),
+        ValueType::Json,
+    )?;
+    store.set("public".to_owned(), "hello".to_owned())?;
+    store.rpush(
+        "secret:list".to_owned(),
+        vec!["a".to_owned(), "b".to_owned()],
+    )?;
+    store.hset(
+        "pii:hash".to_owned(),
+        "email".to_owned(),
+        "ann@example.com".to_owned(),
+    )?;
+    assert_eq!(
+        store.get("secret:token".to_owned())?,
+        Some("hunter2".to_owned())
+    );
+    assert_eq!(
+        store.get_with_type("pii:user".to_owned())?,
+        Some((r#"{"name":"Ann"}"#.to_owned(), ValueType::Json))
+    );
+    assert_eq!(
+        store.lrange("secret:list".to_owned(), 0, -1)?,
+        vec!["a", "b"]
+    );
+    assert_eq!(
+        store.hget("pii:hash".to_owned(), "email".to_owned())?,
+        Some("ann@example.com".to_owned())
+    );
+    // counters and set members cannot be encrypted
+    assert!(matches!(
+        store.incr("secret:count".to_owned(), 1),
+        Err(KvsError::InvalidCommand(_))
+    ));
+    assert!(matches!(
+        store.sadd("pii:set".to_owned(), vec!["a".to_owned()]),
+        Err(KvsError::InvalidCommand(_))
+    ));
+
+    // only the values under the prefixes are encrypted on disk
+    let mut inner = store.into_inner();
+    let (sealed, value_type) = inner.get_with_type("secret:token".to_owned())?.unwrap();
+    assert_eq!(value_type, ValueType::Bytes);
+    assert!(!sealed.contains("hunter2"));
+    assert_ne!(
+        inner.lrange("secret:list".to_owned(), 0, -1)?,
+        vec!["a", "b"]
+    );
+    assert_eq!(inner.get("public".to_owned())?, Some("hello".to_owned()));
+
+    // a wrong key fails to decrypt rather than returning garbage
+    let wrong = BTreeMap::from([("secret:".to_owned(), [3; 32])]);
+    let mut store = EncryptedEngine::new(inner, wrong).encrypt_prefix("secret:");
+    assert!(matches!(
+        store.get("secret:token".to_owned()),
+        Err(KvsError::InvalidValue(_))
+    ));
+    let mut store = EncryptedEngine::new(store.into_inner(), keys).encrypt_prefix("secret:");
+    assert_eq!(
+        store.get("secret:token".to_owned())?,
+        Some("hunter2".to_owned())
+    );
+    Ok(())
+}
+
+// Should keep long keys, indexed by digest, in every keyspace across a
+// compaction and reopens, and refuse keys longer than `MAX_KEY_LEN`
+#[test]
+fn long_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let long = |c: char| c.to_string().repeat(10_000);
+
+    store.set(long('s'), "value".to_owned())?;
+    store.expire(long('s'), Duration::from_secs(60))?;
+    store.set(long('r'), "value".to_owned())?;
+    store.rpush(long('l'), vec!["a".to_owned(), "b".to_owned()])?;
+    store.hset(long('h'), "field".to_owned(), "value".to_owned())?;
+    store.sadd(long('m'), vec!["member".to_owned()])?;
+    store.remove(long('r'))?;
+    // rewriting a large value triggers a compaction
+    for _ in 0..3 {
+        store.set("filler".to_owned(), "x".repeat(600_000))?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(long('s'))?, Some("value".to_owned()));
+    assert!(store.ttl(long('s'))?.is_some());
+    assert_eq!(store.get(long('r'))?, None);
+    assert_eq!(store.lrange(long('l'), 0, -1)?, vec!["a", "b"]);
+    assert_eq!(
+        store.hget(long('h'), "field".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert!(store.sismember(long('m'), "member".to_owned())?);
+    assert_eq!(
+        store.keys()?,
+        vec![
+            "filler".to_owned(),
+            long('h'),
+            long('l'),
+            long('m'),
+            long('s')
+        ]
+    );
+
+    let too_long = "k".repeat(MAX_KEY_LEN + 1);
+    match store.set(too_long.clone(), "value".to_owned()) {
+        Err(KvsError::KeyTooLong { len, max }) => {
+            assert_eq!((len, max), (MAX_KEY_LEN + 1, MAX_KEY_LEN))
+        }
+        res => panic!("expected KeyTooLong, got {:?}", res),
+    }
+    assert!(matches!(
+        store.rpush(too_long, vec!["a".to_owned()]),
+        Err(KvsError::KeyTooLong { .. })
+    ));
+    Ok(())
+}
+
+// Should write the same digest of a long key in its records on every
+// build, as replay matches them to the key by it, and keep keys starting
+// with a NUL out of the digests
+#[test]
+fn long_key_digest_is_stable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("k".repeat(300), "value".to_owned())?;
+    store.expire("k".repeat(300), Duration::from_secs(60))?;
+    drop(store);
+
+    let buf = fs::read(temp_dir.path().join(format::log_file_name(1)))?;
+    let touched: Vec<String> = format::records(&buf)
+        .filter_map(|record| match record.unwrap().1 {
+            KvLog::Touch { key, .. } => Some(key),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(touched, vec!["\0ab6561be82b62df87931d03940006e09"]);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("k".repeat(300))?, Some("value".to_owned()));
+    assert!(store.ttl("k".repeat(300))?.is_some());
+    assert_eq!(store.get(touched[0].clone())?, None);
+    assert!(matches!(
+        store.set(touched[0].clone(), "value".to_owned()),
+        Err(KvsError::InvalidCommand(_))
+    ));
+    assert!(store
+        .sadd("\0set".to_owned(), vec!["member".to_owned()])
+        .is_err());
+    assert_eq!(store.changed_since(0)?, vec!["k".repeat(300)]);
+    Ok(())
+}
+
+// Should run scheduled writes once due, in order, keep the pending ones
+// across a compaction and reopens, and never run one twice
+#[test]
+fn scheduled_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.set_at("due".to_owned(), "first".to_owned(), now - 2000)?;
+    store.set_at("due".to_owned(), "second".to_owned(), now - 1000)?;
+    store.remove_at("gone".to_owned(), now - 1000)?;
+    store.remove_at("missing".to_owned(), now - 1000)?;
+    store.set_at("later".to_owned(), "value".to_owned(), now + 60_000)?;
+    assert_eq!(store.get("due".to_owned())?, None);
+
+    assert_eq!(store.run_scheduled()?, 4);
+    assert_eq!(store.get("due".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.run_scheduled()?, 0);
+    store.set("due".to_owned(), "third".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.run_scheduled()?, 0);
+    assert_eq!(store.get("due".to_owned())?, Some("third".to_owned()));
+    assert_eq!(store.get("later".to_owned())?, None);
+    store.set_at("later".to_owned(), "sooner".to_owned(), now)?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.run_scheduled()?, 1);
+    assert_eq!(store.get("later".to_owned())?, Some("sooner".to_owned()));
+    Ok(())
+}
+
+// Should list the keys written or removed after an LSN, long ones whole,
+// until compaction drops that part of the log
+#[test]
+fn changed_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let long = "k".repeat(1000);
+
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    store.set(long.clone(), "1".to_owned())?;
+    store.rpush("l".to_owned(), vec!["x".to_owned()])?;
+    let since = store.lsn().unwrap();
+    assert_eq!(store.changed_since(since)?, Vec::<String>::new());
+
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.remove(long.clone())?;
+    store.hset("h".to_owned(), "f".to_owned(), "v".to_owned())?;
+    assert_eq!(
+        store.changed_since(since)?,
+        vec!["a".to_owned(), "b".to_owned(), "h".to_owned(), long.clone()]
+    );
+    assert_eq!(store.changed_since(0)?.len(), 5);
+
+    store.compact()?;
+    match store.changed_since(since) {
+        Err(KvsError::LsnCompacted { lsn, oldest }) => {
+            assert_eq!(lsn, since);
+            assert!(oldest > since);
+        }
+        r => panic!("expected LsnCompacted, got {:?}", r),
+    }
+    let since = store.lsn().unwrap();
+    store.set("c".to_owned(), "1".to_owned())?;
+    assert_eq!(store.changed_since(since)?, vec!["c".to_owned()]);
+    Ok(())
+}
+
+// Should stop reporting LSNs once the generations outgrow their bits,
+// rather than report overlapping ones
+#[test]
+fn lsn_bounds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join(format::log_file_name((1 << 24) - 2)),
+        "",
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let lsn = store.lsn().unwrap();
+    assert_eq!(lsn >> 40, (1 << 24) - 1);
+    assert_eq!(store.meta("key".to_owned())?.unwrap().lsn, Some(lsn));
+
+    store.compact()?;
+    store.set("key".to_owned(), "other".to_owned())?;
+    assert_eq!(store.lsn(), None);
+    assert_eq!(store.meta("key".to_owned())?.unwrap().lsn, None);
+    assert_eq!(store.get("key".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
+
+// Should report its log files, stale bytes and compactions
+#[test]
+fn engine_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    let metrics = store.metrics()?;
+    assert_eq!(metrics["keys"], 2);
+    assert_eq!(metrics["log_files"], 1);
+    assert_eq!(metrics["compactions"], 0);
+    assert!(metrics["uncompacted_bytes"] > 0);
+    assert!(!metrics.contains_key("cache_used_bytes"));
+
+    store.compact()?;
+    let metrics = store.metrics()?;
+    assert_eq!(metrics["compactions"], 1);
+    assert_eq!(metrics["uncompacted_bytes"], 0);
+    assert_eq!(metrics["log_files"], 2);
+    Ok(())
+}
+
+// Should compact a log mostly made of stale records on request only past
+// the given ratio, and keep every value
+#[test]
+fn compact_if_bloated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("live".to_owned(), "x".repeat(100))?;
+    for i in 0..100 {
+        store.set("churn".to_owned(), format!("{:0100}", i))?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.compact_if_bloated(1000)?);
+    assert_eq!(store.metrics()?["compactions"], 0);
+    assert!(store.compact_if_bloated(4)?);
+    assert_eq!(store.metrics()?["uncompacted_bytes"], 0);
+    assert!(!store.compact_if_bloated(4)?);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("live".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(store.get("churn".to_owned())?, Some(format!("{:0100}", 99)));
+    Ok(())
+}
+
+// Should keep no more generation files open than allowed, reopening them
+// to read
+#[test]
+fn max_open_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // every open starts a generation
+    for i in 0..5 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics()?["open_files"], 7);
+    let mut store = store.max_open_files(2);
+    assert_eq!(store.metrics()?["open_files"], 3);
+    for _ in 0..2 {
+        for i in 0..5 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    let metrics = store.metrics()?;
+    assert_eq!(metrics["open_files"], 3);
+    assert_eq!(metrics["log_files"], 6);
+
+    store.compact()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+// Should keep the generation files while pinned, compacting once unpinned
+#[test]
+fn pin_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+
+    let guard = store.pin_generations()?;
+    let files = guard.files().to_vec();
+    assert_eq!(files.len(), 1);
+    let (path, len) = &files[0];
+    assert_eq!(fs::metadata(path)?.len(), *len);
+    assert!(store.compact().is_err());
+
+    // writes go on, appended past the pinned length
+    for i in 0..1000 {
+        store.set("a".to_owned(), i.to_string())?;
+    }
+    assert!(path.exists());
+    assert!(fs::metadata(path)?.len() > *len);
+    assert_eq!(store.metrics()?["compactions"], 0);
+
+    drop(guard);
+    store.compact()?;
+    assert!(!path.exists());
+    assert_eq!(store.get("a".to_owned())?, Some("999".to_owned()));
+    Ok(())
+}
+
+// Should export a snapshot that opens read-only, unaffected by later writes
+// and compactions of the store
+#[test]
+fn export_snapshot_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir)?;
+    let mut store = KvStore::open(&data_dir)?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.rpush("list".to_owned(), vec!["x".to_owned(), "y".to_owned()])?;
+    store.compact()?;
+    store.set("b".to_owned(), "2".to_owned())?;
+
+    let dest = temp_dir.path().join("snapshot");
+    store.export_snapshot_dir(&dest)?;
+    assert!(store.export_snapshot_dir(&dest).is_err());
+    // the compacted generation is shared rather than copied
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let linked = fs::read_dir(&dest)?
+            .map(|entry| entry.unwrap().metadata().unwrap())
+            .any(|meta| meta.nlink() > 1);
+        assert!(linked);
+    }
+
+    store.set("a".to_owned(), "changed".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+    store.compact()?;
+
+    let mut snapshot = KvStore::open_read_only(&[dest], None)?;
+    assert_eq!(snapshot.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(snapshot.get("c".to_owned())?, None);
+    assert_eq!(snapshot.lrange("list".to_owned(), 0, -1)?, vec!["x", "y"]);
+    Ok(())
+}
+
+// Should tell which generation file an error is about
+#[test]
+fn error_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .unwrap();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(b"{\"Set\":")?;
+    let err = match KvStore::open(temp_dir.path()) {
+        Err(err) => err,
+        Ok(_) => panic!("opened a corrupted log"),
+    };
+    let context = err.context().expect("no context");
+    assert_eq!(context.op, Some("open"));
+    assert_eq!(context.gen, Some(1));
+    assert_eq!(context.path.as_deref(), Some(log.as_path()));
+    assert!(err.to_string().contains(&log.display().to_string()));
+
+    // errors to match on are left as they are
+    assert!(matches!(
+        KvsError::KeyNotFound.with_key("key"),
+        KvsError::KeyNotFound
+    ));
+    Ok(())
+}
+
+// Should scan the string keys of a range in order, with their values, on
+// both engines
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    scan_range_on(&mut store)?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let long = "k".repeat(300);
+    assert_eq!(
+        store
+            .scan("k".to_owned().."l".to_owned())?
+            .collect::<Vec<_>>(),
+        vec![(long, "long".to_owned())]
+    );
+
+    #[cfg(feature = "engine-sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        scan_range_on(&mut SledStore::new(sled::open(temp_dir.path())?))?;
+    }
+    Ok(())
+}
+
+fn scan_range_on<E: KvsEngine>(store: &mut E) -> Result<()> {
+    for key in ["user/3", "user/1", "user/2", "item/1", "users"] {
+        store.set(key.to_owned(), format!("value of {}", key))?;
+    }
+    store.set("k".repeat(300), "long".to_owned())?;
+    store.rpush("user/list".to_owned(), vec!["a".to_owned()])?;
+    store.set("user/4".to_owned(), "gone".to_owned())?;
+    store.expire("user/4".to_owned(), Duration::from_millis(0))?;
+
+    let page: Vec<(String, String)> = store
+        .scan("user/".to_owned().."user0".to_owned())?
+        .collect();
+    assert_eq!(
+        page,
+        vec![
+            ("user/1".to_owned(), "value of user/1".to_owned()),
+            ("user/2".to_owned(), "value of user/2".to_owned()),
+            ("user/3".to_owned(), "value of user/3".to_owned()),
+        ]
+    );
+    let next = "user/2".to_owned();
+    let keys: Vec<String> = store
+        .scan(next.."user0".to_owned())?
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["user/2", "user/3"]);
+    assert_eq!(store.scan("z".to_owned().."a".to_owned())?.count(), 0);
+    Ok(())
+}
+
+// Should list and scan keys in the order the store was created with, kept
+// on later opens
+#[test]
+fn key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = [temp_dir.path().to_owned()];
+    let mut store = KvStore::open_ordered(&dirs, KeyOrder::Natural)?;
+    for key in [
+        "item:10", "item:9", "item:100", "item:09", "item:1b", "other",
+    ] {
+        store.set(key.to_owned(), key.to_owned())?;
+    }
+    assert_eq!(
+        store.keys()?,
+        vec!["item:1b", "item:9", "item:09", "item:10", "item:100", "other"]
+    );
+    let keys: Vec<String> = store
+        .scan("item:2".to_owned().."item:50".to_owned())?
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["item:9", "item:09", "item:10"]);
+    assert_eq!(
+        store
+            .scan("item:50".to_owned().."item:2".to_owned())?
+            .count(),
+        0
+    );
+    drop(store);
+
+    // recorded, and kept
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(ORDER_FILE))?,
+        "natural"
+    );
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.key_order(), KeyOrder::Natural);
+    assert_eq!(
+        store.scan_prefix("item:1")?,
+        vec!["item:1b", "item:10", "item:100"]
+    );
+    drop(store);
+    assert!(KvStore::open_ordered(&dirs, KeyOrder::Bytewise).is_err());
+
+    // an existing store is bytewise
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = [temp_dir.path().to_owned()];
+    KvStore::open(temp_dir.path())?.set("key".to_owned(), "value".to_owned())?;
+    assert!(KvStore::open_ordered(&dirs, KeyOrder::Natural).is_err());
+
+    // a custom order is only opened with itself
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = [temp_dir.path().to_owned()];
+    let reverse = KeyOrder::Custom {
+        name: "reverse",
+        cmp: |a, b| b.cmp(a),
+    };
+    let mut store = KvStore::open_ordered(&dirs, reverse)?;
+    for key in ["a", "b", "c"] {
+        store.set(key.to_owned(), key.to_owned())?;
+    }
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let mut store = KvStore::open_ordered(&dirs, reverse)?;
+    assert_eq!(store.keys()?, vec!["c", "b", "a"]);
+    let keys: Vec<String> = store
+        .scan("c".to_owned().."a".to_owned())?
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys, vec!["c", "b"]);
+    Ok(())
+}
+
+// Should list the keys under a prefix in every keyspace, sorted, on both
+// engines
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_prefix_on(&mut KvStore::open(temp_dir.path())?)?;
+    #[cfg(feature = "engine-sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        scan_prefix_on(&mut SledStore::new(sled::open(temp_dir.path())?))?;
+    }
+    Ok(())
+}
+
+fn scan_prefix_on<E: KvsEngine>(store: &mut E) -> Result<()> {
+    let long = format!("user:1:{}", "x".repeat(300));
+    store.set("user:1:name".to_owned(), "ann".to_owned())?;
+    store.set("user:2:name".to_owned(), "bob".to_owned())?;
+    store.set(long.clone(), "long".to_owned())?;
+    store.rpush("user:1:posts".to_owned(), vec!["a".to_owned()])?;
+    store.hset(
+        "user:1:prefs".to_owned(),
+        "theme".to_owned(),
+        "dark".to_owned(),
+    )?;
+    store.sadd("user:1:tags".to_owned(), vec!["admin".to_owned()])?;
+    store.set("user:1:gone".to_owned(), "x".to_owned())?;
+    store.expire("user:1:gone".to_owned(), Duration::from_millis(0))?;
+    store.set("user:10".to_owned(), "other".to_owned())?;
+
+    assert_eq!(
+        store.scan_prefix("user:1:")?,
+        vec![
+            "user:1:name".to_owned(),
+            "user:1:posts".to_owned(),
+            "user:1:prefs".to_owned(),
+            "user:1:tags".to_owned(),
+            long,
+        ]
+    );
+    assert_eq!(store.scan_prefix("user:3")?, Vec::<String>::new());
+    assert_eq!(store.scan_prefix("")?, store.keys()?);
+    Ok(())
+}
+
+// Should get the values of string keys in the order asked, reading records
+// from several log files
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let long = "k".repeat(300);
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set(long.clone(), "long".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("gone".to_owned(), "x".to_owned())?;
+    store.expire("gone".to_owned(), Duration::from_millis(0))?;
+    store.rpush("list".to_owned(), vec!["item".to_owned()])?;
+
+    assert_eq!(
+        store.get_many(vec![
+            "b".to_owned(),
+            "missing".to_owned(),
+            long,
+            "gone".to_owned(),
+            "list".to_owned(),
+            "a".to_owned(),
+            "b".to_owned(),
+        ])?,
+        vec![
+            Some("2".to_owned()),
+            None,
+            Some("long".to_owned()),
+            None,
+            None,
+            Some("1".to_owned()),
+            Some("2".to_owned()),
+        ]
+    );
+    assert!(store.get_many(Vec::new())?.is_empty());
+    Ok(())
+}
+
+// Should store arbitrary bytes, newlines and invalid UTF-8 included, on
+// both engines, and read the bytes values sled held before as they were
+#[test]
+fn bytes_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    bytes_values_on(&mut KvStore::open(temp_dir.path())?)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    let all: Vec<u8> = (0..=255).collect();
+    assert_eq!(store.get_bytes("all".to_owned())?, Some(all));
+    #[cfg(feature = "engine-sled")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        bytes_values_on(&mut SledStore::new(sled::open(temp_dir.path())?))?;
+
+        // bytes values written to sled before they were encoded
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db = sled::open(temp_dir.path())?;
+        db.insert("old", "héllo\n")?;
+        db.open_tree("value_types")?.insert("old", "bytes")?;
+        let mut store = SledStore::new(db);
+        assert_eq!(
+            store.get_bytes("old".to_owned())?,
+            Some("héllo\n".as_bytes().to_vec())
+        );
+    }
+    Ok(())
+}
+
+fn bytes_values_on<E: KvsEngine>(store: &mut E) -> Result<()> {
+    let all: Vec<u8> = (0..=255).collect();
+    store.set_bytes("all".to_owned(), all.clone())?;
+    assert_eq!(store.get_bytes("all".to_owned())?, Some(all));
+    assert_eq!(
+        store.get_with_type("all".to_owned())?.map(|(_, t)| t),
+        Some(ValueType::Bytes)
+    );
+    store.set("text".to_owned(), "héllo".to_owned())?;
+    assert_eq!(
+        store.get_bytes("text".to_owned())?,
+        Some("héllo".as_bytes().to_vec())
+    );
+    assert_eq!(store.get_bytes("missing".to_owned())?, None);
+    assert!(matches!(
+        store.set_with_type("raw".to_owned(), "not base64!".to_owned(), ValueType::Bytes),
+        Err(KvsError::InvalidValue(_))
+    ));
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Order {
+    id: u64,
+    customer: Customer,
+    lines: Vec<(String, u32)>,
+    note: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Customer {
+    name: String,
+    tags: BTreeMap<String, bool>,
+}
+
+// Should store values of any serializable type as JSON, and refuse to read
+// them as another
+#[test]
+fn typed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let order = Order {
+        id: 7,
+        customer: Customer {
+            name: "ada".to_owned(),
+            tags: BTreeMap::from([("vip".to_owned(), true)]),
+        },
+        lines: vec![("book".to_owned(), 2), ("pen".to_owned(), 10)],
+        note: None,
+    };
+    store.set_typed("order".to_owned(), &order)?;
+    assert_eq!(store.get_typed("order".to_owned())?, Some(order));
+    assert_eq!(
+        store.get_with_type("order".to_owned())?.map(|(_, t)| t),
+        Some(ValueType::Json)
+    );
+    assert_eq!(store.get_typed::<Order>("missing".to_owned())?, None);
+    assert!(matches!(
+        store.get_typed::<Customer>("order".to_owned()),
+        Err(KvsError::Serde(_))
+    ));
+    Ok(())
+}
+
+// Should encode the bytes values of a version 4 directory, which held their
+// bytes as text, chunked ones included, keeping values larger than a chunk
+// once encoded chunked, and go on past a corrupt record
+#[test]
+fn migrate_bytes_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let line = |log: &KvLog| format!("{}\n", serde_json::to_string(log).unwrap());
+    let chunked = |key: &str, pieces: &[&str]| -> String {
+        let chunks: String = pieces
+            .iter()
+            .map(|data| {
+                line(&KvLog::Chunk {
+                    key: key.to_owned(),
+                    data: data.to_string(),
+                })
+            })
+            .collect();
+        let manifest = line(&KvLog::Manifest {
+            key: key.to_owned(),
+            span: chunks.len() as u64,
+            value_type: ValueType::Bytes,
+            expires_at: None,
+            written_at: None,
+        });
+        chunks + &manifest
+    };
+    let piece = "a".repeat(700_000);
+    let mut log = line(&KvLog::Chunk {
+        key: "big".to_owned(),
+        data: "left over".to_owned(),
+    });
+    log += "{\"Set\":{\"key\":\"torn\"\n";
+    log += &line(&KvLog::Set {
+        key: "small".to_owned(),
+        value: "héllo\n".to_owned(),
+        value_type: ValueType::Bytes,
+        expires_at: None,
+        written_at: None,
+    });
+    log += &chunked("big", &["big ", "value"]);
+    log += &chunked("huge", &[&piece, &piece]);
+    log += &line(&KvLog::Set {
+        key: "text".to_owned(),
+        value: "plain".to_owned(),
+        value_type: ValueType::String,
+        expires_at: None,
+        written_at: None,
+    });
+    fs::write(temp_dir.path().join(format::log_file_name(1)), log)?;
+    fs::write(temp_dir.path().join(FORMAT_FILE), "4")?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_bytes("small".to_owned())?,
+        Some("héllo\n".as_bytes().to_vec())
+    );
+    assert_eq!(
+        store.get_bytes("big".to_owned())?,
+        Some(b"big value".to_vec())
+    );
+    assert_eq!(
+        store.get_bytes("huge".to_owned())?,
+        Some(piece.repeat(2).into_bytes())
+    );
+    assert_eq!(store.get("text".to_owned())?, Some("plain".to_owned()));
+    assert!(temp_dir.path().join("backup-v4").is_dir());
+
+    let buf = fs::read(temp_dir.path().join(format::log_file_name(1)))?;
+    let huge: Vec<usize> = buf
+        .split_inclusive(|&b| b == b'\n')
+        .filter_map(|line| match KvLog::decode(line).ok()? {
+            KvLog::Chunk { key, data } if key == "huge" => Some(data.len()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(huge, vec![1024 * 1024, 1_866_668 - 1024 * 1024]);
+    Ok(())
+}
+
+// Should write large values compressed, read them back whole, and compress
+// the values written before on compaction
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_values() -> Result<()> {
+    let blob = format!(
+        "[{}]",
+        vec!["{\"name\":\"kvs\",\"tags\":[1,2,3]}"; 100].join(",")
+    );
+    // the keys of the compressed values of the JSON generations of `dir`
+    let compressed = |dir: &Path| -> Vec<String> {
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some(format::LOG_EXTENSION.as_ref()) {
+                continue;
+            }
+            let buf = fs::read(path).unwrap();
+            keys.extend(
+                format::records(&buf).filter_map(|record| match record.ok()?.1 {
+                    KvLog::CompressedSet { key, .. } => Some(key),
+                    _ => None,
+                }),
+            );
+        }
+        keys.sort();
+        keys
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("old".to_owned(), blob.clone())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?.compress_above(1024);
+    store.set_with_type("doc".to_owned(), blob.clone(), ValueType::Json)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(compressed(temp_dir.path()), vec!["doc"]);
+    assert_eq!(store.get("doc".to_owned())?, Some(blob.clone()));
+    assert_eq!(
+        store.get_with_type("doc".to_owned())?,
+        Some((blob.clone(), ValueType::Json))
+    );
+    assert_eq!(
+        store.get_raw("doc".to_owned())?.unwrap().get(),
+        serde_json::to_string(&blob)?
+    );
+    let mut out = Vec::new();
+    assert!(store.get_to_writer("doc".to_owned(), &mut out)?);
+    assert_eq!(out, blob.as_bytes());
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    // compaction compresses the value written before, binary records too
+    let mut store = store.log_encoding(LogEncoding::Binary);
+    store.compact()?;
+    assert_eq!(store.get("old".to_owned())?, Some(blob.clone()));
+    drop(store);
+
+    // a store not compressing still reads them, and fsck checks them
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("old".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get("doc".to_owned())?, Some(blob.clone()));
+    let mut store = store.log_encoding(LogEncoding::Json);
+    store.compact()?;
+    assert_eq!(compressed(temp_dir.path()), vec!["doc", "old"]);
+    drop(store);
+    let report = fsck::check(temp_dir.path(), false)?;
+    assert!(report.issues.is_empty());
+    Ok(())
+}
+
+// Should write the compacted generation as a compressed segment, read
+// back from its hints or replayed, in either encoding
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_segments() -> Result<()> {
+    for encoding in [LogEncoding::Json, LogEncoding::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?
+            .log_encoding(encoding)
+            .compress_segments(true);
+        for i in 0..2000 {
+            store.set(format!("key{:04}", i), format!("value of key {}", i))?;
+        }
+        store.rpush("list".to_owned(), vec!["a".to_owned(), "b".to_owned()])?;
+        store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+        let log_files = || {
+            fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension() == Some(format::LOG_EXTENSION.as_ref()))
+                .collect::<Vec<_>>()
+        };
+        let plain: u64 = log_files()
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum();
+        store.compact()?;
+
+        let segment = log_files()
+            .into_iter()
+            .find(|path| fs::read(path).unwrap().starts_with(&format::SEGMENT_MAGIC))
+            .expect("no compressed segment");
+        assert!(fs::metadata(&segment)?.len() * 3 < plain);
+        assert_eq!(
+            store.get("key1234".to_owned())?,
+            Some("value of key 1234".to_owned())
+        );
+        let mut out = Vec::new();
+        assert!(store.get_to_writer("key0007".to_owned(), &mut out)?);
+        assert_eq!(out, b"value of key 7");
+        assert_eq!(store.scan_prefix("key1")?.len(), 1000);
+        drop(store);
+        let report = fsck::check(temp_dir.path(), false)?;
+        assert_eq!(report.records, 2003);
+        assert!(report.issues.is_empty());
+
+        // loaded from the hints, then replayed without them
+        for hinted in [true, false] {
+            if !hinted {
+                fs::remove_file(segment.with_extension(format::HINT_EXTENSION))?;
+            }
+            let mut store = KvStore::open(temp_dir.path())?;
+            assert_eq!(
+                store.get("key1999".to_owned())?,
+                Some("value of key 1999".to_owned())
+            );
+            assert_eq!(store.lrange("list".to_owned(), 0, -1)?, vec!["a", "b"]);
+            assert_eq!(
+                store.hget("hash".to_owned(), "field".to_owned())?,
+                Some("value".to_owned())
+            );
+            assert_eq!(store.keys()?.len(), 2002);
+        }
+    }
+    Ok(())
+}
+
+// Records are sealed on disk, keys included, and only the right key opens
+// the store again
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_at_rest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [7; 32];
+    let on_disk = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .flat_map(|entry| fs::read(entry.path()).unwrap())
+            .collect::<Vec<u8>>()
+    };
+    let leaks = |needle: &str| {
+        on_disk()
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    };
+
+    // written in the clear before, encrypted by compaction
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("plainkey".to_owned(), "plainvalue".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_encrypted(
+        &[temp_dir.path().to_owned()],
+        kvs::EncryptionConfig::key(key),
+    )?
+    .log_encoding(LogEncoding::Json);
+    assert_eq!(
+        store.get("plainkey".to_owned())?,
+        Some("plainvalue".to_owned())
+    );
+    store.set("secretkey".to_owned(), "secretvalue".to_owned())?;
+    store.hset("hash".to_owned(), "field".to_owned(), "hidden".to_owned())?;
+    assert!(!leaks("secretkey") && !leaks("secretvalue") && !leaks("hidden"));
+    assert!(leaks("plainvalue"));
+    store.compact()?;
+    assert!(!leaks("plainkey") && !leaks("plainvalue"));
+    assert_eq!(
+        store.get("secretkey".to_owned())?,
+        Some("secretvalue".to_owned())
+    );
+    drop(store);
+    let report = fsck::check(temp_dir.path(), false)?;
+    assert!(report.issues.is_empty());
+    assert!(report.records >= 3);
+
+    // neither without the key nor with another one
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    assert!(KvStore::open_encrypted(
+        &[temp_dir.path().to_owned()],
+        kvs::EncryptionConfig::key([8; 32]),
+    )
+    .is_err());
+    assert!(KvStore::open_encrypted(
+        &[temp_dir.path().to_owned()],
+        kvs::EncryptionConfig::provider(|| Err(KvsError::Other("no key".to_owned()))),
+    )
+    .is_err());
+
+    let mut store = KvStore::open_encrypted(
+        &[temp_dir.path().to_owned()],
+        kvs::EncryptionConfig::provider(move || Ok(key)),
+    )?;
+    assert_eq!(
+        store.get("plainkey".to_owned())?,
+        Some("plainvalue".to_owned())
+    );
+    assert_eq!(
+        store.hget("hash".to_owned(), "field".to_owned())?,
+        Some("hidden".to_owned())
+    );
+    assert_eq!(store.keys()?.len(), 3);
+    Ok(())
+}
+
+// Sealed records are bound to where they were written: swapped on disk,
+// they fail to open and are quarantined rather than read in each other's
+// place
+#[cfg(feature = "encryption")]
+#[test]
+fn sealed_records_stay_in_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = [temp_dir.path().to_owned()];
+    let key = [7; 32];
+    let mut store = KvStore::open_encrypted(&dirs, kvs::EncryptionConfig::key(key))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // both records are as long, a nonce and a sealed payload each
+    let path = temp_dir.path().join(format::log_file_name(1));
+    let buf = fs::read(&path)?;
+    let (magic, records) = buf.split_at(format::ENCRYPTED_MAGIC.len());
+    assert_eq!(magic, format::ENCRYPTED_MAGIC);
+    assert_eq!(records.len() % 2, 0);
+    let (first, second) = records.split_at(records.len() / 2);
+    fs::write(&path, [magic, second, first].concat())?;
+
+    let mut store = KvStore::open_encrypted(&dirs, kvs::EncryptionConfig::key(key))?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.metrics()?["quarantined_records"], 2);
+    Ok(())
+}
+
+// Turning encryption on for a directory holding data leaves no value in
+// the clear under it once compacted: the backups of a migration are
+// removed and the quarantine file sealed
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_leaves_no_plaintext() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = [temp_dir.path().to_owned()];
+    let key = [7; 32];
+    let leaks = |needle: &str| {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .any(|entry| {
+                fs::read(entry.path())
+                    .unwrap()
+                    .windows(needle.len())
+                    .any(|window| window == needle.as_bytes())
+            })
+    };
+
+    // a version 4 directory, with a record to quarantine, migrated in the
+    // clear
+    let set = KvLog::Set {
+        key: "plainkey".to_owned(),
+        value: "plainvalue".to_owned(),
+        value_type: ValueType::String,
+        expires_at: None,
+        written_at: None,
+    };
+    let log = format!(
+        "{}\n{{\"Set\":{{\"key\":\"torn\",\"value\":\"tornvalue\"\n",
+        serde_json::to_string(&set)?
+    );
+    fs::write(temp_dir.path().join(format::log_file_name(1)), log)?;
+    fs::write(temp_dir.path().join(FORMAT_FILE), "4")?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics()?["quarantined_records"], 1);
+    drop(store);
+    assert!(temp_dir.path().join("backup-v4").is_dir());
+    assert!(leaks("plainvalue") && leaks("tornvalue"));
+
+    let mut store = KvStore::open_encrypted(&dirs, kvs::EncryptionConfig::key(key))?;
+    assert!(!temp_dir.path().join("backup-v4").exists());
+    let quarantine = fs::read(temp_dir.path().join(QUARANTINE_FILE))?;
+    assert!(!quarantine.windows(9).any(|window| window == b"tornvalue"));
+    store.set("secretkey".to_owned(), "secretvalue".to_owned())?;
+    store.compact()?;
+    assert!(!leaks("plainvalue") && !leaks("secretvalue"));
+    assert_eq!(
+        store.get("plainkey".to_owned())?,
+        Some("plainvalue".to_owned())
+    );
+    drop(store);
+
+    // found again replaying the encrypted open, but quarantined once
+    let quarantine = fs::read_to_string(temp_dir.path().join(QUARANTINE_FILE))?;
+    let letters: Vec<serde_json::Value> = quarantine
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<_>>()?;
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0]["sealed"], true);
+    assert!(!leaks("plainvalue") && !leaks("tornvalue"));
+    Ok(())
+}
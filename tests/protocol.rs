@@ -0,0 +1,128 @@
+use kvs::protocol::testvectors;
+use kvs::{KvStore, KvsClient, KvsServer};
+use serde_json::{Deserializer, Value};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &'static str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+    temp_dir
+}
+
+// Send raw request bytes and return the raw response.
+fn roundtrip(conn: &mut TcpStream, req: &str) -> String {
+    conn.write_all(req.as_bytes()).unwrap();
+    let reader = BufReader::new(conn.try_clone().unwrap());
+    let mut resp = Deserializer::from_reader(reader).into_iter::<Value>();
+    resp.next().unwrap().unwrap().to_string()
+}
+
+// Requests exactly as the previous release encoded them.
+#[test]
+fn server_accepts_previous_release_requests() {
+    let _dir = start_server("127.0.0.1:4011");
+    let mut conn = TcpStream::connect("127.0.0.1:4011").unwrap();
+
+    assert_eq!(
+        roundtrip(&mut conn, r#"{"Set":{"key":"key1","value":"value1"}}"#),
+        r#"{"Ok":null}"#
+    );
+    assert_eq!(
+        roundtrip(&mut conn, r#"{"Get":{"key":"key1"}}"#),
+        r#"{"Ok":"value1"}"#
+    );
+    assert_eq!(
+        roundtrip(&mut conn, r#"{"Get":{"key":"key2"}}"#),
+        r#"{"Ok":null}"#
+    );
+    assert_eq!(
+        roundtrip(&mut conn, r#"{"Remove":{"key":"key1"}}"#),
+        r#"{"Ok":null}"#
+    );
+    assert_eq!(
+        roundtrip(&mut conn, r#"{"Remove":{"key":"key1"}}"#),
+        r#"{"Err":"Key not found"}"#
+    );
+}
+
+// Requests from a newer client: extra fields are ignored and unknown
+// commands are rejected without dropping the connection.
+#[test]
+fn server_tolerates_newer_requests() {
+    let _dir = start_server("127.0.0.1:4012");
+    let mut conn = TcpStream::connect("127.0.0.1:4012").unwrap();
+
+    assert_eq!(
+        roundtrip(
+            &mut conn,
+            r#"{"Set":{"key":"key1","value":"value1","ttl":60}}"#
+        ),
+        r#"{"Ok":null}"#
+    );
+    let resp = roundtrip(&mut conn, r#"{"Frobnicate":{"key":"key1"}}"#);
+    assert!(resp.starts_with(r#"{"Err":"Unsupported request"#));
+    assert_eq!(
+        roundtrip(&mut conn, r#"{"Get":{"key":"key1"}}"#),
+        r#"{"Ok":"value1"}"#
+    );
+}
+
+// Responses from another server version: unknown fields are ignored and
+// missing ones fall back to their defaults.
+#[test]
+fn client_tolerates_other_server_versions() {
+    let addr = "127.0.0.1:4013";
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let reader = BufReader::new(conn.try_clone().unwrap());
+        let responses = [
+            r#"{"Ok":"value1"}"#,
+            r#"{"Ok":{"connections":3,"requests":7,"cpu_usage":0.5}}"#,
+        ];
+        let requests = Deserializer::from_reader(reader).into_iter::<Value>();
+        for (_, resp) in requests.zip(responses) {
+            conn.write_all(resp.as_bytes()).unwrap();
+        }
+    });
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.connections, 3);
+    assert_eq!(stats.requests, 7);
+    assert_eq!(stats.yields, 0);
+}
+
+// The published test vectors are exactly what the server answers.
+#[test]
+fn server_matches_test_vectors() {
+    let _dir = start_server("127.0.0.1:4037");
+    testvectors::replay("127.0.0.1:4037").unwrap();
+
+    for vector in testvectors::VECTORS {
+        testvectors::check_request(vector, vector.request.as_bytes()).unwrap();
+        testvectors::check_response(vector, vector.response.as_bytes()).unwrap();
+    }
+}
+
+// Another client may order fields and space them freely, not rename them.
+#[test]
+fn test_vectors_check_requests() {
+    let set = &testvectors::VECTORS[0];
+    testvectors::check_request(set, br#"{ "Set": { "value": "value1", "key": "key1" } }"#).unwrap();
+    assert!(testvectors::check_request(set, br#"{"Set":{"key":"key1","val":"value1"}}"#).is_err());
+    assert!(
+        testvectors::check_request(set, br#"{"Set":{"key":"key2","value":"value1"}}"#).is_err()
+    );
+    assert!(testvectors::check_response(set, br#"{"Err":"Key not found"}"#).is_err());
+}
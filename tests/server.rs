@@ -0,0 +1,1461 @@
+use kvs::trace::{self, CachePoint, TraceEvent};
+use kvs::{
+    diff, ClientMetrics, Health, KeyOrder, KvStore, KvsClient, KvsEngine, KvsError, KvsServer,
+    Lease, ValueFilter, ValueType, MAX_KEY_LEN,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+fn start_server(addr: &'static str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).budget(4).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+    temp_dir
+}
+
+// A client pipelining lots of requests must not keep others waiting until
+// all of its requests are served.
+#[test]
+fn pipelined_client_does_not_starve_others() {
+    let addr = "127.0.0.1:4010";
+    let _dir = start_server(addr);
+
+    let mut flood = TcpStream::connect(addr).unwrap();
+    let mut batch = String::new();
+    for i in 0..2000 {
+        batch.push_str(&format!(
+            r#"{{"Set":{{"key":"key{}","value":"value"}}}}"#,
+            i
+        ));
+    }
+    flood.write_all(batch.as_bytes()).unwrap();
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        client.get("other".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    // drain the responses of the flooding client
+    let mut reader = BufReader::new(flood);
+    let mut received = 0;
+    while received < 2000 {
+        let buf = reader.fill_buf().unwrap();
+        received += buf.iter().filter(|&&b| b == b'}').count();
+        let len = buf.len();
+        reader.consume(len);
+    }
+
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.requests, 2003);
+    assert_eq!(stats.connections, 2);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.engine["keys"], 2001);
+}
+
+#[test]
+fn clients_reports_per_ip_counters() {
+    let addr = "127.0.0.1:4014";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    client.get("key2".to_owned()).unwrap();
+
+    let clients = client.clients().unwrap();
+    assert_eq!(clients.len(), 1);
+    let stats = &clients[0];
+    assert_eq!(stats.ip, "127.0.0.1");
+    assert_eq!(stats.connections, 1);
+    assert_eq!(stats.requests, 4);
+    assert_eq!(stats.ops["Get"], 2);
+    assert_eq!(stats.ops["Set"], 1);
+    assert_eq!(stats.ops["Clients"], 1);
+    assert!(stats.bytes_in > 0);
+    assert!(stats.bytes_out > 0);
+}
+
+#[test]
+fn read_only_rejects_writes() {
+    let addr = "127.0.0.1:4015";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::spawn(move || KvsServer::new(store).read_only(true).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(matches!(
+        client.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    // compaction would rewrite the directory under a backup
+    let files = || {
+        let mut files: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files();
+    assert!(matches!(client.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(files(), before);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(client.stats().unwrap().read_only);
+
+    client.set_read_only(false).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert!(matches!(
+        client.remove("key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(!client.stats().unwrap().read_only);
+}
+
+#[test]
+fn hot_keys_finds_skewed_keys() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).hot_keys(8).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..100 {
+        client.get("hot".to_owned()).unwrap();
+        client.get(format!("cold{}", i)).unwrap();
+        if i % 2 == 0 {
+            client.get("warm".to_owned()).unwrap();
+        }
+    }
+
+    let top = client.hot_keys(2).unwrap();
+    assert_eq!(top.len(), 2);
+    assert_eq!(top[0].key, "hot");
+    assert!(top[0].count >= 100);
+    assert_eq!(top[1].key, "warm");
+}
+
+#[test]
+fn hot_keys_disabled_by_default() {
+    let addr = "127.0.0.1:4017";
+    let _dir = start_server(addr);
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.hot_keys(10).is_err());
+}
+
+#[test]
+fn list_commands() {
+    let addr = "127.0.0.1:4018";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client
+            .rpush("queue".to_owned(), vec!["a".to_owned(), "b".to_owned()])
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        client
+            .lpush("queue".to_owned(), vec!["z".to_owned()])
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        client.lrange("queue".to_owned(), 0, -1).unwrap(),
+        vec!["z", "a", "b"]
+    );
+    assert_eq!(
+        client.lpop("queue".to_owned()).unwrap(),
+        Some("z".to_owned())
+    );
+    assert_eq!(
+        client.lrange("queue".to_owned(), -1, -1).unwrap(),
+        vec!["b"]
+    );
+    assert_eq!(client.get("queue".to_owned()).unwrap(), None);
+
+    client.set_read_only(true).unwrap();
+    assert!(matches!(
+        client.lpop("queue".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(
+        client.lrange("queue".to_owned(), 0, -1).unwrap(),
+        vec!["a", "b"]
+    );
+}
+
+#[test]
+fn hash_commands() {
+    let addr = "127.0.0.1:4019";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .hset("user".to_owned(), "name".to_owned(), "alice".to_owned())
+        .unwrap();
+    client
+        .hset("user".to_owned(), "city".to_owned(), "paris".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.hget("user".to_owned(), "name".to_owned()).unwrap(),
+        Some("alice".to_owned())
+    );
+    client.hdel("user".to_owned(), "name".to_owned()).unwrap();
+    assert!(matches!(
+        client.hdel("user".to_owned(), "name".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    let fields = client.hgetall("user".to_owned()).unwrap();
+    assert_eq!(fields.len(), 1);
+    assert_eq!(fields["city"], "paris");
+}
+
+#[test]
+fn set_commands() {
+    let addr = "127.0.0.1:4020";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let members = vec!["a".to_owned(), "b".to_owned(), "a".to_owned()];
+    assert_eq!(client.sadd("tags".to_owned(), members).unwrap(), 2);
+    assert!(client.sismember("tags".to_owned(), "a".to_owned()).unwrap());
+    assert_eq!(
+        client
+            .srem("tags".to_owned(), vec!["a".to_owned(), "z".to_owned()])
+            .unwrap(),
+        1
+    );
+    assert!(!client.sismember("tags".to_owned(), "a".to_owned()).unwrap());
+    let members = client.smembers("tags".to_owned()).unwrap();
+    assert_eq!(members.into_iter().collect::<Vec<_>>(), vec!["b"]);
+}
+
+#[test]
+fn rate_is_shared_between_clients() {
+    let addr = "127.0.0.1:4021";
+    let _dir = start_server(addr);
+
+    let mut first = KvsClient::connect(addr).unwrap();
+    let mut second = KvsClient::connect(addr).unwrap();
+    let window = Duration::from_secs(60);
+    assert!(first.rate("api".to_owned(), window, 2).unwrap().allowed);
+    assert!(second.rate("api".to_owned(), window, 2).unwrap().allowed);
+    let rate = first.rate("api".to_owned(), window, 2).unwrap();
+    assert!(!rate.allowed);
+    assert_eq!(rate.count, 3);
+    assert!(rate.reset_ms > 50_000);
+
+    // a window out of bounds is refused, leaving the server up
+    let forever = Duration::from_millis(u64::MAX);
+    assert!(first.rate("api".to_owned(), forever, 2).is_err());
+    assert_eq!(second.rate("api".to_owned(), window, 2).unwrap().count, 4);
+}
+
+#[test]
+fn copy_to_clones_keyspace_and_resumes() {
+    let (from, to) = ("127.0.0.1:4027", "127.0.0.1:4028");
+    let _from_dir = start_server(from);
+    let _to_dir = start_server(to);
+
+    let mut source = KvsClient::connect(from).unwrap();
+    for i in 0..10 {
+        source
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    source
+        .rpush("list".to_owned(), vec!["a".to_owned(), "b".to_owned()])
+        .unwrap();
+    source
+        .hset("hash".to_owned(), "field".to_owned(), "value".to_owned())
+        .unwrap();
+
+    // resuming after "key4" only copies the keys sorting after it
+    let mut target = KvsClient::connect(to).unwrap();
+    let mut checkpoints = Vec::new();
+    let copied = source
+        .copy_to(&mut target, Some("key4".to_owned()), 3, |last| {
+            checkpoints.push(last.to_owned());
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(copied, 6);
+    assert_eq!(checkpoints, vec!["key7", "list"]);
+    assert_eq!(target.get("key3".to_owned()).unwrap(), None);
+
+    let copied = source.copy_to(&mut target, None, 100, |_| Ok(())).unwrap();
+    assert_eq!(copied, 12);
+    for i in 0..10 {
+        assert_eq!(
+            target.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(
+        target.lrange("list".to_owned(), 0, -1).unwrap(),
+        vec!["a".to_owned(), "b".to_owned()]
+    );
+    assert_eq!(
+        target.hget("hash".to_owned(), "field".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn stale_fencing_token_rejects_writes() {
+    let addr = "127.0.0.1:4033";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let fence_file = temp_dir.path().join("fence");
+    let path = fence_file.clone();
+    thread::spawn(move || KvsServer::new(store).fence_file(path).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut old_primary = KvsClient::connect(addr).unwrap();
+    let token = old_primary.fence().unwrap();
+    assert_eq!(token, 1);
+    old_primary.use_fence(token).unwrap();
+    old_primary.set("key".to_owned(), "old".to_owned()).unwrap();
+
+    // failover: the new primary gets a newer token
+    let mut new_primary = KvsClient::connect(addr).unwrap();
+    let token = new_primary.fence().unwrap();
+    assert_eq!(token, 2);
+    new_primary.use_fence(token).unwrap();
+    new_primary.set("key".to_owned(), "new".to_owned()).unwrap();
+
+    match old_primary.set("key".to_owned(), "stale".to_owned()) {
+        Err(KvsError::Fenced { token, current }) => {
+            assert_eq!((token, current), (1, 2));
+        }
+        other => panic!("expected a fencing error, got {:?}", other),
+    }
+    // reads and unfenced clients are not affected
+    assert_eq!(
+        old_primary.get("key".to_owned()).unwrap(),
+        Some("new".to_owned())
+    );
+    let mut other = KvsClient::connect(addr).unwrap();
+    other.set("other".to_owned(), "value".to_owned()).unwrap();
+    assert!(other.use_fence(3).is_err());
+
+    // the token survives restarts through the fence file
+    assert_eq!(std::fs::read_to_string(&fence_file).unwrap(), "2");
+    let addr = "127.0.0.1:4034";
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(other_dir.path()).unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .fence_file(fence_file)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(KvsClient::connect(addr).unwrap().fence().unwrap(), 3);
+}
+
+#[test]
+fn large_values_round_trip() {
+    let addr = "127.0.0.1:4035";
+    let _dir = start_server(addr);
+
+    let value = "a \"quoted\"\n✓ value ".repeat(200_000);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("big".to_owned(), value.clone()).unwrap();
+    assert_eq!(client.get("big".to_owned()).unwrap(), Some(value));
+    assert_eq!(client.get("missing".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn upgrade_hands_listener_over_and_drains() {
+    let addr = "127.0.0.1:4039";
+    let old_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(old_dir.path()).unwrap();
+    let (tx, rx) = mpsc::channel();
+    let old = thread::spawn(move || {
+        KvsServer::new(store)
+            .upgrade(move |listener: &TcpListener| {
+                tx.send(listener.try_clone()?).unwrap();
+                Ok(())
+            })
+            .run(addr)
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "old".to_owned()).unwrap();
+    KvsClient::connect(addr).unwrap().upgrade().unwrap();
+    let listener = rx.recv().unwrap();
+    let new_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(new_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run_on(listener).unwrap());
+
+    // established connections are still served by the old server
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("old".to_owned())
+    );
+    drop(client);
+    old.join().unwrap().unwrap();
+
+    // the accept thread of the old server lives on in this process, and
+    // may take one more connection before giving up the listener
+    let found = (0..2)
+        .find_map(|_| KvsClient::connect(addr).ok()?.get("key".to_owned()).ok())
+        .unwrap();
+    assert_eq!(found, None);
+}
+
+#[test]
+fn scan_matching_filters_keys_with_glob() {
+    let addr = "127.0.0.1:4040";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for key in [
+        "user:1:settings",
+        "user:2:settings",
+        "user:2:profile",
+        "user:10:settings",
+        "team:1:settings",
+        "star*",
+    ] {
+        client.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+
+    let scan = |client: &mut KvsClient, pattern: &str| {
+        client.scan_matching(pattern.to_owned(), None, 100).unwrap()
+    };
+    assert_eq!(
+        scan(&mut client, "user:*:settings"),
+        ["user:10:settings", "user:1:settings", "user:2:settings"]
+    );
+    assert_eq!(
+        scan(&mut client, "user:?:settings"),
+        ["user:1:settings", "user:2:settings"]
+    );
+    assert_eq!(scan(&mut client, "[!u]*:[0-9]:*"), ["team:1:settings"]);
+    assert_eq!(scan(&mut client, "star\\*"), ["star*"]);
+    assert!(scan(&mut client, "nothing*").is_empty());
+
+    // pages are counted in matching keys
+    assert_eq!(
+        client
+            .scan_matching(
+                "*settings".to_owned(),
+                Some("team:1:settings".to_owned()),
+                2
+            )
+            .unwrap(),
+        ["user:10:settings", "user:1:settings"]
+    );
+    assert!(client.scan_matching("user[".to_owned(), None, 10).is_err());
+}
+
+#[test]
+fn scan_values_filters_on_the_server() {
+    let addr = "127.0.0.1:4041";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for (key, value) in [
+        ("user:1", "alice@example.com"),
+        ("user:2", "bob@example.org"),
+        ("user:3", "carol@example.com"),
+        ("team:1", "ops@example.com"),
+    ] {
+        client.set(key.to_owned(), value.to_owned()).unwrap();
+    }
+    client
+        .lpush("list".to_owned(), vec!["example.com".to_owned()])
+        .unwrap();
+
+    let contains = ValueFilter::Contains("example.com".to_owned());
+    assert_eq!(
+        client
+            .scan_values(None, contains.clone(), None, 10)
+            .unwrap(),
+        ["team:1", "user:1", "user:3"]
+    );
+    assert_eq!(
+        client
+            .scan_values(Some("user:*".to_owned()), contains, None, 1)
+            .unwrap(),
+        ["user:1"]
+    );
+    let regex = ValueFilter::Regex(r"^[a-c]\w+@example\.(com|org)$".to_owned());
+    assert_eq!(
+        client
+            .scan_values(None, regex, Some("user:1".to_owned()), 10)
+            .unwrap(),
+        ["user:2", "user:3"]
+    );
+
+    let invalid = ValueFilter::Regex("(unclosed".to_owned());
+    assert!(client.scan_values(None, invalid, None, 10).is_err());
+    let huge = ValueFilter::Regex(r"\w{1000}{1000}".to_owned());
+    assert!(client.scan_values(None, huge, None, 10).is_err());
+}
+
+#[test]
+fn scan_values_stops_at_the_scan_budget() {
+    let addr = "127.0.0.1:4042";
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path()).unwrap();
+    for i in 0..100 {
+        store
+            .set(format!("key{:03}", i), "value".to_owned())
+            .unwrap();
+    }
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .scan_budget(Duration::ZERO)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let all = ValueFilter::Contains("value".to_owned());
+    assert!(client.scan_values(None, all, None, 10).is_err());
+    // the budget applies to value filters only
+    assert_eq!(client.scan(None, 10).unwrap().len(), 10);
+}
+
+// Should open a span per connection, holding the spans of its requests, down
+// to the engine call and the file IO
+#[cfg(feature = "tracing")]
+#[test]
+fn requests_are_traced_down_to_disk() {
+    use std::sync::{Arc, Mutex};
+    use tracing::span::{Attributes, Id};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    // every span opened, as its name and its parent's
+    type Opened = Vec<(String, Option<String>)>;
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<Opened>>);
+
+    impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Spans {
+        fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let parent = span.parent().map(|parent| parent.name().to_owned());
+            self.0
+                .lock()
+                .unwrap()
+                .push((span.name().to_owned(), parent));
+        }
+    }
+
+    let spans = Spans::default();
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(spans.clone()))
+        .unwrap();
+
+    let addr = "127.0.0.1:4043";
+    let _dir = start_server(addr);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    client.get("key".to_owned()).unwrap();
+
+    let spans = spans.0.lock().unwrap();
+    let has = |name: &str, parent: &str| {
+        spans
+            .iter()
+            .any(|(n, p)| n == name && p.as_deref() == Some(parent))
+    };
+    assert!(has("request", "connection"));
+    assert!(has("engine", "request"));
+    assert!(has("disk.append", "engine"));
+    assert!(has("disk.read", "engine"));
+}
+
+// Should fail a request instead of hanging on a server that never answers
+#[test]
+fn builder_read_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:4044").unwrap();
+    let mut client = KvsClient::builder()
+        .connect_timeout(Duration::from_secs(1))
+        .read_timeout(Duration::from_millis(200))
+        .keepalive(Duration::from_secs(30))
+        .nodelay(true)
+        .buffer_size(64)
+        .connect("127.0.0.1:4044")
+        .unwrap();
+    let (_conn, _) = listener.accept().unwrap();
+
+    let start = std::time::Instant::now();
+    assert!(matches!(
+        client.get("key".to_owned()),
+        Err(KvsError::Io(_)) | Err(KvsError::Serde(_))
+    ));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+// Should surface an unresolvable address as its own error, and move on
+// from an address dropping packets to the next one
+#[test]
+fn connect_resolution_and_fallback() {
+    assert!(matches!(
+        KvsClient::connect("no-such-host.invalid:4063"),
+        Err(KvsError::Resolve(_))
+    ));
+
+    let _listener = TcpListener::bind("127.0.0.1:4063").unwrap();
+    let addrs = [
+        "10.255.255.1:4063".parse().unwrap(),
+        "127.0.0.1:4063".parse().unwrap(),
+    ];
+    let start = std::time::Instant::now();
+    KvsClient::builder()
+        .connect_timeout(Duration::from_secs(30))
+        .attempt_delay(Duration::from_millis(100))
+        .connect(&addrs[..])
+        .unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+// Should append the server version, the command and the key to the
+// errors, still recovered as typed errors by the client
+#[test]
+fn error_context() {
+    let addr = "127.0.0.1:4064";
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).error_context(true).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut raw = TcpStream::connect(addr).unwrap();
+    raw.write_all(br#"{"Remove":{"key":"user:42\n"}}"#).unwrap();
+    let resp: serde_json::Value = serde_json::Deserializer::from_reader(raw)
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        resp["Err"],
+        format!(
+            r#"Key not found (kvs {}, Remove key "user:42\n")"#,
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(matches!(
+        client.remove("user:42".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    match client.get_field("name".to_owned(), "no-slash".to_owned()) {
+        Err(KvsError::Other(message)) => {
+            assert!(message.ends_with(r#", GetField key "name")"#))
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+// Should report an increasing LSN with every write, and no metadata to a
+// plain set
+#[test]
+fn set_with_meta() {
+    let addr = "127.0.0.1:4045";
+    let _dir = start_server(addr);
+    let mut client = KvsClient::connect(addr).unwrap();
+
+    let first = client
+        .set_with_meta("key".to_owned(), "value".to_owned(), ValueType::String)
+        .unwrap()
+        .unwrap();
+    let second = client
+        .set_with_meta("key".to_owned(), "other".to_owned(), ValueType::String)
+        .unwrap()
+        .unwrap();
+    assert!(second.lsn.unwrap() > first.lsn.unwrap());
+    assert_eq!(second.node, None);
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    // an older server answers with no metadata
+    let listener = TcpListener::bind("127.0.0.1:4046").unwrap();
+    thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.write_all(br#"{"Ok":null}"#).unwrap();
+        // keep the connection open until the answer is read
+        thread::sleep(Duration::from_secs(1));
+    });
+    let mut old = KvsClient::connect("127.0.0.1:4046").unwrap();
+    let meta = old
+        .set_with_meta("key".to_owned(), "value".to_owned(), ValueType::String)
+        .unwrap();
+    assert_eq!(meta, None);
+}
+
+// Should upload and download values in pieces, chunked in the store, with
+// multi-byte chars cut across pieces
+#[test]
+fn streamed_values() {
+    let addr = "127.0.0.1:4047";
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap().chunk_size(1000);
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+    let mut client = KvsClient::connect(addr).unwrap();
+
+    let value = "abc é ✓ 😀\n".repeat(20_000);
+    client
+        .set_reader("big".to_owned(), value.as_bytes())
+        .unwrap();
+    let mut out = Vec::new();
+    assert!(client.get_writer("big".to_owned(), &mut out).unwrap());
+    assert_eq!(String::from_utf8(out).unwrap(), value);
+    assert_eq!(client.get("big".to_owned()).unwrap(), Some(value));
+
+    let mut out = Vec::new();
+    assert!(!client.get_writer("missing".to_owned(), &mut out).unwrap());
+    assert!(out.is_empty());
+
+    let invalid: &[u8] = b"abc\xff";
+    assert!(matches!(
+        client.set_reader("bad".to_owned(), invalid),
+        Err(KvsError::InvalidValue(_))
+    ));
+    assert_eq!(client.get("bad".to_owned()).unwrap(), None);
+}
+
+// Should refuse writes, scheduled ones included, but serve reads, below
+// the free space threshold
+#[test]
+fn writes_refused_on_full_disk() {
+    let addr = "127.0.0.1:4048";
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    store
+        .set_at("due".to_owned(), "value".to_owned(), now.as_millis() as u64)
+        .unwrap();
+    let path = dir.path().to_owned();
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .min_free_space(path, u64::MAX)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(matches!(
+        client.set("key".to_owned(), "other".to_owned()),
+        Err(KvsError::DiskFull {
+            threshold: u64::MAX,
+            ..
+        })
+    ));
+    assert!(matches!(client.compact(), Err(KvsError::DiskFull { .. })));
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    // several sweeps later
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.get("due".to_owned()).unwrap(), None);
+}
+
+// Should hand out increasing IDs from 1, writing the store once per block
+#[test]
+fn next_id_leases_blocks() {
+    let addr = "127.0.0.1:4049";
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).id_block(10).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+    let mut client = KvsClient::connect(addr).unwrap();
+
+    let ids: Vec<u64> = (0..12)
+        .map(|_| client.next_id("orders".to_owned()).unwrap())
+        .collect();
+    assert_eq!(ids, (1..=12).collect::<Vec<u64>>());
+    assert_eq!(client.next_id("users".to_owned()).unwrap(), 1);
+    assert_eq!(
+        client.get("orders".to_owned()).unwrap(),
+        Some("20".to_owned())
+    );
+}
+
+// A server sharing its directory should serve reads while another holds
+// the lease, then take the lease over once it expires
+#[test]
+fn lease_failover() {
+    let addr = "127.0.0.1:4050";
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().to_owned();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    std::fs::write(
+        path.join("leader"),
+        format!(
+            r#"{{"holder":"127.0.0.1:4999","epoch":7,"expires_at":{}}}"#,
+            now.as_millis() + 1500
+        ),
+    )
+    .unwrap();
+    let mut leader = KvStore::open(&path).unwrap();
+    leader.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    let dirs = vec![path.clone()];
+    let follower = KvStore::open_read_only(&dirs, None).unwrap();
+    let lease = Lease::new(path.join("leader"), addr, Duration::from_millis(600));
+    thread::spawn(move || {
+        KvsServer::new(follower)
+            .lease(lease, move |writable| match writable {
+                true => KvStore::open(&path),
+                false => KvStore::open_read_only(&dirs, None),
+            })
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(matches!(
+        client.set("key".to_owned(), "mine".to_owned()),
+        Err(KvsError::NotLeader { leader: Some(leader) }) if leader == "127.0.0.1:4999"
+    ));
+    leader.set("key".to_owned(), "newer".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("newer".to_owned())
+    );
+    assert_eq!(client.stats().unwrap().epoch, None);
+
+    drop(leader);
+    thread::sleep(Duration::from_millis(1700));
+    client.set("key".to_owned(), "mine".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("mine".to_owned())
+    );
+    assert_eq!(client.stats().unwrap().epoch, Some(8));
+}
+
+// Should report the keys missing from either side and those holding
+// different data, comparing a server with a data directory
+#[test]
+fn diff_server_with_store() {
+    let addr = "127.0.0.1:4051";
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path()).unwrap();
+    for i in 0..1500 {
+        store
+            .set(format!("key{:04}", i), "value".to_owned())
+            .unwrap();
+    }
+    store.set("changed".to_owned(), "old".to_owned()).unwrap();
+    store.set("stale".to_owned(), "value".to_owned()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let backup_dir = TempDir::new().unwrap();
+    let mut backup = KvStore::open(backup_dir.path()).unwrap();
+    for i in 0..1500 {
+        backup
+            .set(format!("key{:04}", i), "value".to_owned())
+            .unwrap();
+    }
+    backup.set("changed".to_owned(), "new".to_owned()).unwrap();
+    backup
+        .rpush("fresh".to_owned(), vec!["a".to_owned()])
+        .unwrap();
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let report = diff::diff(&mut client, &mut backup).unwrap();
+    assert_eq!(report.matched, 1500);
+    let keys = |sums: &[diff::KeySum]| sums.iter().map(|s| s.key.clone()).collect::<Vec<_>>();
+    assert_eq!(keys(&report.only_left), vec!["stale"]);
+    assert_eq!(keys(&report.only_right), vec!["fresh"]);
+    assert_eq!(report.mismatched.len(), 1);
+    assert_eq!(report.mismatched[0].key, "changed");
+    assert_ne!(report.mismatched[0].left, report.mismatched[0].right);
+    assert!(!report.is_empty());
+}
+
+// Pages of a scan resume after the cursor in the key order of the store,
+// neither repeating nor skipping keys, and a diff against a store listing
+// its keys in another order still matches them up
+#[test]
+fn scan_pages_follow_key_order() {
+    let addr = "127.0.0.1:4072";
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_ordered(&[dir.path().to_owned()], KeyOrder::Natural).unwrap();
+    let bytewise_dir = TempDir::new().unwrap();
+    let mut bytewise = KvStore::open(bytewise_dir.path()).unwrap();
+    for i in [2, 10, 100, 7, 33] {
+        store.set(i.to_string(), "value".to_owned()).unwrap();
+        bytewise.set(i.to_string(), "value".to_owned()).unwrap();
+    }
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut pages = Vec::new();
+    let mut after = None;
+    loop {
+        let page = client.scan(after, 2).unwrap();
+        if page.is_empty() {
+            break;
+        }
+        after = page.last().cloned();
+        pages.push(page);
+    }
+    assert_eq!(pages, [vec!["2", "7"], vec!["10", "33"], vec!["100"]]);
+    assert_eq!(
+        client
+            .scan_matching("1*".to_owned(), Some("10".to_owned()), 10)
+            .unwrap(),
+        ["100"]
+    );
+
+    let report = diff::diff(&mut client, &mut bytewise).unwrap();
+    assert_eq!(report.matched, 5);
+    assert!(report.is_empty());
+}
+
+// Should report the size, type and write position of a key, and its write
+// time when the store records it
+#[test]
+fn meta_of_key() {
+    let addr = "127.0.0.1:4052";
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap().write_times(true);
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let before = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let write = client
+        .set_with_meta("key".to_owned(), "42".to_owned(), ValueType::Int)
+        .unwrap()
+        .unwrap();
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+
+    let meta = client.meta("key".to_owned()).unwrap().unwrap();
+    assert_eq!(meta.size, 2);
+    assert_eq!(meta.value_type, ValueType::Int);
+    assert_eq!(meta.ttl_ms, None);
+    assert!(meta.written_at.unwrap() >= before);
+    assert_eq!(meta.lsn, write.lsn);
+    assert_eq!(client.meta("missing".to_owned()).unwrap(), None);
+}
+
+// Should count the keys and their bytes by prefix, keys with fewer
+// components going to the shorter prefix
+#[test]
+fn usage_by_prefix() {
+    let addr = "127.0.0.1:4053";
+    let dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(dir.path()).unwrap();
+    store
+        .set("app1/users/1".to_owned(), "a".to_owned())
+        .unwrap();
+    store
+        .set("app1/users/2".to_owned(), "b".to_owned())
+        .unwrap();
+    store
+        .rpush("app1/orders/1".to_owned(), vec!["x".repeat(100)])
+        .unwrap();
+    store.set("app1/name".to_owned(), "c".to_owned()).unwrap();
+    store.set("plain".to_owned(), "d".to_owned()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let usage = client.usage("/".to_owned(), 2).unwrap();
+    let counts: Vec<(&str, u64)> = usage.iter().map(|u| (u.prefix.as_str(), u.keys)).collect();
+    assert_eq!(
+        counts,
+        vec![
+            ("", 1),
+            ("app1/", 1),
+            ("app1/orders/", 1),
+            ("app1/users/", 2)
+        ]
+    );
+    assert!(usage[2].bytes > 100);
+    assert!(usage[2].bytes > usage[3].bytes);
+
+    let usage = client.usage("/".to_owned(), 1).unwrap();
+    assert_eq!(usage[1].prefix, "app1/");
+    assert_eq!(usage[1].keys, 4);
+}
+
+// Should refuse requests on keys longer than `MAX_KEY_LEN`, reads included
+#[test]
+fn key_too_long() {
+    let addr = "127.0.0.1:4054";
+    let dir = TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let key = "k".repeat(MAX_KEY_LEN + 1);
+    assert!(matches!(
+        client.set(key.clone(), "value".to_owned()),
+        Err(KvsError::KeyTooLong { len, .. }) if len == MAX_KEY_LEN + 1
+    ));
+    assert!(matches!(client.get(key), Err(KvsError::KeyTooLong { .. })));
+    let key = "k".repeat(MAX_KEY_LEN);
+    client.set(key.clone(), "value".to_owned()).unwrap();
+    assert_eq!(client.get(key).unwrap(), Some("value".to_owned()));
+}
+
+#[cfg(unix)]
+#[test]
+fn admin_console() {
+    use std::os::unix::net::UnixStream;
+
+    let addr = "127.0.0.1:4056";
+    let dir = TempDir::new().unwrap();
+    let socket = dir.path().join("admin.sock");
+    let store = KvStore::open(dir.path()).unwrap();
+    let server = KvsServer::new(store).admin_socket(&socket);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    let mut console = UnixStream::connect(&socket).unwrap();
+    let mut lines = BufReader::new(console.try_clone().unwrap()).lines();
+    let mut command = |line: &str| {
+        writeln!(console, "{}", line).unwrap();
+        lines.next().unwrap().unwrap()
+    };
+    assert_eq!(command("readonly on"), "OK");
+    assert!(matches!(
+        client.set("key".to_owned(), "other".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(command("compact").starts_with("ERR"));
+    assert_eq!(command("readonly off"), "OK");
+    client.set("key".to_owned(), "other".to_owned()).unwrap();
+    assert_eq!(command("compact"), "OK");
+    assert_eq!(command("loglevel debug"), "OK");
+    assert_eq!(command("loglevel"), "DEBUG");
+    assert!(command("frobnicate").starts_with("ERR"));
+    assert_eq!(command("stats"), "connections: 1");
+    assert_eq!(lines.next().unwrap().unwrap(), "requests: 3");
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("other".to_owned())
+    );
+}
+
+#[test]
+fn scheduled_writes() {
+    let addr = "127.0.0.1:4057";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("gone".to_owned(), "value".to_owned()).unwrap();
+    let at = SystemTime::now() + Duration::from_millis(300);
+    client
+        .set_at("key".to_owned(), "value".to_owned(), at)
+        .unwrap();
+    client.remove_at("gone".to_owned(), at).unwrap();
+    assert_eq!(client.get("key".to_owned()).unwrap(), None);
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert_eq!(client.get("gone".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn export_since_lsn() {
+    let addr = "127.0.0.1:4058";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("a".to_owned(), "1".to_owned()).unwrap();
+    client.set("b".to_owned(), "1".to_owned()).unwrap();
+    let mut exported = Vec::new();
+    let since = client
+        .export(0, |key, dump| {
+            exported.push((key, dump.and_then(|dump| dump.value)));
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        exported,
+        vec![
+            ("a".to_owned(), Some("1".to_owned())),
+            ("b".to_owned(), Some("1".to_owned()))
+        ]
+    );
+
+    client.set("a".to_owned(), "2".to_owned()).unwrap();
+    client.remove("b".to_owned()).unwrap();
+    let mut exported = Vec::new();
+    client
+        .export(since, |key, dump| {
+            exported.push((key, dump.and_then(|dump| dump.value)));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(
+        exported,
+        vec![
+            ("a".to_owned(), Some("2".to_owned())),
+            ("b".to_owned(), None)
+        ]
+    );
+
+    // the connection stays usable after the callback fails
+    let result = client.export(0, |_, _| Err(KvsError::Other("stop".to_owned())));
+    assert!(matches!(result, Err(KvsError::Other(_))));
+    assert_eq!(client.get("a".to_owned()).unwrap(), Some("2".to_owned()));
+}
+
+#[test]
+fn trace_for_cache_sizing() {
+    let addr = "127.0.0.1:4059";
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let path = temp_dir.path().join("trace");
+    let server = KvsServer::new(store).trace(&path, 1.0);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("a".to_owned(), "0123456789".to_owned()).unwrap();
+    for _ in 0..3 {
+        client.get("a".to_owned()).unwrap();
+    }
+    client.get("missing".to_owned()).unwrap();
+    client.remove("a".to_owned()).unwrap();
+
+    let events = trace::read(&path).unwrap();
+    let a = trace::key_hash("a");
+    assert_eq!(
+        events,
+        vec![
+            TraceEvent::Start { sample_rate: 1.0 },
+            TraceEvent::Set { key: a, size: 10 },
+            TraceEvent::Get {
+                key: a,
+                size: Some(10)
+            },
+            TraceEvent::Get {
+                key: a,
+                size: Some(10)
+            },
+            TraceEvent::Get {
+                key: a,
+                size: Some(10)
+            },
+            TraceEvent::Get {
+                key: trace::key_hash("missing"),
+                size: None
+            },
+            TraceEvent::Remove { key: a },
+        ]
+    );
+
+    let report = trace::analyze(&events, &[5, 10]).unwrap();
+    assert_eq!((report.gets, report.keys, report.bytes), (3, 1, 10));
+    assert_eq!(
+        report.curve,
+        vec![
+            CachePoint {
+                cache_bytes: 5,
+                hit_rate: 0.0
+            },
+            CachePoint {
+                cache_bytes: 10,
+                hit_rate: 1.0
+            },
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn ping_reports_draining_on_shutdown() {
+    use std::os::unix::net::UnixStream;
+
+    let addr = "127.0.0.1:4060";
+    let dir = TempDir::new().unwrap();
+    let socket = dir.path().join("admin.sock");
+    let store = KvStore::open(dir.path()).unwrap();
+    let server = KvsServer::new(store)
+        .admin_socket(&socket)
+        .drain_delay(Duration::from_millis(500))
+        .drain_timeout(Duration::from_secs(5));
+    let (done, exited) = mpsc::channel();
+    thread::spawn(move || {
+        server.run(addr).unwrap();
+        done.send(()).unwrap();
+    });
+    thread::sleep(Duration::from_millis(300));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.ping().unwrap(), Health::Ok);
+
+    let mut console = UnixStream::connect(&socket).unwrap();
+    writeln!(console, "shutdown").unwrap();
+    let mut lines = BufReader::new(console).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "OK");
+
+    // still serving, and accepting connections until the delay is over
+    assert_eq!(client.ping().unwrap(), Health::Draining);
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    let mut late = KvsClient::connect(addr).unwrap();
+    assert_eq!(late.ping().unwrap(), Health::Draining);
+    assert_eq!(
+        late.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+
+    thread::sleep(Duration::from_millis(500));
+    assert!(exited.try_recv().is_err());
+    drop(client);
+    drop(late);
+    exited.recv_timeout(Duration::from_secs(2)).unwrap();
+}
+
+// Should answer with the part of a JSON value at a pointer only, and refuse
+// values which are not JSON
+#[test]
+fn get_json_field() {
+    let addr = "127.0.0.1:4061";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let user = r#"{"name":"ann","roles":["admin","dev"],"address":{"city":"Oslo"}}"#;
+    client
+        .set_with_type("user".to_owned(), user.to_owned(), ValueType::Json)
+        .unwrap();
+    client.set("plain".to_owned(), "text".to_owned()).unwrap();
+
+    let field = |client: &mut KvsClient, pointer: &str| {
+        client.get_field("user".to_owned(), pointer.to_owned())
+    };
+    assert_eq!(
+        field(&mut client, "/name").unwrap(),
+        Some(r#""ann""#.to_owned())
+    );
+    assert_eq!(
+        field(&mut client, "/roles/1").unwrap(),
+        Some(r#""dev""#.to_owned())
+    );
+    assert_eq!(
+        field(&mut client, "/address").unwrap(),
+        Some(r#"{"city":"Oslo"}"#.to_owned())
+    );
+    assert_eq!(field(&mut client, "").unwrap(), Some(user.to_owned()));
+    assert_eq!(field(&mut client, "/age").unwrap(), None);
+    assert!(field(&mut client, "name").is_err());
+    assert_eq!(
+        client
+            .get_field("missing".to_owned(), "/name".to_owned())
+            .unwrap(),
+        None
+    );
+    assert!(matches!(
+        client.get_field("plain".to_owned(), "/name".to_owned()),
+        Err(KvsError::InvalidValue(_))
+    ));
+}
+
+// Should get the values of several keys in one request
+#[test]
+fn get_many_keys() {
+    let addr = "127.0.0.1:4065";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("a".to_owned(), "1".to_owned()).unwrap();
+    client.set("c".to_owned(), "3".to_owned()).unwrap();
+    assert_eq!(
+        client
+            .get_many(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+            .unwrap(),
+        vec![Some("1".to_owned()), None, Some("3".to_owned())]
+    );
+    assert!(matches!(
+        client.get_many(vec!["a".to_owned(), "x".repeat(MAX_KEY_LEN + 1)]),
+        Err(KvsError::KeyTooLong { .. })
+    ));
+}
+
+// Should carry arbitrary bytes both ways
+#[test]
+fn bytes_round_trip() {
+    let addr = "127.0.0.1:4067";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let image = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0, 0xff];
+    client.set_bytes("image".to_owned(), image.clone()).unwrap();
+    assert_eq!(client.get_bytes("image".to_owned()).unwrap(), Some(image));
+    assert_eq!(client.get_bytes("missing".to_owned()).unwrap(), None);
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Profile {
+    name: String,
+    langs: Vec<String>,
+    address: Address,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Address {
+    city: String,
+    zip: Option<u32>,
+}
+
+// Should store serializable values as JSON through the server
+#[test]
+fn typed_round_trip() {
+    let addr = "127.0.0.1:4069";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let profile = Profile {
+        name: "ada".to_owned(),
+        langs: vec!["rust".to_owned(), "ocaml".to_owned()],
+        address: Address {
+            city: "london".to_owned(),
+            zip: None,
+        },
+    };
+    client.set_typed("profile".to_owned(), &profile).unwrap();
+    assert_eq!(
+        client
+            .get_field("profile".to_owned(), "/address/city".to_owned())
+            .unwrap(),
+        Some("\"london\"".to_owned())
+    );
+    assert_eq!(
+        client.get_typed("profile".to_owned()).unwrap(),
+        Some(profile)
+    );
+    assert_eq!(
+        client.get_typed::<Profile>("missing".to_owned()).unwrap(),
+        None
+    );
+}
+
+// Should compact the data on request, keeping every key
+#[test]
+fn compact_on_request() {
+    let addr = "127.0.0.1:4070";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..100 {
+        client.set("key".to_owned(), i.to_string()).unwrap();
+    }
+    let before = client.stats().unwrap().engine;
+    assert!(before["uncompacted_bytes"] > 0);
+    client.compact().unwrap();
+    let after = client.stats().unwrap().engine;
+    assert_eq!(after["compactions"], before["compactions"] + 1);
+    assert_eq!(after["uncompacted_bytes"], 0);
+    assert_eq!(client.get("key".to_owned()).unwrap(), Some("99".to_owned()));
+}
+
+// Should list the keys under a prefix only
+#[test]
+fn scan_by_prefix() {
+    let addr = "127.0.0.1:4062";
+    let _dir = start_server(addr);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for key in ["user:1:name", "user:1:mail", "user:2:name", "user:10"] {
+        client.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+    assert_eq!(
+        client.scan_prefix("user:1:".to_owned()).unwrap(),
+        vec!["user:1:mail", "user:1:name"]
+    );
+    assert!(client.scan_prefix("item:".to_owned()).unwrap().is_empty());
+}
+
+// Should record the latency and outcome of the requests of a client, by
+// operation, shared by every client built with the same metrics
+#[test]
+fn client_metrics() {
+    let addr = "127.0.0.1:4071";
+    let _dir = start_server(addr);
+
+    let metrics = ClientMetrics::new();
+    let builder = KvsClient::builder().metrics(metrics.clone());
+    let mut client = builder.connect(addr).unwrap();
+    let mut other = builder.connect(addr).unwrap();
+    assert!(KvsClient::connect(addr).unwrap().metrics().is_none());
+
+    for i in 0..10 {
+        client.set(format!("key{}", i), i.to_string()).unwrap();
+        other.get(format!("key{}", i)).unwrap();
+    }
+    client.get("missing".to_owned()).unwrap();
+    assert!(client
+        .set("k".repeat(MAX_KEY_LEN + 1), "value".to_owned())
+        .is_err());
+
+    let ops = client.metrics().unwrap().ops();
+    let get = &ops["Get"];
+    assert_eq!(get.count, 11);
+    assert_eq!(get.errors, 0);
+    assert_eq!(get.buckets.iter().sum::<u64>(), 11);
+    assert!(get.quantile(0.99).is_some());
+    assert!(get.mean().unwrap() <= get.quantile(1.0).unwrap());
+    assert_eq!((ops["Set"].count, ops["Set"].errors), (11, 1));
+    assert_eq!(metrics.reconnects(), 0);
+
+    #[cfg(feature = "prometheus")]
+    {
+        let registry = prometheus::Registry::new();
+        registry.register(Box::new(metrics.clone())).unwrap();
+        let families = registry.gather();
+        let latency = families
+            .iter()
+            .find(|family| family.name() == "kvs_client_request_duration_seconds")
+            .unwrap();
+        let count: u64 = latency
+            .get_metric()
+            .iter()
+            .map(|metric| metric.get_histogram().get_sample_count())
+            .sum();
+        assert_eq!(count, 22);
+    }
+}